@@ -51,9 +51,12 @@ pub fn main() -> anyhow::Result<()> {
     let model = candle_onnx::read_file(model)?;
     let graph = model.graph.as_ref().unwrap();
     let mut inputs = std::collections::HashMap::new();
-    inputs.insert(graph.input[0].name.to_string(), image.unsqueeze(0)?);
+    inputs.insert(graph.input[0].name.to_string(), image.unsqueeze(0)?.into());
     let mut outputs = candle_onnx::simple_eval(&model, inputs)?;
-    let output = outputs.remove(&graph.output[0].name).unwrap();
+    let output = outputs
+        .remove(&graph.output[0].name)
+        .unwrap()
+        .into_tensor()?;
     let prs = match args.which {
         Which::SqueezeNet => candle_nn::ops::softmax(&output, D::Minus1)?,
         Which::EfficientNet => output,
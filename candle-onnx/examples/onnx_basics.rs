@@ -75,7 +75,7 @@ pub fn main() -> Result<()> {
                     type_ => anyhow::bail!("unsupported input type {type_:?}"),
                 };
                 println!("input {}: {value:?}", input.name);
-                inputs.insert(input.name.clone(), value);
+                inputs.insert(input.name.clone(), value.into());
             }
             let outputs = candle_onnx::simple_eval(&model, inputs)?;
             for (name, value) in outputs.iter() {
@@ -0,0 +1,201 @@
+use anyhow::Result;
+use candle::{Device, Tensor};
+use candle_onnx::onnx::{
+    attribute_proto::AttributeType, tensor_proto::DataType, AttributeProto, GraphProto, ModelProto,
+    NodeProto, TensorProto, ValueInfoProto,
+};
+use std::collections::HashMap;
+
+use clap::{Parser, Subcommand};
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// A chain of Add nodes over large Constant nodes, evaluated through simple_eval and through
+    /// a Model that decodes the constants once.
+    Constants {
+        #[arg(long, default_value_t = 64)]
+        nodes: usize,
+
+        #[arg(long, default_value_t = 512)]
+        dim: usize,
+    },
+    /// ScatterND updating whole rows of a large data tensor, with and without the add reduction.
+    Scatter {
+        #[arg(long, default_value_t = 4096)]
+        rows: usize,
+
+        #[arg(long, default_value_t = 1024)]
+        cols: usize,
+
+        /// The number of rows that get updated.
+        #[arg(long, default_value_t = 256)]
+        updates: usize,
+    },
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Command,
+
+    /// The number of evaluations to time.
+    #[arg(long, default_value_t = 20)]
+    iters: usize,
+}
+
+fn value_info(name: &str) -> ValueInfoProto {
+    ValueInfoProto {
+        name: name.to_string(),
+        ..ValueInfoProto::default()
+    }
+}
+
+fn node(op_type: &str, inputs: &[&str], output: &str) -> NodeProto {
+    NodeProto {
+        op_type: op_type.to_string(),
+        name: output.to_string(),
+        input: inputs.iter().map(|s| s.to_string()).collect(),
+        output: vec![output.to_string()],
+        ..NodeProto::default()
+    }
+}
+
+fn model(graph: GraphProto) -> ModelProto {
+    ModelProto {
+        graph: Some(graph),
+        ..ModelProto::default()
+    }
+}
+
+fn constants_model(nodes: usize, dim: usize) -> ModelProto {
+    let nodes = nodes.max(1);
+    let mut graph_nodes = vec![];
+    let mut prev = "x".to_string();
+    for i in 0..nodes {
+        let data = (0..dim * dim)
+            .flat_map(|j| ((i + j) as f32 * 1e-3).to_le_bytes())
+            .collect();
+        let value = TensorProto {
+            dims: vec![dim as i64, dim as i64],
+            data_type: DataType::Float.into(),
+            raw_data: data,
+            ..TensorProto::default()
+        };
+        let c = format!("c{i}");
+        // The last Add produces the graph output.
+        let out = if i + 1 == nodes {
+            "z".to_string()
+        } else {
+            format!("y{i}")
+        };
+        graph_nodes.push(NodeProto {
+            attribute: vec![AttributeProto {
+                name: "value".to_string(),
+                r#type: AttributeType::Tensor.into(),
+                t: Some(value),
+                ..AttributeProto::default()
+            }],
+            ..node("Constant", &[], &c)
+        });
+        graph_nodes.push(node("Add", &[&prev, &c], &out));
+        prev = out;
+    }
+    model(GraphProto {
+        node: graph_nodes,
+        input: vec![value_info("x")],
+        output: vec![value_info("z")],
+        ..GraphProto::default()
+    })
+}
+
+fn scatter_model(reduction: &str) -> ModelProto {
+    let reduction = AttributeProto {
+        name: "reduction".to_string(),
+        r#type: AttributeType::String.into(),
+        s: reduction.as_bytes().to_vec(),
+        ..AttributeProto::default()
+    };
+    model(GraphProto {
+        node: vec![NodeProto {
+            attribute: vec![reduction],
+            ..node("ScatterND", &["data", "indices", "updates"], "z")
+        }],
+        input: vec![
+            value_info("data"),
+            value_info("indices"),
+            value_info("updates"),
+        ],
+        output: vec![value_info("z")],
+        ..GraphProto::default()
+    })
+}
+
+fn bench(name: &str, iters: usize, mut f: impl FnMut() -> Result<()>) -> Result<()> {
+    // One untimed run so that the first allocations are not part of the measure.
+    f()?;
+    let start = std::time::Instant::now();
+    for _ in 0..iters {
+        f()?;
+    }
+    let per_iter = start.elapsed() / iters as u32;
+    println!("{name:12} {per_iter:?} per evaluation");
+    Ok(())
+}
+
+pub fn main() -> Result<()> {
+    let args = Args::parse();
+    let iters = args.iters.max(1);
+    match args.command {
+        Command::Constants { nodes, dim } => {
+            let proto = constants_model(nodes, dim);
+            let mut inputs = HashMap::new();
+            inputs.insert(
+                "x".to_string(),
+                Tensor::zeros((dim, dim), candle::DType::F32, &Device::Cpu)?.into(),
+            );
+            bench("simple_eval", iters, || {
+                candle_onnx::simple_eval(&proto, inputs.clone())?;
+                Ok(())
+            })?;
+            let model = candle_onnx::Model::new(proto.clone())?;
+            bench("Model::eval", iters, || {
+                model.eval(inputs.clone())?;
+                Ok(())
+            })?;
+        }
+        Command::Scatter {
+            rows,
+            cols,
+            updates,
+        } => {
+            let dev = &Device::Cpu;
+            // Distinct rows spread over data so that the none reduction has no duplicates.
+            let step = (rows / updates.max(1)).max(1) as i64;
+            let indices = (0..updates as i64)
+                .map(|i| (i * step) % rows as i64)
+                .collect::<Vec<_>>();
+            let mut inputs = HashMap::new();
+            inputs.insert(
+                "data".to_string(),
+                Tensor::ones((rows, cols), candle::DType::F32, dev)?.into(),
+            );
+            inputs.insert(
+                "indices".to_string(),
+                Tensor::from_vec(indices, (updates, 1), dev)?.into(),
+            );
+            inputs.insert(
+                "updates".to_string(),
+                Tensor::ones((updates, cols), candle::DType::F32, dev)?.into(),
+            );
+            for reduction in ["none", "add"] {
+                let model = candle_onnx::Model::new(scatter_model(reduction))?;
+                bench(reduction, iters, || {
+                    model.eval(inputs.clone())?;
+                    Ok(())
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,10 +1,76 @@
 use crate::onnx;
 use crate::onnx::attribute_proto::AttributeType;
 use crate::onnx::tensor_proto::DataType;
-use candle::{bail, DType, Device, Result, Tensor};
-use std::collections::HashMap;
+use candle::{bail, DType, Device, Result, Tensor, WithDType};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-pub type Value = Tensor;
+/// The values manipulated by the evaluation, most ops only operate on tensors.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Tensor(Tensor),
+    /// An ordered list of values, all the elements are expected to share the same type.
+    Sequence(Vec<Value>),
+    /// A map from integer or string keys to values, as produced by some ml ops.
+    Map(BTreeMap<MapKey, Value>),
+    /// An optional value as introduced in opset 15, `None` when the value is absent.
+    Optional(Option<Box<Value>>),
+}
+
+/// The key type for map values, ONNX only allows for integral and string keys.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MapKey {
+    Int(i64),
+    String(String),
+}
+
+impl Value {
+    pub fn as_tensor(&self) -> Result<&Tensor> {
+        match self {
+            Self::Tensor(t) => Ok(t),
+            _ => bail!("expected a tensor value, got {self:?}"),
+        }
+    }
+
+    pub fn into_tensor(self) -> Result<Tensor> {
+        match self {
+            Self::Tensor(t) => Ok(t),
+            _ => bail!("expected a tensor value, got {self:?}"),
+        }
+    }
+
+    pub fn as_sequence(&self) -> Result<&[Value]> {
+        match self {
+            Self::Sequence(vs) => Ok(vs),
+            _ => bail!("expected a sequence value, got {self:?}"),
+        }
+    }
+
+    pub fn into_sequence(self) -> Result<Vec<Value>> {
+        match self {
+            Self::Sequence(vs) => Ok(vs),
+            _ => bail!("expected a sequence value, got {self:?}"),
+        }
+    }
+
+    pub fn as_map(&self) -> Result<&BTreeMap<MapKey, Value>> {
+        match self {
+            Self::Map(m) => Ok(m),
+            _ => bail!("expected a map value, got {self:?}"),
+        }
+    }
+}
+
+impl From<Tensor> for Value {
+    fn from(t: Tensor) -> Self {
+        Self::Tensor(t)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(vs: Vec<Value>) -> Self {
+        Self::Sequence(vs)
+    }
+}
 
 pub fn dtype(dt: DataType) -> Option<DType> {
     match dt {
@@ -44,6 +110,30 @@ impl Attr for [i64] {
     }
 }
 
+impl Attr for [f32] {
+    const TYPE: AttributeType = AttributeType::Floats;
+    fn get(attr: &onnx::AttributeProto) -> Result<&Self> {
+        Ok(attr.floats.as_slice())
+    }
+}
+
+impl Attr for [Vec<u8>] {
+    const TYPE: AttributeType = AttributeType::Strings;
+    fn get(attr: &onnx::AttributeProto) -> Result<&Self> {
+        Ok(attr.strings.as_slice())
+    }
+}
+
+impl Attr for onnx::GraphProto {
+    const TYPE: AttributeType = AttributeType::Graph;
+    fn get(attr: &onnx::AttributeProto) -> Result<&Self> {
+        match &attr.g {
+            Some(g) => Ok(g),
+            None => bail!("no graph set for attribute {}", attr.name),
+        }
+    }
+}
+
 impl Attr for str {
     const TYPE: AttributeType = AttributeType::String;
     fn get(attr: &onnx::AttributeProto) -> Result<&Self> {
@@ -60,6 +150,12 @@ fn get_attr_<'a>(node: &'a onnx::NodeProto, name: &str) -> Result<&'a onnx::Attr
                 node.name
             )
         }
+        Some(attr) if !attr.ref_attr_name.is_empty() => bail!(
+            "unresolved reference to '{}' for the '{name}' attribute in '{}' for {}",
+            attr.ref_attr_name,
+            node.op_type,
+            node.name
+        ),
         Some(dt) => Ok(dt),
     }
 }
@@ -84,6 +180,14 @@ fn get_attr_opt<'a, T: Attr + ?Sized>(
     match node.attribute.iter().find(|attr| attr.name == name) {
         None => Ok(None),
         Some(attr) => {
+            if !attr.ref_attr_name.is_empty() {
+                bail!(
+                    "unresolved reference to '{}' for the '{name}' attribute in '{}' for {}",
+                    attr.ref_attr_name,
+                    node.op_type,
+                    node.name
+                )
+            }
             if attr.r#type() != T::TYPE {
                 bail!(
                     "unsupported type {:?} for '{name}' attribute in '{}' for {}",
@@ -98,9 +202,97 @@ fn get_attr_opt<'a, T: Attr + ?Sized>(
     }
 }
 
-fn get_tensor(t: &onnx::TensorProto, name: &str) -> Result<Tensor> {
+/// Attributes of the nodes in a function body can refer to the attributes of the calling node
+/// using `ref_attr_name`. This returns a copy of the node where these references are replaced by
+/// the bound attributes, references with no binding are dropped so that the op defaults apply.
+pub fn bind_attributes(
+    node: &onnx::NodeProto,
+    bindings: &HashMap<String, onnx::AttributeProto>,
+) -> onnx::NodeProto {
+    let attribute = node
+        .attribute
+        .iter()
+        .filter_map(|attr| {
+            if attr.ref_attr_name.is_empty() {
+                return Some(attr.clone());
+            }
+            bindings
+                .get(&attr.ref_attr_name)
+                .map(|bound| onnx::AttributeProto {
+                    name: attr.name.clone(),
+                    ..bound.clone()
+                })
+        })
+        .collect();
+    onnx::NodeProto {
+        attribute,
+        ..node.clone()
+    }
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Constant
+fn constant_tensor(node: &onnx::NodeProto) -> Result<Tensor> {
+    let value = match node.attribute.iter().find(|attr| attr.name == "value") {
+        None => {
+            // TODO: support sparse_value etc.
+            bail!("cannot find 'value' attr in 'Constant' for {}", node.name)
+        }
+        Some(value) => value,
+    };
+    match value.r#type() {
+        AttributeType::Tensor => {
+            let t = value.t.as_ref().unwrap();
+            get_tensor(t, &node.name)
+        }
+        rtype => bail!("unsupported 'value' type {rtype:?} for {}", node.name),
+    }
+}
+
+/// Decodes the outputs of the Constant nodes of the main graph, as well as the initializers unless
+/// they are loaded lazily. These do not depend on the inputs so they can be reused across
+/// evaluations.
+pub(crate) fn decode_constants(
+    graph: &onnx::GraphProto,
+    lazy_initializers: bool,
+) -> Result<HashMap<String, Value>> {
+    let mut constants = HashMap::new();
+    if !lazy_initializers {
+        for t in graph.initializer.iter() {
+            let tensor = get_tensor(t, t.name.as_str())?;
+            constants.insert(t.name.to_string(), tensor.into());
+        }
+    }
+    for node in graph.node.iter() {
+        if node.op_type == "Constant" && node.domain.is_empty() {
+            let tensor = constant_tensor(node)?;
+            constants.insert(node.output[0].clone(), tensor.into());
+        }
+    }
+    Ok(constants)
+}
+
+pub(crate) fn get_tensor(t: &onnx::TensorProto, name: &str) -> Result<Tensor> {
     let dims: Vec<usize> = t.dims.iter().map(|&x| x as usize).collect();
     match DataType::try_from(t.data_type) {
+        // There is no i32 dtype in candle, these values are widened to i64.
+        Ok(DataType::Int32) => {
+            let vs = if !t.int32_data.is_empty() {
+                t.int32_data.iter().map(|&v| v as i64).collect::<Vec<_>>()
+            } else {
+                let elem_count = dims.iter().product::<usize>();
+                if t.raw_data.len() != elem_count * 4 {
+                    bail!(
+                        "unexpected raw_data size {} for {name}, expected {elem_count} int32 values",
+                        t.raw_data.len()
+                    )
+                }
+                t.raw_data
+                    .chunks(4)
+                    .map(|c| i32::from_le_bytes([c[0], c[1], c[2], c[3]]) as i64)
+                    .collect()
+            };
+            Tensor::from_vec(vs, dims.as_slice(), &Device::Cpu)
+        }
         Ok(dt) => match dtype(dt) {
             Some(dt) => {
                 if dt == DType::F32 && !t.float_data.is_empty() {
@@ -110,12 +302,26 @@ fn get_tensor(t: &onnx::TensorProto, name: &str) -> Result<Tensor> {
                 } else if dt == DType::I64 && !t.int64_data.is_empty() {
                     Tensor::from_slice(&t.int64_data, dims.as_slice(), &Device::Cpu)
                 } else {
-                    Tensor::from_raw_buffer(
-                        t.raw_data.as_slice(),
-                        dt,
-                        dims.as_slice(),
-                        &Device::Cpu,
-                    )
+                    let elem_count = dims.iter().product::<usize>();
+                    if t.raw_data.len() != elem_count * dt.size_in_bytes() {
+                        bail!(
+                            "unexpected raw_data size {} for {name}, expected {elem_count} {dt:?} values",
+                            t.raw_data.len()
+                        )
+                    }
+                    // The raw data is always stored in little-endian order whereas
+                    // from_raw_buffer reinterprets the bytes using the native endianness.
+                    let raw_data = if cfg!(target_endian = "big") {
+                        std::borrow::Cow::Owned(
+                            t.raw_data
+                                .chunks(dt.size_in_bytes())
+                                .flat_map(|c| c.iter().rev().copied())
+                                .collect::<Vec<u8>>(),
+                        )
+                    } else {
+                        std::borrow::Cow::Borrowed(t.raw_data.as_slice())
+                    };
+                    Tensor::from_raw_buffer(&raw_data, dt, dims.as_slice(), &Device::Cpu)
                 }
             }
             None => {
@@ -128,180 +334,2923 @@ fn get_tensor(t: &onnx::TensorProto, name: &str) -> Result<Tensor> {
     }
 }
 
-// This function provides a direct evaluation of the proto.
-// Longer-term, we should first convert the proto to an intermediate representation of the compute
-// graph so as to make multiple evaluations more efficient.
-// An example upside of this would be to remove intermediary values when they are not needed
-// anymore.
-pub fn simple_eval(
-    model: &onnx::ModelProto,
-    inputs: HashMap<String, Value>,
-) -> Result<HashMap<String, Value>> {
-    let graph = match &model.graph {
-        None => bail!("no graph defined in proto"),
-        Some(graph) => graph,
+fn bitwise_binary(
+    lhs: &Tensor,
+    rhs: &Tensor,
+    op_name: &'static str,
+    f: impl Fn(i64, i64) -> i64,
+) -> Result<Tensor> {
+    let dtype = lhs.dtype();
+    if !dtype.is_int() || rhs.dtype() != dtype {
+        bail!(
+            "{op_name} expects integer inputs with the same dtype, got {dtype:?} and {:?}",
+            rhs.dtype()
+        )
+    }
+    let shape = lhs
+        .shape()
+        .broadcast_shape_binary_op(rhs.shape(), op_name)?;
+    // The integer dtypes all fit in an i64 so the ops are applied there, casting back to the
+    // original dtype truncates the result as expected.
+    let lhs_vs = lhs
+        .broadcast_as(&shape)?
+        .to_dtype(DType::I64)?
+        .flatten_all()?
+        .to_vec1::<i64>()?;
+    let rhs_vs = rhs
+        .broadcast_as(&shape)?
+        .to_dtype(DType::I64)?
+        .flatten_all()?
+        .to_vec1::<i64>()?;
+    let vs = lhs_vs
+        .iter()
+        .zip(rhs_vs.iter())
+        .map(|(&l, &r)| f(l, r))
+        .collect::<Vec<_>>();
+    Tensor::from_vec(vs, shape, lhs.device())?.to_dtype(dtype)
+}
+
+// Reflects a coordinate into the [x_min, x_max] range, used by the "reflection" padding mode.
+fn reflect_coordinate(x: f64, x_min: f64, x_max: f64) -> f64 {
+    let range = x_max - x_min;
+    if range <= 0. {
+        return x_min;
+    }
+    if x < x_min {
+        let dx = x_min - x;
+        let n = (dx / range) as i64;
+        let r = dx - n as f64 * range;
+        if n % 2 == 0 {
+            x_min + r
+        } else {
+            x_max - r
+        }
+    } else if x > x_max {
+        let dx = x - x_max;
+        let n = (dx / range) as i64;
+        let r = dx - n as f64 * range;
+        if n % 2 == 0 {
+            x_max - r
+        } else {
+            x_min + r
+        }
+    } else {
+        x
+    }
+}
+
+// Rounds half-way cases to the nearest even value, matching `std::nearbyint`.
+fn round_half_to_even(x: f64) -> f64 {
+    let r = x.round();
+    if (x - x.trunc()).abs() == 0.5 {
+        2.0 * (x / 2.0).round()
+    } else {
+        r
+    }
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#AffineGrid
+fn affine_grid(theta: &Tensor, size: &[usize], align_corners: bool) -> Result<Tensor> {
+    let (b_sz, n_out, n_in) = theta.dims3()?;
+    let spatial = match size {
+        [b, _c, spatial @ ..] if *b == b_sz && spatial.len() == n_out && n_in == n_out + 1 => {
+            spatial
+        }
+        _ => bail!(
+            "unexpected size {size:?} for theta {:?} in AffineGrid",
+            theta.shape()
+        ),
     };
-    let mut values = inputs;
-    for t in graph.initializer.iter() {
-        let tensor = get_tensor(t, t.name.as_str())?;
-        values.insert(t.name.to_string(), tensor);
+    // Normalized coordinates in [-1, 1], either of the corner pixels or of the pixel borders.
+    let coords = |n: usize| -> Vec<f64> {
+        (0..n)
+            .map(|i| {
+                if align_corners {
+                    if n == 1 {
+                        -1.
+                    } else {
+                        -1. + 2. * i as f64 / (n - 1) as f64
+                    }
+                } else {
+                    (2 * i + 1) as f64 / n as f64 - 1.
+                }
+            })
+            .collect()
+    };
+    let coords = spatial.iter().map(|&n| coords(n)).collect::<Vec<_>>();
+    // The base grid holds the homogeneous coordinates (x, y[, z], 1), x being the innermost dim.
+    let n_points = spatial.iter().product::<usize>();
+    let mut base = Vec::with_capacity(n_points * n_in);
+    for point in 0..n_points {
+        let mut rem = point;
+        let mut point_coords = vec![0.; n_out];
+        for (d, &n) in spatial.iter().enumerate().rev() {
+            point_coords[n_out - 1 - d] = coords[d][rem % n];
+            rem /= n;
+        }
+        base.extend(point_coords);
+        base.push(1.)
     }
-    for input in graph.input.iter() {
-        let input_type = match &input.r#type {
-            Some(input_type) => input_type,
-            None => continue,
-        };
-        let input_type = match &input_type.value {
-            Some(input_type) => input_type,
-            None => continue,
-        };
-        let tensor_type = match input_type {
-            onnx::type_proto::Value::TensorType(tt) => tt,
-            _ => continue,
-        };
+    let base = Tensor::from_vec(base, (n_points, n_in), theta.device())?.to_dtype(theta.dtype())?;
+    let mut grid_dims = vec![b_sz];
+    grid_dims.extend_from_slice(spatial);
+    grid_dims.push(n_out);
+    base.broadcast_matmul(&theta.transpose(1, 2)?)?
+        .reshape(grid_dims)
+}
 
-        let tensor = match values.get(&input.name) {
-            None => bail!("missing input {}", input.name),
-            Some(tensor) => tensor,
-        };
-        let dt = match DataType::try_from(tensor_type.elem_type) {
-            Ok(dt) => match dtype(dt) {
-                Some(dt) => dt,
-                None => {
-                    bail!("unsupported 'value' data-type {dt:?} for {}", input.name)
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#GridSample
+fn grid_sample(
+    xs: &Tensor,
+    grid: &Tensor,
+    mode: &str,
+    padding_mode: &str,
+    align_corners: bool,
+) -> Result<Tensor> {
+    let (b_sz, c, h, w) = xs.dims4()?;
+    let (g_b_sz, h_out, w_out, two) = grid.dims4()?;
+    if g_b_sz != b_sz || two != 2 {
+        bail!(
+            "unexpected grid shape {:?} for input {:?} in GridSample",
+            grid.shape(),
+            xs.shape()
+        )
+    }
+    let bilinear = match mode {
+        "bilinear" | "linear" => true,
+        "nearest" => false,
+        mode => bail!("unsupported mode {mode} for GridSample"),
+    };
+    match padding_mode {
+        "zeros" | "border" | "reflection" => (),
+        p => bail!("unsupported padding_mode {p} for GridSample"),
+    }
+    let dtype = xs.dtype();
+    let xs_vs = xs.to_dtype(DType::F64)?.flatten_all()?.to_vec1::<f64>()?;
+    let grid_vs = grid.to_dtype(DType::F64)?.flatten_all()?.to_vec1::<f64>()?;
+    // The coordinates are normalized in [-1, 1], with -1 mapping to the center of the first
+    // pixel when align_corners is set and to its left edge otherwise.
+    let denormalize = |v: f64, size: usize| {
+        if align_corners {
+            (v + 1.) / 2. * (size as f64 - 1.)
+        } else {
+            ((v + 1.) * size as f64 - 1.) / 2.
+        }
+    };
+    let bounds = |size: usize| {
+        if align_corners {
+            (0., size as f64 - 1.)
+        } else {
+            (-0.5, size as f64 - 0.5)
+        }
+    };
+    let (x_min, x_max) = bounds(w);
+    let (y_min, y_max) = bounds(h);
+    let pixel_at = |plane: &[f64], y: i64, x: i64| -> f64 {
+        let (y, x) = match padding_mode {
+            "zeros" => {
+                if y < 0 || y >= h as i64 || x < 0 || x >= w as i64 {
+                    return 0.;
                 }
-            },
-            type_ => bail!("unsupported input type {type_:?}"),
+                (y, x)
+            }
+            "border" => (y.clamp(0, h as i64 - 1), x.clamp(0, w as i64 - 1)),
+            _ => {
+                let y = reflect_coordinate(y as f64, y_min, y_max) as i64;
+                let x = reflect_coordinate(x as f64, x_min, x_max) as i64;
+                (y.clamp(0, h as i64 - 1), x.clamp(0, w as i64 - 1))
+            }
         };
-        let shape = match &tensor_type.shape {
-            None => continue,
-            Some(shape) => shape
-                .dim
-                .iter()
-                .map(|dim| match dim.value.as_ref().expect("no dim value") {
-                    onnx::tensor_shape_proto::dimension::Value::DimValue(v) => Ok(*v as usize),
-                    onnx::tensor_shape_proto::dimension::Value::DimParam(_) => {
-                        bail!("DimParam is unsupported for input {}", input.name)
+        plane[y as usize * w + x as usize]
+    };
+    let mut ys = Vec::with_capacity(b_sz * c * h_out * w_out);
+    for b_idx in 0..b_sz {
+        for c_idx in 0..c {
+            let offset = (b_idx * c + c_idx) * h * w;
+            let plane = &xs_vs[offset..offset + h * w];
+            for i in 0..h_out * w_out {
+                let g_idx = (b_idx * h_out * w_out + i) * 2;
+                let mut x = denormalize(grid_vs[g_idx], w);
+                let mut y = denormalize(grid_vs[g_idx + 1], h);
+                match padding_mode {
+                    "border" => {
+                        x = x.clamp(0., w as f64 - 1.);
+                        y = y.clamp(0., h as f64 - 1.);
                     }
-                })
-                .collect::<Result<Vec<usize>>>()?,
-        };
-        if dt != tensor.dtype() {
-            bail!(
-                "unexpected dtype for {}, got {:?}, expected {dt:?}",
-                input.name,
-                tensor.dtype()
-            )
+                    "reflection" => {
+                        x = reflect_coordinate(x, x_min, x_max);
+                        y = reflect_coordinate(y, y_min, y_max);
+                    }
+                    _ => (),
+                }
+                let v = if bilinear {
+                    let x0 = x.floor();
+                    let y0 = y.floor();
+                    let dx = x - x0;
+                    let dy = y - y0;
+                    let (x0, y0) = (x0 as i64, y0 as i64);
+                    pixel_at(plane, y0, x0) * (1. - dx) * (1. - dy)
+                        + pixel_at(plane, y0, x0 + 1) * dx * (1. - dy)
+                        + pixel_at(plane, y0 + 1, x0) * (1. - dx) * dy
+                        + pixel_at(plane, y0 + 1, x0 + 1) * dx * dy
+                } else {
+                    let x = round_half_to_even(x) as i64;
+                    let y = round_half_to_even(y) as i64;
+                    pixel_at(plane, y, x)
+                };
+                ys.push(v)
+            }
         }
-        if shape.as_slice() != tensor.dims() {
-            bail!(
-                "unexpected shape for {}, got {:?}, expected {shape:?}",
-                input.name,
-                tensor.dims()
+    }
+    Tensor::from_vec(ys, (b_sz, c, h_out, w_out), xs.device())?.to_dtype(dtype)
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#NonMaxSuppression
+fn non_max_suppression(
+    boxes: &Tensor,
+    scores: &Tensor,
+    max_output_boxes_per_class: usize,
+    iou_threshold: f32,
+    score_threshold: Option<f32>,
+    center_point_box: bool,
+) -> Result<Tensor> {
+    let (b_sz, n_boxes, four) = boxes.dims3()?;
+    let (s_b_sz, _, s_n_boxes) = scores.dims3()?;
+    if four != 4 || s_b_sz != b_sz || s_n_boxes != n_boxes {
+        bail!(
+            "unexpected shapes in NonMaxSuppression, boxes {:?}, scores {:?}",
+            boxes.shape(),
+            scores.shape()
+        )
+    }
+    let boxes = boxes.to_dtype(DType::F32)?.to_vec3::<f32>()?;
+    let scores = scores.to_dtype(DType::F32)?.to_vec3::<f32>()?;
+    // Returns the boxes as (y_min, x_min, y_max, x_max).
+    let corners = |b: &[f32]| {
+        if center_point_box {
+            let (xc, yc, w, h) = (b[0], b[1], b[2], b[3]);
+            (yc - h / 2., xc - w / 2., yc + h / 2., xc + w / 2.)
+        } else {
+            (
+                b[0].min(b[2]),
+                b[1].min(b[3]),
+                b[0].max(b[2]),
+                b[1].max(b[3]),
             )
         }
-    }
-    // The nodes are topologically sorted so we can just process them in order.
-    for node in graph.node.iter() {
-        let get = |input_name: &str| match values.get(input_name) {
-            Some(value) => Ok(value),
-            None => bail!("cannot find {input_name} for op {}", node.name),
-        };
-        // TODO: Validate node.input for each operator.
-        match node.op_type.as_str() {
-            "Add" => {
-                let input0 = get(&node.input[0])?;
-                let input1 = get(&node.input[1])?;
-                let output = input0.broadcast_add(input1)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Sub" => {
-                let input0 = get(&node.input[0])?;
-                let input1 = get(&node.input[1])?;
-                let output = input0.broadcast_sub(input1)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Mul" => {
-                let input0 = get(&node.input[0])?;
-                let input1 = get(&node.input[1])?;
-                let output = input0.broadcast_mul(input1)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Div" => {
-                let input0 = get(&node.input[0])?;
-                let input1 = get(&node.input[1])?;
-                let output = input0.broadcast_div(input1)?;
-                values.insert(node.output[0].clone(), output);
-            }
-            "Equal" => {
-                let input0 = get(&node.input[0])?;
-                let input1 = get(&node.input[1])?;
-                let output = input0.eq(input1)?;
-                values.insert(node.output[0].clone(), output);
+    };
+    let iou = |b1: &[f32], b2: &[f32]| {
+        let (y1_min, x1_min, y1_max, x1_max) = corners(b1);
+        let (y2_min, x2_min, y2_max, x2_max) = corners(b2);
+        let area1 = (y1_max - y1_min) * (x1_max - x1_min);
+        let area2 = (y2_max - y2_min) * (x2_max - x2_min);
+        if area1 <= 0. || area2 <= 0. {
+            return 0.;
+        }
+        let inter_h = (y1_max.min(y2_max) - y1_min.max(y2_min)).max(0.);
+        let inter_w = (x1_max.min(x2_max) - x1_min.max(x2_min)).max(0.);
+        let inter = inter_h * inter_w;
+        inter / (area1 + area2 - inter)
+    };
+    let mut selected = vec![];
+    for (b_idx, (boxes, scores)) in boxes.iter().zip(scores.iter()).enumerate() {
+        for (c_idx, scores) in scores.iter().enumerate() {
+            let mut candidates = (0..n_boxes)
+                .filter(|&i| match score_threshold {
+                    None => true,
+                    Some(t) => scores[i] > t,
+                })
+                .collect::<Vec<_>>();
+            // The sort is stable so boxes with the same score are considered in index order.
+            candidates.sort_by(|&i, &j| scores[j].total_cmp(&scores[i]));
+            let mut kept: Vec<usize> = vec![];
+            for i in candidates {
+                if kept.len() >= max_output_boxes_per_class {
+                    break;
+                }
+                if kept
+                    .iter()
+                    .all(|&j| iou(&boxes[i], &boxes[j]) <= iou_threshold)
+                {
+                    kept.push(i)
+                }
             }
-            "MatMul" => {
-                let input0 = get(&node.input[0])?;
-                let input1 = get(&node.input[1])?;
-                let output = input0.broadcast_matmul(input1)?;
-                values.insert(node.output[0].clone(), output);
+            for i in kept {
+                selected.extend_from_slice(&[b_idx as i64, c_idx as i64, i as i64])
             }
-            "Reshape" => {
-                let input0 = get(&node.input[0])?;
-                let input1 = get(&node.input[1])?.to_vec1::<i64>()?;
-                // TODO: Check that there is at most a single -1 or 0, handle other neg values.
-                let mut other_than_minus1 = 1usize;
-                for &v in input1.iter() {
-                    if v != -1 && v != 0 {
-                        other_than_minus1 *= v as usize
+        }
+    }
+    let n_selected = selected.len() / 3;
+    Tensor::from_vec(selected, (n_selected, 3), &Device::Cpu)
+}
+
+struct RoiAlignConfig {
+    output_height: usize,
+    output_width: usize,
+    sampling_ratio: usize,
+    spatial_scale: f64,
+    max_mode: bool,
+    half_pixel: bool,
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#RoiAlign
+fn roi_align(
+    xs: &Tensor,
+    rois: &Tensor,
+    batch_indices: &Tensor,
+    cfg: &RoiAlignConfig,
+) -> Result<Tensor> {
+    let (b_sz, c, h, w) = xs.dims4()?;
+    let (num_rois, four) = rois.dims2()?;
+    if four != 4 || batch_indices.dims1()? != num_rois {
+        bail!(
+            "unexpected shapes in RoiAlign, rois {:?}, batch_indices {:?}",
+            rois.shape(),
+            batch_indices.shape()
+        )
+    }
+    let dtype = xs.dtype();
+    let xs_vs = xs.to_dtype(DType::F64)?.flatten_all()?.to_vec1::<f64>()?;
+    let rois = rois.to_dtype(DType::F64)?.to_vec2::<f64>()?;
+    let batch_indices = batch_indices.to_dtype(DType::I64)?.to_vec1::<i64>()?;
+    let (out_h, out_w) = (cfg.output_height, cfg.output_width);
+    // Returns the four bilinear interpolation weights and their flat positions within a plane,
+    // samples falling outside of the feature map have all their weights set to zero.
+    let bilinear = |y: f64, x: f64| -> [(f64, usize); 4] {
+        if y < -1. || y > h as f64 || x < -1. || x > w as f64 {
+            return [(0., 0); 4];
+        }
+        let (y, x) = (y.max(0.), x.max(0.));
+        let (y_low, y_high, y) = if y as usize >= h - 1 {
+            (h - 1, h - 1, (h - 1) as f64)
+        } else {
+            (y as usize, y as usize + 1, y)
+        };
+        let (x_low, x_high, x) = if x as usize >= w - 1 {
+            (w - 1, w - 1, (w - 1) as f64)
+        } else {
+            (x as usize, x as usize + 1, x)
+        };
+        let (ly, lx) = (y - y_low as f64, x - x_low as f64);
+        let (hy, hx) = (1. - ly, 1. - lx);
+        [
+            (hy * hx, y_low * w + x_low),
+            (hy * lx, y_low * w + x_high),
+            (ly * hx, y_high * w + x_low),
+            (ly * lx, y_high * w + x_high),
+        ]
+    };
+    let offset = if cfg.half_pixel { 0.5 } else { 0. };
+    let mut ys = Vec::with_capacity(num_rois * c * out_h * out_w);
+    for (roi, &b_idx) in rois.iter().zip(batch_indices.iter()) {
+        if b_idx < 0 || b_idx as usize >= b_sz {
+            bail!("invalid batch index {b_idx} in RoiAlign")
+        }
+        let start_w = roi[0] * cfg.spatial_scale - offset;
+        let start_h = roi[1] * cfg.spatial_scale - offset;
+        let mut roi_w = roi[2] * cfg.spatial_scale - offset - start_w;
+        let mut roi_h = roi[3] * cfg.spatial_scale - offset - start_h;
+        if !cfg.half_pixel {
+            roi_w = roi_w.max(1.);
+            roi_h = roi_h.max(1.);
+        }
+        let bin_h = roi_h / out_h as f64;
+        let bin_w = roi_w / out_w as f64;
+        let grid_h = if cfg.sampling_ratio > 0 {
+            cfg.sampling_ratio
+        } else {
+            bin_h.ceil() as usize
+        };
+        let grid_w = if cfg.sampling_ratio > 0 {
+            cfg.sampling_ratio
+        } else {
+            bin_w.ceil() as usize
+        };
+        let count = usize::max(grid_h * grid_w, 1) as f64;
+        for c_idx in 0..c {
+            let plane_offset = (b_idx as usize * c + c_idx) * h * w;
+            let plane = &xs_vs[plane_offset..plane_offset + h * w];
+            for ph in 0..out_h {
+                for pw in 0..out_w {
+                    let mut acc = if cfg.max_mode { f64::NEG_INFINITY } else { 0. };
+                    for iy in 0..grid_h {
+                        let y =
+                            start_h + ph as f64 * bin_h + (iy as f64 + 0.5) * bin_h / grid_h as f64;
+                        for ix in 0..grid_w {
+                            let x = start_w
+                                + pw as f64 * bin_w
+                                + (ix as f64 + 0.5) * bin_w / grid_w as f64;
+                            let weights = bilinear(y, x);
+                            if cfg.max_mode {
+                                for (wgt, pos) in weights {
+                                    acc = acc.max(wgt * plane[pos])
+                                }
+                            } else {
+                                acc += weights
+                                    .iter()
+                                    .map(|(wgt, pos)| wgt * plane[*pos])
+                                    .sum::<f64>()
+                            }
+                        }
                     }
+                    let v = if cfg.max_mode {
+                        if acc.is_finite() {
+                            acc
+                        } else {
+                            0.
+                        }
+                    } else {
+                        acc / count
+                    };
+                    ys.push(v)
                 }
-                let input1 = input1
-                    .iter()
-                    .enumerate()
-                    .map(|(idx, &v)| match v {
-                        -1 => Ok(input0.elem_count() / other_than_minus1),
-                        0 => input0.dim(idx),
-                        _ => Ok(v as usize),
-                    })
-                    .collect::<Result<Vec<usize>>>()?;
-                let output = input0.reshape(input1)?;
-                values.insert(node.output[0].clone(), output);
             }
-            "LogSoftmax" => {
-                let input = get(&node.input[0])?;
-                let output = match get_attr_opt::<i64>(node, "axis")? {
-                    None => candle_nn::ops::softmax_last_dim(input)?,
-                    Some(&axis) => {
-                        let num_axis = input.rank() as i64;
-                        let axis = if axis >= 0 {
-                            axis as usize
-                        } else if axis < -num_axis {
-                            bail!("wrong axis in concat {axis} for shape {:?}", input.shape())
-                        } else {
-                            (num_axis - axis) as usize
-                        };
-                        candle_nn::ops::log_softmax(input, axis)?
-                    }
-                };
-                values.insert(node.output[0].clone(), output);
+        }
+    }
+    Tensor::from_vec(ys, (num_rois, c, out_h, out_w), xs.device())?.to_dtype(dtype)
+}
+
+// Generates a 1d window of the given size, `f` is evaluated on 2 * pi * n / N where the denominator
+// N is the size for periodic windows and the size minus one for symmetric ones.
+fn window(size: i64, periodic: bool, dtype: DType, f: impl Fn(f64) -> f64) -> Result<Tensor> {
+    if size < 0 {
+        bail!("negative window size {size}")
+    }
+    let size = size as usize;
+    let denominator = if periodic {
+        size
+    } else {
+        size.saturating_sub(1)
+    };
+    let vs = (0..size)
+        .map(|n| {
+            if denominator == 0 {
+                f(0.)
+            } else {
+                f(2. * std::f64::consts::PI * n as f64 / denominator as f64)
             }
-            "Softmax" => {
+        })
+        .collect::<Vec<_>>();
+    Tensor::from_vec(vs, size, &Device::Cpu)?.to_dtype(dtype)
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Multinomial
+fn multinomial(logits: &Tensor, sample_size: usize, seed: Option<f32>) -> Result<Tensor> {
+    use rand::{distributions::Distribution, SeedableRng};
+
+    let (b_sz, _) = logits.dims2()?;
+    let mut rng = match seed {
+        Some(seed) => rand::rngs::StdRng::seed_from_u64(seed.to_bits() as u64),
+        None => rand::rngs::StdRng::from_entropy(),
+    };
+    // The inputs are unnormalized log-probabilities, the max is removed for numerical stability.
+    let logits = logits.to_dtype(DType::F64)?.to_vec2::<f64>()?;
+    let mut samples = Vec::with_capacity(b_sz * sample_size);
+    for row in logits.iter() {
+        let max = row.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let prs = row.iter().map(|v| (v - max).exp()).collect::<Vec<_>>();
+        let distr = rand::distributions::WeightedIndex::new(&prs).map_err(candle::Error::wrap)?;
+        for _ in 0..sample_size {
+            samples.push(distr.sample(&mut rng) as i64)
+        }
+    }
+    Tensor::from_vec(samples, (b_sz, sample_size), &Device::Cpu)
+}
+
+// A dropout mask where each element is kept, i.e. set to 1, with probability 1 - ratio.
+fn dropout_mask(shape: &candle::Shape, ratio: f64, seed: Option<i64>) -> Result<Tensor> {
+    use rand::{Rng, SeedableRng};
+
+    let mut rng = match seed {
+        Some(seed) => rand::rngs::StdRng::seed_from_u64(seed as u64),
+        None => rand::rngs::StdRng::from_entropy(),
+    };
+    let mask = (0..shape.elem_count())
+        .map(|_| u8::from(rng.gen::<f64>() >= ratio))
+        .collect::<Vec<_>>();
+    Tensor::from_vec(mask, shape, &Device::Cpu)
+}
+
+// Computes the Lp norm over the given dims, p = 2 uses a sum of squares so as to avoid powf.
+fn lp_norm_keepdim(xs: &Tensor, dims: &[usize], p: i64) -> Result<Tensor> {
+    lp_norm_with(xs, p, |xs| xs.sum_keepdim(dims))
+}
+
+// Computes the Lp norm where sum adds up the |x|^p values, e.g. over the pooling windows.
+fn lp_norm_with(xs: &Tensor, p: i64, sum: impl FnOnce(Tensor) -> Result<Tensor>) -> Result<Tensor> {
+    match p {
+        1 => sum(xs.abs()?),
+        2 => sum(xs.sqr()?)?.sqrt(),
+        p if p > 0 => sum(xs.abs()?.powf(p as f64)?)?.powf(1. / p as f64),
+        p => bail!("unsupported p {p} for the Lp norm"),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScatterReduction {
+    None,
+    Add,
+    Mul,
+    Min,
+    Max,
+}
+
+impl ScatterReduction {
+    fn from_node(node: &onnx::NodeProto) -> Result<Self> {
+        match get_attr_opt::<str>(node, "reduction")?.unwrap_or("none") {
+            "none" => Ok(Self::None),
+            "add" => Ok(Self::Add),
+            "mul" => Ok(Self::Mul),
+            "min" => Ok(Self::Min),
+            "max" => Ok(Self::Max),
+            r => bail!(
+                "unsupported reduction {r} for {} {}",
+                node.op_type,
+                node.name
+            ),
+        }
+    }
+
+    fn apply<T: WithDType>(&self, dst: T, src: T) -> T {
+        match self {
+            Self::None => src,
+            Self::Add => dst + src,
+            Self::Mul => dst * src,
+            Self::Min => {
+                if src < dst {
+                    src
+                } else {
+                    dst
+                }
+            }
+            Self::Max => {
+                if src > dst {
+                    src
+                } else {
+                    dst
+                }
+            }
+        }
+    }
+}
+
+fn scatter_flat_<T: WithDType>(
+    data: &Tensor,
+    offsets: &[usize],
+    updates: &Tensor,
+    reduction: ScatterReduction,
+) -> Result<Tensor> {
+    let mut vs = data.flatten_all()?.to_vec1::<T>()?;
+    let updates = updates.flatten_all()?.to_vec1::<T>()?;
+    for (&offset, &update) in offsets.iter().zip(updates.iter()) {
+        vs[offset] = reduction.apply(vs[offset], update)
+    }
+    Tensor::from_vec(vs, data.shape(), data.device())
+}
+
+// ScatterND, ScatterElements and MaxUnpool are lowered to a list of flat offsets in data, one per
+// element of updates, the updates are then combined with the data using the reduction.
+fn scatter_flat(
+    data: &Tensor,
+    offsets: &[usize],
+    updates: &Tensor,
+    reduction: ScatterReduction,
+) -> Result<Tensor> {
+    if offsets.len() != updates.elem_count() {
+        bail!(
+            "scatter has {} offsets for {} updates",
+            offsets.len(),
+            updates.elem_count()
+        )
+    }
+    let updates = updates.to_dtype(data.dtype())?;
+    match data.dtype() {
+        DType::U8 => scatter_flat_::<u8>(data, offsets, &updates, reduction),
+        DType::U32 => scatter_flat_::<u32>(data, offsets, &updates, reduction),
+        DType::I64 => scatter_flat_::<i64>(data, offsets, &updates, reduction),
+        DType::F32 => scatter_flat_::<f32>(data, offsets, &updates, reduction),
+        DType::F64 => scatter_flat_::<f64>(data, offsets, &updates, reduction),
+        dtype @ (DType::F16 | DType::BF16) => {
+            let data = data.to_dtype(DType::F32)?;
+            let updates = updates.to_dtype(DType::F32)?;
+            scatter_flat_::<f32>(&data, offsets, &updates, reduction)?.to_dtype(dtype)
+        }
+    }
+}
+
+fn contiguous_strides(dims: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; dims.len()];
+    for i in (0..dims.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * dims[i + 1]
+    }
+    strides
+}
+
+// Prefixes an error with the op and the shapes and dtypes of its inputs, the candle errors only
+// describe the tensors reaching the failing kernel which may have been reshaped by the op.
+fn inputs_context<'a>(
+    node: &'a onnx::NodeProto,
+    inputs: &'a [&'a Tensor],
+) -> impl FnOnce(candle::Error) -> candle::Error + 'a {
+    move |err| {
+        let inputs = inputs
+            .iter()
+            .map(|t| format!("{:?} {:?}", t.dims(), t.dtype()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        candle::Error::Msg(format!(
+            "{} {} with inputs {inputs}: {err}",
+            node.op_type, node.name
+        ))
+    }
+}
+
+// Normalizes a possibly negative index along a dimension of the given size.
+fn normalize_index(idx: i64, size: usize) -> Result<usize> {
+    let size = size as i64;
+    let normalized = if idx < 0 { idx + size } else { idx };
+    if normalized < 0 || normalized >= size {
+        bail!("index {idx} is out of bounds for a dimension of size {size}")
+    }
+    Ok(normalized as usize)
+}
+
+// The values of a 1D shape-like input such as the target shape of Reshape or Expand, these can
+// use any integer dtype.
+fn shape_values(t: &Tensor) -> Result<Vec<i64>> {
+    if !t.dtype().is_int() {
+        bail!("expected an integer shape tensor, got {:?}", t.dtype())
+    }
+    t.flatten_all()?.to_dtype(DType::I64)?.to_vec1::<i64>()
+}
+
+// Selects the slices of xs along axis, the axis dim is replaced by the dims of indices in the
+// output. Negative indices count from the end.
+fn gather(xs: &Tensor, indices: &Tensor, axis: usize) -> Result<Tensor> {
+    let size = xs.dim(axis)?;
+    let idx = indices
+        .flatten_all()?
+        .to_dtype(DType::I64)?
+        .to_vec1::<i64>()?
+        .into_iter()
+        .map(|i| normalize_index(i, size).map(|i| i as u32))
+        .collect::<Result<Vec<_>>>()?;
+    let idx = Tensor::from_vec(idx, indices.elem_count(), xs.device())?;
+    let mut dims = xs.dims()[..axis].to_vec();
+    dims.extend_from_slice(indices.dims());
+    dims.extend_from_slice(&xs.dims()[axis + 1..]);
+    xs.contiguous()?.index_select(&idx, axis)?.reshape(dims)
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#GatherElements
+fn gather_elements(xs: &Tensor, indices: &Tensor, axis: usize) -> Result<Tensor> {
+    if indices.rank() != xs.rank() {
+        bail!(
+            "GatherElements expects indices with the rank of data, got data {:?}, indices {:?}",
+            xs.shape(),
+            indices.shape()
+        )
+    }
+    // Along the other axes, indices can be smaller than data in which case only the leading
+    // part of data is used.
+    let mut xs = xs.clone();
+    for (d, (&x_dim, &i_dim)) in xs.dims().to_vec().iter().zip(indices.dims()).enumerate() {
+        if d == axis || i_dim == x_dim {
+            continue;
+        }
+        if i_dim > x_dim {
+            bail!(
+                "GatherElements indices {:?} do not fit in data {:?} on axis {d}",
+                indices.shape(),
+                xs.shape()
+            )
+        }
+        xs = xs.narrow(d, 0, i_dim)?
+    }
+    let size = xs.dim(axis)?;
+    let idx = indices
+        .flatten_all()?
+        .to_dtype(DType::I64)?
+        .to_vec1::<i64>()?
+        .into_iter()
+        .map(|i| normalize_index(i, size).map(|i| i as u32))
+        .collect::<Result<Vec<_>>>()?;
+    let idx = Tensor::from_vec(idx, indices.shape(), xs.device())?;
+    xs.contiguous()?.gather(&idx, axis)
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#GatherND
+fn gather_nd(data: &Tensor, indices: &Tensor, batch_dims: usize) -> Result<Tensor> {
+    let data_dims = data.dims();
+    let idx_dims = indices.dims();
+    let k = match idx_dims.last() {
+        None => bail!("GatherND expects indices with a rank of at least 1"),
+        Some(&k) => k,
+    };
+    if batch_dims >= idx_dims.len() || batch_dims + k > data_dims.len() {
+        bail!(
+            "GatherND incompatible shapes, data {data_dims:?}, indices {idx_dims:?}, \
+             batch_dims {batch_dims}"
+        )
+    }
+    // The leading batch dims of data and indices are iterated in lockstep.
+    if data_dims[..batch_dims] != idx_dims[..batch_dims] {
+        bail!("GatherND batch dims mismatch, data {data_dims:?}, indices {idx_dims:?}")
+    }
+    let batch_count = data_dims[..batch_dims].iter().product::<usize>();
+    let indexed_dims = &data_dims[batch_dims..batch_dims + k];
+    let indexed_count = indexed_dims.iter().product::<usize>();
+    let slice_size = data_dims[batch_dims + k..].iter().product::<usize>();
+    let n_per_batch = idx_dims[batch_dims..idx_dims.len() - 1]
+        .iter()
+        .product::<usize>();
+    let strides = contiguous_strides(indexed_dims);
+    let indices = indices
+        .to_dtype(DType::I64)?
+        .flatten_all()?
+        .to_vec1::<i64>()?;
+    // Each index tuple selects a row of data once reshaped to (batch * indexed, slice_size).
+    let mut rows = Vec::with_capacity(batch_count * n_per_batch);
+    for i in 0..batch_count * n_per_batch {
+        let mut row = (i / n_per_batch) * indexed_count;
+        for (d, &idx) in indices[i * k..(i + 1) * k].iter().enumerate() {
+            row += normalize_index(idx, indexed_dims[d])? * strides[d]
+        }
+        rows.push(row as u32)
+    }
+    let rows = Tensor::from_vec(rows, batch_count * n_per_batch, data.device())?;
+    let mut out_dims = idx_dims[..idx_dims.len() - 1].to_vec();
+    out_dims.extend_from_slice(&data_dims[batch_dims + k..]);
+    data.reshape((batch_count * indexed_count, slice_size))?
+        .index_select(&rows, 0)?
+        .reshape(out_dims)
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#ScatterND
+fn scatter_nd_offsets(data: &Tensor, indices: &Tensor) -> Result<Vec<usize>> {
+    let data_dims = data.dims();
+    let idx_rank = indices.rank();
+    if idx_rank == 0 {
+        bail!("ScatterND expects indices with a rank of at least 1")
+    }
+    let k = indices.dim(idx_rank - 1)?;
+    if k > data_dims.len() {
+        bail!(
+            "ScatterND indices last dim {k} is larger than the data rank {}",
+            data_dims.len()
+        )
+    }
+    let strides = contiguous_strides(data_dims);
+    let slice_size = data_dims[k..].iter().product::<usize>();
+    let indices = indices
+        .to_dtype(DType::I64)?
+        .flatten_all()?
+        .to_vec1::<i64>()?;
+    let mut offsets = Vec::with_capacity(indices.len() / usize::max(k, 1) * slice_size);
+    for index in indices.chunks(usize::max(k, 1)) {
+        let mut base = 0;
+        for (dim_idx, &idx) in index.iter().take(k).enumerate() {
+            base += normalize_index(idx, data_dims[dim_idx])? * strides[dim_idx]
+        }
+        offsets.extend(base..base + slice_size)
+    }
+    Ok(offsets)
+}
+
+// ScatterND with the none or add reductions, each index tuple selects a row of data once reshaped
+// to (indexed, slice_size) and whole rows are updated on the device of data. This avoids copying
+// data to the host and computing an offset per element as scatter_flat does.
+// Returns None when this does not apply, i.e. for the other reductions or for duplicate indices
+// without reduction.
+fn scatter_nd_rows(
+    data: &Tensor,
+    indices: &Tensor,
+    updates: &Tensor,
+    reduction: ScatterReduction,
+) -> Result<Option<Tensor>> {
+    let idx_rank = indices.rank();
+    if !matches!(reduction, ScatterReduction::None | ScatterReduction::Add) || idx_rank == 0 {
+        return Ok(None);
+    }
+    let data_dims = data.dims();
+    let k = indices.dim(idx_rank - 1)?;
+    if k == 0 || k > data_dims.len() {
+        return Ok(None);
+    }
+    let mut expected = indices.dims()[..idx_rank - 1].to_vec();
+    expected.extend_from_slice(&data_dims[k..]);
+    if updates.dims() != expected {
+        bail!(
+            "ScatterND expects updates of shape {expected:?}, got {:?}",
+            updates.shape()
+        )
+    }
+    let indexed_dims = &data_dims[..k];
+    let indexed = indexed_dims.iter().product::<usize>();
+    let slice_size = data_dims[k..].iter().product::<usize>();
+    let strides = contiguous_strides(indexed_dims);
+    let indices = indices
+        .to_dtype(DType::I64)?
+        .flatten_all()?
+        .to_vec1::<i64>()?;
+    let rows = indices
+        .chunks(k)
+        .map(|index| {
+            let mut row = 0;
+            for (dim_idx, &idx) in index.iter().enumerate() {
+                row += normalize_index(idx, indexed_dims[dim_idx])? * strides[dim_idx]
+            }
+            Ok(row)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // With no rows in data there cannot be any index either, and concatenating no pieces fails.
+    if indexed == 0 {
+        return Ok(Some(data.clone()));
+    }
+    let n = rows.len();
+    let xs = data.reshape((indexed, slice_size))?;
+    let updates = updates.to_dtype(data.dtype())?.reshape((n, slice_size))?;
+    let ys = match reduction {
+        ScatterReduction::Add => {
+            let rows = rows.iter().map(|&r| r as u32).collect::<Vec<_>>();
+            xs.index_add(&Tensor::from_vec(rows, n, data.device())?, &updates, 0)?
+        }
+        _ => {
+            // The output is the concatenation of the untouched runs of data rows and of the update
+            // rows sorted by position, so data only gets copied once.
+            let mut order = (0..n).collect::<Vec<_>>();
+            order.sort_by_key(|&i| rows[i]);
+            if order.windows(2).any(|w| rows[w[0]] == rows[w[1]]) {
+                return Ok(None);
+            }
+            let sorted = order.iter().map(|&i| i as u32).collect::<Vec<_>>();
+            let updates = updates.index_select(&Tensor::from_vec(sorted, n, data.device())?, 0)?;
+            let mut pieces = Vec::with_capacity(2 * n + 1);
+            let mut next = 0;
+            for (j, &i) in order.iter().enumerate() {
+                if rows[i] > next {
+                    pieces.push(xs.narrow(0, next, rows[i] - next)?)
+                }
+                pieces.push(updates.narrow(0, j, 1)?);
+                next = rows[i] + 1
+            }
+            if next < indexed {
+                pieces.push(xs.narrow(0, next, indexed - next)?)
+            }
+            Tensor::cat(&pieces, 0)?
+        }
+    };
+    Ok(Some(ys.reshape(data.shape())?))
+}
+
+// ScatterElements with the add reduction maps to scatter_add which also runs on the device of data,
+// this requires the indices to have the same dims as data except along axis. Returns None when
+// this is not the case.
+fn scatter_elements_add(
+    data: &Tensor,
+    indices: &Tensor,
+    updates: &Tensor,
+    axis: usize,
+) -> Result<Option<Tensor>> {
+    let (data_dims, idx_dims) = (data.dims(), indices.dims());
+    if idx_dims.len() != data_dims.len()
+        || updates.dims() != idx_dims
+        || (0..data_dims.len()).any(|d| d != axis && idx_dims[d] != data_dims[d])
+    {
+        return Ok(None);
+    }
+    let size = data_dims[axis];
+    let idx = indices
+        .to_dtype(DType::I64)?
+        .flatten_all()?
+        .to_vec1::<i64>()?
+        .into_iter()
+        .map(|i| normalize_index(i, size).map(|i| i as i64))
+        .collect::<Result<Vec<_>>>()?;
+    let idx = Tensor::from_vec(idx, idx_dims, data.device())?;
+    let updates = updates.to_dtype(data.dtype())?;
+    data.scatter_add(&idx, &updates, axis).map(Some)
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#ScatterElements
+fn scatter_elements_offsets(data: &Tensor, indices: &Tensor, axis: usize) -> Result<Vec<usize>> {
+    if indices.rank() != data.rank() {
+        bail!(
+            "ScatterElements indices rank {} differs from the data rank {}",
+            indices.rank(),
+            data.rank()
+        )
+    }
+    let data_dims = data.dims();
+    let idx_dims = indices.dims().to_vec();
+    let strides = contiguous_strides(data_dims);
+    let indices = indices
+        .to_dtype(DType::I64)?
+        .flatten_all()?
+        .to_vec1::<i64>()?;
+    let mut position = vec![0usize; idx_dims.len()];
+    let mut offsets = Vec::with_capacity(indices.len());
+    for &idx in indices.iter() {
+        let mut offset = 0;
+        for (dim_idx, &p) in position.iter().enumerate() {
+            let p = if dim_idx == axis {
+                normalize_index(idx, data_dims[dim_idx])?
+            } else {
+                p
+            };
+            offset += p * strides[dim_idx]
+        }
+        offsets.push(offset);
+        // Move to the next position in row-major order.
+        for dim_idx in (0..position.len()).rev() {
+            position[dim_idx] += 1;
+            if position[dim_idx] < idx_dims[dim_idx] {
+                break;
+            }
+            position[dim_idx] = 0;
+        }
+    }
+    Ok(offsets)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ResizeMode {
+    Nearest,
+    Linear,
+    Cubic { a: f64, exclude_outside: bool },
+}
+
+struct ResizeConfig<'a> {
+    mode: ResizeMode,
+    coordinate_transformation_mode: &'a str,
+    nearest_mode: &'a str,
+    // The region of interest, starts followed by ends, only used by tf_crop_and_resize.
+    roi: Option<Vec<f64>>,
+    // The value used by tf_crop_and_resize for the positions outside of the input.
+    extrapolation_value: f64,
+    // Whether the linear and cubic kernels are stretched when downsampling.
+    antialias: bool,
+}
+
+// Maps a coordinate in the resized tensor to a coordinate in the original one, roi holds the
+// start and end of the region of interest for this axis.
+fn resize_original_coordinate(
+    x_resized: f64,
+    scale: f64,
+    in_size: usize,
+    out_size: usize,
+    coordinate_transformation_mode: &str,
+    (roi_start, roi_end): (f64, f64),
+) -> Result<f64> {
+    let x = match coordinate_transformation_mode {
+        "half_pixel" => (x_resized + 0.5) / scale - 0.5,
+        // Same as half_pixel except for a single output position that maps to the first input.
+        "pytorch_half_pixel" => {
+            if out_size > 1 {
+                (x_resized + 0.5) / scale - 0.5
+            } else {
+                0.
+            }
+        }
+        "half_pixel_symmetric" => {
+            let adjustment = out_size as f64 / (in_size as f64 * scale);
+            let offset = in_size as f64 / 2. * (1. - adjustment);
+            offset + (x_resized + 0.5) / scale - 0.5
+        }
+        "tf_half_pixel_for_nn" => (x_resized + 0.5) / scale,
+        "asymmetric" => x_resized / scale,
+        "align_corners" => {
+            if out_size == 1 {
+                0.
+            } else {
+                x_resized * (in_size as f64 - 1.) / (out_size as f64 - 1.)
+            }
+        }
+        "tf_crop_and_resize" => {
+            let in_size = in_size as f64 - 1.;
+            if out_size == 1 {
+                (roi_start + roi_end) * in_size / 2.
+            } else {
+                roi_start * in_size
+                    + x_resized * (roi_end - roi_start) * in_size / (out_size as f64 - 1.)
+            }
+        }
+        mode => bail!("unsupported coordinate_transformation_mode {mode} for Resize"),
+    };
+    Ok(x)
+}
+
+fn cubic_kernel(x: f64, a: f64) -> f64 {
+    let x = x.abs();
+    if x <= 1. {
+        ((a + 2.) * x - (a + 3.)) * x * x + 1.
+    } else if x < 2. {
+        ((a * x - 5. * a) * x + 8. * a) * x - 4. * a
+    } else {
+        0.
+    }
+}
+
+fn cubic_coeffs(ratio: f64, a: f64) -> [f64; 4] {
+    [
+        cubic_kernel(ratio + 1., a),
+        cubic_kernel(ratio, a),
+        cubic_kernel(1. - ratio, a),
+        cubic_kernel(2. - ratio, a),
+    ]
+}
+
+// When downsampling with antialias, the interpolation kernel of the given support is stretched by
+// 1 / scale so that all the input positions contribute to some output, the weights are then
+// normalized to sum to one.
+fn antialias_weights(
+    x: f64,
+    in_size: usize,
+    scale: f64,
+    support: f64,
+    kernel: impl Fn(f64) -> f64,
+    exclude_outside: bool,
+) -> Vec<(usize, f64)> {
+    let start = (x - support / scale).floor() as i64;
+    let end = (x + support / scale).ceil() as i64;
+    let mut weights = (start..=end)
+        .filter(|&j| !exclude_outside || (j >= 0 && j < in_size as i64))
+        .map(|j| {
+            let idx = j.clamp(0, in_size as i64 - 1) as usize;
+            (idx, kernel((j as f64 - x) * scale))
+        })
+        .filter(|(_, w)| *w != 0.)
+        .collect::<Vec<_>>();
+    let sum = weights.iter().map(|(_, w)| w).sum::<f64>();
+    weights.iter_mut().for_each(|(_, w)| *w /= sum);
+    weights
+}
+
+// The input positions contributing to an output position and their weights, None when the
+// extrapolation value is used.
+type ResizeWeights = Option<Vec<(usize, f64)>>;
+
+// Returns for each output position along an axis the input positions contributing to it and
+// their weights. Out of bounds positions are clamped which matches padding with the edge values,
+// except for tf_crop_and_resize where None is returned so that the extrapolation value is used.
+fn resize_weights(
+    in_size: usize,
+    out_size: usize,
+    scale: f64,
+    roi: (f64, f64),
+    cfg: &ResizeConfig,
+) -> Result<Vec<ResizeWeights>> {
+    let clamp = |i: i64| i.clamp(0, in_size as i64 - 1) as usize;
+    (0..out_size)
+        .map(|i| {
+            let x = resize_original_coordinate(
+                i as f64,
+                scale,
+                in_size,
+                out_size,
+                cfg.coordinate_transformation_mode,
+                roi,
+            )?;
+            if cfg.coordinate_transformation_mode == "tf_crop_and_resize"
+                && (x < 0. || x > in_size as f64 - 1.)
+            {
+                return Ok(None);
+            }
+            let weights = match cfg.mode {
+                ResizeMode::Nearest => {
+                    let idx = match cfg.nearest_mode {
+                        "round_prefer_floor" if x - x.floor() == 0.5 => x.floor(),
+                        "round_prefer_ceil" if x - x.floor() == 0.5 => x.ceil(),
+                        "round_prefer_floor" | "round_prefer_ceil" => x.round(),
+                        "floor" => x.floor(),
+                        "ceil" => x.ceil(),
+                        mode => bail!("unsupported nearest_mode {mode} for Resize"),
+                    };
+                    vec![(clamp(idx as i64), 1.)]
+                }
+                ResizeMode::Linear if cfg.antialias && scale < 1. => {
+                    let kernel = |t: f64| f64::max(0., 1. - t.abs());
+                    antialias_weights(x, in_size, scale, 1., kernel, false)
+                }
+                ResizeMode::Cubic { a, exclude_outside } if cfg.antialias && scale < 1. => {
+                    let kernel = |t: f64| cubic_kernel(t, a);
+                    antialias_weights(x, in_size, scale, 2., kernel, exclude_outside)
+                }
+                ResizeMode::Linear => {
+                    let x = x.clamp(0., in_size as f64 - 1.);
+                    let x0 = x.floor();
+                    let ratio = x - x0;
+                    let x0 = x0 as i64;
+                    vec![(clamp(x0), 1. - ratio), (clamp(x0 + 1), ratio)]
+                }
+                ResizeMode::Cubic { a, exclude_outside } => {
+                    let x0 = x.floor();
+                    let coeffs = cubic_coeffs(x - x0, a);
+                    let x0 = x0 as i64;
+                    let mut weights = (-1..3)
+                        .zip(coeffs.iter())
+                        .filter(|(offset, _)| {
+                            let pos = x0 + offset;
+                            !exclude_outside || (pos >= 0 && pos < in_size as i64)
+                        })
+                        .map(|(offset, &c)| (clamp(x0 + offset), c))
+                        .collect::<Vec<_>>();
+                    if exclude_outside {
+                        let sum = weights.iter().map(|(_, w)| w).sum::<f64>();
+                        weights.iter_mut().for_each(|(_, w)| *w /= sum)
+                    }
+                    weights
+                }
+            };
+            Ok(Some(weights))
+        })
+        .collect()
+}
+
+// Resamples a row-major buffer with the given dims along a single axis, the positions without
+// weights are set to the extrapolation value.
+fn resize_axis(
+    vs: &[f64],
+    dims: &[usize],
+    axis: usize,
+    weights: &[ResizeWeights],
+    extrapolation_value: f64,
+) -> Vec<f64> {
+    let outer = dims[..axis].iter().product::<usize>();
+    let inner = dims[axis + 1..].iter().product::<usize>();
+    let in_size = dims[axis];
+    let mut ys = Vec::with_capacity(outer * weights.len() * inner);
+    for o in 0..outer {
+        for ws in weights.iter() {
+            for k in 0..inner {
+                let v = match ws {
+                    None => extrapolation_value,
+                    Some(ws) => ws
+                        .iter()
+                        .map(|&(idx, w)| w * vs[(o * in_size + idx) * inner + k])
+                        .sum::<f64>(),
+                };
+                ys.push(v)
+            }
+        }
+    }
+    ys
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Resize
+// When sizes is set, it provides the output dims and the scales are derived from it.
+fn resize(
+    xs: &Tensor,
+    scales: &[f64],
+    sizes: Option<&[usize]>,
+    cfg: &ResizeConfig,
+) -> Result<Tensor> {
+    if scales.len() != xs.rank() {
+        bail!(
+            "Resize got {} scales for an input of shape {:?}",
+            scales.len(),
+            xs.shape()
+        )
+    }
+    let crop = cfg.coordinate_transformation_mode == "tf_crop_and_resize";
+    let rank = xs.rank();
+    let roi = match &cfg.roi {
+        Some(roi) if crop && roi.len() != 2 * rank => {
+            bail!("Resize got a roi of length {} for rank {rank}", roi.len())
+        }
+        Some(roi) if crop => roi.clone(),
+        _ if crop => bail!("tf_crop_and_resize requires the roi input for Resize"),
+        _ => [vec![0.; rank], vec![1.; rank]].concat(),
+    };
+    let dtype = xs.dtype();
+    let mut dims = xs.dims().to_vec();
+    let mut vs = xs.to_dtype(DType::F64)?.flatten_all()?.to_vec1::<f64>()?;
+    for (axis, &scale) in scales.iter().enumerate() {
+        if scale <= 0. {
+            bail!("invalid scale {scale} in Resize")
+        }
+        let roi = (roi[axis], roi[rank + axis]);
+        if scale == 1. && roi == (0., 1.) {
+            continue;
+        }
+        let in_size = dims[axis];
+        // With tf_crop_and_resize, only the region of interest gets resized.
+        let out_size = match sizes {
+            Some(sizes) => sizes[axis],
+            None => (in_size as f64 * (roi.1 - roi.0) * scale).floor() as usize,
+        };
+        if in_size == 0 && out_size > 0 {
+            bail!("Resize cannot resize the empty axis {axis} to {out_size} elements")
+        }
+        let weights = resize_weights(in_size, out_size, scale, roi, cfg)?;
+        vs = resize_axis(&vs, &dims, axis, &weights, cfg.extrapolation_value);
+        dims[axis] = out_size;
+    }
+    Tensor::from_vec(vs, dims, xs.device())?.to_dtype(dtype)
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Pad
+// Negative pads crop the tensor, positive pads are filled according to the mode.
+fn pad_axis(
+    xs: &Tensor,
+    axis: usize,
+    begin: i64,
+    end: i64,
+    mode: &str,
+    value: f64,
+) -> Result<Tensor> {
+    let size = xs.dim(axis)? as i64;
+    let crop_begin = (-begin).max(0);
+    let crop_end = (-end).max(0);
+    if crop_begin + crop_end > size {
+        bail!("pads ({begin}, {end}) crop more than the size {size} of axis {axis}")
+    }
+    let xs = if crop_begin > 0 || crop_end > 0 {
+        xs.narrow(
+            axis,
+            crop_begin as usize,
+            (size - crop_begin - crop_end) as usize,
+        )?
+    } else {
+        xs.clone()
+    };
+    let (begin, end) = (begin.max(0) as usize, end.max(0) as usize);
+    if begin == 0 && end == 0 {
+        return Ok(xs);
+    }
+    let size = xs.dim(axis)?;
+    match mode {
+        "constant" => {
+            let mut dims = xs.dims().to_vec();
+            let pad = |len: usize, dims: &mut Vec<usize>| {
+                dims[axis] = len;
+                Tensor::ones(dims.as_slice(), xs.dtype(), xs.device())?.affine(value, 0.)
+            };
+            let before = pad(begin, &mut dims)?;
+            let after = pad(end, &mut dims)?;
+            Tensor::cat(&[&before, &xs, &after], axis)
+        }
+        "edge" | "reflect" | "wrap" => {
+            if size == 0 {
+                bail!("cannot use {mode} padding on the empty axis {axis}")
+            }
+            let size = size as i64;
+            let indexes = (-(begin as i64)..size + end as i64)
+                .map(|i| {
+                    let i = match mode {
+                        "edge" => i.clamp(0, size - 1),
+                        // Wrap around from the opposite side, e.g. [2, 3, 0, 1, 2, 3, 0, 1].
+                        "wrap" => i.rem_euclid(size),
+                        _ => {
+                            // Reflect without repeating the border, e.g. [2, 1, 0, 1, 2, 3, 2].
+                            let period = 2 * (size - 1);
+                            if period == 0 {
+                                0
+                            } else {
+                                let i = i.rem_euclid(period);
+                                if i < size {
+                                    i
+                                } else {
+                                    period - i
+                                }
+                            }
+                        }
+                    };
+                    i as u32
+                })
+                .collect::<Vec<_>>();
+            let indexes = Tensor::new(indexes.as_slice(), xs.device())?;
+            xs.contiguous()?.index_select(&indexes, axis)
+        }
+        mode => bail!("unsupported mode {mode} for Pad"),
+    }
+}
+
+// Broadcasts a quantization scale or zero-point to the shape of the quantized tensor. The
+// parameter is either a scalar for per-tensor quantization, a 1D tensor along axis for per-axis
+// quantization, or has the same rank as the input with the axis dimension split in blocks of
+// block_size elements for blocked quantization.
+fn broadcast_quantization_param(
+    param: &Tensor,
+    dims: &[usize],
+    axis: i64,
+    block_size: usize,
+) -> Result<Tensor> {
+    let rank = dims.len();
+    if param.rank() == 0 || (block_size == 0 && param.elem_count() == 1) {
+        return param.reshape(())?.broadcast_as(dims);
+    }
+    let axis = normalize_index(axis, rank)?;
+    if block_size == 0 {
+        if param.dims() != [dims[axis]] {
+            bail!(
+                "per-axis quantization expects a parameter of shape [{}], got {:?}",
+                dims[axis],
+                param.shape()
+            )
+        }
+        let mut shape = vec![1; rank];
+        shape[axis] = dims[axis];
+        return param.reshape(shape)?.broadcast_as(dims);
+    }
+    let n_blocks = dims[axis].div_ceil(block_size);
+    let mut expected = dims.to_vec();
+    expected[axis] = n_blocks;
+    if param.dims() != expected {
+        bail!(
+            "blocked quantization with block_size {block_size} expects a parameter of shape \
+             {expected:?}, got {:?}",
+            param.shape()
+        )
+    }
+    // Repeat each element block_size times along axis, the last block may be incomplete.
+    let mut repeated = expected.clone();
+    repeated.insert(axis + 1, block_size);
+    expected[axis] = n_blocks * block_size;
+    param
+        .unsqueeze(axis + 1)?
+        .broadcast_as(repeated)?
+        .reshape(expected)?
+        .narrow(axis, 0, dims[axis])
+}
+
+// Unpacks the 4-bit values stored two per byte along the last dim, the low nibble coming first.
+fn unpack_uint4(xs: &Tensor) -> Result<Tensor> {
+    let mut dims = xs.dims().to_vec();
+    match dims.last_mut() {
+        None => bail!("cannot unpack 4-bit values from a scalar"),
+        Some(d) => *d *= 2,
+    }
+    let vs = xs
+        .flatten_all()?
+        .to_vec1::<u8>()?
+        .into_iter()
+        .flat_map(|v| [v & 0x0F, v >> 4])
+        .collect::<Vec<_>>();
+    Tensor::from_vec(vs, dims, xs.device())
+}
+
+// Dequantizes the unpacked values of a com.microsoft blocked quantization op, the zero points
+// default to the middle of the range 2^(bits-1).
+fn dequantize_blocks(
+    xs: &Tensor,
+    scales: &Tensor,
+    zero_points: Option<&Tensor>,
+    axis: usize,
+    block_size: usize,
+    bits: usize,
+) -> Result<Tensor> {
+    let dtype = scales.dtype();
+    let scales = broadcast_quantization_param(scales, xs.dims(), axis as i64, block_size)?;
+    let xs = xs.to_dtype(dtype)?;
+    let xs = match zero_points {
+        None => xs.affine(1., -((1 << (bits - 1)) as f64))?,
+        Some(zero_points) => {
+            let zero_points = zero_points.to_dtype(dtype)?;
+            let zero_points =
+                broadcast_quantization_param(&zero_points, xs.dims(), axis as i64, block_size)?;
+            (xs - zero_points)?
+        }
+    };
+    xs * scales
+}
+
+// Rotates the pairs of values in the last dim of xs, of shape (B, S, N, D), by the angles of cos
+// and sin, of shape (B, S, D/2). The pairs are made of adjacent values when interleaved is set,
+// otherwise of values from each half of the last dim.
+fn rotary_embedding(xs: &Tensor, cos: &Tensor, sin: &Tensor, interleaved: bool) -> Result<Tensor> {
+    let (b, s, n, d) = xs.dims4()?;
+    let (cos, sin) = (cos.unsqueeze(2)?, sin.unsqueeze(2)?);
+    let (x1, x2) = if interleaved {
+        let xs = xs.reshape((b, s, n, d / 2, 2))?;
+        (
+            xs.narrow(4, 0, 1)?.squeeze(4)?,
+            xs.narrow(4, 1, 1)?.squeeze(4)?,
+        )
+    } else {
+        (xs.narrow(3, 0, d / 2)?, xs.narrow(3, d / 2, d / 2)?)
+    };
+    let y1 = (x1.broadcast_mul(&cos)? - x2.broadcast_mul(&sin)?)?;
+    let y2 = (x2.broadcast_mul(&cos)? + x1.broadcast_mul(&sin)?)?;
+    if interleaved {
+        Tensor::stack(&[y1, y2], 4)?.reshape((b, s, n, d))
+    } else {
+        Tensor::cat(&[y1, y2], 3)
+    }
+}
+
+// Rounds the values to the nearest float8 value, ties to even, as candle has no float8 dtype the
+// result is kept as f32. Out of range values are clamped to the largest finite value when
+// saturate is set, otherwise they become infinite for FLOAT8E5M2 and NaN for the types without
+// infinities.
+fn cast_float8(xs: &Tensor, dt: DataType, saturate: bool) -> Result<Tensor> {
+    // Mantissa bits, exponent of the smallest normal value, largest finite value.
+    let (mantissa_bits, min_exp, max) = match dt {
+        DataType::Float8e4m3fn => (3, -6, 448f32),
+        DataType::Float8e4m3fnuz => (3, -7, 240.),
+        DataType::Float8e5m2 => (2, -14, 57344.),
+        DataType::Float8e5m2fnuz => (2, -15, 57344.),
+        dt => bail!("{dt:?} is not a float8 type"),
+    };
+    let overflow = if dt == DataType::Float8e5m2 {
+        f32::INFINITY
+    } else {
+        f32::NAN
+    };
+    let round = |v: f32| -> f32 {
+        if v.is_nan() {
+            return v;
+        }
+        let abs = v.abs();
+        let rounded = if abs.is_infinite() {
+            abs
+        } else if abs == 0. {
+            0.
+        } else {
+            // Subnormal values share the exponent of the smallest normal value.
+            let exp = (abs.log2().floor() as i32).max(min_exp);
+            let ulp = 2f32.powi(exp - mantissa_bits);
+            let q = abs / ulp;
+            let mut r = q.round();
+            if q - q.floor() == 0.5 && r % 2. != 0. {
+                r -= 1.
+            }
+            r * ulp
+        };
+        let rounded = match (rounded > max, saturate) {
+            (false, _) => rounded,
+            (true, true) => max,
+            (true, false) => overflow,
+        };
+        if v.is_sign_negative() && rounded != 0. {
+            -rounded
+        } else {
+            rounded
+        }
+    };
+    let vs = xs
+        .to_dtype(DType::F32)?
+        .flatten_all()?
+        .to_vec1::<f32>()?
+        .into_iter()
+        .map(round)
+        .collect::<Vec<_>>();
+    Tensor::from_vec(vs, xs.shape(), xs.device())
+}
+
+// The flat positions of the max elements of each MaxPool window within the whole input tensor,
+// the position in the spatial dims uses a column major layout when column_major is set.
+fn max_pool2d_indices(
+    xs: &Tensor,
+    (k1, k2): (usize, usize),
+    (s1, s2): (usize, usize),
+    column_major: bool,
+) -> Result<Tensor> {
+    let (b_sz, c, h, w) = xs.dims4()?;
+    if h < k1 || w < k2 {
+        bail!(
+            "MaxPool kernel ({k1}, {k2}) is larger than the input {:?}",
+            xs.shape()
+        )
+    }
+    let (h_out, w_out) = ((h - k1) / s1 + 1, (w - k2) / s2 + 1);
+    let vs = xs.to_dtype(DType::F64)?.flatten_all()?.to_vec1::<f64>()?;
+    let mut indices = Vec::with_capacity(b_sz * c * h_out * w_out);
+    for plane in 0..b_sz * c {
+        let vs = &vs[plane * h * w..(plane + 1) * h * w];
+        for i_out in 0..h_out {
+            for j_out in 0..w_out {
+                let mut best = (i_out * s1, j_out * s2);
+                for i in i_out * s1..i_out * s1 + k1 {
+                    for j in j_out * s2..j_out * s2 + k2 {
+                        // Keep the first max element on ties.
+                        if vs[i * w + j] > vs[best.0 * w + best.1] {
+                            best = (i, j)
+                        }
+                    }
+                }
+                let (i, j) = best;
+                let offset = if column_major { j * h + i } else { i * w + j };
+                indices.push((plane * h * w + offset) as i64)
+            }
+        }
+    }
+    Tensor::from_vec(indices, (b_sz, c, h_out, w_out), xs.device())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#NegativeLogLikelihoodLoss
+// The log-probabilities have a shape (N, C, d1, ...) and the targets a shape (N, d1, ...).
+fn nll_loss(
+    log_prob: &Tensor,
+    target: &Tensor,
+    weight: Option<&Tensor>,
+    reduction: &str,
+    ignore_index: Option<i64>,
+) -> Result<Tensor> {
+    let rank = log_prob.rank();
+    if rank < 2 || target.rank() + 1 != rank {
+        bail!(
+            "unexpected shapes for the loss, input {:?}, target {:?}",
+            log_prob.shape(),
+            target.shape()
+        )
+    }
+    let dtype = log_prob.dtype();
+    let n_classes = log_prob.dim(1)?;
+    // Move the class dim last so that each row holds the log-probabilities for a single target.
+    let mut perm = (0..rank).filter(|&d| d != 1).collect::<Vec<_>>();
+    perm.push(1);
+    let log_prob = log_prob.permute(perm)?.reshape(((), n_classes))?;
+    let target_shape = target.shape().clone();
+    let target = target.to_dtype(DType::I64)?.flatten_all()?;
+    let mask = match ignore_index {
+        None => Tensor::ones(target.shape(), DType::U8, target.device())?,
+        Some(ignore_index) => target.ne(ignore_index)?,
+    };
+    // The ignored targets may be out of range so they are replaced by 0 and masked out.
+    let target = mask.where_cond(&target, &target.zeros_like()?)?;
+    let mask = mask.to_dtype(dtype)?;
+    let weight = match weight {
+        None => mask,
+        Some(weight) => (weight.index_select(&target, 0)?.to_dtype(dtype)? * mask)?,
+    };
+    let log_prob = log_prob
+        .contiguous()?
+        .gather(&target.unsqueeze(1)?, 1)?
+        .squeeze(1)?;
+    let loss = (log_prob * &weight)?.neg()?;
+    match reduction {
+        "none" => loss.reshape(target_shape),
+        "sum" => loss.sum_all(),
+        "mean" => loss.sum_all()? / weight.sum_all()?,
+        _ => bail!("unsupported reduction {reduction} for the loss"),
+    }
+}
+
+// Gauss-Jordan elimination with scaled partial pivoting of the n x n row-major matrix a, returns
+// the determinant and the inverse, the latter being None for singular matrices.
+fn gauss_jordan(mut a: Vec<f64>, n: usize) -> (f64, Option<Vec<f64>>) {
+    let mut inv = vec![0f64; n * n];
+    for i in 0..n {
+        inv[i * n + i] = 1.
+    }
+    // Pivots are compared to the largest entry of their row so that badly scaled rows, e.g. for
+    // diag(1e10, 1e-10), are not mistaken for zeros.
+    let mut row_scales: Vec<f64> = a
+        .chunks(n.max(1))
+        .map(|row| row.iter().fold(0f64, |acc, v| acc.max(v.abs())))
+        .collect();
+    if row_scales.contains(&0.) {
+        return (0., None);
+    }
+    let tolerance = n as f64 * f64::EPSILON;
+    let mut det = 1f64;
+    for col in 0..n {
+        let scaled = |i: usize| a[i * n + col].abs() / row_scales[i];
+        let pivot_row = (col..n)
+            .max_by(|&i, &j| scaled(i).total_cmp(&scaled(j)))
+            .unwrap_or(col);
+        if scaled(pivot_row) <= tolerance {
+            return (0., None);
+        }
+        let pivot = a[pivot_row * n + col];
+        if pivot_row != col {
+            for k in 0..n {
+                a.swap(col * n + k, pivot_row * n + k);
+                inv.swap(col * n + k, pivot_row * n + k);
+            }
+            row_scales.swap(col, pivot_row);
+            det = -det
+        }
+        det *= pivot;
+        for k in 0..n {
+            a[col * n + k] /= pivot;
+            inv[col * n + k] /= pivot;
+        }
+        for row in 0..n {
+            let factor = a[row * n + col];
+            if row == col || factor == 0. {
+                continue;
+            }
+            for k in 0..n {
+                a[row * n + k] -= factor * a[col * n + k];
+                inv[row * n + k] -= factor * inv[col * n + k];
+            }
+        }
+    }
+    (det, Some(inv))
+}
+
+// The activation functions f, g and h of the recurrent ops.
+fn rnn_activation(xs: &Tensor, name: &str) -> Result<Tensor> {
+    match name {
+        "Sigmoid" => candle_nn::ops::sigmoid(xs),
+        "Tanh" => xs.tanh(),
+        "Relu" => xs.relu(),
+        _ => bail!("unsupported activation {name} for a recurrent op"),
+    }
+}
+
+// The time steps of a recurrent op direction, in the order in which they are processed.
+fn rnn_steps(seq_len: usize, reverse: bool) -> Vec<usize> {
+    if reverse {
+        (0..seq_len).rev().collect()
+    } else {
+        (0..seq_len).collect()
+    }
+}
+
+// Runs a single direction of an LSTM over xs of shape (seq, batch, input). The weights and
+// peepholes are the ones of this direction, the activations are f, g and h. Returns the hidden
+// states of each step of shape (seq, batch, hidden), as well as the final hidden and cell states.
+#[allow(clippy::too_many_arguments)]
+fn lstm_direction(
+    xs: &Tensor,
+    (w, r, b): (&Tensor, &Tensor, Option<&Tensor>),
+    (h0, c0): (Tensor, Tensor),
+    peepholes: Option<&Tensor>,
+    activations: &[&str],
+    clip: Option<f64>,
+    reverse: bool,
+) -> Result<(Tensor, Tensor, Tensor)> {
+    let (seq_len, b_sz, _) = xs.dims3()?;
+    let hidden = r.dim(1)?;
+    // The input projections of all the steps are computed at once, the input and recurrence
+    // biases are both added to the gates so they get merged.
+    let xw = xs.reshape((seq_len * b_sz, ()))?.matmul(&w.t()?)?;
+    let xw = match b {
+        None => xw,
+        Some(b) => xw.broadcast_add(
+            &(b.narrow(0, 0, 4 * hidden)? + b.narrow(0, 4 * hidden, 4 * hidden)?)?,
+        )?,
+    };
+    let xw = xw.reshape((seq_len, b_sz, 4 * hidden))?;
+    let clip = |xs: Tensor| match clip {
+        None => Ok(xs),
+        Some(clip) => xs.clamp(-clip, clip),
+    };
+    let (f, g, h) = (activations[0], activations[1], activations[2]);
+    let (mut ht, mut ct) = (h0, c0);
+    let mut hs = vec![None; seq_len];
+    for t in rnn_steps(seq_len, reverse) {
+        // The gates are in the iofc order, the peepholes in the iof one.
+        let gates = (xw.get(t)? + ht.matmul(&r.t()?)?)?;
+        let gate = |i: usize| gates.narrow(1, i * hidden, hidden);
+        let peephole = |i: usize, c: &Tensor| -> Result<Option<Tensor>> {
+            match peepholes {
+                None => Ok(None),
+                Some(p) => Ok(Some(c.broadcast_mul(&p.narrow(
+                    0,
+                    i * hidden,
+                    hidden,
+                )?)?)),
+            }
+        };
+        let (gi, go, gf, gc) = (gate(0)?, gate(1)?, gate(2)?, gate(3)?);
+        let (gi, gf) = match (peephole(0, &ct)?, peephole(2, &ct)?) {
+            (Some(pi), Some(pf)) => ((gi + pi)?, (gf + pf)?),
+            _ => (gi, gf),
+        };
+        let it = rnn_activation(&clip(gi)?, f)?;
+        let ft = rnn_activation(&clip(gf)?, f)?;
+        let c_tilde = rnn_activation(&clip(gc)?, g)?;
+        ct = ((ft * &ct)? + (it * c_tilde)?)?;
+        let go = match peephole(1, &ct)? {
+            Some(po) => (go + po)?,
+            None => go,
+        };
+        let ot = rnn_activation(&clip(go)?, f)?;
+        ht = (ot * rnn_activation(&ct, h)?)?;
+        hs[t] = Some(ht.clone());
+    }
+    let hs = hs.into_iter().flatten().collect::<Vec<_>>();
+    Ok((Tensor::stack(&hs, 0)?, ht, ct))
+}
+
+// Runs a single direction of a GRU over xs of shape (seq, batch, input), the activations are f and
+// g. Returns the hidden states of each step of shape (seq, batch, hidden) and the final one.
+#[allow(clippy::too_many_arguments)]
+fn gru_direction(
+    xs: &Tensor,
+    (w, r, b): (&Tensor, &Tensor, Option<&Tensor>),
+    h0: Tensor,
+    activations: &[&str],
+    clip: Option<f64>,
+    linear_before_reset: bool,
+    reverse: bool,
+) -> Result<(Tensor, Tensor)> {
+    let (seq_len, b_sz, _) = xs.dims3()?;
+    let hidden = r.dim(1)?;
+    let xw = xs.reshape((seq_len * b_sz, ()))?.matmul(&w.t()?)?;
+    let (xw, rb) = match b {
+        None => (xw, None),
+        Some(b) => (
+            xw.broadcast_add(&b.narrow(0, 0, 3 * hidden)?)?,
+            Some(b.narrow(0, 3 * hidden, 3 * hidden)?),
+        ),
+    };
+    let xw = xw.reshape((seq_len, b_sz, 3 * hidden))?;
+    let add_rb = |xs: Tensor, i: usize, n: usize| match &rb {
+        None => Ok(xs),
+        Some(rb) => xs.broadcast_add(&rb.narrow(0, i * hidden, n * hidden)?),
+    };
+    let clip = |xs: Tensor| match clip {
+        None => Ok(xs),
+        Some(clip) => xs.clamp(-clip, clip),
+    };
+    let (f, g) = (activations[0], activations[1]);
+    // The gates are in the zrh order, the update and reset gates are computed together.
+    let r_zr = r.narrow(0, 0, 2 * hidden)?;
+    let r_h = r.narrow(0, 2 * hidden, hidden)?;
+    let mut ht = h0;
+    let mut hs = vec![None; seq_len];
+    for t in rnn_steps(seq_len, reverse) {
+        let xw = xw.get(t)?;
+        let zr = (xw.narrow(1, 0, 2 * hidden)? + add_rb(ht.matmul(&r_zr.t()?)?, 0, 2)?)?;
+        let zt = rnn_activation(&clip(zr.narrow(1, 0, hidden)?)?, f)?;
+        let rt = rnn_activation(&clip(zr.narrow(1, hidden, hidden)?)?, f)?;
+        let rh = if linear_before_reset {
+            (rt * add_rb(ht.matmul(&r_h.t()?)?, 2, 1)?)?
+        } else {
+            add_rb((rt * &ht)?.matmul(&r_h.t()?)?, 2, 1)?
+        };
+        let h_tilde = rnn_activation(&clip((xw.narrow(1, 2 * hidden, hidden)? + rh)?)?, g)?;
+        // (1 - z) * h_tilde + z * h
+        ht = (&h_tilde + zt * (ht - &h_tilde)?)?;
+        hs[t] = Some(ht.clone());
+    }
+    let hs = hs.into_iter().flatten().collect::<Vec<_>>();
+    Ok((Tensor::stack(&hs, 0)?, ht))
+}
+
+// Normalizes over the dims starting from axis, gamma and beta have the shape of these dims.
+fn layer_norm(
+    xs: &Tensor,
+    gamma: &Tensor,
+    beta: Option<&Tensor>,
+    axis: i64,
+    eps: f64,
+    remove_mean: bool,
+) -> Result<Tensor> {
+    use candle_nn::Module;
+    let axis = normalize_index(axis, xs.rank())?;
+    let norm_size = xs.dims()[axis..].iter().product::<usize>();
+    let gamma = gamma.flatten_all()?;
+    let ln = match (beta, remove_mean) {
+        (_, false) => candle_nn::LayerNorm::rms_norm(gamma, eps),
+        (None, true) => candle_nn::LayerNorm::new_no_bias(gamma, eps),
+        (Some(beta), true) => candle_nn::LayerNorm::new(gamma, beta.flatten_all()?, eps),
+    };
+    ln.forward(&xs.reshape(((), norm_size))?)?
+        .reshape(xs.shape())
+}
+
+// Normalizes the channels of a [N, C, ...] input in num_groups groups. Scale and bias are either per
+// channel or, before opset 21, per group. Half precision inputs are normalized in f32.
+fn group_norm(
+    xs: &Tensor,
+    scale: &Tensor,
+    bias: &Tensor,
+    num_groups: usize,
+    eps: f64,
+) -> Result<Tensor> {
+    let (b_sz, c) = match xs.dims() {
+        &[b_sz, c, ..] => (b_sz, c),
+        dims => bail!("GroupNormalization expects an input of rank at least 2, got {dims:?}"),
+    };
+    if num_groups == 0 || c % num_groups != 0 {
+        bail!("GroupNormalization cannot split {c} channels in {num_groups} groups")
+    }
+    let dtype = xs.dtype();
+    let internal_dtype = match dtype {
+        DType::F16 | DType::BF16 => DType::F32,
+        dtype => dtype,
+    };
+    let per_channel = |t: &Tensor| -> Result<Tensor> {
+        let t = t.flatten_all()?.to_dtype(internal_dtype)?;
+        let t = match t.elem_count() {
+            n if n == c => t,
+            n if n == num_groups => t
+                .reshape((num_groups, 1))?
+                .broadcast_as((num_groups, c / num_groups))?
+                .reshape(c)?,
+            n => bail!("GroupNormalization expects {c} or {num_groups} scales, got {n}"),
+        };
+        t.reshape((1, c, 1))
+    };
+    let scale = per_channel(scale)?;
+    let bias = per_channel(bias)?;
+    let ys = xs
+        .to_dtype(internal_dtype)?
+        .reshape((b_sz, num_groups, ()))?;
+    let ys = ys.broadcast_sub(&ys.mean_keepdim(2)?)?;
+    let var = ys.sqr()?.mean_keepdim(2)?;
+    ys.broadcast_div(&(var + eps)?.sqrt()?)?
+        .reshape((b_sz, c, ()))?
+        .broadcast_mul(&scale)?
+        .broadcast_add(&bias)?
+        .reshape(xs.shape())?
+        .to_dtype(dtype)
+}
+
+// A (rows, cols) mask set to 1 on and above the k-th diagonal when upper, on and below it
+// otherwise, and 0 elsewhere. A lower mask with k = 0 is the usual causal attention mask.
+fn tri_mask(
+    rows: usize,
+    cols: usize,
+    k: i64,
+    upper: bool,
+    device: &Device,
+    dtype: DType,
+) -> Result<Tensor> {
+    let r = Tensor::arange(0i64, rows as i64, device)?.reshape((rows, 1))?;
+    let c = Tensor::arange(0i64, cols as i64, device)?.reshape((1, cols))?;
+    let diagonal = c.broadcast_sub(&r)?;
+    let mask = if upper {
+        diagonal.ge(k)?
+    } else {
+        diagonal.le(k)?
+    };
+    mask.to_dtype(dtype)
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#CumSum
+// Output i sums the inputs j with j <= i, or j >= i when reversed, excluding j = i when exclusive,
+// e.g. [1, 2, 3] gives [5, 3, 0] with both flags. Each row is scanned once, floats accumulate in
+// f64 so that half precision inputs do not lose the small terms, and integers are summed in i64.
+fn cumsum(xs: &Tensor, axis: usize, exclusive: bool, reverse: bool) -> Result<Tensor> {
+    fn scan<T: Copy + Default + std::ops::AddAssign>(
+        vs: &[T],
+        n: usize,
+        exclusive: bool,
+        reverse: bool,
+    ) -> Vec<T> {
+        let mut ys = vec![T::default(); vs.len()];
+        for (row, out) in vs.chunks(n).zip(ys.chunks_mut(n)) {
+            let mut acc = T::default();
+            for idx in 0..n {
+                let i = if reverse { n - 1 - idx } else { idx };
+                if exclusive {
+                    out[i] = acc;
+                    acc += row[i];
+                } else {
+                    acc += row[i];
+                    out[i] = acc;
+                }
+            }
+        }
+        ys
+    }
+
+    let n = xs.dim(axis)?;
+    if xs.elem_count() == 0 {
+        return Ok(xs.clone());
+    }
+    let last = xs.rank() - 1;
+    let xs_t = xs.transpose(axis, last)?.contiguous()?;
+    let ys = if xs.dtype().is_float() {
+        let vs = xs_t.to_dtype(DType::F64)?.flatten_all()?.to_vec1::<f64>()?;
+        Tensor::from_vec(scan(&vs, n, exclusive, reverse), xs_t.shape(), xs.device())?
+    } else {
+        let vs = xs_t.to_dtype(DType::I64)?.flatten_all()?.to_vec1::<i64>()?;
+        Tensor::from_vec(scan(&vs, n, exclusive, reverse), xs_t.shape(), xs.device())?
+    };
+    ys.to_dtype(xs.dtype())?.transpose(axis, last)?.contiguous()
+}
+
+// The key padding mask of the com.microsoft Attention op as an additive mask of shape (B, 1, S, T),
+// masked positions get mask_filter_value and others 0.
+fn attention_key_mask(
+    mask_index: &Tensor,
+    (b_sz, seq_len, total_len): (usize, usize, usize),
+    mask_filter_value: f32,
+) -> Result<Tensor> {
+    let mask = mask_index
+        .to_dtype(DType::I64)?
+        .flatten_all()?
+        .to_vec1::<i64>()?;
+    let dims = mask_index.dims();
+    let mut additive = vec![0f32; b_sz * seq_len * total_len];
+    for b in 0..b_sz {
+        for i in 0..seq_len {
+            for j in 0..total_len {
+                let masked = match dims {
+                    // The lengths of the right padded sequences, or the start and end positions.
+                    [n] if *n == b_sz => j as i64 >= mask[b],
+                    [n] if *n == 2 * b_sz => j as i64 >= mask[b] || (j as i64) < mask[b_sz + b],
+                    // 1 for the keys to attend, 0 for the masked ones.
+                    [n, t] if *n == b_sz && *t == total_len => mask[b * total_len + j] == 0,
+                    [n, s, t] if *n == b_sz && *s == seq_len && *t == total_len => {
+                        mask[(b * seq_len + i) * total_len + j] == 0
+                    }
+                    _ => bail!("unsupported mask_index shape {dims:?} for Attention"),
+                };
+                if masked {
+                    additive[(b * seq_len + i) * total_len + j] = mask_filter_value
+                }
+            }
+        }
+    }
+    Tensor::from_vec(additive, (b_sz, 1, seq_len, total_len), mask_index.device())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#MatMul
+// This follows numpy.matmul, 1D operands are promoted to matrices and the batch dims of both
+// operands are broadcast together, the ranks can differ.
+fn matmul(lhs: &Tensor, rhs: &Tensor) -> Result<Tensor> {
+    let (lhs_1d, rhs_1d) = (lhs.rank() == 1, rhs.rank() == 1);
+    // The inputs can come out of a Transpose, with strides the matmul kernels do not support.
+    let lhs = if lhs_1d {
+        lhs.unsqueeze(0)?
+    } else {
+        lhs.contiguous()?
+    };
+    let rhs = if rhs_1d {
+        rhs.unsqueeze(1)?
+    } else {
+        rhs.contiguous()?
+    };
+    let ys = if rhs.rank() == 2 && lhs.rank() > 2 {
+        // Fold the batch dims of lhs in the rows rather than materializing a broadcast rhs.
+        let (k, n) = rhs.dims2()?;
+        let mut dims = lhs.dims().to_vec();
+        *dims.last_mut().unwrap() = n;
+        lhs.reshape(((), k))?.matmul(&rhs)?.reshape(dims)?
+    } else {
+        lhs.broadcast_matmul(&rhs)?
+    };
+    let ys = if lhs_1d {
+        ys.squeeze(ys.rank() - 2)?
+    } else {
+        ys
+    };
+    if rhs_1d {
+        ys.squeeze(ys.rank() - 1)
+    } else {
+        Ok(ys)
+    }
+}
+
+// The labels used by the dims bound to an ellipsis, these cannot clash with the letters used in
+// the equation.
+fn ellipsis_label(i: usize) -> char {
+    char::from_u32(0xE000 + i as u32).unwrap_or('\u{E000}')
+}
+
+// Parses an einsum term, the ellipsis is replaced by n_ellipsis_dims labels that are aligned on
+// the right with the ellipsis_rank labels of the whole equation.
+fn einsum_term(term: &str, n_ellipsis_dims: usize, ellipsis_rank: usize) -> Result<Vec<char>> {
+    let mut labels = vec![];
+    let mut rest = term;
+    while let Some(c) = rest.chars().next() {
+        if let Some(r) = rest.strip_prefix("...") {
+            labels.extend((ellipsis_rank - n_ellipsis_dims..ellipsis_rank).map(ellipsis_label));
+            rest = r
+        } else if c.is_ascii_alphabetic() {
+            labels.push(c);
+            rest = &rest[1..]
+        } else {
+            bail!("unexpected character '{c}' in einsum term {term}")
+        }
+    }
+    Ok(labels)
+}
+
+// Takes the diagonal over the dims that share a label within a term, e.g. "ii->i", the dims of
+// the diagonal are moved last.
+fn einsum_diagonal(xs: &Tensor, labels: Vec<char>) -> Result<(Tensor, Vec<char>)> {
+    let (mut xs, mut labels) = (xs.clone(), labels);
+    loop {
+        let repeated = (0..labels.len()).find_map(|j| {
+            labels[..j]
+                .iter()
+                .position(|l| *l == labels[j])
+                .map(|i| (i, j))
+        });
+        let (i, j) = match repeated {
+            None => return Ok((xs, labels)),
+            Some(ij) => ij,
+        };
+        let n = xs.dim(i)?;
+        if xs.dim(j)? != n {
+            bail!(
+                "einsum repeated index {} has different sizes in {:?}",
+                labels[i],
+                xs.shape()
+            )
+        }
+        // Move the two dims last and flatten them, the diagonal is every (n + 1)-th element.
+        let mut perm = (0..labels.len())
+            .filter(|&d| d != i && d != j)
+            .collect::<Vec<_>>();
+        let mut dims = perm.iter().map(|&d| xs.dims()[d]).collect::<Vec<_>>();
+        let mut new_labels = perm.iter().map(|&d| labels[d]).collect::<Vec<_>>();
+        new_labels.push(labels[i]);
+        perm.extend([i, j]);
+        let last = dims.len();
+        dims.push(n * n);
+        let idx = (0..n).map(|k| (k * (n + 1)) as u32).collect::<Vec<_>>();
+        let idx = Tensor::from_vec(idx, n, xs.device())?;
+        xs = xs
+            .permute(perm)?
+            .contiguous()?
+            .reshape(dims)?
+            .index_select(&idx, last)?;
+        labels = new_labels
+    }
+}
+
+// Sums out the dims whose label is not in keep.
+fn einsum_reduce(xs: &Tensor, labels: &[char], keep: &[char]) -> Result<(Tensor, Vec<char>)> {
+    let sum_dims = (0..labels.len())
+        .filter(|&i| !keep.contains(&labels[i]))
+        .collect::<Vec<_>>();
+    if sum_dims.is_empty() {
+        return Ok((xs.clone(), labels.to_vec()));
+    }
+    let labels = labels
+        .iter()
+        .filter(|l| keep.contains(l))
+        .copied()
+        .collect();
+    Ok((xs.sum(sum_dims)?, labels))
+}
+
+// Contracts two operands using a batched matmul, only the labels in keep are preserved.
+fn einsum_contract(
+    (lhs, lhs_labels): (&Tensor, &[char]),
+    (rhs, rhs_labels): (&Tensor, &[char]),
+    keep: &[char],
+) -> Result<(Tensor, Vec<char>)> {
+    let lhs_keep = lhs_labels
+        .iter()
+        .filter(|l| keep.contains(l) || rhs_labels.contains(l))
+        .copied()
+        .collect::<Vec<_>>();
+    let rhs_keep = rhs_labels
+        .iter()
+        .filter(|l| keep.contains(l) || lhs_labels.contains(l))
+        .copied()
+        .collect::<Vec<_>>();
+    let (lhs, lhs_labels) = einsum_reduce(lhs, lhs_labels, &lhs_keep)?;
+    let (rhs, rhs_labels) = einsum_reduce(rhs, rhs_labels, &rhs_keep)?;
+    let (mut batch, mut contracted, mut lhs_only, mut rhs_only) = (vec![], vec![], vec![], vec![]);
+    for &l in lhs_labels.iter() {
+        match (rhs_labels.contains(&l), keep.contains(&l)) {
+            (true, true) => batch.push(l),
+            (true, false) => contracted.push(l),
+            (false, _) => lhs_only.push(l),
+        }
+    }
+    rhs_only.extend(rhs_labels.iter().filter(|l| !lhs_labels.contains(l)));
+    let size = |l: &char| -> usize {
+        let lhs_size = lhs_labels
+            .iter()
+            .position(|x| x == l)
+            .map(|i| lhs.dims()[i]);
+        let rhs_size = rhs_labels
+            .iter()
+            .position(|x| x == l)
+            .map(|i| rhs.dims()[i]);
+        usize::max(lhs_size.unwrap_or(1), rhs_size.unwrap_or(1))
+    };
+    // Arrange lhs as (batch, lhs_only, contracted) and rhs as (batch, contracted, rhs_only).
+    let arrange = |xs: &Tensor, labels: &[char], order: &[&[char]]| -> Result<Tensor> {
+        let order = order.concat();
+        let perm = order
+            .iter()
+            .map(|l| labels.iter().position(|x| x == l).unwrap())
+            .collect::<Vec<_>>();
+        let dims = order.iter().map(size).collect::<Vec<_>>();
+        xs.permute(perm)?.broadcast_as(dims)?.contiguous()
+    };
+    let prod = |labels: &[char]| labels.iter().map(size).product::<usize>();
+    let (b, m, k, n) = (
+        prod(&batch),
+        prod(&lhs_only),
+        prod(&contracted),
+        prod(&rhs_only),
+    );
+    let lhs = arrange(&lhs, &lhs_labels, &[&batch, &lhs_only, &contracted])?.reshape((b, m, k))?;
+    let rhs = arrange(&rhs, &rhs_labels, &[&batch, &contracted, &rhs_only])?.reshape((b, k, n))?;
+    let labels = [batch, lhs_only, rhs_only].concat();
+    let dims = labels.iter().map(size).collect::<Vec<_>>();
+    Ok((lhs.matmul(&rhs)?.reshape(dims)?, labels))
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Einsum
+fn einsum(equation: &str, inputs: &[&Tensor]) -> Result<Tensor> {
+    let equation = equation.replace(' ', "");
+    let (lhs, output) = match equation.split_once("->") {
+        Some((lhs, output)) => (lhs, Some(output)),
+        None => (equation.as_str(), None),
+    };
+    let terms = lhs.split(',').collect::<Vec<_>>();
+    if terms.len() != inputs.len() {
+        bail!(
+            "einsum {equation} expects {} inputs, got {}",
+            terms.len(),
+            inputs.len()
+        )
+    }
+    let n_ellipsis_dims = terms
+        .iter()
+        .zip(inputs.iter())
+        .map(|(term, xs)| {
+            let n_letters = term.chars().filter(|c| c.is_ascii_alphabetic()).count();
+            if !term.contains("...") {
+                return Ok(0);
+            }
+            match xs.rank().checked_sub(n_letters) {
+                Some(n) => Ok(n),
+                None => bail!(
+                    "einsum term {term} does not match the shape {:?}",
+                    xs.shape()
+                ),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let ellipsis_rank = n_ellipsis_dims.iter().copied().max().unwrap_or(0);
+    let mut operands = Vec::with_capacity(inputs.len());
+    for ((term, &n), &xs) in terms.iter().zip(n_ellipsis_dims.iter()).zip(inputs.iter()) {
+        let labels = einsum_term(term, n, ellipsis_rank)?;
+        if labels.len() != xs.rank() {
+            bail!(
+                "einsum term {term} does not match the shape {:?}",
+                xs.shape()
+            )
+        }
+        operands.push(einsum_diagonal(xs, labels)?)
+    }
+    let output = match output {
+        Some(output) => einsum_term(output, ellipsis_rank, ellipsis_rank)?,
+        None => {
+            // In implicit mode, the output has the ellipsis dims followed by the letters that
+            // appear only once, in alphabetical order.
+            let letters = terms.iter().flat_map(|t| t.chars()).collect::<Vec<_>>();
+            let mut once = letters
+                .iter()
+                .filter(|c| {
+                    c.is_ascii_alphabetic() && letters.iter().filter(|x| x == c).count() == 1
+                })
+                .copied()
+                .collect::<Vec<_>>();
+            once.sort();
+            (0..ellipsis_rank).map(ellipsis_label).chain(once).collect()
+        }
+    };
+    // Contract the operands from left to right, keeping the labels used later on.
+    let mut operands = operands.into_iter();
+    let (xs, labels) = operands.next().unwrap();
+    let remaining = operands.collect::<Vec<_>>();
+    let (mut xs, mut labels) = (xs, labels);
+    for (i, (rhs, rhs_labels)) in remaining.iter().enumerate() {
+        let mut keep = output.clone();
+        keep.extend(
+            remaining[i + 1..]
+                .iter()
+                .flat_map(|(_, l)| l.iter().copied()),
+        );
+        (xs, labels) = einsum_contract((&xs, &labels), (rhs, rhs_labels), &keep)?;
+    }
+    let (xs, labels) = einsum_reduce(&xs, &labels, &output)?;
+    let perm = output
+        .iter()
+        .map(|l| match labels.iter().position(|x| x == l) {
+            Some(i) => Ok(i),
+            None => bail!("einsum output label {l} does not appear in the inputs"),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    xs.permute(perm)
+}
+
+// This function provides a direct evaluation of the proto.
+// Longer-term, we should first convert the proto to an intermediate representation of the compute
+// graph so as to make multiple evaluations more efficient.
+// An example upside of this would be to remove intermediary values when they are not needed
+// anymore.
+pub fn simple_eval(
+    model: &onnx::ModelProto,
+    inputs: HashMap<String, Value>,
+) -> Result<HashMap<String, Value>> {
+    let graph = match &model.graph {
+        None => bail!("no graph defined in proto"),
+        Some(graph) => graph,
+    };
+    let mut values = inputs;
+    for t in graph.initializer.iter() {
+        // An initializer only provides a default value for a graph input of the same name, the
+        // value passed by the caller takes precedence.
+        if !values.contains_key(&t.name) {
+            let tensor = get_tensor(t, t.name.as_str())?;
+            values.insert(t.name.to_string(), tensor.into());
+        }
+    }
+    eval_model(model, values, false)
+}
+
+/// The name, dtype and dims of a model input, `None` is used for the symbolic dims.
+pub type InputInfo = (String, DType, Vec<Option<usize>>);
+
+// The dtype of the tensor expected for a graph input of the given elem type, int32 values are held
+// in i64 tensors, see get_tensor.
+fn input_dtype(elem_type: i32, name: &str) -> Result<DType> {
+    match DataType::try_from(elem_type) {
+        Ok(DataType::Int32) => Ok(DType::I64),
+        Ok(dt) => match dtype(dt) {
+            Some(dt) => Ok(dt),
+            None => bail!("unsupported 'value' data-type {dt:?} for {name}"),
+        },
+        type_ => bail!("unsupported input type {type_:?}"),
+    }
+}
+
+/// Returns the name, dtype and shape of the inputs that have to be provided when evaluating the
+/// model, the inputs with a default value from an initializer are excluded. Symbolic dims are
+/// returned as `None` and inputs without a shape get an empty list of dims.
+pub fn required_inputs(model: &onnx::ModelProto) -> Result<Vec<InputInfo>> {
+    let graph = match &model.graph {
+        None => bail!("no graph defined in proto"),
+        Some(graph) => graph,
+    };
+    let mut inputs = vec![];
+    for input in graph.input.iter() {
+        if graph.initializer.iter().any(|t| t.name == input.name) {
+            continue;
+        }
+        let tensor_type = match input.r#type.as_ref().and_then(|t| t.value.as_ref()) {
+            Some(onnx::type_proto::Value::TensorType(tt)) => tt,
+            type_ => bail!("unsupported type {type_:?} for input {}", input.name),
+        };
+        let dt = input_dtype(tensor_type.elem_type, &input.name)?;
+        let dims = match &tensor_type.shape {
+            None => vec![],
+            Some(shape) => shape
+                .dim
+                .iter()
+                .map(|dim| match dim.value {
+                    Some(onnx::tensor_shape_proto::dimension::Value::DimValue(v)) => {
+                        Some(v as usize)
+                    }
+                    _ => None,
+                })
+                .collect(),
+        };
+        inputs.push((input.name.clone(), dt, dims))
+    }
+    Ok(inputs)
+}
+
+/// The version of the ONNX IR used by the model.
+pub fn ir_version(model: &onnx::ModelProto) -> i64 {
+    model.ir_version
+}
+
+/// The name of the tool that generated the model, e.g. "pytorch", empty if not set.
+pub fn producer_name(model: &onnx::ModelProto) -> &str {
+    &model.producer_name
+}
+
+/// The key/value pairs stored in the model metadata, exporters often use these for configuration
+/// such as the tokenizer settings. When a key is repeated the last value wins.
+pub fn metadata_props(model: &onnx::ModelProto) -> HashMap<String, String> {
+    model
+        .metadata_props
+        .iter()
+        .map(|p| (p.key.clone(), p.value.clone()))
+        .collect()
+}
+
+/// Same as `simple_eval` but the whole graph is checked before evaluating any node: all the values
+/// consumed by the nodes must be produced by some node or be a graph input, an initializer or one of
+/// the provided inputs, and the graph must not contain cycles.
+pub fn simple_eval_strict(
+    model: &onnx::ModelProto,
+    inputs: HashMap<String, Value>,
+) -> Result<HashMap<String, Value>> {
+    let graph = match &model.graph {
+        None => bail!("no graph defined in proto"),
+        Some(graph) => graph,
+    };
+    let provided = inputs.keys().map(|k| k.as_str()).collect();
+    crate::graph::check_producers(graph, &provided)?;
+    crate::graph::topological_sort(&graph.node)?;
+    simple_eval(model, inputs)
+}
+
+/// Evaluates a single node in isolation, inputs should contain a value for each of the node inputs
+/// and the returned map holds the node outputs.
+pub fn eval_node(
+    node: &onnx::NodeProto,
+    inputs: HashMap<String, Value>,
+) -> Result<HashMap<String, Value>> {
+    let mut values = inputs;
+    eval_graph(std::slice::from_ref(node), &mut values, &HashMap::new())?;
+    node.output
+        .iter()
+        .filter(|output| !output.is_empty())
+        .map(|output| match values.remove(output) {
+            None => bail!("cannot find output {output} for {}", node.name),
+            Some(value) => Ok((output.clone(), value)),
+        })
+        .collect()
+}
+
+// Evaluates the main graph of the model, values should contain the graph inputs as well as the
+// initializers unless lazy_initializers is set.
+pub(crate) fn eval_model(
+    model: &onnx::ModelProto,
+    mut values: HashMap<String, Value>,
+    lazy_initializers: bool,
+) -> Result<HashMap<String, Value>> {
+    let graph = match &model.graph {
+        None => bail!("no graph defined in proto"),
+        Some(graph) => graph,
+    };
+    for input in graph.input.iter() {
+        let input_type = match &input.r#type {
+            Some(input_type) => input_type,
+            None => continue,
+        };
+        let input_type = match &input_type.value {
+            Some(input_type) => input_type,
+            None => continue,
+        };
+        let tensor_type = match input_type {
+            onnx::type_proto::Value::TensorType(tt) => tt,
+            _ => continue,
+        };
+
+        let tensor = match values.get(&input.name) {
+            // Older models also list the initializers as graph inputs.
+            None if lazy_initializers && graph.initializer.iter().any(|t| t.name == input.name) => {
+                continue
+            }
+            None => bail!("missing input {}", input.name),
+            Some(value) => value.as_tensor()?,
+        };
+        let dt = input_dtype(tensor_type.elem_type, &input.name)?;
+        let shape = match &tensor_type.shape {
+            None => continue,
+            Some(shape) => shape
+                .dim
+                .iter()
+                .map(|dim| match dim.value.as_ref().expect("no dim value") {
+                    onnx::tensor_shape_proto::dimension::Value::DimValue(v) => Ok(*v as usize),
+                    onnx::tensor_shape_proto::dimension::Value::DimParam(_) => {
+                        bail!("DimParam is unsupported for input {}", input.name)
+                    }
+                })
+                .collect::<Result<Vec<usize>>>()?,
+        };
+        if dt != tensor.dtype() {
+            bail!(
+                "unexpected dtype for {}, got {:?}, expected {dt:?}",
+                input.name,
+                tensor.dtype()
+            )
+        }
+        if shape.as_slice() != tensor.dims() {
+            bail!(
+                "unexpected shape for {}, got {:?}, expected {shape:?}",
+                input.name,
+                tensor.dims()
+            )
+        }
+    }
+    let functions: Functions = model
+        .functions
+        .iter()
+        .map(|f| ((f.domain.as_str(), f.name.as_str()), f))
+        .collect();
+    if lazy_initializers {
+        eval_graph_lazy(graph, &mut values, &functions)?
+    } else {
+        eval_graph(&graph.node, &mut values, &functions)?
+    }
+    graph
+        .output
+        .iter()
+        .map(|output| match values.remove(&output.name) {
+            None => bail!("cannot find output {}", output.name),
+            Some(value) => Ok((output.name.clone(), value)),
+        })
+        .collect()
+}
+
+// The domains and op types handled by eval_graph, the ai.onnx domain is the empty string. This has
+// to be kept in sync with the match below, see the supported_ops_dispatch test.
+const SUPPORTED_OPS: &[(&str, &str)] = &[
+    ("", "Abs"),
+    ("", "Add"),
+    ("", "AffineGrid"),
+    ("", "ArgMax"),
+    ("", "ArgMin"),
+    ("", "AveragePool"),
+    ("", "BatchNormalization"),
+    ("", "BitShift"),
+    ("", "BitwiseAnd"),
+    ("", "BitwiseNot"),
+    ("", "BitwiseOr"),
+    ("", "BitwiseXor"),
+    ("", "BlackmanWindow"),
+    ("", "Cast"),
+    ("", "CenterCropPad"),
+    ("", "Clip"),
+    ("", "Concat"),
+    ("", "ConcatFromSequence"),
+    ("", "Constant"),
+    ("", "Conv"),
+    ("", "Cos"),
+    ("", "CumSum"),
+    ("", "DequantizeLinear"),
+    ("", "Det"),
+    ("", "Div"),
+    ("", "Dropout"),
+    ("", "Einsum"),
+    ("", "Equal"),
+    ("", "Erf"),
+    ("", "Expand"),
+    ("", "FusedAdd"),
+    ("", "FusedConv"),
+    ("", "GRU"),
+    ("", "Gather"),
+    ("", "GatherElements"),
+    ("", "GatherND"),
+    ("", "Gelu"),
+    ("", "Gemm"),
+    ("", "GlobalAveragePool"),
+    ("", "GlobalLpPool"),
+    ("", "GlobalMaxPool"),
+    ("", "GridSample"),
+    ("", "GroupNormalization"),
+    ("", "HammingWindow"),
+    ("", "HannWindow"),
+    ("", "If"),
+    ("", "LRN"),
+    ("", "LSTM"),
+    ("", "LayerNormalization"),
+    ("", "LogSoftmax"),
+    ("", "LpPool"),
+    ("", "MatMul"),
+    ("", "MaxPool"),
+    ("", "MaxUnpool"),
+    ("", "MeanVarianceNormalization"),
+    ("", "Mul"),
+    ("", "Multinomial"),
+    ("", "Neg"),
+    ("", "NegativeLogLikelihoodLoss"),
+    ("", "NonMaxSuppression"),
+    ("", "Optional"),
+    ("", "OptionalGetElement"),
+    ("", "OptionalHasElement"),
+    ("", "Pad"),
+    ("", "QuantizeLinear"),
+    ("", "Range"),
+    ("", "ReduceMax"),
+    ("", "ReduceMean"),
+    ("", "ReduceMin"),
+    ("", "ReduceSum"),
+    ("", "Relu"),
+    ("", "Reshape"),
+    ("", "Resize"),
+    ("", "ReverseSequence"),
+    ("", "RoiAlign"),
+    ("", "ScatterElements"),
+    ("", "ScatterND"),
+    ("", "SequenceAt"),
+    ("", "SequenceConstruct"),
+    ("", "SequenceInsert"),
+    ("", "SequenceLength"),
+    ("", "Shape"),
+    ("", "SiLU"),
+    ("", "Sigmoid"),
+    ("", "SimplifiedLayerNormalization"),
+    ("", "Sin"),
+    ("", "Slice"),
+    ("", "Softmax"),
+    ("", "SoftmaxCrossEntropyLoss"),
+    ("", "Split"),
+    ("", "SplitToSequence"),
+    ("", "Squeeze"),
+    ("", "Sub"),
+    ("", "Swish"),
+    ("", "Tanh"),
+    ("", "Tile"),
+    ("", "Transpose"),
+    ("", "Trilu"),
+    ("", "Upsample"),
+    ("", "Where"),
+    ("ai.onnx.ml", "Binarizer"),
+    ("ai.onnx.ml", "CastMap"),
+    ("ai.onnx.ml", "Imputer"),
+    ("ai.onnx.ml", "LabelEncoder"),
+    ("ai.onnx.ml", "Normalizer"),
+    ("ai.onnx.ml", "Scaler"),
+    ("com.microsoft", "Attention"),
+    ("com.microsoft", "BiasGelu"),
+    ("com.microsoft", "FastGelu"),
+    ("com.microsoft", "GatherBlockQuantized"),
+    ("com.microsoft", "Gelu"),
+    ("com.microsoft", "GroupNorm"),
+    ("com.microsoft", "Inverse"),
+    ("com.microsoft", "MatMulNBits"),
+    ("com.microsoft", "QuickGelu"),
+    ("com.microsoft", "RotaryEmbedding"),
+    ("com.microsoft", "SkipLayerNormalization"),
+];
+
+/// Splits the op types used in the model, including the ones in subgraphs and local functions,
+/// between the ones that the evaluator can handle and the ones it cannot. Nothing gets evaluated
+/// so this can be used to check the compatibility of a model upfront. The ops that are not in the
+/// default ai.onnx domain are reported as `domain.op_type`.
+pub fn supported_ops(model: &onnx::ModelProto) -> (Vec<String>, Vec<String>) {
+    fn collect<'a>(nodes: &'a [onnx::NodeProto], ops: &mut BTreeSet<(&'a str, &'a str)>) {
+        for node in nodes {
+            ops.insert((node.domain.as_str(), node.op_type.as_str()));
+            for attr in node.attribute.iter() {
+                for graph in attr.g.iter().chain(attr.graphs.iter()) {
+                    collect(&graph.node, ops)
+                }
+            }
+        }
+    }
+    let mut ops = BTreeSet::new();
+    if let Some(graph) = model.graph.as_ref() {
+        collect(&graph.node, &mut ops)
+    }
+    for function in model.functions.iter() {
+        collect(&function.node, &mut ops)
+    }
+    let mut supported = BTreeSet::new();
+    let mut unsupported = BTreeSet::new();
+    for (domain, op_type) in ops {
+        let is_function = model
+            .functions
+            .iter()
+            .any(|f| f.domain == domain && f.name == op_type);
+        let op_domain = if domain == "ai.onnx" { "" } else { domain };
+        let name = if op_domain.is_empty() {
+            op_type.to_string()
+        } else {
+            format!("{domain}.{op_type}")
+        };
+        if is_function || SUPPORTED_OPS.contains(&(op_domain, op_type)) {
+            supported.insert(name);
+        } else {
+            unsupported.insert(name);
+        }
+    }
+    (
+        supported.into_iter().collect(),
+        unsupported.into_iter().collect(),
+    )
+}
+
+// Local functions defined in the model, indexed by domain and name.
+type Functions<'a> = HashMap<(&'a str, &'a str), &'a onnx::FunctionProto>;
+
+// Evaluates the nodes in order, the outputs of each node are added to values.
+fn eval_graph(
+    nodes: &[onnx::NodeProto],
+    values: &mut HashMap<String, Value>,
+    functions: &Functions,
+) -> Result<()> {
+    // Converters do not always emit the nodes in topological order so sort them first.
+    for node in crate::graph::topological_sort(nodes)? {
+        let key = (node.domain.as_str(), node.op_type.as_str());
+        if let Some(function) = functions.get(&key) {
+            eval_function(node, function, values, functions)?;
+            continue;
+        }
+        // The constants may have been decoded ahead of time, see Model.
+        if node.op_type == "Constant" && values.contains_key(&node.output[0]) {
+            continue;
+        }
+        if (node.op_type == "FusedConv" || node.op_type == "FusedAdd") && node.domain.is_empty() {
+            eval_fused(node, values, functions)?;
+            continue;
+        }
+        if node.op_type == "If" && node.domain.is_empty() {
+            eval_if(node, values, functions)?;
+            continue;
+        }
+        let get_value = |input_name: &str| match values.get(input_name) {
+            Some(value) => Ok(value),
+            None => bail!("cannot find {input_name} for op {}", node.name),
+        };
+        let get = |input_name: &str| match get_value(input_name)? {
+            Value::Tensor(t) => Ok(t),
+            value => bail!(
+                "expected a tensor for {input_name} in op {}, got {value:?}",
+                node.name
+            ),
+        };
+        // Optional inputs can be omitted or skipped using an empty name.
+        let get_opt = |i: usize| match node.input.get(i) {
+            Some(input_name) if !input_name.is_empty() => get(input_name).map(Some),
+            _ => Ok(None),
+        };
+        // TODO: Validate node.input for each operator.
+        match node.op_type.as_str() {
+            "Add" => {
+                let input0 = get(&node.input[0])?;
+                let input1 = get(&node.input[1])?;
+                let output = input0
+                    .broadcast_add(input1)
+                    .map_err(inputs_context(node, &[input0, input1]))?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "Sub" => {
+                let input0 = get(&node.input[0])?;
+                let input1 = get(&node.input[1])?;
+                let output = input0
+                    .broadcast_sub(input1)
+                    .map_err(inputs_context(node, &[input0, input1]))?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "Mul" => {
+                let input0 = get(&node.input[0])?;
+                let input1 = get(&node.input[1])?;
+                let output = input0
+                    .broadcast_mul(input1)
+                    .map_err(inputs_context(node, &[input0, input1]))?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "Div" => {
+                let input0 = get(&node.input[0])?;
+                let input1 = get(&node.input[1])?;
+                let output = input0
+                    .broadcast_div(input1)
+                    .map_err(inputs_context(node, &[input0, input1]))?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "Equal" => {
+                let input0 = get(&node.input[0])?;
+                let input1 = get(&node.input[1])?;
+                // The inputs broadcast together, there is no bool dtype in candle so the output
+                // is a U8 mask that Where and the logical ops accept as a condition.
+                let shape = input0
+                    .shape()
+                    .broadcast_shape_binary_op(input1.shape(), "equal")?;
+                let output = input0
+                    .broadcast_as(&shape)?
+                    .eq(&input1.broadcast_as(&shape)?)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#BitShift
+            "BitShift" => {
+                let input0 = get(&node.input[0])?;
+                let input1 = get(&node.input[1])?;
+                let output = match get_attr::<str>(node, "direction")? {
+                    "LEFT" => bitwise_binary(input0, input1, "bit_shift", |l, r| {
+                        l.checked_shl(r as u32).unwrap_or(0)
+                    })?,
+                    "RIGHT" => bitwise_binary(input0, input1, "bit_shift", |l, r| {
+                        l.checked_shr(r as u32).unwrap_or(0)
+                    })?,
+                    d => bail!("unsupported direction {d} for BitShift {}", node.name),
+                };
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "BitwiseAnd" => {
+                let input0 = get(&node.input[0])?;
+                let input1 = get(&node.input[1])?;
+                let output = bitwise_binary(input0, input1, "bitwise_and", |l, r| l & r)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "BitwiseOr" => {
+                let input0 = get(&node.input[0])?;
+                let input1 = get(&node.input[1])?;
+                let output = bitwise_binary(input0, input1, "bitwise_or", |l, r| l | r)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "BitwiseXor" => {
+                let input0 = get(&node.input[0])?;
+                let input1 = get(&node.input[1])?;
+                let output = bitwise_binary(input0, input1, "bitwise_xor", |l, r| l ^ r)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "BitwiseNot" => {
+                let input = get(&node.input[0])?;
+                let output = bitwise_binary(input, input, "bitwise_not", |l, _| !l)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "MatMul" => {
+                let input0 = get(&node.input[0])?;
+                let input1 = get(&node.input[1])?;
+                let output =
+                    matmul(input0, input1).map_err(inputs_context(node, &[input0, input1]))?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Gemm
+            "Gemm" => {
+                let a = get(&node.input[0])?;
+                let b = get(&node.input[1])?;
+                let alpha = get_attr_opt::<f32>(node, "alpha")?.copied().unwrap_or(1.);
+                let beta = get_attr_opt::<f32>(node, "beta")?.copied().unwrap_or(1.);
+                let trans_a = get_attr_opt::<i64>(node, "transA")?.copied().unwrap_or(0);
+                let trans_b = get_attr_opt::<i64>(node, "transB")?.copied().unwrap_or(0);
+                // Some exporters emit a [1, M, K] input rather than [M, K], the leading size 1
+                // dim is dropped here and restored on the output.
+                let squeeze_batch = |t: &Tensor| match t.dims() {
+                    [1, _, _] => t.squeeze(0),
+                    _ => Ok(t.clone()),
+                };
+                let (a_in, b_in) = (a, b);
+                let (a, b) = (squeeze_batch(a)?, squeeze_batch(b)?);
+                if a.rank() != 2 || b.rank() != 2 {
+                    bail!(
+                        "Gemm expects 2D inputs, got {:?} and {:?}",
+                        a_in.shape(),
+                        b_in.shape()
+                    )
+                }
+                let a = if trans_a != 0 { a.t()? } else { a };
+                let b = if trans_b != 0 { b.t()? } else { b };
+                let ys = matmul(&a, &b).map_err(inputs_context(node, &[a_in, b_in]))?;
+                let ys = if alpha != 1. {
+                    (ys * alpha as f64)?
+                } else {
+                    ys
+                };
+                // C is unidirectionally broadcast to [M, N], e.g. a [N] bias gets added to each
+                // row and a [M, 1] one to each column.
+                let ys = match get_opt(2)? {
+                    None => ys,
+                    Some(c) => {
+                        let c = if beta != 1. {
+                            (c * beta as f64)?
+                        } else {
+                            c.clone()
+                        };
+                        let (m, n) = ys.dims2()?;
+                        ys.add(&c.broadcast_as((m, n))?)?
+                    }
+                };
+                let ys = if a_in.rank() == 3 || b_in.rank() == 3 {
+                    ys.unsqueeze(0)?
+                } else {
+                    ys
+                };
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Einsum
+            "Einsum" => {
+                let equation = get_attr::<str>(node, "equation")?;
+                let inputs = node
+                    .input
+                    .iter()
+                    .map(|name| get(name))
+                    .collect::<Result<Vec<_>>>()?;
+                let output = einsum(equation, &inputs)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#CenterCropPad
+            "CenterCropPad" => {
+                let xs = get(&node.input[0])?;
+                let shape = shape_values(get(&node.input[1])?)?;
+                let axes = match get_attr_opt::<[i64]>(node, "axes")? {
+                    Some(axes) => axes
+                        .iter()
+                        .map(|&axis| normalize_index(axis, xs.rank()))
+                        .collect::<Result<Vec<_>>>()?,
+                    None => (0..xs.rank()).collect(),
+                };
+                if axes.len() != shape.len() {
+                    bail!("CenterCropPad shape {shape:?} does not match the axes {axes:?}")
+                }
+                let mut output = xs.clone();
+                for (&axis, &target) in axes.iter().zip(shape.iter()) {
+                    let dim = xs.dim(axis)?;
+                    let target = target as usize;
+                    // The extra elements are split evenly between both sides, the odd one being
+                    // removed from or added to the end.
+                    output = if target < dim {
+                        output.narrow(axis, (dim - target) / 2, target)?
+                    } else if target > dim {
+                        let diff = target - dim;
+                        output.pad_with_zeros(axis, diff / 2, diff - diff / 2)?
+                    } else {
+                        output
+                    };
+                }
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Where
+            "Where" => {
+                let cond = get(&node.input[0])?;
+                let on_true = get(&node.input[1])?;
+                let on_false = get(&node.input[2])?;
+                // The three inputs broadcast together, e.g. a rank-0 branch gets expanded to the
+                // shape of the condition.
+                let shape = cond
+                    .shape()
+                    .broadcast_shape_binary_op(on_true.shape(), "where")?
+                    .broadcast_shape_binary_op(on_false.shape(), "where")?;
+                let cond = if cond.dtype().is_int() {
+                    cond.clone()
+                } else {
+                    cond.ne(0f64)?
+                };
+                let output = cond.broadcast_as(&shape)?.where_cond(
+                    &on_true.broadcast_as(&shape)?,
+                    &on_false.broadcast_as(&shape)?,
+                )?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Expand
+            "Expand" => {
+                let xs = get(&node.input[0])?;
+                let shape = shape_values(get(&node.input[1])?)?
+                    .iter()
+                    .map(|&d| d as usize)
+                    .collect::<Vec<_>>();
+                // The target shape is broadcast with the input shape, so a 1 keeps the input dim.
+                let shape = xs
+                    .shape()
+                    .broadcast_shape_binary_op(&shape.into(), "expand")?;
+                let output = xs.broadcast_as(shape)?.contiguous()?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Tile
+            "Tile" => {
+                let xs = get(&node.input[0])?;
+                let repeats = shape_values(get(&node.input[1])?)?;
+                if repeats.len() != xs.rank() || repeats.iter().any(|&r| r < 0) {
+                    bail!(
+                        "unexpected repeats {repeats:?} for Tile on {:?}",
+                        xs.shape()
+                    )
+                }
+                let repeats = repeats.iter().map(|&r| r as usize).collect::<Vec<_>>();
+                let mut output = xs.repeat(repeats.as_slice())?;
+                // repeat leaves the dims with 0 repeats unchanged rather than emptying them.
+                for (dim, _) in repeats.iter().enumerate().filter(|(_, &r)| r == 0) {
+                    output = output.narrow(dim, 0, 0)?
+                }
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Reshape
+            "Reshape" => {
+                let input0 = get(&node.input[0])?;
+                let input1 = shape_values(get(&node.input[1])?)?;
+                // With allowzero set, a 0 is a zero-sized dim rather than a copy of the input dim.
+                let allow_zero = get_attr_opt::<i64>(node, "allowzero")?
+                    .copied()
+                    .unwrap_or(0)
+                    != 0;
+                if allow_zero && input1.contains(&0) && input1.contains(&-1) {
+                    bail!("Reshape with allowzero cannot have both 0 and -1 in the shape")
+                }
+                if input1.iter().filter(|&&v| v == -1).count() > 1 {
+                    bail!("Reshape shape {input1:?} has more than one -1")
+                }
+                let mut dims = input1
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, &v)| match v {
+                        -1 => Ok(1),
+                        0 if !allow_zero => input0.dim(idx),
+                        v if v < 0 => bail!("unexpected value {v} in Reshape shape {input1:?}"),
+                        _ => Ok(v as usize),
+                    })
+                    .collect::<Result<Vec<usize>>>()?;
+                if let Some(idx) = input1.iter().position(|&v| v == -1) {
+                    let known = dims.iter().product::<usize>();
+                    if known == 0 {
+                        bail!("Reshape cannot infer the -1 dim of {input1:?} with a zero-sized dim")
+                    }
+                    dims[idx] = input0.elem_count() / known
+                }
+                let output = input0
+                    .reshape(dims)
+                    .map_err(inputs_context(node, &[input0]))?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "LogSoftmax" => {
+                let input = get(&node.input[0])?;
+                let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(-1);
+                let axis = normalize_index(axis, input.rank())?;
+                let output = candle_nn::ops::log_softmax(input, axis)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "Softmax" => {
                 let input = get(&node.input[0])?;
+                // Both paths subtract the max along the axis before exponentiating so that large
+                // logits do not overflow.
                 let output = match get_attr_opt::<i64>(node, "axis")? {
                     None => candle_nn::ops::softmax_last_dim(input)?,
                     Some(&axis) => {
-                        let num_axis = input.rank() as i64;
-                        let axis = if axis >= 0 {
-                            axis as usize
-                        } else if axis < -num_axis {
-                            bail!("wrong axis in concat {axis} for shape {:?}", input.shape())
+                        let axis = normalize_index(axis, input.rank())?;
+                        if axis + 1 == input.rank() {
+                            candle_nn::ops::softmax_last_dim(input)?
                         } else {
-                            (num_axis - axis) as usize
-                        };
-                        candle_nn::ops::softmax(input, axis)?
+                            candle_nn::ops::softmax(input, axis)?
+                        }
                     }
                 };
-                values.insert(node.output[0].clone(), output);
+                values.insert(node.output[0].clone(), output.into());
             }
             "Transpose" => {
                 let input = get(&node.input[0])?;
@@ -312,82 +3261,1133 @@ pub fn simple_eval(
                         input.permute(perm)?
                     }
                 };
-                values.insert(node.output[0].clone(), output);
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#LSTM
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#GRU
+            "LSTM" | "GRU" => {
+                let is_lstm = node.op_type == "LSTM";
+                let xs = get(&node.input[0])?;
+                let w = get(&node.input[1])?;
+                let r = get(&node.input[2])?;
+                let b = get_opt(3)?;
+                // With layout 1, the batch dim comes first in the inputs and outputs.
+                let batch_first = get_attr_opt::<i64>(node, "layout")?.copied().unwrap_or(0) == 1;
+                let xs = if batch_first {
+                    xs.transpose(0, 1)?.contiguous()?
+                } else {
+                    xs.clone()
+                };
+                let initial_state = |i: usize| -> Result<Option<Tensor>> {
+                    match get_opt(i)? {
+                        Some(s) if batch_first => Ok(Some(s.transpose(0, 1)?.contiguous()?)),
+                        s => Ok(s.cloned()),
+                    }
+                };
+                let (initial_h, initial_c) = (initial_state(5)?, initial_state(6)?);
+                let (seq_len, b_sz, _) = xs.dims3()?;
+                if let Some(seq_lens) = get_opt(4)? {
+                    let seq_lens = seq_lens.to_dtype(DType::I64)?.to_vec1::<i64>()?;
+                    if seq_lens.iter().any(|&l| l != seq_len as i64) {
+                        bail!(
+                            "{} with variable sequence lengths is not supported",
+                            node.op_type
+                        )
+                    }
+                }
+                let hidden = r.dim(2)?;
+                let direction = get_attr_opt::<str>(node, "direction")?.unwrap_or("forward");
+                let directions: &[bool] = match direction {
+                    "forward" => &[false],
+                    "reverse" => &[true],
+                    "bidirectional" => &[false, true],
+                    _ => bail!("unsupported direction {direction} for {}", node.name),
+                };
+                if w.dim(0)? != directions.len() {
+                    bail!(
+                        "{} with direction {direction} expects weights for {} directions, got {:?}",
+                        node.op_type,
+                        directions.len(),
+                        w.shape()
+                    )
+                }
+                let n_activations = if is_lstm { 3 } else { 2 };
+                let activations = match get_attr_opt::<[Vec<u8>]>(node, "activations")? {
+                    None => {
+                        let default: &[&str] = if is_lstm {
+                            &["Sigmoid", "Tanh", "Tanh"]
+                        } else {
+                            &["Sigmoid", "Tanh"]
+                        };
+                        default.repeat(directions.len())
+                    }
+                    Some(activations) => activations
+                        .iter()
+                        .map(|a| std::str::from_utf8(a).map_err(candle::Error::wrap))
+                        .collect::<Result<Vec<_>>>()?,
+                };
+                if activations.len() != n_activations * directions.len() {
+                    bail!("unexpected activations {activations:?} for {}", node.name)
+                }
+                let clip = get_attr_opt::<f32>(node, "clip")?.map(|&c| c as f64);
+                let zeros = Tensor::zeros((b_sz, hidden), xs.dtype(), xs.device())?;
+                let initial = |state: &Option<Tensor>, d: usize| match state {
+                    None => Ok(zeros.clone()),
+                    Some(state) => state.get(d),
+                };
+                let (mut ys, mut y_hs, mut y_cs) = (vec![], vec![], vec![]);
+                for (d, &reverse) in directions.iter().enumerate() {
+                    let b = b.map(|b| b.get(d)).transpose()?;
+                    let weights = (&w.get(d)?, &r.get(d)?, b.as_ref());
+                    let activations = &activations[d * n_activations..(d + 1) * n_activations];
+                    let h0 = initial(&initial_h, d)?;
+                    let (y, y_h) = if is_lstm {
+                        if get_attr_opt::<i64>(node, "input_forget")?
+                            .copied()
+                            .unwrap_or(0)
+                            != 0
+                        {
+                            bail!("LSTM with input_forget is not supported for {}", node.name)
+                        }
+                        let peepholes = get_opt(7)?.map(|p| p.get(d)).transpose()?;
+                        let (y, y_h, y_c) = lstm_direction(
+                            &xs,
+                            weights,
+                            (h0, initial(&initial_c, d)?),
+                            peepholes.as_ref(),
+                            activations,
+                            clip,
+                            reverse,
+                        )?;
+                        y_cs.push(y_c);
+                        (y, y_h)
+                    } else {
+                        let linear_before_reset = get_attr_opt::<i64>(node, "linear_before_reset")?
+                            .copied()
+                            .unwrap_or(0)
+                            != 0;
+                        gru_direction(
+                            &xs,
+                            weights,
+                            h0,
+                            activations,
+                            clip,
+                            linear_before_reset,
+                            reverse,
+                        )?
+                    };
+                    ys.push(y);
+                    y_hs.push(y_h);
+                }
+                // Y has shape (seq, num_directions, batch, hidden), Y_h and Y_c have shape
+                // (num_directions, batch, hidden).
+                let y = Tensor::stack(&ys, 1)?;
+                let mut outputs = vec![y, Tensor::stack(&y_hs, 0)?];
+                if is_lstm {
+                    outputs.push(Tensor::stack(&y_cs, 0)?)
+                }
+                if batch_first {
+                    outputs[0] = outputs[0].permute((2, 0, 1, 3))?;
+                    for output in outputs[1..].iter_mut() {
+                        *output = output.transpose(0, 1)?
+                    }
+                }
+                for (name, output) in node.output.iter().zip(outputs) {
+                    if !name.is_empty() {
+                        values.insert(name.clone(), output.contiguous()?.into());
+                    }
+                }
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Dropout
+            "Dropout" => {
+                let input = get(&node.input[0])?;
+                // Before opset 12, the ratio is an attribute and there is no training mode.
+                let ratio = match get_opt(1)? {
+                    Some(t) => match t
+                        .to_dtype(DType::F64)?
+                        .flatten_all()?
+                        .to_vec1::<f64>()?
+                        .first()
+                    {
+                        Some(&ratio) => ratio,
+                        None => bail!("Dropout {} has an empty ratio", node.name),
+                    },
+                    None => get_attr_opt::<f32>(node, "ratio")?.copied().unwrap_or(0.5) as f64,
+                };
+                let training_mode = match get_opt(2)? {
+                    Some(t) => match t
+                        .to_dtype(DType::U8)?
+                        .flatten_all()?
+                        .to_vec1::<u8>()?
+                        .first()
+                    {
+                        Some(&mode) => mode != 0,
+                        None => bail!("Dropout {} has an empty training_mode", node.name),
+                    },
+                    None => false,
+                };
+                // In inference mode, the input is passed through and nothing gets masked.
+                let (output, mask) = if training_mode && ratio > 0. {
+                    let seed = get_attr_opt::<i64>(node, "seed")?.copied();
+                    let mask =
+                        dropout_mask(input.shape(), ratio, seed)?.to_device(input.device())?;
+                    let output = if ratio >= 1. {
+                        input.zeros_like()?
+                    } else {
+                        (input * mask.to_dtype(input.dtype())?)?.affine(1. / (1. - ratio), 0.)?
+                    };
+                    (output, mask)
+                } else {
+                    (input.clone(), input.ones_like()?.to_dtype(DType::U8)?)
+                };
+                values.insert(node.output[0].clone(), output.into());
+                if let Some(name) = node.output.get(1).filter(|name| !name.is_empty()) {
+                    values.insert(name.clone(), mask.into());
+                }
+            }
+            "MaxPool" => {
+                // https://github.com/onnx/onnx/blob/main/docs/Operators.md#MaxPool
+                let dilations = get_attr_opt::<[i64]>(node, "dilations")?;
+                let kernel_shape = get_attr::<[i64]>(node, "kernel_shape")?;
+                let pads = get_attr_opt::<[i64]>(node, "pads")?;
+                let strides = get_attr_opt::<[i64]>(node, "strides")?;
+                let auto_pad = get_attr_opt::<str>(node, "auto_pad")?;
+                match auto_pad {
+                    None | Some("NOTSET") => (),
+                    Some(s) => bail!("unsupported auto_pad {s}"),
+                };
+                if let Some(d) = dilations {
+                    if d.iter().any(|&v| v != 1) {
+                        bail!("MaxPool with dilation != 1, {dilations:?}")
+                    }
+                }
+                if let Some(d) = pads {
+                    if d.iter().any(|&v| v != 0) {
+                        bail!("MaxPool with pads != 0, {pads:?}")
+                    }
+                }
+                let xs = get(&node.input[0])?;
+                let (k1, k2) = match kernel_shape {
+                    [k1, k2] => (*k1 as usize, *k2 as usize),
+                    _ => bail!("only 2d MaxPool is supported, kernel shape {kernel_shape:?}"),
+                };
+                // The strides default to 1 rather than to the kernel size.
+                let (s1, s2) = match strides {
+                    None => (1, 1),
+                    Some([s1, s2]) => (*s1 as usize, *s2 as usize),
+                    Some(strides) => bail!("only 2d MaxPool is supported, strides {strides:?}"),
+                };
+                let ys = xs.max_pool2d_with_stride((k1, k2), (s1, s2))?;
+                let indices = match node.output.get(1).filter(|o| !o.is_empty()) {
+                    None => None,
+                    Some(output) => {
+                        let storage_order = get_attr_opt::<i64>(node, "storage_order")?
+                            .copied()
+                            .unwrap_or(0);
+                        let column_major = storage_order != 0;
+                        let indices = max_pool2d_indices(xs, (k1, k2), (s1, s2), column_major)?;
+                        Some((output.clone(), indices))
+                    }
+                };
+                values.insert(node.output[0].clone(), ys.into());
+                if let Some((output, indices)) = indices {
+                    values.insert(output, indices.into());
+                }
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#MaxUnpool
+            "MaxUnpool" => {
+                let xs = get(&node.input[0])?;
+                let indices = get(&node.input[1])?;
+                let kernel_shape = get_attr::<[i64]>(node, "kernel_shape")?;
+                let strides = get_attr_opt::<[i64]>(node, "strides")?;
+                let pads = get_attr_opt::<[i64]>(node, "pads")?;
+                let n_spatial = kernel_shape.len();
+                if xs.rank() != n_spatial + 2 || indices.dims() != xs.dims() {
+                    bail!(
+                        "unexpected shapes for MaxUnpool {}, x {:?}, indices {:?}, kernel {kernel_shape:?}",
+                        node.name,
+                        xs.shape(),
+                        indices.shape()
+                    )
+                }
+                let out_dims = match get_opt(2)? {
+                    Some(output_shape) => output_shape
+                        .to_dtype(DType::I64)?
+                        .to_vec1::<i64>()?
+                        .into_iter()
+                        .map(|d| d as usize)
+                        .collect::<Vec<_>>(),
+                    None => {
+                        // The inverse of the MaxPool output shape computation.
+                        let mut dims = xs.dims()[..2].to_vec();
+                        for (i, &k) in kernel_shape.iter().enumerate() {
+                            let s = strides.map_or(1, |s| s[i]);
+                            let p = pads.map_or(0, |p| p[i] + p[i + n_spatial]);
+                            dims.push(((xs.dim(i + 2)? as i64 - 1) * s + k - p) as usize)
+                        }
+                        dims
+                    }
+                };
+                // The indices are flat positions in the whole output tensor, as produced by
+                // MaxPool with the default storage order.
+                let n_out = out_dims.iter().product::<usize>();
+                let offsets = indices
+                    .to_dtype(DType::I64)?
+                    .flatten_all()?
+                    .to_vec1::<i64>()?
+                    .into_iter()
+                    .map(|idx| {
+                        if idx < 0 || idx as usize >= n_out {
+                            bail!("MaxUnpool index {idx} out of bounds for {out_dims:?}")
+                        }
+                        Ok(idx as usize)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let zeros = Tensor::zeros(out_dims.as_slice(), xs.dtype(), xs.device())?;
+                let ys = scatter_flat(&zeros, &offsets, xs, ScatterReduction::None)?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            "AveragePool" => {
+                // https://github.com/onnx/onnx/blob/main/docs/Operators.md#AveragePool
+                let dilations = get_attr_opt::<[i64]>(node, "dilations")?;
+                let kernel_shape = get_attr::<[i64]>(node, "kernel_shape")?;
+                let pads = get_attr_opt::<[i64]>(node, "pads")?;
+                let strides = get_attr_opt::<[i64]>(node, "strides")?;
+                let auto_pad = get_attr_opt::<str>(node, "auto_pad")?;
+                match auto_pad {
+                    None | Some("NOTSET") => (),
+                    Some(s) => bail!("unsupported auto_pad {s}"),
+                };
+                if let Some(d) = dilations {
+                    if d.iter().any(|&v| v != 1) {
+                        bail!("AvgPool with dilation != 1, {dilations:?}")
+                    }
+                }
+                if let Some(d) = pads {
+                    if d.iter().any(|&v| v != 0) {
+                        bail!("AvgPool with pads != 0, {pads:?}")
+                    }
+                }
+                let xs = get(&node.input[0])?;
+                let (k1, k2) = match kernel_shape {
+                    [k1, k2] => (*k1 as usize, *k2 as usize),
+                    _ => bail!("only 2d AvgPool is supported, kernel shape {kernel_shape:?}"),
+                };
+                let ys = match strides {
+                    None => xs.avg_pool2d((k1, k2))?,
+                    Some([s1, s2]) => {
+                        xs.avg_pool2d_with_stride((k1, k2), (*s1 as usize, *s2 as usize))?
+                    }
+                    Some(strides) => bail!("only 2d AvgPool is supported, strides {strides:?}"),
+                };
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#AffineGrid
+            "AffineGrid" => {
+                let theta = get(&node.input[0])?;
+                let size = get(&node.input[1])?
+                    .to_dtype(DType::I64)?
+                    .to_vec1::<i64>()?
+                    .into_iter()
+                    .map(|s| s as usize)
+                    .collect::<Vec<_>>();
+                let align_corners = get_attr_opt::<i64>(node, "align_corners")?
+                    .copied()
+                    .unwrap_or(0);
+                let grid = affine_grid(theta, &size, align_corners != 0)?;
+                values.insert(node.output[0].clone(), grid.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#GridSample
+            "GridSample" => {
+                let xs = get(&node.input[0])?;
+                let grid = get(&node.input[1])?;
+                let mode = get_attr_opt::<str>(node, "mode")?.unwrap_or("bilinear");
+                let padding_mode = get_attr_opt::<str>(node, "padding_mode")?.unwrap_or("zeros");
+                let align_corners = get_attr_opt::<i64>(node, "align_corners")?
+                    .copied()
+                    .unwrap_or(0);
+                let ys = grid_sample(xs, grid, mode, padding_mode, align_corners != 0)?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#NonMaxSuppression
+            "NonMaxSuppression" => {
+                let boxes = get(&node.input[0])?;
+                let scores = get(&node.input[1])?;
+                // The trailing inputs are optional and can be skipped with an empty name.
+                let opt_input = |idx: usize| match node.input.get(idx) {
+                    Some(name) if !name.is_empty() => get(name).map(Some),
+                    _ => Ok(None),
+                };
+                let scalar_input = |idx: usize| -> Result<Option<f64>> {
+                    let t = match opt_input(idx)? {
+                        Some(t) => t,
+                        None => return Ok(None),
+                    };
+                    match t
+                        .flatten_all()?
+                        .to_dtype(DType::F64)?
+                        .to_vec1::<f64>()?
+                        .first()
+                    {
+                        Some(&v) => Ok(Some(v)),
+                        None => bail!(
+                            "NonMaxSuppression expects a scalar for input {}, got an empty tensor",
+                            node.input[idx]
+                        ),
+                    }
+                };
+                let max_output_boxes_per_class = scalar_input(2)?.map_or(0, |v| (v as i64).max(0));
+                let iou_threshold = scalar_input(3)?.map_or(0., |v| v as f32);
+                let score_threshold = scalar_input(4)?.map(|v| v as f32);
+                let center_point_box = get_attr_opt::<i64>(node, "center_point_box")?
+                    .copied()
+                    .unwrap_or(0);
+                let output = non_max_suppression(
+                    boxes,
+                    scores,
+                    max_output_boxes_per_class as usize,
+                    iou_threshold,
+                    score_threshold,
+                    center_point_box != 0,
+                )?
+                .to_device(boxes.device())?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#RoiAlign
+            "RoiAlign" => {
+                let xs = get(&node.input[0])?;
+                let rois = get(&node.input[1])?;
+                let batch_indices = get(&node.input[2])?;
+                let max_mode = match get_attr_opt::<str>(node, "mode")?.unwrap_or("avg") {
+                    "avg" => false,
+                    "max" => true,
+                    mode => bail!("unsupported mode {mode} for RoiAlign {}", node.name),
+                };
+                let half_pixel = match get_attr_opt::<str>(node, "coordinate_transformation_mode")?
+                    .unwrap_or("half_pixel")
+                {
+                    "half_pixel" => true,
+                    "output_half_pixel" => false,
+                    mode => bail!(
+                        "unsupported coordinate_transformation_mode {mode} for RoiAlign {}",
+                        node.name
+                    ),
+                };
+                let get_usize = |name: &str, default: i64| -> Result<usize> {
+                    let v = get_attr_opt::<i64>(node, name)?.copied().unwrap_or(default);
+                    if v < 0 {
+                        bail!("negative {name} {v} for RoiAlign {}", node.name)
+                    }
+                    Ok(v as usize)
+                };
+                let cfg = RoiAlignConfig {
+                    output_height: get_usize("output_height", 1)?,
+                    output_width: get_usize("output_width", 1)?,
+                    sampling_ratio: get_usize("sampling_ratio", 0)?,
+                    spatial_scale: get_attr_opt::<f32>(node, "spatial_scale")?
+                        .copied()
+                        .unwrap_or(1.0) as f64,
+                    max_mode,
+                    half_pixel,
+                };
+                let ys = roi_align(xs, rois, batch_indices, &cfg)?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#ReverseSequence
+            "ReverseSequence" => {
+                let xs = get(&node.input[0])?;
+                let sequence_lens = get(&node.input[1])?
+                    .to_dtype(DType::I64)?
+                    .to_vec1::<i64>()?;
+                let batch_axis = get_attr_opt::<i64>(node, "batch_axis")?
+                    .copied()
+                    .unwrap_or(1);
+                let time_axis = get_attr_opt::<i64>(node, "time_axis")?
+                    .copied()
+                    .unwrap_or(0);
+                let (batch_axis, time_axis) = match (batch_axis, time_axis) {
+                    (1, 0) => (1, 0),
+                    (0, 1) => (0, 1),
+                    _ => bail!(
+                        "unsupported batch_axis {batch_axis} and time_axis {time_axis} for {}",
+                        node.name
+                    ),
+                };
+                let batch_size = xs.dim(batch_axis)?;
+                let max_seq_len = xs.dim(time_axis)?;
+                if sequence_lens.len() != batch_size {
+                    bail!(
+                        "sequence_lens has {} elements, expected {batch_size} for {}",
+                        sequence_lens.len(),
+                        node.name
+                    )
+                }
+                // Each sequence only has its first len steps reversed, the padding is kept as is.
+                let ys = sequence_lens
+                    .iter()
+                    .enumerate()
+                    .map(|(b_idx, &len)| {
+                        let len = len.clamp(0, max_seq_len as i64) as u32;
+                        let indexes = (0..len)
+                            .rev()
+                            .chain(len..max_seq_len as u32)
+                            .collect::<Vec<_>>();
+                        let indexes = Tensor::new(indexes.as_slice(), xs.device())?;
+                        xs.narrow(batch_axis, b_idx, 1)?
+                            .contiguous()?
+                            .index_select(&indexes, time_axis)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let ys = Tensor::cat(&ys, batch_axis)?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Multinomial
+            "Multinomial" => {
+                let xs = get(&node.input[0])?;
+                let sample_size = get_attr_opt::<i64>(node, "sample_size")?
+                    .copied()
+                    .unwrap_or(1);
+                let seed = get_attr_opt::<f32>(node, "seed")?.copied();
+                // Int32 is not available in candle so the samples are returned as I64 for both
+                // integer output types.
+                let dt = get_attr_opt::<i64>(node, "dtype")?
+                    .copied()
+                    .unwrap_or(DataType::Int32 as i64);
+                if dt != DataType::Int32 as i64 && dt != DataType::Int64 as i64 {
+                    bail!("unsupported dtype {dt} for Multinomial {}", node.name)
+                }
+                if sample_size < 0 {
+                    bail!("negative sample_size {sample_size} for {}", node.name)
+                }
+                let ys = multinomial(xs, sample_size as usize, seed)?.to_device(xs.device())?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#LRN
+            "LRN" => {
+                let xs = get(&node.input[0])?;
+                let alpha = get_attr_opt::<f32>(node, "alpha")?.copied().unwrap_or(1e-4);
+                let beta = get_attr_opt::<f32>(node, "beta")?.copied().unwrap_or(0.75);
+                let bias = get_attr_opt::<f32>(node, "bias")?.copied().unwrap_or(1.0);
+                let size = *get_attr::<i64>(node, "size")?;
+                if size <= 0 {
+                    bail!("invalid size {size} for LRN {}", node.name)
+                }
+                let size = size as usize;
+                // The window over channels c is [c - floor((size - 1) / 2), c + ceil((size - 1) / 2)],
+                // padding the squares with zeros lets each window be a narrow of the padded tensor.
+                let num_channels = xs.dim(1)?;
+                let sq = xs.sqr()?.pad_with_zeros(1, (size - 1) / 2, size / 2)?;
+                let mut square_sum = sq.narrow(1, 0, num_channels)?;
+                for offset in 1..size {
+                    square_sum = (square_sum + sq.narrow(1, offset, num_channels)?)?;
+                }
+                let scale = square_sum
+                    .affine(alpha as f64 / size as f64, bias as f64)?
+                    .powf(beta as f64)?;
+                let ys = xs.div(&scale)?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#MeanVarianceNormalization
+            "MeanVarianceNormalization" => {
+                let xs = get(&node.input[0])?;
+                let axes = match get_attr_opt::<[i64]>(node, "axes")? {
+                    None => vec![0, 2, 3],
+                    Some(axes) => axes.to_vec(),
+                };
+                let axes = axes
+                    .iter()
+                    .map(|&axis| normalize_index(axis, xs.rank()))
+                    .collect::<Result<Vec<_>>>()?;
+                let mean = xs.mean_keepdim(axes.as_slice())?;
+                let xs = xs.broadcast_sub(&mean)?;
+                let var = xs.sqr()?.mean_keepdim(axes.as_slice())?;
+                let ys = xs.broadcast_div(&(var + 1e-9)?.sqrt()?)?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#GlobalLpPool
+            "GlobalLpPool" => {
+                let xs = get(&node.input[0])?;
+                let p = get_attr_opt::<i64>(node, "p")?.copied().unwrap_or(2);
+                if xs.rank() < 3 {
+                    bail!(
+                        "GlobalLpPool expects a [N, C, ...] input, got {:?}",
+                        xs.shape()
+                    )
+                }
+                let spatial_dims = (2..xs.rank()).collect::<Vec<_>>();
+                let ys = lp_norm_keepdim(xs, &spatial_dims, p)?;
+                values.insert(node.output[0].clone(), ys.into());
             }
-            "Dropout" => {
-                let input = get(&node.input[0])?;
-                // Do not apply dropout at the moment, consider that we're only doing inference.
-                values.insert(node.output[0].clone(), input.clone());
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#GlobalAveragePool
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#GlobalMaxPool
+            "GlobalAveragePool" | "GlobalMaxPool" => {
+                let xs = get(&node.input[0])?;
+                if xs.rank() < 3 {
+                    bail!(
+                        "{} expects a [N, C, ...] input, got {:?}",
+                        node.op_type,
+                        xs.shape()
+                    )
+                }
+                // All the spatial dims get reduced to 1 whatever the rank, e.g. [N, C, L] for 1D
+                // inputs or [N, C, D, H, W] for 3D ones.
+                let (n, c) = (xs.dim(0)?, xs.dim(1)?);
+                let mut dims = vec![n, c];
+                dims.resize(xs.rank(), 1);
+                let xs = xs.reshape((n, c, ()))?;
+                let ys = if node.op_type == "GlobalMaxPool" {
+                    xs.max_keepdim(2)?
+                } else {
+                    xs.mean_keepdim(2)?
+                };
+                values.insert(node.output[0].clone(), ys.reshape(dims)?.into());
             }
-            "MaxPool" => {
-                // https://github.com/onnx/onnx/blob/main/docs/Operators.md#MaxPool
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#LpPool
+            "LpPool" => {
                 let dilations = get_attr_opt::<[i64]>(node, "dilations")?;
                 let kernel_shape = get_attr::<[i64]>(node, "kernel_shape")?;
                 let pads = get_attr_opt::<[i64]>(node, "pads")?;
                 let strides = get_attr_opt::<[i64]>(node, "strides")?;
                 let auto_pad = get_attr_opt::<str>(node, "auto_pad")?;
+                let p = get_attr_opt::<i64>(node, "p")?.copied().unwrap_or(2);
                 match auto_pad {
                     None | Some("NOTSET") => (),
                     Some(s) => bail!("unsupported auto_pad {s}"),
                 };
                 if let Some(d) = dilations {
                     if d.iter().any(|&v| v != 1) {
-                        bail!("MaxPool with dilation != 1, {dilations:?}")
-                    }
-                }
-                if let Some(d) = pads {
-                    if d.iter().any(|&v| v != 0) {
-                        bail!("MaxPool with pads != 0, {pads:?}")
+                        bail!("LpPool with dilation != 1, {dilations:?}")
                     }
                 }
                 let xs = get(&node.input[0])?;
                 let (k1, k2) = match kernel_shape {
                     [k1, k2] => (*k1 as usize, *k2 as usize),
-                    _ => bail!("only 2d MaxPool is supported, kernel shape {kernel_shape:?}"),
+                    _ => bail!("only 2d LpPool is supported, kernel shape {kernel_shape:?}"),
                 };
-                let ys = match strides {
-                    None => xs.max_pool2d((k1, k2))?,
-                    Some([s1, s2]) => {
-                        xs.max_pool2d_with_stride((k1, k2), (*s1 as usize, *s2 as usize))?
+                let xs = match pads {
+                    None => xs.clone(),
+                    Some([p1, p2, p3, p4]) => xs
+                        .pad_with_zeros(2, *p1 as usize, *p3 as usize)?
+                        .pad_with_zeros(3, *p2 as usize, *p4 as usize)?,
+                    Some(pads) => bail!("only 2d LpPool is supported, pads {pads:?}"),
+                };
+                let (s1, s2) = match strides {
+                    None => (1, 1),
+                    Some([s1, s2]) => (*s1 as usize, *s2 as usize),
+                    Some(strides) => bail!("only 2d LpPool is supported, strides {strides:?}"),
+                };
+                // The sum over each window is obtained by rescaling an average pooling.
+                let ys = lp_norm_with(&xs, p, |ys| {
+                    ys.avg_pool2d_with_stride((k1, k2), (s1, s2))? * (k1 * k2) as f64
+                })?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#ArgMax
+            "ArgMax" | "ArgMin" => {
+                let xs = get(&node.input[0])?;
+                let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(0);
+                let keepdims = get_attr_opt::<i64>(node, "keepdims")?.copied().unwrap_or(1);
+                let select_last_index = get_attr_opt::<i64>(node, "select_last_index")?
+                    .copied()
+                    .unwrap_or(0);
+                let axis = normalize_index(axis, xs.rank())?;
+                let dim = xs.dim(axis)?;
+                // candle returns the first index on ties, to get the last one the axis is reversed
+                // and the index is then mapped back to the original order.
+                let xs = if select_last_index != 0 {
+                    let rev = (0..dim as u32).rev().collect::<Vec<_>>();
+                    let rev = Tensor::new(rev.as_slice(), xs.device())?;
+                    xs.contiguous()?.index_select(&rev, axis)?
+                } else {
+                    xs.clone()
+                };
+                let ys = if node.op_type == "ArgMax" {
+                    xs.argmax_keepdim(axis)?
+                } else {
+                    xs.argmin_keepdim(axis)?
+                };
+                let ys = ys.to_dtype(DType::I64)?;
+                let ys = if select_last_index != 0 {
+                    ys.affine(-1., (dim - 1) as f64)?
+                } else {
+                    ys
+                };
+                let ys = if keepdims == 0 { ys.squeeze(axis)? } else { ys };
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators-ml.md#ai.onnx.ml.CastMap
+            "CastMap" => {
+                let map = get_value(&node.input[0])?.as_map()?;
+                let dtype = match get_attr_opt::<str>(node, "cast_to")?.unwrap_or("TO_FLOAT") {
+                    "TO_FLOAT" => DType::F32,
+                    "TO_INT64" => DType::I64,
+                    cast_to => bail!("unsupported cast_to {cast_to} for CastMap {}", node.name),
+                };
+                let mut entries = vec![];
+                for (key, value) in map.iter() {
+                    let key = match key {
+                        MapKey::Int(key) => *key,
+                        MapKey::String(_) => bail!("CastMap {} expects int64 keys", node.name),
+                    };
+                    let value = value.as_tensor()?.to_dtype(DType::F64)?.to_vec0::<f64>()?;
+                    entries.push((key, value));
+                }
+                // The map is ordered by key so the dense form is the values in key order, the
+                // sparse form has max_map entries indexed by key with zeros for missing keys.
+                let ys = match get_attr_opt::<str>(node, "map_form")?.unwrap_or("DENSE") {
+                    "DENSE" => entries.iter().map(|&(_, v)| v).collect::<Vec<_>>(),
+                    "SPARSE" => {
+                        let max_map = get_attr_opt::<i64>(node, "max_map")?.copied().unwrap_or(1);
+                        let mut ys = vec![0f64; max_map.max(0) as usize];
+                        for (key, value) in entries {
+                            if key < 0 || key >= max_map {
+                                bail!("key {key} out of range for CastMap {}", node.name)
+                            }
+                            ys[key as usize] = value
+                        }
+                        ys
                     }
-                    Some(strides) => bail!("only 2d MaxPool is supported, strides {strides:?}"),
+                    map_form => bail!("unsupported map_form {map_form} for CastMap {}", node.name),
                 };
-                values.insert(node.output[0].clone(), ys);
+                let n = ys.len();
+                let ys = Tensor::from_vec(ys, (1, n), &Device::Cpu)?.to_dtype(dtype)?;
+                values.insert(node.output[0].clone(), ys.into());
             }
-            "AveragePool" => {
-                // https://github.com/onnx/onnx/blob/main/docs/Operators.md#AveragePool
-                let dilations = get_attr_opt::<[i64]>(node, "dilations")?;
-                let kernel_shape = get_attr::<[i64]>(node, "kernel_shape")?;
-                let pads = get_attr_opt::<[i64]>(node, "pads")?;
-                let strides = get_attr_opt::<[i64]>(node, "strides")?;
-                let auto_pad = get_attr_opt::<str>(node, "auto_pad")?;
-                match auto_pad {
-                    None | Some("NOTSET") => (),
-                    Some(s) => bail!("unsupported auto_pad {s}"),
+            // https://github.com/onnx/onnx/blob/main/docs/Operators-ml.md#ai.onnx.ml.LabelEncoder
+            "LabelEncoder" => {
+                let xs = get(&node.input[0])?;
+                let (shape, device) = (xs.shape().clone(), xs.device());
+                let xs = xs.flatten_all()?;
+                // The keys are looked up by their bit representation so that both integer and
+                // float keys can be stored in the same map.
+                let (keys, xs) = if let Some(keys) = get_attr_opt::<[i64]>(node, "keys_int64s")? {
+                    let keys = keys.iter().map(|&k| k as u64).collect::<Vec<_>>();
+                    let xs = xs.to_dtype(DType::I64)?.to_vec1::<i64>()?;
+                    (keys, xs.into_iter().map(|x| x as u64).collect::<Vec<_>>())
+                } else if let Some(keys) = get_attr_opt::<[f32]>(node, "keys_floats")? {
+                    let keys = keys.iter().map(|&k| k.to_bits() as u64).collect::<Vec<_>>();
+                    let xs = xs.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+                    (keys, xs.into_iter().map(|x| x.to_bits() as u64).collect())
+                } else {
+                    bail!(
+                        "only int64 and float keys are supported in LabelEncoder {}",
+                        node.name
+                    )
                 };
-                if let Some(d) = dilations {
-                    if d.iter().any(|&v| v != 1) {
-                        bail!("AvgPool with dilation != 1, {dilations:?}")
+                let ys = if let Some(vs) = get_attr_opt::<[i64]>(node, "values_int64s")? {
+                    if vs.len() != keys.len() {
+                        bail!(
+                            "mismatched keys and values_int64s in LabelEncoder {}",
+                            node.name
+                        )
+                    }
+                    let default = get_attr_opt::<i64>(node, "default_int64")?
+                        .copied()
+                        .unwrap_or(-1);
+                    let map = keys.iter().zip(vs.iter()).collect::<HashMap<_, _>>();
+                    let ys = xs
+                        .iter()
+                        .map(|x| map.get(x).map_or(default, |&&v| v))
+                        .collect::<Vec<_>>();
+                    Tensor::from_vec(ys, shape, &Device::Cpu)?
+                } else if let Some(vs) = get_attr_opt::<[f32]>(node, "values_floats")? {
+                    if vs.len() != keys.len() {
+                        bail!(
+                            "mismatched keys and values_floats in LabelEncoder {}",
+                            node.name
+                        )
+                    }
+                    let default = get_attr_opt::<f32>(node, "default_float")?
+                        .copied()
+                        .unwrap_or(-0.0);
+                    let map = keys.iter().zip(vs.iter()).collect::<HashMap<_, _>>();
+                    let ys = xs
+                        .iter()
+                        .map(|x| map.get(x).map_or(default, |&&v| v))
+                        .collect::<Vec<_>>();
+                    Tensor::from_vec(ys, shape, &Device::Cpu)?
+                } else {
+                    bail!(
+                        "only int64 and float values are supported in LabelEncoder {}",
+                        node.name
+                    )
+                };
+                values.insert(node.output[0].clone(), ys.to_device(device)?.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators-ml.md#ai.onnx.ml.Scaler
+            "Scaler" => {
+                let xs = get(&node.input[0])?.to_dtype(DType::F32)?;
+                let num_features = xs.dim(xs.rank() - 1)?;
+                // A single scale or offset value applies to all the features.
+                let per_feature = |name: &str, default: f32| -> Result<Tensor> {
+                    let vs = get_attr_opt::<[f32]>(node, name)?.unwrap_or(&[]);
+                    let vs = match vs.len() {
+                        0 => vec![default; num_features],
+                        1 => vec![vs[0]; num_features],
+                        n if n == num_features => vs.to_vec(),
+                        n => bail!(
+                            "{name} has {n} values, expected {num_features} in {}",
+                            node.name
+                        ),
+                    };
+                    Tensor::from_vec(vs, num_features, xs.device())
+                };
+                let scale = per_feature("scale", 1.)?;
+                let offset = per_feature("offset", 0.)?;
+                let ys = xs.broadcast_sub(&offset)?.broadcast_mul(&scale)?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators-ml.md#ai.onnx.ml.Normalizer
+            "Normalizer" => {
+                let xs = get(&node.input[0])?.to_dtype(DType::F32)?;
+                let last_dim = xs.rank() - 1;
+                let norm = match get_attr_opt::<str>(node, "norm")?.unwrap_or("MAX") {
+                    "MAX" => xs.max_keepdim(last_dim)?,
+                    "L1" => xs.abs()?.sum_keepdim(last_dim)?,
+                    "L2" => xs.sqr()?.sum_keepdim(last_dim)?.sqrt()?,
+                    norm => bail!("unsupported norm {norm} for Normalizer {}", node.name),
+                };
+                let ys = xs.broadcast_div(&norm)?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators-ml.md#ai.onnx.ml.Binarizer
+            "Binarizer" => {
+                let xs = get(&node.input[0])?;
+                let threshold = get_attr_opt::<f32>(node, "threshold")?
+                    .copied()
+                    .unwrap_or(0.);
+                let ys = xs
+                    .to_dtype(DType::F64)?
+                    .gt(threshold as f64)?
+                    .to_dtype(xs.dtype())?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators-ml.md#ai.onnx.ml.Imputer
+            "Imputer" => {
+                let xs = get(&node.input[0])?;
+                let num_features = xs.dim(xs.rank() - 1)?;
+                let (imputed, replaced) =
+                    if let Some(vs) = get_attr_opt::<[f32]>(node, "imputed_value_floats")? {
+                        let replaced = get_attr_opt::<f32>(node, "replaced_value_float")?
+                            .copied()
+                            .unwrap_or(0.);
+                        let vs = vs.iter().map(|&v| v as f64).collect::<Vec<_>>();
+                        (vs, replaced as f64)
+                    } else if let Some(vs) = get_attr_opt::<[i64]>(node, "imputed_value_int64s")? {
+                        let replaced = get_attr_opt::<i64>(node, "replaced_value_int64")?
+                            .copied()
+                            .unwrap_or(0);
+                        let vs = vs.iter().map(|&v| v as f64).collect::<Vec<_>>();
+                        (vs, replaced as f64)
+                    } else {
+                        bail!("no imputed values in Imputer {}", node.name)
+                    };
+                // A single imputed value applies to all the features.
+                let imputed = match imputed.len() {
+                    1 => vec![imputed[0]; num_features],
+                    n if n == num_features => imputed,
+                    n => bail!(
+                        "{n} imputed values, expected {num_features} in Imputer {}",
+                        node.name
+                    ),
+                };
+                let imputed = Tensor::from_vec(imputed, num_features, xs.device())?
+                    .to_dtype(xs.dtype())?
+                    .broadcast_as(xs.shape())?;
+                // NaN never compares equal to itself so a NaN sentinel is matched using `ne`.
+                let mask = if replaced.is_nan() {
+                    xs.ne(xs)?
+                } else {
+                    xs.eq(&Tensor::new(replaced, xs.device())?
+                        .to_dtype(xs.dtype())?
+                        .broadcast_as(xs.shape())?)?
+                };
+                let ys = mask.where_cond(&imputed, xs)?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#GatherND
+            "GatherND" => {
+                let data = get(&node.input[0])?;
+                let indices = get(&node.input[1])?;
+                let batch_dims = get_attr_opt::<i64>(node, "batch_dims")?
+                    .copied()
+                    .unwrap_or(0);
+                let output = gather_nd(data, indices, batch_dims as usize)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#ScatterND
+            "ScatterND" => {
+                let data = get(&node.input[0])?;
+                let indices = get(&node.input[1])?;
+                let updates = get(&node.input[2])?;
+                let reduction = ScatterReduction::from_node(node)?;
+                let ys = match scatter_nd_rows(data, indices, updates, reduction)? {
+                    Some(ys) => ys,
+                    None => {
+                        let offsets = scatter_nd_offsets(data, indices)?;
+                        scatter_flat(data, &offsets, updates, reduction)?
+                    }
+                };
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#ScatterElements
+            "ScatterElements" => {
+                let data = get(&node.input[0])?;
+                let indices = get(&node.input[1])?;
+                let updates = get(&node.input[2])?;
+                let reduction = ScatterReduction::from_node(node)?;
+                let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(0);
+                let axis = normalize_index(axis, data.rank())?;
+                let ys = match reduction {
+                    ScatterReduction::Add => scatter_elements_add(data, indices, updates, axis)?,
+                    _ => None,
+                };
+                let ys = match ys {
+                    Some(ys) => ys,
+                    None => {
+                        let offsets = scatter_elements_offsets(data, indices, axis)?;
+                        scatter_flat(data, &offsets, updates, reduction)?
+                    }
+                };
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Resize
+            "Resize" => {
+                let xs = get(&node.input[0])?;
+                // Exactly one of scales and sizes is set, the other one is either omitted or empty.
+                let scales = match get_opt(2)? {
+                    Some(scales) if scales.elem_count() > 0 => {
+                        Some(scales.to_dtype(DType::F64)?.to_vec1::<f64>()?)
+                    }
+                    _ => None,
+                };
+                let sizes = match get_opt(3)? {
+                    Some(sizes) if sizes.elem_count() > 0 => Some(shape_values(sizes)?),
+                    _ => None,
+                };
+                let (scales, sizes) = match (scales, sizes) {
+                    (Some(scales), None) => (scales, None),
+                    (None, Some(sizes)) => {
+                        if sizes.len() != xs.rank() || sizes.iter().any(|&s| s <= 0) {
+                            bail!(
+                                "invalid sizes {sizes:?} for shape {:?} in Resize",
+                                xs.shape()
+                            )
+                        }
+                        let sizes = sizes.iter().map(|&s| s as usize).collect::<Vec<_>>();
+                        let scales = sizes
+                            .iter()
+                            .zip(xs.dims())
+                            .map(|(&out, &in_)| out as f64 / in_ as f64)
+                            .collect();
+                        (scales, Some(sizes))
                     }
+                    _ => bail!(
+                        "Resize expects exactly one of the scales and sizes inputs {}",
+                        node.name
+                    ),
+                };
+                let policy = get_attr_opt::<str>(node, "keep_aspect_ratio_policy")?;
+                if policy.is_some_and(|p| p != "stretch") {
+                    bail!(
+                        "unsupported keep_aspect_ratio_policy {policy:?} for Resize {}",
+                        node.name
+                    )
                 }
-                if let Some(d) = pads {
-                    if d.iter().any(|&v| v != 0) {
-                        bail!("AvgPool with pads != 0, {pads:?}")
+                let mode = match get_attr_opt::<str>(node, "mode")?.unwrap_or("nearest") {
+                    "nearest" => ResizeMode::Nearest,
+                    "linear" => ResizeMode::Linear,
+                    "cubic" => {
+                        let a = get_attr_opt::<f32>(node, "cubic_coeff_a")?
+                            .copied()
+                            .unwrap_or(-0.75);
+                        let exclude_outside = get_attr_opt::<i64>(node, "exclude_outside")?
+                            .copied()
+                            .unwrap_or(0);
+                        ResizeMode::Cubic {
+                            a: a as f64,
+                            exclude_outside: exclude_outside != 0,
+                        }
                     }
+                    mode => bail!("unsupported mode {mode} for Resize {}", node.name),
+                };
+                let cfg = ResizeConfig {
+                    mode,
+                    coordinate_transformation_mode: get_attr_opt::<str>(
+                        node,
+                        "coordinate_transformation_mode",
+                    )?
+                    .unwrap_or("half_pixel"),
+                    nearest_mode: get_attr_opt::<str>(node, "nearest_mode")?
+                        .unwrap_or("round_prefer_floor"),
+                    roi: match get_opt(1)? {
+                        Some(roi) => Some(roi.to_dtype(DType::F64)?.to_vec1::<f64>()?),
+                        None => None,
+                    },
+                    extrapolation_value: get_attr_opt::<f32>(node, "extrapolation_value")?
+                        .copied()
+                        .unwrap_or(0.) as f64,
+                    antialias: get_attr_opt::<i64>(node, "antialias")?
+                        .copied()
+                        .unwrap_or(0)
+                        != 0,
+                };
+                let ys = resize(xs, &scales, sizes.as_deref(), &cfg)?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Upsample
+            // Upsample was deprecated in favor of Resize in opset 10, it is equivalent to Resize
+            // with the asymmetric coordinate transformation and the floor nearest mode.
+            "Upsample" => {
+                let xs = get(&node.input[0])?;
+                // In opset 7, the scales are an attribute rather than an input.
+                let scales = match get_opt(1)? {
+                    Some(scales) => scales.to_dtype(DType::F64)?.to_vec1::<f64>()?,
+                    None => get_attr::<[f32]>(node, "scales")?
+                        .iter()
+                        .map(|&s| s as f64)
+                        .collect(),
+                };
+                if scales.iter().any(|&s| s < 1.) {
+                    bail!("Upsample expects scales greater or equal to 1, got {scales:?}")
                 }
+                let mode = match get_attr_opt::<str>(node, "mode")?.unwrap_or("nearest") {
+                    "nearest" => ResizeMode::Nearest,
+                    "linear" | "bilinear" => ResizeMode::Linear,
+                    mode => bail!("unsupported mode {mode} for Upsample {}", node.name),
+                };
+                let cfg = ResizeConfig {
+                    mode,
+                    coordinate_transformation_mode: "asymmetric",
+                    nearest_mode: "floor",
+                    roi: None,
+                    extrapolation_value: 0.,
+                    antialias: false,
+                };
+                let ys = resize(xs, &scales, None, &cfg)?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Pad
+            "Pad" => {
                 let xs = get(&node.input[0])?;
-                let (k1, k2) = match kernel_shape {
-                    [k1, k2] => (*k1 as usize, *k2 as usize),
-                    _ => bail!("only 2d AvgPool is supported, kernel shape {kernel_shape:?}"),
+                let mode = get_attr_opt::<str>(node, "mode")?.unwrap_or("constant");
+                // The trailing inputs are optional and can be skipped with an empty name.
+                let opt_input = |idx: usize| match node.input.get(idx) {
+                    Some(name) if !name.is_empty() => get(name).map(Some),
+                    _ => Ok(None),
                 };
-                let ys = match strides {
-                    None => xs.avg_pool2d((k1, k2))?,
-                    Some([s1, s2]) => {
-                        xs.avg_pool2d_with_stride((k1, k2), (*s1 as usize, *s2 as usize))?
-                    }
-                    Some(strides) => bail!("only 2d AvgPool is supported, strides {strides:?}"),
+                // Before opset 11, pads and value were attributes.
+                let pads = match opt_input(1)? {
+                    Some(pads) => pads.to_dtype(DType::I64)?.to_vec1::<i64>()?,
+                    None => get_attr::<[i64]>(node, "pads")?.to_vec(),
+                };
+                let value = match opt_input(2)? {
+                    Some(value) => value
+                        .to_dtype(DType::F64)?
+                        .flatten_all()?
+                        .to_vec1::<f64>()?[0],
+                    None => get_attr_opt::<f32>(node, "value")?.copied().unwrap_or(0.) as f64,
                 };
-                values.insert(node.output[0].clone(), ys);
+                let axes = match opt_input(3)? {
+                    Some(axes) => axes
+                        .to_dtype(DType::I64)?
+                        .to_vec1::<i64>()?
+                        .iter()
+                        .map(|&axis| normalize_index(axis, xs.rank()))
+                        .collect::<Result<Vec<_>>>()?,
+                    None => (0..xs.rank()).collect(),
+                };
+                if pads.len() != 2 * axes.len() {
+                    bail!(
+                        "expected {} pads for {:?}, got {pads:?} in Pad {}",
+                        2 * axes.len(),
+                        xs.shape(),
+                        node.name
+                    )
+                }
+                let mut ys = xs.clone();
+                for (i, &axis) in axes.iter().enumerate() {
+                    ys = pad_axis(&ys, axis, pads[i], pads[i + axes.len()], mode, value)?;
+                }
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Slice
+            "Slice" => {
+                let xs = get(&node.input[0])?;
+                let get_ints = |i: usize| match get_opt(i)? {
+                    Some(t) => t.to_dtype(DType::I64)?.to_vec1::<i64>().map(Some),
+                    None => Ok(None),
+                };
+                // Before opset 10, starts, ends and axes were attributes.
+                let starts = match get_ints(1)? {
+                    Some(starts) => starts,
+                    None => get_attr::<[i64]>(node, "starts")?.to_vec(),
+                };
+                let ends = match get_ints(2)? {
+                    Some(ends) => ends,
+                    None => get_attr::<[i64]>(node, "ends")?.to_vec(),
+                };
+                let axes = match get_ints(3)? {
+                    Some(axes) => axes,
+                    None => match get_attr_opt::<[i64]>(node, "axes")? {
+                        Some(axes) => axes.to_vec(),
+                        None => (0..starts.len() as i64).collect(),
+                    },
+                };
+                let steps = match get_ints(4)? {
+                    Some(steps) => steps,
+                    None => vec![1; starts.len()],
+                };
+                if starts.len() != ends.len()
+                    || starts.len() != axes.len()
+                    || starts.len() != steps.len()
+                {
+                    bail!(
+                        "inconsistent starts {starts:?}, ends {ends:?}, axes {axes:?}, steps {steps:?} in Slice {}",
+                        node.name
+                    )
+                }
+                let mut ys = xs.clone();
+                for (((&start, &end), &axis), &step) in starts
+                    .iter()
+                    .zip(ends.iter())
+                    .zip(axes.iter())
+                    .zip(steps.iter())
+                {
+                    let axis = normalize_index(axis, xs.rank())?;
+                    if step == 0 {
+                        bail!("zero step in Slice {}", node.name)
+                    }
+                    let dim = xs.dim(axis)? as i64;
+                    // Out of range starts and ends are clamped rather than rejected, ends are often
+                    // set to i64::MAX (or i64::MIN for negative steps) to mean slicing to the end
+                    // of the axis. With negative steps, the slice goes down from start to end + 1.
+                    let (lo, hi) = if step > 0 { (0, dim) } else { (-1, dim - 1) };
+                    let clamp = |i: i64| {
+                        let i = if i < 0 { i.saturating_add(dim) } else { i };
+                        i.clamp(lo, hi)
+                    };
+                    let (start, end) = (clamp(start), clamp(end));
+                    ys = if step == 1 {
+                        ys.narrow(axis, start as usize, (end - start).max(0) as usize)?
+                    } else {
+                        let indexes = if step > 0 {
+                            (start..end)
+                                .step_by(step as usize)
+                                .map(|i| i as u32)
+                                .collect::<Vec<_>>()
+                        } else {
+                            (end + 1..=start)
+                                .rev()
+                                .step_by(step.unsigned_abs() as usize)
+                                .map(|i| i as u32)
+                                .collect::<Vec<_>>()
+                        };
+                        let indexes = Tensor::new(indexes.as_slice(), xs.device())?;
+                        ys.contiguous()?.index_select(&indexes, axis)?
+                    };
+                }
+                values.insert(node.output[0].clone(), ys.into());
             }
             "BatchNormalization" => {
                 let training_mode = get_attr_opt::<i64>(node, "training_mode")?;
@@ -402,6 +4402,14 @@ pub fn simple_eval(
                 let bias = get(&node.input[2])?;
                 let running_mean = get(&node.input[3])?;
                 let running_var = get(&node.input[4])?;
+                if xs.rank() < 2 {
+                    bail!(
+                        "BatchNorm expects an input with a channel dim, got {:?}",
+                        xs.shape()
+                    )
+                }
+                // The stats are per channel on dim 1 and broadcast over the other dims, this
+                // handles [N, C], [N, C, L] as well as any number of spatial dims.
                 let target_shape: Vec<usize> = xs
                     .dims()
                     .iter()
@@ -415,7 +4423,71 @@ pub fn simple_eval(
                 let weight = weight.reshape(target_shape)?;
                 let bias = bias.reshape(target_shape)?;
                 let xs = xs.broadcast_mul(&weight)?.broadcast_add(&bias)?;
-                values.insert(node.output[0].clone(), xs);
+                values.insert(node.output[0].clone(), xs.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#ReduceSum
+            "ReduceSum" | "ReduceMean" | "ReduceMax" | "ReduceMin" => {
+                let xs = get(&node.input[0])?;
+                let keepdims = get_attr_opt::<i64>(node, "keepdims")?.copied().unwrap_or(1);
+                let noop_with_empty_axes = get_attr_opt::<i64>(node, "noop_with_empty_axes")?
+                    .copied()
+                    .unwrap_or(0);
+                // Since opset 18 (13 for ReduceSum) the axes are an optional input rather than an
+                // attribute. An absent input and an empty axes tensor are handled the same way.
+                let axes = match get_opt(1)? {
+                    Some(axes) => axes.to_dtype(DType::I64)?.to_vec1::<i64>()?,
+                    None => get_attr_opt::<[i64]>(node, "axes")?
+                        .map(|axes| axes.to_vec())
+                        .unwrap_or_default(),
+                };
+                let mut axes = if axes.is_empty() {
+                    if noop_with_empty_axes != 0 {
+                        values.insert(node.output[0].clone(), xs.clone().into());
+                        continue;
+                    }
+                    (0..xs.rank()).collect::<Vec<_>>()
+                } else {
+                    axes.iter()
+                        .map(|&axis| normalize_index(axis, xs.rank()))
+                        .collect::<Result<Vec<_>>>()?
+                };
+                axes.sort();
+                axes.dedup();
+                let mut ys = match node.op_type.as_str() {
+                    "ReduceSum" => xs.sum_keepdim(axes.as_slice())?,
+                    // mean_keepdim scales the sum by 1 / n which rounds to 0 for integers. The
+                    // sum is computed exactly in i64 instead then divided by n, this truncates
+                    // like the reference which casts the mean back to the input dtype.
+                    "ReduceMean" if xs.dtype().is_int() => {
+                        let n = axes.iter().map(|&axis| xs.dims()[axis]).product::<usize>();
+                        // The mean of no element is nan which has no integer representation.
+                        if n == 0 {
+                            bail!(
+                                "ReduceMean {} reduces no element of the integer input {:?}",
+                                node.name,
+                                xs.shape()
+                            )
+                        }
+                        let n = Tensor::new(n as i64, xs.device())?;
+                        xs.to_dtype(DType::I64)?
+                            .sum_keepdim(axes.as_slice())?
+                            .broadcast_div(&n)?
+                            .to_dtype(xs.dtype())?
+                    }
+                    "ReduceMean" => xs.mean_keepdim(axes.as_slice())?,
+                    "ReduceMax" => axes
+                        .iter()
+                        .try_fold(xs.clone(), |ys, &axis| ys.max_keepdim(axis))?,
+                    _ => axes
+                        .iter()
+                        .try_fold(xs.clone(), |ys, &axis| ys.min_keepdim(axis))?,
+                };
+                if keepdims == 0 {
+                    for &axis in axes.iter().rev() {
+                        ys = ys.squeeze(axis)?
+                    }
+                }
+                values.insert(node.output[0].clone(), ys.into());
             }
             "Squeeze" => {
                 let xs = get(&node.input[0])?;
@@ -444,23 +4516,22 @@ pub fn simple_eval(
                 for &axis in axes.iter().rev() {
                     xs = xs.squeeze(axis)?
                 }
-                values.insert(node.output[0].clone(), xs);
+                values.insert(node.output[0].clone(), xs.into());
             }
             "Clip" => {
                 let xs = get(&node.input[0])?;
-                let xs = if node.input.len() >= 2 {
-                    let mins = get(&node.input[1])?;
-                    xs.broadcast_maximum(mins)?
-                } else {
-                    xs.clone()
+                // Bounds follow the numpy broadcasting rules, per-channel bounds of a [N, C, H, W]
+                // input have the shape [C, 1, 1].
+                // Either bound can be skipped with an empty name, e.g. ["x", "", "max"].
+                let xs = match get_opt(1)? {
+                    Some(mins) => xs.broadcast_maximum(mins)?,
+                    None => xs.clone(),
                 };
-                let xs = if node.input.len() >= 3 {
-                    let maxs = get(&node.input[2])?;
-                    xs.broadcast_minimum(maxs)?
-                } else {
-                    xs.clone()
+                let xs = match get_opt(2)? {
+                    Some(maxs) => xs.broadcast_minimum(maxs)?,
+                    None => xs,
                 };
-                values.insert(node.output[0].clone(), xs);
+                values.insert(node.output[0].clone(), xs.into());
             }
             "Conv" => {
                 // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Conv
@@ -476,6 +4547,8 @@ pub fn simple_eval(
                 };
                 let xs = get(&node.input[0])?;
                 let ws = get(&node.input[1])?;
+                // The inputs before padding, for the error messages.
+                let inputs = [xs, ws];
                 let ys = match ws.rank() {
                     3 => {
                         let (pads, xs) = match pads {
@@ -506,7 +4579,8 @@ pub fn simple_eval(
                                 bail!("more dilations than expected in conv1d {s:?} {}", node.name)
                             }
                         };
-                        xs.conv1d(ws, pads, strides, dilations, groups as usize)?
+                        xs.conv1d(ws, pads, strides, dilations, groups as usize)
+                            .map_err(inputs_context(node, &inputs))?
                     }
                     4 => {
                         let (pads, xs) = match pads {
@@ -559,119 +4633,982 @@ pub fn simple_eval(
                                 bail!("more dilations than expected in conv2d {s:?} {}", node.name)
                             }
                         };
-                        xs.conv2d(ws, pads, strides, dilations, groups as usize)?
+                        xs.conv2d(ws, pads, strides, dilations, groups as usize)
+                            .map_err(inputs_context(node, &inputs))?
                     }
                     rank => bail!(
-                        "unsupported rank for weight matrix {rank} in conv {}",
+                        "unsupported rank for weight matrix {rank} in conv {}, input {:?} {:?}, \
+                         weight {:?} {:?}",
+                        node.name,
+                        xs.dims(),
+                        xs.dtype(),
+                        ws.dims(),
+                        ws.dtype()
+                    ),
+                };
+                let ys = if node.input.len() > 2 {
+                    let bs = get(&node.input[2])?;
+                    let mut bs_shape = vec![1; ys.rank()];
+                    bs_shape[1] = bs.elem_count();
+                    ys.broadcast_add(&bs.reshape(bs_shape)?)?
+                } else {
+                    ys
+                };
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            "Concat" => {
+                // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Concat
+                let inputs = node
+                    .input
+                    .iter()
+                    .map(|n| Ok(get(n.as_str())?.clone()))
+                    .collect::<Result<Vec<Tensor>>>()?;
+                let axis: i64 = *get_attr(node, "axis")?;
+                if inputs.is_empty() {
+                    bail!("empty concat")
+                }
+                // Empty inputs are skipped, these may not even have the rank of the other inputs.
+                let non_empty = inputs
+                    .iter()
+                    .filter(|t| t.elem_count() > 0)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let inputs = if non_empty.is_empty() {
+                    inputs
+                } else {
+                    non_empty
+                };
+                let axis = normalize_index(axis, inputs[0].rank())?;
+                let output = Tensor::cat(&inputs, axis)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "Abs" => {
+                let input = get(&node.input[0])?;
+                let output = input.abs()?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "Cos" => {
+                let input = get(&node.input[0])?;
+                let output = input.cos()?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "Sin" => {
+                let input = get(&node.input[0])?;
+                let output = input.sin()?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "Neg" => {
+                let input = get(&node.input[0])?;
+                let output = input.neg()?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "Erf" => {
+                let input = get(&node.input[0])?;
+                let output = input.erf()?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "Tanh" => {
+                let input = get(&node.input[0])?;
+                let output = input.tanh()?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "Sigmoid" => {
+                let input = get(&node.input[0])?;
+                let output = candle_nn::ops::sigmoid(input)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            "Gelu" => {
+                let input = get(&node.input[0])?;
+                let output = input.gelu_erf()?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/microsoft/onnxruntime/blob/main/docs/ContribOperators.md#com.microsoft.Attention
+            "Attention" => {
+                let xs = get(&node.input[0])?;
+                let weights = get(&node.input[1])?;
+                let num_heads = *get_attr::<i64>(node, "num_heads")? as usize;
+                let unidirectional = get_attr_opt::<i64>(node, "unidirectional")?
+                    .copied()
+                    .unwrap_or(0);
+                let mask_filter_value = get_attr_opt::<f32>(node, "mask_filter_value")?
+                    .copied()
+                    .unwrap_or(-10000.);
+                if get_opt(4)?.is_some() {
+                    bail!("past state is not supported for Attention {}", node.name)
+                }
+                let (b_sz, seq_len, _) = xs.dims3()?;
+                let qkv = xs.broadcast_matmul(weights)?;
+                let qkv = match get_opt(2)? {
+                    None => qkv,
+                    Some(bias) => qkv.broadcast_add(bias)?,
+                };
+                let qkv_size = qkv.dim(2)?;
+                let sizes = match get_attr_opt::<[i64]>(node, "qkv_hidden_sizes")? {
+                    Some([q, k, v]) => [*q as usize, *k as usize, *v as usize],
+                    Some(sizes) => bail!("unexpected qkv_hidden_sizes {sizes:?}"),
+                    None => [qkv_size / 3; 3],
+                };
+                // Split the packed projections and move the heads before the sequence dim.
+                let mut offset = 0;
+                let mut heads = Vec::with_capacity(3);
+                for size in sizes {
+                    let t = qkv
+                        .narrow(2, offset, size)?
+                        .reshape((b_sz, seq_len, num_heads, size / num_heads))?
+                        .transpose(1, 2)?
+                        .contiguous()?;
+                    heads.push(t);
+                    offset += size
+                }
+                let (q, k, v) = (&heads[0], &heads[1], &heads[2]);
+                let head_size = sizes[0] / num_heads;
+                let scale = match get_attr_opt::<f32>(node, "scale")? {
+                    Some(&scale) => scale as f64,
+                    None => 1. / (head_size as f64).sqrt(),
+                };
+                let scores = (q.matmul(&k.t()?)? * scale)?;
+                let dims = (b_sz, seq_len, seq_len);
+                let scores = match get_opt(3)? {
+                    None => scores,
+                    Some(mask_index) => {
+                        let mask = attention_key_mask(mask_index, dims, mask_filter_value)?;
+                        scores.broadcast_add(&mask.to_dtype(scores.dtype())?)?
+                    }
+                };
+                // The attention bias is added as is, with a shape (B or 1, N, S, T).
+                let scores = match get_opt(5)? {
+                    None => scores,
+                    Some(attention_bias) => scores.broadcast_add(attention_bias)?,
+                };
+                let scores = if unidirectional != 0 {
+                    let causal = tri_mask(seq_len, seq_len, 0, false, scores.device(), DType::U8)?
+                        .broadcast_as(scores.shape())?;
+                    let masked = Tensor::new(f32::NEG_INFINITY, scores.device())?
+                        .to_dtype(scores.dtype())?
+                        .broadcast_as(scores.shape())?;
+                    causal.where_cond(&scores, &masked)?
+                } else {
+                    scores
+                };
+                let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+                let ys = probs
+                    .matmul(v)?
+                    .transpose(1, 2)?
+                    .reshape((b_sz, seq_len, sizes[2]))?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#LayerNormalization
+            // SimplifiedLayerNormalization is the RMSNorm variant used by onnxruntime, it does not
+            // subtract the mean and has no bias.
+            "LayerNormalization" | "SimplifiedLayerNormalization" => {
+                let xs = get(&node.input[0])?;
+                let gamma = get(&node.input[1])?;
+                let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(-1);
+                let eps = get_attr_opt::<f32>(node, "epsilon")?
+                    .copied()
+                    .unwrap_or(1e-5);
+                let remove_mean = node.op_type == "LayerNormalization";
+                let beta = if remove_mean { get_opt(2)? } else { None };
+                let ys = layer_norm(xs, gamma, beta, axis, eps as f64, remove_mean)?;
+                values.insert(node.output[0].clone(), ys.into());
+            }
+            // https://github.com/microsoft/onnxruntime/blob/main/docs/ContribOperators.md#com.microsoft.SkipLayerNormalization
+            "SkipLayerNormalization" => {
+                let xs = get(&node.input[0])?;
+                let skip = get(&node.input[1])?;
+                let gamma = get(&node.input[2])?;
+                let eps = get_attr_opt::<f32>(node, "epsilon")?
+                    .copied()
+                    .unwrap_or(1e-12);
+                let sum = xs.broadcast_add(skip)?;
+                let sum = match get_opt(4)? {
+                    None => sum,
+                    Some(bias) => sum.broadcast_add(bias)?,
+                };
+                let ys = layer_norm(&sum, gamma, get_opt(3)?, -1, eps as f64, true)?;
+                values.insert(node.output[0].clone(), ys.into());
+                // The outputs 1 and 2 hold the mean and inverse std and are only used for training.
+                if let Some(output) = node.output.get(3).filter(|o| !o.is_empty()) {
+                    values.insert(output.clone(), sum.into());
+                }
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#GroupNormalization
+            // https://github.com/microsoft/onnxruntime/blob/main/docs/ContribOperators.md#com.microsoft.GroupNorm
+            // The com.microsoft variant used in diffusion models defaults to a channels last layout
+            // and can apply a swish activation to the output.
+            "GroupNormalization" | "GroupNorm" => {
+                let xs = get(&node.input[0])?;
+                let scale = get(&node.input[1])?;
+                let bias = get(&node.input[2])?;
+                let is_contrib = node.op_type == "GroupNorm";
+                let num_groups = if is_contrib {
+                    *get_attr::<i64>(node, "groups")?
+                } else {
+                    *get_attr::<i64>(node, "num_groups")?
+                };
+                let eps = get_attr_opt::<f32>(node, "epsilon")?
+                    .copied()
+                    .unwrap_or(1e-5);
+                let channels_last = get_attr_opt::<i64>(node, "channels_last")?
+                    .copied()
+                    .unwrap_or(is_contrib as i64)
+                    != 0;
+                let activation = get_attr_opt::<i64>(node, "activation")?
+                    .copied()
+                    .unwrap_or(0);
+                let rank = xs.rank();
+                // The channels last [N, ..., C] input is normalized as [N, C, ...].
+                let xs = if channels_last && rank > 2 {
+                    let mut perm = vec![0, rank - 1];
+                    perm.extend(1..rank - 1);
+                    xs.permute(perm)?
+                } else {
+                    xs.clone()
+                };
+                let ys = group_norm(&xs, scale, bias, num_groups as usize, eps as f64)?;
+                let ys = match activation {
+                    0 => ys,
+                    1 => (&ys * candle_nn::ops::sigmoid(&ys)?)?,
+                    _ => bail!(
+                        "unsupported activation {activation} for GroupNorm {}",
                         node.name
                     ),
                 };
-                let ys = if node.input.len() > 2 {
-                    let bs = get(&node.input[2])?;
-                    let mut bs_shape = vec![1; ys.rank()];
-                    bs_shape[1] = bs.elem_count();
-                    ys.broadcast_add(&bs.reshape(bs_shape)?)?
+                let ys = if channels_last && rank > 2 {
+                    let mut perm = vec![0];
+                    perm.extend(2..rank);
+                    perm.push(1);
+                    ys.permute(perm)?.contiguous()?
                 } else {
                     ys
                 };
-                values.insert(node.output[0].clone(), ys);
+                values.insert(node.output[0].clone(), ys.into());
             }
-            "Concat" => {
-                // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Concat
-                let inputs = node
-                    .input
-                    .iter()
-                    .map(|n| Ok(get(n.as_str())?.clone()))
-                    .collect::<Result<Vec<Value>>>()?;
-                let axis: i64 = *get_attr(node, "axis")?;
-                let num_axis = if inputs.is_empty() {
-                    bail!("empty concat")
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Swish
+            "Swish" | "SiLU" => {
+                let input = get(&node.input[0])?;
+                let alpha = get_attr_opt::<f32>(node, "alpha")?.copied().unwrap_or(1.0);
+                let output = if alpha == 1.0 {
+                    (input * candle_nn::ops::sigmoid(input)?)?
                 } else {
-                    inputs[0].rank() as i64
+                    (input * candle_nn::ops::sigmoid(&input.affine(alpha as f64, 0.)?)?)?
                 };
-                let axis = if axis >= 0 {
-                    axis as usize
-                } else if axis < -num_axis {
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/microsoft/onnxruntime/blob/main/docs/ContribOperators.md#com.microsoft.GatherBlockQuantized
+            "GatherBlockQuantized" => {
+                let data = get(&node.input[0])?;
+                let indices = get(&node.input[1])?;
+                let scales = get(&node.input[2])?;
+                let bits = get_attr_opt::<i64>(node, "bits")?.copied().unwrap_or(4) as usize;
+                let block_size = get_attr_opt::<i64>(node, "block_size")?
+                    .copied()
+                    .unwrap_or(128) as usize;
+                let gather_axis = get_attr_opt::<i64>(node, "gather_axis")?
+                    .copied()
+                    .unwrap_or(0);
+                let quantize_axis = get_attr_opt::<i64>(node, "quantize_axis")?
+                    .copied()
+                    .unwrap_or(1);
+                let rank = data.rank();
+                let gather_axis = normalize_index(gather_axis, rank)?;
+                let quantize_axis = normalize_index(quantize_axis, rank)?;
+                if data.dtype() != DType::U8 {
                     bail!(
-                        "wrong axis in concat {axis} for shape {:?}",
-                        inputs[0].shape()
+                        "GatherBlockQuantized expects uint8 data, got {:?}",
+                        data.dtype()
                     )
+                }
+                if gather_axis == quantize_axis {
+                    bail!("GatherBlockQuantized gather_axis and quantize_axis must differ")
+                }
+                // The rows are gathered first so that only these get dequantized.
+                let (data, zero_points) = match bits {
+                    // Two values are packed per byte along the quantized dim.
+                    4 if quantize_axis != rank - 1 => {
+                        bail!("GatherBlockQuantized with 4 bits requires quantize_axis to be last")
+                    }
+                    4 => {
+                        let data = unpack_uint4(&gather(data, indices, gather_axis)?)?;
+                        let zero_points = match get_opt(3)? {
+                            None => None,
+                            Some(zp) => {
+                                let zp = unpack_uint4(&gather(zp, indices, gather_axis)?)?;
+                                let n_blocks = scales.dim(quantize_axis)?;
+                                Some(zp.narrow(zp.rank() - 1, 0, n_blocks)?)
+                            }
+                        };
+                        (data, zero_points)
+                    }
+                    8 => {
+                        let data = gather(data, indices, gather_axis)?;
+                        let zero_points = match get_opt(3)? {
+                            None => None,
+                            Some(zp) => Some(gather(zp, indices, gather_axis)?),
+                        };
+                        (data, zero_points)
+                    }
+                    _ => bail!("unsupported bits {bits} for GatherBlockQuantized"),
+                };
+                let scales = gather(scales, indices, gather_axis)?;
+                let quantize_axis = if quantize_axis < gather_axis {
+                    quantize_axis
                 } else {
-                    (num_axis - axis) as usize
+                    quantize_axis + indices.rank() - 1
                 };
-                let output = Tensor::cat(&inputs, axis)?;
-                values.insert(node.output[0].clone(), output);
+                let output = dequantize_blocks(
+                    &data,
+                    &scales,
+                    zero_points.as_ref(),
+                    quantize_axis,
+                    block_size,
+                    bits,
+                )?;
+                values.insert(node.output[0].clone(), output.into());
             }
-            "Abs" => {
-                let input = get(&node.input[0])?;
-                let output = input.abs()?;
-                values.insert(node.output[0].clone(), output);
+            // https://github.com/microsoft/onnxruntime/blob/main/docs/ContribOperators.md#com.microsoft.MatMulNBits
+            "MatMulNBits" => {
+                let a = get(&node.input[0])?;
+                let b = get(&node.input[1])?;
+                let scales = get(&node.input[2])?;
+                let k = *get_attr::<i64>(node, "K")? as usize;
+                let n = *get_attr::<i64>(node, "N")? as usize;
+                let bits = get_attr_opt::<i64>(node, "bits")?.copied().unwrap_or(4) as usize;
+                let block_size = *get_attr::<i64>(node, "block_size")? as usize;
+                if get_opt(4)?.is_some() {
+                    bail!("MatMulNBits with g_idx is not supported for {}", node.name)
+                }
+                if b.dtype() != DType::U8 {
+                    bail!("MatMulNBits expects uint8 weights, got {:?}", b.dtype())
+                }
+                let n_blocks = k.div_ceil(block_size);
+                // The weights are stored transposed as (N, n_blocks, blob_size) with the values of
+                // each block packed in a blob.
+                let unpack = |xs: &Tensor, len: usize| -> Result<Tensor> {
+                    let xs = xs.reshape((n, ()))?;
+                    let xs = match bits {
+                        4 => unpack_uint4(&xs)?,
+                        8 => xs,
+                        _ => bail!("unsupported bits {bits} for MatMulNBits"),
+                    };
+                    xs.narrow(1, 0, len)
+                };
+                let w = unpack(b, k)?;
+                let scales = scales.reshape((n, n_blocks))?;
+                let zero_points = match get_opt(3)? {
+                    None => None,
+                    Some(zp) if zp.dtype() == DType::U8 => Some(unpack(zp, n_blocks)?),
+                    Some(zp) => Some(zp.reshape((n, n_blocks))?),
+                };
+                let w = dequantize_blocks(&w, &scales, zero_points.as_ref(), 1, block_size, bits)?;
+                let output = matmul(a, &w.to_dtype(a.dtype())?.t()?)?;
+                let output = match get_opt(5)? {
+                    None => output,
+                    Some(bias) => output.broadcast_add(bias)?,
+                };
+                values.insert(node.output[0].clone(), output.into());
             }
-            "Cos" => {
+            // https://github.com/microsoft/onnxruntime/blob/main/docs/ContribOperators.md#com.microsoft.RotaryEmbedding
+            "RotaryEmbedding" => {
                 let input = get(&node.input[0])?;
-                let output = input.cos()?;
-                values.insert(node.output[0].clone(), output);
+                let position_ids = get(&node.input[1])?;
+                let cos_cache = get(&node.input[2])?;
+                let sin_cache = get(&node.input[3])?;
+                let interleaved = get_attr_opt::<i64>(node, "interleaved")?
+                    .copied()
+                    .unwrap_or(0)
+                    != 0;
+                let num_heads = get_attr_opt::<i64>(node, "num_heads")?
+                    .copied()
+                    .unwrap_or(0) as usize;
+                let rotary_dim = match get_attr_opt::<i64>(node, "rotary_embedding_dim")? {
+                    Some(&d) if d > 0 => d as usize,
+                    _ => cos_cache.dim(1)? * 2,
+                };
+                // The rotation is applied in the (B, S, N, H) layout.
+                let xs = match input.dims() {
+                    &[b, s, hidden] => {
+                        let num_heads = if num_heads == 0 {
+                            hidden.checked_div(rotary_dim).unwrap_or(0)
+                        } else {
+                            num_heads
+                        };
+                        if num_heads == 0 || hidden % num_heads != 0 {
+                            bail!(
+                                "RotaryEmbedding cannot split a hidden size of {hidden} in {num_heads} heads"
+                            )
+                        }
+                        input.reshape((b, s, num_heads, hidden / num_heads))?
+                    }
+                    &[_, _, _, _] => input.transpose(1, 2)?,
+                    dims => bail!("RotaryEmbedding expects a 3D or 4D input, got {dims:?}"),
+                };
+                let (b, s, _, head_size) = xs.dims4()?;
+                if rotary_dim > head_size || rotary_dim % 2 != 0 {
+                    bail!("unexpected rotary_embedding_dim {rotary_dim} for heads of size {head_size}")
+                }
+                // A single position id is the offset of the first position of the sequence.
+                let position_ids = if position_ids.elem_count() == 1 {
+                    let offset = position_ids.flatten_all()?.to_dtype(DType::I64)?;
+                    Tensor::arange(0i64, s as i64, position_ids.device())?
+                        .broadcast_add(&offset)?
+                        .broadcast_as((b, s))?
+                } else {
+                    position_ids.to_dtype(DType::I64)?
+                };
+                let cos = gather(&cos_cache.narrow(1, 0, rotary_dim / 2)?, &position_ids, 0)?;
+                let sin = gather(&sin_cache.narrow(1, 0, rotary_dim / 2)?, &position_ids, 0)?;
+                let rotated = rotary_embedding(
+                    &xs.narrow(3, 0, rotary_dim)?,
+                    &cos.to_dtype(xs.dtype())?,
+                    &sin.to_dtype(xs.dtype())?,
+                    interleaved,
+                )?;
+                let xs = if rotary_dim < head_size {
+                    let pass = xs.narrow(3, rotary_dim, head_size - rotary_dim)?;
+                    Tensor::cat(&[rotated, pass], 3)?
+                } else {
+                    rotated
+                };
+                let output = match input.rank() {
+                    3 => xs.reshape(input.dims())?,
+                    _ => xs.transpose(1, 2)?.contiguous()?,
+                };
+                values.insert(node.output[0].clone(), output.into());
             }
-            "Sin" => {
+            // https://github.com/microsoft/onnxruntime/blob/main/docs/ContribOperators.md#com.microsoft.FastGelu
+            "FastGelu" => {
                 let input = get(&node.input[0])?;
-                let output = input.sin()?;
-                values.insert(node.output[0].clone(), output);
+                let input = match get_opt(1)? {
+                    None => input.clone(),
+                    Some(bias) => input.broadcast_add(bias)?,
+                };
+                // The tanh approximation 0.5x(1 + tanh(sqrt(2/pi)(x + 0.044715x^3))) uses the same
+                // constants as onnxruntime.
+                let output = input.gelu()?;
+                values.insert(node.output[0].clone(), output.into());
             }
-            "Neg" => {
+            // https://github.com/microsoft/onnxruntime/blob/main/docs/ContribOperators.md#com.microsoft.QuickGelu
+            "QuickGelu" => {
                 let input = get(&node.input[0])?;
-                let output = input.neg()?;
-                values.insert(node.output[0].clone(), output);
+                let alpha = get_attr_opt::<f32>(node, "alpha")?
+                    .copied()
+                    .unwrap_or(1.702);
+                // x * sigmoid(alpha * x), a cheaper approximation than both gelu variants.
+                let output = (input * candle_nn::ops::sigmoid(&(input * alpha as f64)?)?)?;
+                values.insert(node.output[0].clone(), output.into());
             }
-            "Erf" => {
+            // https://github.com/microsoft/onnxruntime/blob/main/docs/ContribOperators.md#com.microsoft.BiasGelu
+            "BiasGelu" => {
                 let input = get(&node.input[0])?;
-                let output = input.erf()?;
-                values.insert(node.output[0].clone(), output);
+                let bias = get(&node.input[1])?;
+                let output = input.broadcast_add(bias)?.gelu_erf()?;
+                values.insert(node.output[0].clone(), output.into());
             }
-            "Tanh" => {
+            "Relu" => {
                 let input = get(&node.input[0])?;
-                let output = input.tanh()?;
-                values.insert(node.output[0].clone(), output);
+                let output = input.relu()?;
+                values.insert(node.output[0].clone(), output.into());
             }
-            "Sigmoid" => {
-                let input = get(&node.input[0])?;
-                let output = candle_nn::ops::sigmoid(input)?;
-                values.insert(node.output[0].clone(), output);
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#HannWindow
+            "HannWindow" | "HammingWindow" | "BlackmanWindow" => {
+                let size = get(&node.input[0])?;
+                let device = size.device().clone();
+                let size = size.flatten_all()?.to_dtype(DType::I64)?.to_vec1::<i64>()?[0];
+                let periodic = get_attr_opt::<i64>(node, "periodic")?.copied().unwrap_or(1);
+                let dt = get_attr_opt::<i64>(node, "output_datatype")?
+                    .copied()
+                    .unwrap_or(DataType::Float as i64);
+                let dtype = match DataType::try_from(dt as i32).ok().and_then(dtype) {
+                    Some(dtype) => dtype,
+                    None => bail!(
+                        "unsupported output_datatype {dt} for {} {}",
+                        node.op_type,
+                        node.name
+                    ),
+                };
+                let periodic = periodic != 0;
+                let output = match node.op_type.as_str() {
+                    "HannWindow" => window(size, periodic, dtype, |x| 0.5 - 0.5 * x.cos())?,
+                    "HammingWindow" => {
+                        let a0 = 25. / 46.;
+                        window(size, periodic, dtype, |x| a0 - (1. - a0) * x.cos())?
+                    }
+                    _ => window(size, periodic, dtype, |x| {
+                        0.42 - 0.5 * x.cos() + 0.08 * (2. * x).cos()
+                    })?,
+                };
+                values.insert(node.output[0].clone(), output.to_device(&device)?.into());
             }
-            "Gelu" => {
-                let input = get(&node.input[0])?;
-                let output = input.gelu_erf()?;
-                values.insert(node.output[0].clone(), output);
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#SoftmaxCrossEntropyLoss
+            "SoftmaxCrossEntropyLoss" => {
+                let scores = get(&node.input[0])?;
+                let labels = get(&node.input[1])?;
+                let reduction = get_attr_opt::<str>(node, "reduction")?.unwrap_or("mean");
+                let ignore_index = get_attr_opt::<i64>(node, "ignore_index")?.copied();
+                let log_prob = candle_nn::ops::log_softmax(scores, 1)?;
+                let loss = nll_loss(&log_prob, labels, get_opt(2)?, reduction, ignore_index)?;
+                values.insert(node.output[0].clone(), loss.into());
+                if let Some(output) = node.output.get(1).filter(|o| !o.is_empty()) {
+                    values.insert(output.clone(), log_prob.into());
+                }
             }
-            "Relu" => {
-                let input = get(&node.input[0])?;
-                let output = input.relu()?;
-                values.insert(node.output[0].clone(), output);
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#NegativeLogLikelihoodLoss
+            "NegativeLogLikelihoodLoss" => {
+                let log_prob = get(&node.input[0])?;
+                let target = get(&node.input[1])?;
+                let reduction = get_attr_opt::<str>(node, "reduction")?.unwrap_or("mean");
+                let ignore_index = get_attr_opt::<i64>(node, "ignore_index")?.copied();
+                let loss = nll_loss(log_prob, target, get_opt(2)?, reduction, ignore_index)?;
+                values.insert(node.output[0].clone(), loss.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Range
+            "Range" => {
+                let start = get(&node.input[0])?;
+                let limit = get(&node.input[1])?;
+                let delta = get(&node.input[2])?;
+                let (dtype, device) = (start.dtype(), start.device().clone());
+                let output = if dtype.is_int() {
+                    // The element count is computed with integer arithmetic, rounding a float
+                    // count can add or drop an element.
+                    let scalar = |t: &Tensor| t.to_dtype(DType::I64)?.to_vec0::<i64>();
+                    let (start, limit, delta) = (scalar(start)?, scalar(limit)?, scalar(delta)?);
+                    if delta == 0 {
+                        bail!("Range expects a non-zero delta")
+                    }
+                    let (n, rem) = ((limit - start) / delta, (limit - start) % delta);
+                    let count = n + (rem != 0 && (rem > 0) == (delta > 0)) as i64;
+                    let output = (0..count.max(0))
+                        .map(|i| start + i * delta)
+                        .collect::<Vec<_>>();
+                    let len = output.len();
+                    Tensor::from_vec(output, len, &device)?
+                } else {
+                    let scalar = |t: &Tensor| t.to_dtype(DType::F64)?.to_vec0::<f64>();
+                    let (start, limit, delta) = (scalar(start)?, scalar(limit)?, scalar(delta)?);
+                    if delta == 0. {
+                        bail!("Range expects a non-zero delta")
+                    }
+                    let count = ((limit - start) / delta).ceil().max(0.) as usize;
+                    let output = (0..count)
+                        .map(|i| start + i as f64 * delta)
+                        .collect::<Vec<_>>();
+                    Tensor::from_vec(output, count, &device)?
+                };
+                values.insert(node.output[0].clone(), output.to_dtype(dtype)?.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Shape
+            "Shape" => {
+                let xs = get(&node.input[0])?;
+                let rank = xs.rank() as i64;
+                // The start and end attributes are clamped to [0, rank].
+                let clamp = |v: i64| (if v < 0 { v + rank } else { v }).clamp(0, rank) as usize;
+                let start = clamp(get_attr_opt::<i64>(node, "start")?.copied().unwrap_or(0));
+                let end = clamp(get_attr_opt::<i64>(node, "end")?.copied().unwrap_or(rank));
+                let dims = xs.dims()[start..end.max(start)]
+                    .iter()
+                    .map(|&d| d as i64)
+                    .collect::<Vec<_>>();
+                let output = Tensor::from_vec(dims, end.max(start) - start, xs.device())?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Gather
+            "Gather" => {
+                let xs = get(&node.input[0])?;
+                let indices = get(&node.input[1])?;
+                let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(0);
+                let axis = normalize_index(axis, xs.rank())?;
+                let output = gather(xs, indices, axis)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#GatherElements
+            "GatherElements" => {
+                let xs = get(&node.input[0])?;
+                let indices = get(&node.input[1])?;
+                let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(0);
+                let axis = normalize_index(axis, xs.rank())?;
+                let output = gather_elements(xs, indices, axis)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#CumSum
+            "CumSum" => {
+                let xs = get(&node.input[0])?;
+                let axis = get(&node.input[1])?
+                    .flatten_all()?
+                    .to_dtype(DType::I64)?
+                    .to_vec1::<i64>()?;
+                let axis = match axis.as_slice() {
+                    &[axis] => normalize_index(axis, xs.rank())?,
+                    axis => bail!("CumSum expects a single axis, got {axis:?}"),
+                };
+                let exclusive = get_attr_opt::<i64>(node, "exclusive")?
+                    .copied()
+                    .unwrap_or(0);
+                let reverse = get_attr_opt::<i64>(node, "reverse")?.copied().unwrap_or(0);
+                let output = cumsum(xs, axis, exclusive != 0, reverse != 0)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Trilu
+            "Trilu" => {
+                let xs = get(&node.input[0])?;
+                let upper = get_attr_opt::<i64>(node, "upper")?.copied().unwrap_or(1) != 0;
+                let k = match get_opt(1)? {
+                    None => 0,
+                    Some(k) => k.flatten_all()?.to_dtype(DType::I64)?.to_vec1::<i64>()?[0],
+                };
+                let (rows, cols) = match xs.dims() {
+                    &[.., rows, cols] => (rows, cols),
+                    dims => bail!("Trilu expects an input of rank at least 2, got {dims:?}"),
+                };
+                // The mask applies to the last two dims and is broadcast over the batch dims.
+                let mask = tri_mask(rows, cols, k, upper, xs.device(), DType::U8)?;
+                let zeros = xs.zeros_like()?;
+                let output = mask.broadcast_as(xs.shape())?.where_cond(xs, &zeros)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Det
+            // https://github.com/microsoft/onnxruntime/blob/main/docs/ContribOperators.md#com.microsoft.Inverse
+            "Det" | "Inverse" => {
+                let xs = get(&node.input[0])?;
+                let n = match xs.dims() {
+                    &[.., rows, cols] if rows == cols => rows,
+                    dims => bail!("{} expects square matrices, got {dims:?}", node.op_type),
+                };
+                // The matrices on the last two dims are processed on the cpu in f64.
+                let batch_dims = &xs.dims()[..xs.rank() - 2];
+                let vs = xs.to_dtype(DType::F64)?.flatten_all()?.to_vec1::<f64>()?;
+                let mut dets = vec![];
+                let mut invs = vec![];
+                if n == 0 {
+                    // The determinant of a 0x0 matrix is 1 and its inverse is empty.
+                    dets = vec![1.; batch_dims.iter().product::<usize>()];
+                } else {
+                    for (idx, matrix) in vs.chunks(n * n).enumerate() {
+                        let (det, inv) = gauss_jordan(matrix.to_vec(), n);
+                        dets.push(det);
+                        if node.op_type == "Inverse" {
+                            match inv {
+                                Some(inv) => invs.extend(inv),
+                                None => {
+                                    bail!("Inverse of the singular matrix {idx} in {}", node.name)
+                                }
+                            }
+                        }
+                    }
+                }
+                let output = if node.op_type == "Det" {
+                    Tensor::from_vec(dets, batch_dims, xs.device())?
+                } else {
+                    Tensor::from_vec(invs, xs.shape(), xs.device())?
+                };
+                values.insert(node.output[0].clone(), output.to_dtype(xs.dtype())?.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#SequenceConstruct
+            "SequenceConstruct" => {
+                let seq = node
+                    .input
+                    .iter()
+                    .map(|name| Ok(get(name)?.clone().into()))
+                    .collect::<Result<Vec<Value>>>()?;
+                values.insert(node.output[0].clone(), Value::Sequence(seq));
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#SequenceAt
+            "SequenceAt" => {
+                let seq = get_value(&node.input[0])?.as_sequence()?;
+                let position = get(&node.input[1])?
+                    .to_dtype(DType::I64)?
+                    .to_vec0::<i64>()?;
+                let value = seq[normalize_index(position, seq.len())?].clone();
+                values.insert(node.output[0].clone(), value);
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#SequenceInsert
+            "SequenceInsert" => {
+                let mut seq = get_value(&node.input[0])?.as_sequence()?.to_vec();
+                let tensor = get(&node.input[1])?.clone();
+                let position = match get_opt(2)? {
+                    None => seq.len(),
+                    Some(position) => {
+                        let position = position.to_dtype(DType::I64)?.to_vec0::<i64>()?;
+                        // Valid positions are in [-n, n], inserting at n appends.
+                        let n = seq.len() as i64;
+                        if position < -n || position > n {
+                            bail!("position {position} out of bounds for SequenceInsert with {n} elements")
+                        }
+                        (if position < 0 { position + n } else { position }) as usize
+                    }
+                };
+                seq.insert(position, tensor.into());
+                values.insert(node.output[0].clone(), Value::Sequence(seq));
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#SequenceLength
+            "SequenceLength" => {
+                let seq = get_value(&node.input[0])?.as_sequence()?;
+                let output = Tensor::new(seq.len() as i64, &Device::Cpu)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Split
+            "Split" => {
+                let xs = get(&node.input[0])?;
+                let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(0);
+                let axis = normalize_index(axis, xs.rank())?;
+                let dim = xs.dim(axis)?;
+                // Before opset 13 the split sizes were an attribute rather than an input.
+                let split = match get_opt(1)? {
+                    Some(split) => Some(split.to_dtype(DType::I64)?.to_vec1::<i64>()?),
+                    None => get_attr_opt::<[i64]>(node, "split")?.map(|s| s.to_vec()),
+                };
+                let sizes = match split {
+                    Some(split) => {
+                        if split.iter().any(|&s| s < 0) {
+                            bail!("Split split {split:?} has negative sizes")
+                        }
+                        let sizes = split.into_iter().map(|s| s as usize).collect::<Vec<_>>();
+                        if sizes.iter().sum::<usize>() != dim {
+                            bail!("Split split {sizes:?} does not sum to {dim}")
+                        }
+                        sizes
+                    }
+                    None => {
+                        let num_outputs = match get_attr_opt::<i64>(node, "num_outputs")? {
+                            Some(n) => *n as usize,
+                            None => node.output.len(),
+                        };
+                        if num_outputs == 0 {
+                            bail!("Split expects at least one output")
+                        }
+                        // The chunks have size ceil(dim / num_outputs), the last one gets the
+                        // remainder.
+                        let size = dim.div_ceil(num_outputs);
+                        (0..num_outputs)
+                            .map(|i| dim.saturating_sub(i * size).min(size))
+                            .collect()
+                    }
+                };
+                if sizes.len() != node.output.len() {
+                    bail!(
+                        "Split has {} outputs but splits {dim} in {sizes:?}",
+                        node.output.len()
+                    )
+                }
+                let mut start = 0;
+                let mut chunks = Vec::with_capacity(sizes.len());
+                for size in sizes {
+                    chunks.push(xs.narrow(axis, start, size)?);
+                    start += size
+                }
+                for (output, chunk) in node.output.iter().zip(chunks) {
+                    if !output.is_empty() {
+                        values.insert(output.clone(), chunk.into());
+                    }
+                }
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#SplitToSequence
+            "SplitToSequence" => {
+                let xs = get(&node.input[0])?;
+                let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(0);
+                let axis = normalize_index(axis, xs.rank())?;
+                let keepdims = get_attr_opt::<i64>(node, "keepdims")?.copied().unwrap_or(1);
+                let dim = xs.dim(axis)?;
+                let seq = match get_opt(1)? {
+                    None => {
+                        // Without split, the input is split in chunks of size 1 along axis.
+                        let mut seq = Vec::with_capacity(dim);
+                        for i in 0..dim {
+                            let chunk = xs.narrow(axis, i, 1)?;
+                            let chunk = if keepdims == 0 {
+                                chunk.squeeze(axis)?
+                            } else {
+                                chunk
+                            };
+                            seq.push(chunk.into())
+                        }
+                        seq
+                    }
+                    Some(split) => {
+                        let split = split.to_dtype(DType::I64)?;
+                        let sizes = if split.rank() == 0 {
+                            let size = split.to_vec0::<i64>()?;
+                            if size <= 0 {
+                                bail!("SplitToSequence expects a positive split, got {size}")
+                            }
+                            // The last chunk is smaller if dim is not divisible by size.
+                            let size = size as usize;
+                            (0..dim)
+                                .step_by(size)
+                                .map(|start| usize::min(size, dim - start))
+                                .collect::<Vec<_>>()
+                        } else {
+                            let split = split.to_vec1::<i64>()?;
+                            if split.iter().any(|&s| s < 0) {
+                                bail!("SplitToSequence split {split:?} has negative sizes")
+                            }
+                            let sizes = split.into_iter().map(|s| s as usize).collect::<Vec<_>>();
+                            if sizes.iter().sum::<usize>() != dim {
+                                bail!("SplitToSequence split {sizes:?} does not sum to {dim}")
+                            }
+                            sizes
+                        };
+                        let mut start = 0;
+                        let mut seq = Vec::with_capacity(sizes.len());
+                        for size in sizes {
+                            seq.push(xs.narrow(axis, start, size)?.into());
+                            start += size
+                        }
+                        seq
+                    }
+                };
+                values.insert(node.output[0].clone(), Value::Sequence(seq));
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#ConcatFromSequence
+            "ConcatFromSequence" => {
+                let seq = get_value(&node.input[0])?
+                    .as_sequence()?
+                    .iter()
+                    .map(|v| Ok(v.as_tensor()?.clone()))
+                    .collect::<Result<Vec<Tensor>>>()?;
+                let axis: i64 = *get_attr(node, "axis")?;
+                let new_axis = get_attr_opt::<i64>(node, "new_axis")?.copied().unwrap_or(0);
+                let rank = match seq.first() {
+                    None => bail!("ConcatFromSequence on an empty sequence {}", node.name),
+                    Some(t) => t.rank(),
+                };
+                let output = if new_axis == 1 {
+                    // With new_axis, the axis can also refer to the new trailing dimension.
+                    let axis = normalize_index(axis, rank + 1)?;
+                    Tensor::stack(&seq, axis)?
+                } else {
+                    let axis = normalize_index(axis, rank)?;
+                    Tensor::cat(&seq, axis)?
+                };
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Optional
+            "Optional" => {
+                let value = match node.input.first() {
+                    Some(name) if !name.is_empty() => Some(Box::new(get_value(name)?.clone())),
+                    _ => None,
+                };
+                values.insert(node.output[0].clone(), Value::Optional(value));
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#OptionalHasElement
+            "OptionalHasElement" => {
+                let has_element = match node.input.first() {
+                    Some(name) if !name.is_empty() => match get_value(name)? {
+                        Value::Optional(value) => value.is_some(),
+                        _ => true,
+                    },
+                    _ => false,
+                };
+                let output = Tensor::new(has_element as u8, &Device::Cpu)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#OptionalGetElement
+            "OptionalGetElement" => {
+                let value = match get_value(&node.input[0])? {
+                    Value::Optional(Some(value)) => value.as_ref().clone(),
+                    Value::Optional(None) => {
+                        bail!("OptionalGetElement on an empty optional {}", node.name)
+                    }
+                    value => value.clone(),
+                };
+                values.insert(node.output[0].clone(), value);
             }
             // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Constant
             "Constant" => {
-                let value = match node.attribute.iter().find(|attr| attr.name == "value") {
-                    None => {
-                        // TODO: support sparse_value etc.
-                        bail!("cannot find 'value' attr in 'Constant' for {}", node.name)
+                let output = constant_tensor(node)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#DequantizeLinear
+            "DequantizeLinear" => {
+                let xs = get(&node.input[0])?;
+                let scale = get(&node.input[1])?;
+                let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(1);
+                let block_size = get_attr_opt::<i64>(node, "block_size")?
+                    .copied()
+                    .unwrap_or(0) as usize;
+                let dtype = scale.dtype();
+                let scale = broadcast_quantization_param(scale, xs.dims(), axis, block_size)?;
+                let xs = xs.to_dtype(dtype)?;
+                let xs = match get_opt(2)? {
+                    None => xs,
+                    Some(zero_point) => {
+                        let zero_point = zero_point.to_dtype(dtype)?;
+                        let zero_point =
+                            broadcast_quantization_param(&zero_point, xs.dims(), axis, block_size)?;
+                        (xs - zero_point)?
                     }
-                    Some(value) => value,
                 };
-                let output = match value.r#type() {
-                    AttributeType::Tensor => {
-                        let t = value.t.as_ref().unwrap();
-                        get_tensor(t, &node.name)?
+                let output = (xs * scale)?;
+                values.insert(node.output[0].clone(), output.into());
+            }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#QuantizeLinear
+            "QuantizeLinear" => {
+                let xs = get(&node.input[0])?;
+                let scale = get(&node.input[1])?;
+                let axis = get_attr_opt::<i64>(node, "axis")?.copied().unwrap_or(1);
+                let block_size = get_attr_opt::<i64>(node, "block_size")?
+                    .copied()
+                    .unwrap_or(0) as usize;
+                let zero_point = get_opt(2)?;
+                let out_dtype = match (zero_point, get_attr_opt::<i64>(node, "output_dtype")?) {
+                    (Some(zero_point), _) => zero_point.dtype(),
+                    (None, Some(&dt)) => match DataType::try_from(dt as i32).ok().and_then(dtype) {
+                        Some(dt) => dt,
+                        None => bail!("unsupported output_dtype {dt} for {}", node.name),
+                    },
+                    (None, None) => DType::U8,
+                };
+                let scale = broadcast_quantization_param(scale, xs.dims(), axis, block_size)?
+                    .to_dtype(DType::F64)?;
+                let ys = (xs.to_dtype(DType::F64)? / scale)?;
+                let zero_points = match zero_point {
+                    None => vec![0.; xs.elem_count()],
+                    Some(zero_point) => {
+                        let zero_point = zero_point.to_dtype(DType::F64)?;
+                        broadcast_quantization_param(&zero_point, xs.dims(), axis, block_size)?
+                            .flatten_all()?
+                            .to_vec1::<f64>()?
                     }
-                    rtype => bail!("unsupported 'value' type {rtype:?} for {}", node.name),
                 };
-                values.insert(node.output[0].clone(), output);
+                // Int32 is widened to I64, there is no 64 bits quantized type so I64 outputs
+                // saturate to the int32 range.
+                let (min, max) = match out_dtype {
+                    DType::U8 => (0., u8::MAX as f64),
+                    DType::U32 => (0., u32::MAX as f64),
+                    DType::I64 => (i32::MIN as f64, i32::MAX as f64),
+                    _ => (f64::NEG_INFINITY, f64::INFINITY),
+                };
+                // The zero-point is added after rounding, ties on x / scale round to even
+                // whatever the parity of the zero-point.
+                let ys = ys
+                    .flatten_all()?
+                    .to_vec1::<f64>()?
+                    .into_iter()
+                    .zip(zero_points)
+                    .map(|(y, zero_point)| (round_half_to_even(y) + zero_point).clamp(min, max))
+                    .collect::<Vec<_>>();
+                let output = Tensor::from_vec(ys, xs.dims(), xs.device())?.to_dtype(out_dtype)?;
+                values.insert(node.output[0].clone(), output.into());
             }
             // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Cast
             "Cast" => {
                 let input = get(&node.input[0])?;
                 let dt: i64 = *get_attr(node, "to")?;
-                let dtype = match DataType::try_from(dt as i32) {
+                let saturate = get_attr_opt::<i64>(node, "saturate")?.copied().unwrap_or(1) != 0;
+                let output = match DataType::try_from(dt as i32) {
+                    // There is no float8 dtype in candle, the rounding is emulated in f32.
+                    Ok(
+                        dt @ (DataType::Float8e4m3fn
+                        | DataType::Float8e4m3fnuz
+                        | DataType::Float8e5m2
+                        | DataType::Float8e5m2fnuz),
+                    ) => cast_float8(input, dt, saturate)?,
+                    // Casting floats to integers with to_dtype truncates towards zero as
+                    // required, e.g. -1.9 becomes -1. Int32 is widened to I64 like for the
+                    // inputs and initializers.
+                    Ok(DataType::Int32) => input.to_dtype(DType::I64)?,
                     Ok(dt) => match dtype(dt) {
-                        Some(dt) => dt,
+                        Some(dt) => input.to_dtype(dt)?,
                         None => {
                             bail!("unsupported 'to' value {dt:?} for cast {}", node.name)
                         }
@@ -680,18 +5617,244 @@ pub fn simple_eval(
                         bail!("unsupported 'to' value {dt:?} for cast {}", node.name)
                     }
                 };
-                let output = input.to_dtype(dtype)?;
-                values.insert(node.output[0].clone(), output);
+                values.insert(node.output[0].clone(), output.into());
             }
             op_type => bail!("unsupported op_type {op_type} for op {node:?}"),
         }
     }
-    graph
-        .output
+    Ok(())
+}
+
+// Evaluates the main graph, creating each initializer right before its first use and dropping it
+// after its last one so that the weights are not all held as tensors at once.
+fn eval_graph_lazy(
+    graph: &onnx::GraphProto,
+    values: &mut HashMap<String, Value>,
+    functions: &Functions,
+) -> Result<()> {
+    let nodes = crate::graph::topological_sort(&graph.node)?;
+    let initializers: HashMap<&str, &onnx::TensorProto> = graph
+        .initializer
         .iter()
-        .map(|output| match values.remove(&output.name) {
-            None => bail!("cannot find output {}", output.name),
-            Some(value) => Ok((output.name.clone(), value)),
-        })
-        .collect()
+        .filter(|t| !values.contains_key(&t.name))
+        .map(|t| (t.name.as_str(), t))
+        .collect();
+    let mut last_use = HashMap::new();
+    for (i, node) in nodes.iter().enumerate() {
+        for input in crate::graph::node_inputs(node) {
+            if initializers.contains_key(input) {
+                last_use.insert(input, i);
+            }
+        }
+    }
+    let is_output = |name: &str| graph.output.iter().any(|o| o.name == name);
+    for (i, node) in nodes.iter().enumerate() {
+        let inputs = crate::graph::node_inputs(node);
+        for &input in inputs.iter() {
+            if let Some(t) = initializers.get(input) {
+                if !values.contains_key(input) {
+                    values.insert(input.to_string(), get_tensor(t, &t.name)?.into());
+                }
+            }
+        }
+        eval_graph(std::slice::from_ref(*node), values, functions)?;
+        for input in inputs {
+            if last_use.get(input) == Some(&i) && !is_output(input) {
+                values.remove(input);
+            }
+        }
+    }
+    for output in graph.output.iter() {
+        if let Some(t) = initializers.get(output.name.as_str()) {
+            if !values.contains_key(&output.name) {
+                values.insert(output.name.clone(), get_tensor(t, &t.name)?.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Fused nodes are produced by the optimize pass in the default domain, the com.microsoft FusedConv
+// has more inputs and attributes and is not handled here. The base op is evaluated and the
+// activation is applied directly to its output. There are no in-place ops in candle so applying the
+// activation allocates a new tensor, but the output of the base op is dropped right away rather
+// than being kept in the values.
+fn eval_fused(
+    node: &onnx::NodeProto,
+    values: &mut HashMap<String, Value>,
+    functions: &Functions,
+) -> Result<()> {
+    let activation = get_attr_opt::<str>(node, "activation")?.unwrap_or("Relu");
+    let base = onnx::NodeProto {
+        op_type: node.op_type.trim_start_matches("Fused").to_string(),
+        domain: String::new(),
+        attribute: node
+            .attribute
+            .iter()
+            .filter(|attr| attr.name != "activation")
+            .cloned()
+            .collect(),
+        ..node.clone()
+    };
+    eval_graph(std::slice::from_ref(&base), values, functions)?;
+    let output = match values.remove(&node.output[0]) {
+        None => bail!("cannot find output {} for {}", node.output[0], node.name),
+        Some(output) => output.into_tensor()?,
+    };
+    let output = match activation {
+        "Relu" => output.relu()?,
+        "Sigmoid" => candle_nn::ops::sigmoid(&output)?,
+        "Tanh" => output.tanh()?,
+        _ => bail!("unsupported activation {activation} for {}", node.name),
+    };
+    values.insert(node.output[0].clone(), output.into());
+    Ok(())
+}
+
+// https://onnx.ai/onnx/intro/concepts.html#functions
+// The function body is evaluated in its own scope, with the inputs and attributes of the calling
+// node bound to the function formal parameters.
+fn eval_function(
+    node: &onnx::NodeProto,
+    function: &onnx::FunctionProto,
+    values: &mut HashMap<String, Value>,
+    functions: &Functions,
+) -> Result<()> {
+    let mut fn_values = HashMap::new();
+    for (fn_input, input) in function.input.iter().zip(node.input.iter()) {
+        if input.is_empty() {
+            continue;
+        }
+        match values.get(input) {
+            None => bail!("cannot find {input} for function {}", node.name),
+            Some(value) => fn_values.insert(fn_input.clone(), value.clone()),
+        };
+    }
+    // Attributes set on the calling node take precedence over the function defaults.
+    let mut bindings: HashMap<String, onnx::AttributeProto> = function
+        .attribute_proto
+        .iter()
+        .map(|attr| (attr.name.clone(), attr.clone()))
+        .collect();
+    for attr in node.attribute.iter() {
+        bindings.insert(attr.name.clone(), attr.clone());
+    }
+    let nodes: Vec<_> = function
+        .node
+        .iter()
+        .map(|node| bind_attributes(node, &bindings))
+        .collect();
+    eval_graph(&nodes, &mut fn_values, functions)?;
+    for (fn_output, output) in function.output.iter().zip(node.output.iter()) {
+        if output.is_empty() {
+            continue;
+        }
+        match fn_values.remove(fn_output) {
+            None => bail!("cannot find output {fn_output} for function {}", node.name),
+            Some(value) => values.insert(output.clone(), value),
+        };
+    }
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#If
+// The branches can use any value from the enclosing scope, their outputs are mapped in order to
+// the node outputs. Optional outputs are passed through as is so a branch can return an absent
+// optional, the node outputs past the ones of the branch that ran are set to absent optionals.
+fn eval_if(
+    node: &onnx::NodeProto,
+    values: &mut HashMap<String, Value>,
+    functions: &Functions,
+) -> Result<()> {
+    let cond = match values.get(&node.input[0]) {
+        None => bail!("cannot find {} for op {}", node.input[0], node.name),
+        Some(cond) => cond.as_tensor()?,
+    };
+    if cond.elem_count() != 1 {
+        bail!(
+            "If expects a single element condition, got {:?} for {}",
+            cond.shape(),
+            node.name
+        )
+    }
+    let cond = cond.flatten_all()?.to_dtype(DType::F64)?.to_vec1::<f64>()?[0] != 0.;
+    let branch_name = if cond { "then_branch" } else { "else_branch" };
+    let branch = get_attr::<onnx::GraphProto>(node, branch_name)?;
+    if branch.output.len() > node.output.len() {
+        bail!(
+            "{branch_name} of {} has {} outputs but the node only has {}",
+            node.name,
+            branch.output.len(),
+            node.output.len()
+        )
+    }
+    // Cloning the values is cheap as the tensor storage is shared.
+    let mut branch_values = values.clone();
+    for t in branch.initializer.iter() {
+        let tensor = get_tensor(t, t.name.as_str())?;
+        branch_values.insert(t.name.to_string(), tensor.into());
+    }
+    eval_graph(&branch.node, &mut branch_values, functions)?;
+    for (idx, output) in node.output.iter().enumerate() {
+        if output.is_empty() {
+            continue;
+        }
+        let value = match branch.output.get(idx) {
+            None => Value::Optional(None),
+            Some(branch_output) => match branch_values.remove(&branch_output.name) {
+                None => bail!(
+                    "cannot find output {} of {branch_name} for {}",
+                    branch_output.name,
+                    node.name
+                ),
+                Some(value) => value,
+            },
+        };
+        values.insert(output.clone(), value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_ops_dispatch() {
+        // The listed ops get past the dispatch, evaluating them fails on the missing inputs or
+        // attributes rather than on the op type.
+        for &(domain, op_type) in SUPPORTED_OPS {
+            let node = onnx::NodeProto {
+                op_type: op_type.to_string(),
+                domain: domain.to_string(),
+                input: (0..8).map(|i| format!("input{i}")).collect(),
+                output: (0..8).map(|i| format!("output{i}")).collect(),
+                ..onnx::NodeProto::default()
+            };
+            if let Err(err) = eval_graph(&[node], &mut HashMap::new(), &HashMap::new()) {
+                let err = err.to_string();
+                assert!(
+                    !err.contains("unsupported op_type"),
+                    "{domain} {op_type}: {err}"
+                )
+            }
+        }
+    }
+
+    #[test]
+    fn causal_tri_mask() -> Result<()> {
+        let mask = tri_mask(4, 4, 0, false, &Device::Cpu, DType::F32)?;
+        assert_eq!(
+            mask.to_vec2::<f32>()?,
+            [
+                [1., 0., 0., 0.],
+                [1., 1., 0., 0.],
+                [1., 1., 1., 0.],
+                [1., 1., 1., 1.]
+            ]
+        );
+        let mask = tri_mask(2, 3, 1, true, &Device::Cpu, DType::U8)?;
+        assert_eq!(mask.to_vec2::<u8>()?, [[0, 1, 1], [0, 0, 1]]);
+        Ok(())
+    }
 }
@@ -0,0 +1,169 @@
+use crate::onnx;
+use candle::{bail, Result};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// The values used by a node, including the ones that the nodes of its subgraphs, e.g. the
+/// branches of If, capture from the enclosing scope. Skipped optional inputs are not included.
+pub(crate) fn node_inputs(node: &onnx::NodeProto) -> Vec<&str> {
+    let mut inputs: Vec<&str> = node
+        .input
+        .iter()
+        .filter(|i| !i.is_empty())
+        .map(|i| i.as_str())
+        .collect();
+    let subgraphs = node
+        .attribute
+        .iter()
+        .flat_map(|attr| attr.g.iter().chain(attr.graphs.iter()));
+    for graph in subgraphs {
+        let local: HashSet<&str> = graph
+            .input
+            .iter()
+            .map(|i| i.name.as_str())
+            .chain(graph.initializer.iter().map(|i| i.name.as_str()))
+            .chain(
+                graph
+                    .node
+                    .iter()
+                    .flat_map(|n| n.output.iter().map(|o| o.as_str())),
+            )
+            .collect();
+        let outputs = graph.output.iter().map(|o| o.name.as_str());
+        for input in graph.node.iter().flat_map(node_inputs).chain(outputs) {
+            if !local.contains(input) && !inputs.contains(&input) {
+                inputs.push(input)
+            }
+        }
+    }
+    inputs
+}
+
+/// Checks that every value consumed by a node of the graph is either a graph input, an
+/// initializer, or the output of a node that appears earlier in the node list, i.e. that the
+/// nodes are topologically sorted and that there are no cycles.
+pub fn validate(graph: &onnx::GraphProto) -> Result<()> {
+    let mut producers: HashMap<&str, &onnx::NodeProto> = HashMap::new();
+    for node in graph.node.iter() {
+        for output in node.output.iter().filter(|o| !o.is_empty()) {
+            if let Some(prev) = producers.insert(output.as_str(), node) {
+                bail!(
+                    "{output} is produced by both {} and {}",
+                    prev.name,
+                    node.name
+                )
+            }
+        }
+    }
+    let mut available: HashSet<&str> = graph
+        .input
+        .iter()
+        .map(|i| i.name.as_str())
+        .chain(graph.initializer.iter().map(|i| i.name.as_str()))
+        .collect();
+    for node in graph.node.iter() {
+        // Optional inputs can be skipped using an empty name, these are not returned here.
+        for input in node_inputs(node) {
+            if available.contains(input) {
+                continue;
+            }
+            match producers.get(input) {
+                None => bail!("no producer for {input} used in op {}", node.name),
+                Some(producer) => bail!(
+                    "{input} is used in op {} before being produced by op {}",
+                    node.name,
+                    producer.name
+                ),
+            }
+        }
+        available.extend(node.output.iter().map(|o| o.as_str()));
+    }
+    for output in graph.output.iter() {
+        if !available.contains(output.name.as_str()) {
+            bail!("no producer for the graph output {}", output.name)
+        }
+    }
+    Ok(())
+}
+
+/// Checks that every value consumed by a node is a graph input, an initializer, one of the
+/// provided values, or the output of some node. Unlike `validate`, the nodes do not have to be
+/// sorted and all the missing values are reported at once.
+pub(crate) fn check_producers(graph: &onnx::GraphProto, provided: &HashSet<&str>) -> Result<()> {
+    let available: HashSet<&str> = graph
+        .input
+        .iter()
+        .map(|i| i.name.as_str())
+        .chain(graph.initializer.iter().map(|i| i.name.as_str()))
+        .chain(
+            graph
+                .node
+                .iter()
+                .flat_map(|n| n.output.iter().map(|o| o.as_str())),
+        )
+        .chain(provided.iter().copied())
+        .collect();
+    let mut missing = vec![];
+    for node in graph.node.iter() {
+        for input in node_inputs(node) {
+            if !available.contains(input) {
+                missing.push(format!("{input} used in op {}", node.name))
+            }
+        }
+    }
+    for output in graph.output.iter() {
+        if !available.contains(output.name.as_str()) {
+            missing.push(format!("{} used as a graph output", output.name))
+        }
+    }
+    if !missing.is_empty() {
+        bail!("no producer for {}", missing.join(", "))
+    }
+    Ok(())
+}
+
+/// Sorts the nodes so that each node comes after the nodes producing its inputs, using Kahn's
+/// algorithm. Nodes that are already in a valid order keep their relative position.
+pub(crate) fn topological_sort(nodes: &[onnx::NodeProto]) -> Result<Vec<&onnx::NodeProto>> {
+    let mut producers: HashMap<&str, usize> = HashMap::new();
+    for (idx, node) in nodes.iter().enumerate() {
+        for output in node.output.iter().filter(|o| !o.is_empty()) {
+            producers.insert(output.as_str(), idx);
+        }
+    }
+    let mut in_degree = vec![0usize; nodes.len()];
+    let mut consumers = vec![vec![]; nodes.len()];
+    for (idx, node) in nodes.iter().enumerate() {
+        for input in node_inputs(node) {
+            // Values that are not produced by a node are graph inputs or initializers.
+            if let Some(&producer) = producers.get(input) {
+                in_degree[idx] += 1;
+                consumers[producer].push(idx);
+            }
+        }
+    }
+    // Pop the ready nodes by increasing index so that the original order is used when possible.
+    let mut ready: BinaryHeap<Reverse<usize>> = in_degree
+        .iter()
+        .enumerate()
+        .filter(|(_, &d)| d == 0)
+        .map(|(idx, _)| Reverse(idx))
+        .collect();
+    let mut sorted = Vec::with_capacity(nodes.len());
+    while let Some(Reverse(idx)) = ready.pop() {
+        sorted.push(&nodes[idx]);
+        for &consumer in consumers[idx].iter() {
+            in_degree[consumer] -= 1;
+            if in_degree[consumer] == 0 {
+                ready.push(Reverse(consumer))
+            }
+        }
+    }
+    if let Some(idx) = in_degree.iter().position(|&d| d > 0) {
+        bail!(
+            "the graph contains a cycle involving op {}",
+            nodes[idx].name
+        )
+    }
+    Ok(sorted)
+}
@@ -6,9 +6,39 @@ pub mod onnx {
 }
 
 mod eval;
-pub use eval::{dtype, simple_eval};
+mod graph;
+mod model;
+mod optimize;
+pub use eval::{
+    bind_attributes, dtype, eval_node, ir_version, metadata_props, producer_name, required_inputs,
+    simple_eval, simple_eval_strict, supported_ops, InputInfo, MapKey, Value,
+};
+pub use graph::validate;
+pub use model::Model;
+pub use optimize::optimize;
 
 pub fn read_file<P: AsRef<std::path::Path>>(p: P) -> Result<onnx::ModelProto> {
     let buf = std::fs::read(p)?;
     onnx::ModelProto::decode(buf.as_slice()).map_err(candle::Error::wrap)
 }
+
+/// Reads a model by memory-mapping the file rather than loading it in a buffer first, this avoids
+/// having both the file content and the decoded proto in memory while loading large models.
+///
+/// # Safety
+///
+/// The unsafe is inherited from [`memmap2::MmapOptions`].
+pub unsafe fn read_file_mmap<P: AsRef<std::path::Path>>(p: P) -> Result<onnx::ModelProto> {
+    let file = std::fs::File::open(p)?;
+    let mmap = memmap2::MmapOptions::new().map(&file)?;
+    onnx::ModelProto::decode(&mmap[..]).map_err(candle::Error::wrap)
+}
+
+/// Decodes a serialized model and evaluates it, see `simple_eval`.
+pub fn simple_eval_bytes(
+    bytes: &[u8],
+    inputs: std::collections::HashMap<String, Value>,
+) -> Result<std::collections::HashMap<String, Value>> {
+    let model = onnx::ModelProto::decode(bytes).map_err(candle::Error::wrap)?;
+    simple_eval(&model, inputs)
+}
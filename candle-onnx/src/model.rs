@@ -0,0 +1,56 @@
+use crate::eval::{decode_constants, eval_model, Value};
+use crate::onnx;
+use candle::Result;
+use std::collections::HashMap;
+
+/// A model ready to be evaluated repeatedly, the initializers and the constants of the graph are
+/// only decoded once when creating the model rather than on each evaluation.
+#[derive(Debug, Clone)]
+pub struct Model {
+    proto: onnx::ModelProto,
+    constants: HashMap<String, Value>,
+    lazy_initializers: bool,
+}
+
+impl Model {
+    pub fn new(proto: onnx::ModelProto) -> Result<Self> {
+        Self::new_(proto, false)
+    }
+
+    /// Creates a model where the initializers are not decoded upfront. On each evaluation, the
+    /// tensor for an initializer is created right before the first op using it and dropped after
+    /// the last one, this avoids holding a second copy of all the weights in memory at the cost
+    /// of decoding them on every evaluation.
+    pub fn new_lazy(proto: onnx::ModelProto) -> Result<Self> {
+        Self::new_(proto, true)
+    }
+
+    fn new_(proto: onnx::ModelProto, lazy_initializers: bool) -> Result<Self> {
+        let constants = match &proto.graph {
+            None => candle::bail!("no graph defined in proto"),
+            Some(graph) => decode_constants(graph, lazy_initializers)?,
+        };
+        Ok(Self {
+            proto,
+            constants,
+            lazy_initializers,
+        })
+    }
+
+    pub fn proto(&self) -> &onnx::ModelProto {
+        &self.proto
+    }
+
+    /// Evaluates the model, this is equivalent to `simple_eval` on the underlying proto.
+    pub fn eval(&self, inputs: HashMap<String, Value>) -> Result<HashMap<String, Value>> {
+        let mut values = inputs;
+        // Cloning the values is cheap as the tensor storage is shared. The inputs provided by the
+        // caller override the initializers of the same name.
+        for (name, value) in self.constants.iter() {
+            if !values.contains_key(name) {
+                values.insert(name.clone(), value.clone());
+            }
+        }
+        eval_model(&self.proto, values, self.lazy_initializers)
+    }
+}
@@ -0,0 +1,371 @@
+use crate::eval::{eval_node, get_tensor, Value};
+use crate::onnx;
+use crate::onnx::tensor_proto::DataType;
+use candle::{DType, Device, Result, Tensor};
+use prost::Message;
+use std::collections::{HashMap, HashSet};
+
+/// Rewrites the graph so that it can be evaluated more efficiently, the outputs of the graph are
+/// left unchanged.
+pub fn optimize(graph: &mut onnx::GraphProto) -> Result<()> {
+    eliminate_common_subexpressions(graph)?;
+    fold_shape_computations(graph)?;
+    fuse_relu(graph);
+    fuse_swish(graph);
+    Ok(())
+}
+
+// Number of times each value is used, either as a node input or as a graph output.
+fn use_counts(graph: &onnx::GraphProto) -> HashMap<&str, usize> {
+    let mut counts = HashMap::new();
+    let inputs = graph.node.iter().flat_map(crate::graph::node_inputs);
+    let outputs = graph.output.iter().map(|output| output.name.as_str());
+    for name in inputs.chain(outputs) {
+        *counts.entry(name).or_insert(0) += 1
+    }
+    counts
+}
+
+// Ops whose outputs can differ between two evaluations with the same inputs.
+const RANDOM_OPS: [&str; 7] = [
+    "Bernoulli",
+    "Dropout",
+    "Multinomial",
+    "RandomNormal",
+    "RandomNormalLike",
+    "RandomUniform",
+    "RandomUniformLike",
+];
+
+// Nodes with the same op, attributes and inputs compute the same values so only the first one is
+// kept, the uses of the outputs of the others are renamed to its outputs. The nodes are visited in
+// topological order so that whole chains of identical nodes get merged.
+fn eliminate_common_subexpressions(graph: &mut onnx::GraphProto) -> Result<()> {
+    let nodes: Vec<onnx::NodeProto> = crate::graph::topological_sort(&graph.node)?
+        .into_iter()
+        .cloned()
+        .collect();
+    // The values captured by subgraphs cannot be renamed either as only the node inputs are.
+    let graph_outputs: HashSet<String> = graph
+        .output
+        .iter()
+        .map(|o| o.name.clone())
+        .chain(graph.node.iter().flat_map(|node| {
+            crate::graph::node_inputs(node)
+                .into_iter()
+                .filter(|i| !node.input.iter().any(|n| n == i))
+                .map(|i| i.to_string())
+        }))
+        .collect();
+    let mut renames: HashMap<String, String> = HashMap::new();
+    let mut seen: HashMap<_, Vec<String>> = HashMap::new();
+    let mut kept = Vec::with_capacity(nodes.len());
+    for mut node in nodes {
+        for input in node.input.iter_mut() {
+            if let Some(renamed) = renames.get(input) {
+                *input = renamed.clone()
+            }
+        }
+        if RANDOM_OPS.contains(&node.op_type.as_str())
+            || node.output.iter().any(|o| graph_outputs.contains(o))
+        {
+            kept.push(node);
+            continue;
+        }
+        // The encoded attributes are sorted so that their order does not matter.
+        let mut attributes: Vec<Vec<u8>> =
+            node.attribute.iter().map(|a| a.encode_to_vec()).collect();
+        attributes.sort();
+        let key = (
+            node.domain.clone(),
+            node.op_type.clone(),
+            node.input.clone(),
+            attributes,
+        );
+        match seen.get(&key) {
+            // An optional output that was skipped by the first node cannot be reused.
+            Some(outputs)
+                if outputs.len() >= node.output.len()
+                    && node
+                        .output
+                        .iter()
+                        .zip(outputs.iter())
+                        .all(|(o, first)| o.is_empty() || !first.is_empty()) =>
+            {
+                for (output, first) in node.output.iter().zip(outputs.iter()) {
+                    if !output.is_empty() {
+                        renames.insert(output.clone(), first.clone());
+                    }
+                }
+            }
+            Some(_) => kept.push(node),
+            None => {
+                seen.insert(key, node.output.clone());
+                kept.push(node)
+            }
+        }
+    }
+    graph.node = kept;
+    Ok(())
+}
+
+// Ops that can appear in the computation of a shape from the dims of another value.
+const SHAPE_OPS: [&str; 8] = [
+    "Cast",
+    "Concat",
+    "Constant",
+    "Gather",
+    "Shape",
+    "Slice",
+    "Squeeze",
+    "Unsqueeze",
+];
+
+// The dims of a value when they are all statically known.
+fn static_dims(info: &onnx::ValueInfoProto) -> Option<Vec<usize>> {
+    use onnx::tensor_shape_proto::dimension::Value::DimValue;
+    let tensor_type = match info.r#type.as_ref()?.value.as_ref()? {
+        onnx::type_proto::Value::TensorType(tt) => tt,
+        _ => None?,
+    };
+    tensor_type
+        .shape
+        .as_ref()?
+        .dim
+        .iter()
+        .map(|dim| match dim.value {
+            Some(DimValue(v)) if v >= 0 => Some(v as usize),
+            _ => None,
+        })
+        .collect()
+}
+
+// Dynamic graphs compute the shape input of Reshape and Expand at runtime, e.g. with a
+// Shape -> Slice/Gather -> Concat chain. When the dims involved are all static, the chain gets
+// evaluated once here, its result is stored as an initializer and the nodes that are not used
+// anymore are removed.
+fn fold_shape_computations(graph: &mut onnx::GraphProto) -> Result<()> {
+    let mut static_shapes: HashMap<&str, Vec<usize>> = HashMap::new();
+    for info in graph.input.iter().chain(graph.value_info.iter()) {
+        if let Some(dims) = static_dims(info) {
+            static_shapes.insert(info.name.as_str(), dims);
+        }
+    }
+    // Initializers that are also graph inputs are only defaults, they can be overridden at
+    // evaluation time so nothing gets folded from them.
+    let graph_inputs: HashSet<&str> = graph.input.iter().map(|i| i.name.as_str()).collect();
+    let mut known: HashMap<String, Value> = HashMap::new();
+    for t in graph.initializer.iter() {
+        if graph_inputs.contains(t.name.as_str()) {
+            continue;
+        }
+        static_shapes.insert(
+            t.name.as_str(),
+            t.dims.iter().map(|&d| d as usize).collect(),
+        );
+        if matches!(
+            DataType::try_from(t.data_type),
+            Ok(DataType::Int64 | DataType::Int32)
+        ) {
+            known.insert(t.name.clone(), get_tensor(t, &t.name)?.into());
+        }
+    }
+    // The values computed by the nodes below, the outputs of Constant nodes are excluded as there
+    // is nothing to fold for them.
+    let mut folded = HashSet::new();
+    for node in crate::graph::topological_sort(&graph.node)? {
+        if !node.domain.is_empty() || !SHAPE_OPS.contains(&node.op_type.as_str()) {
+            continue;
+        }
+        let mut inputs = HashMap::new();
+        for (i, input) in node.input.iter().enumerate() {
+            if input.is_empty() {
+                continue;
+            }
+            let value = match (known.get(input), static_shapes.get(input.as_str())) {
+                (Some(value), _) => value.clone(),
+                // Shape only looks at the dims, a broadcast scalar avoids allocating the data.
+                (None, Some(dims)) if node.op_type == "Shape" && i == 0 => {
+                    Tensor::zeros((), DType::U8, &Device::Cpu)?
+                        .broadcast_as(dims.as_slice())?
+                        .into()
+                }
+                (None, _) => break,
+            };
+            inputs.insert(input.clone(), value);
+        }
+        if inputs.len() != node.input.iter().filter(|i| !i.is_empty()).count() {
+            continue;
+        }
+        // Errors are not reported here but when evaluating the graph.
+        if let Ok(outputs) = eval_node(node, inputs) {
+            for (name, value) in outputs {
+                if node.op_type != "Constant" {
+                    folded.insert(name.clone());
+                }
+                known.insert(name, value);
+            }
+        }
+    }
+
+    let mut new_initializers = HashSet::new();
+    for node in graph.node.iter() {
+        if !node.domain.is_empty() || !matches!(node.op_type.as_str(), "Reshape" | "Expand") {
+            continue;
+        }
+        let shape = match node.input.get(1) {
+            Some(shape) if folded.contains(shape) => shape,
+            _ => continue,
+        };
+        if !new_initializers.insert(shape.clone()) {
+            continue;
+        }
+        let t = known[shape].as_tensor()?;
+        graph.initializer.push(onnx::TensorProto {
+            name: shape.clone(),
+            dims: t.dims().iter().map(|&d| d as i64).collect(),
+            data_type: DataType::Int64.into(),
+            int64_data: t.to_dtype(DType::I64)?.flatten_all()?.to_vec1::<i64>()?,
+            ..onnx::TensorProto::default()
+        })
+    }
+    if new_initializers.is_empty() {
+        return Ok(());
+    }
+    // Remove the shape nodes whose outputs have been turned into initializers or are not used
+    // anymore, repeating until the whole chains are gone.
+    loop {
+        let counts = use_counts(graph);
+        let removed: Vec<bool> = graph
+            .node
+            .iter()
+            .map(|node| {
+                SHAPE_OPS.contains(&node.op_type.as_str())
+                    && node.domain.is_empty()
+                    && node.output.iter().all(|o| {
+                        o.is_empty()
+                            || new_initializers.contains(o)
+                            || (known.contains_key(o) && !counts.contains_key(o.as_str()))
+                    })
+            })
+            .collect();
+        if !removed.contains(&true) {
+            break;
+        }
+        let mut idx = 0;
+        graph.node.retain(|_| {
+            idx += 1;
+            !removed[idx - 1]
+        });
+    }
+    Ok(())
+}
+
+// A Conv or Add whose output is only used by a Relu is merged with the Relu into a FusedConv or
+// FusedAdd node, so that the intermediate value is not kept in the graph values.
+fn fuse_relu(graph: &mut onnx::GraphProto) {
+    let mut fusions = vec![];
+    {
+        let counts = use_counts(graph);
+        let producers: HashMap<&str, usize> = graph
+            .node
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, node)| node.output.iter().map(move |o| (o.as_str(), idx)))
+            .collect();
+        for (relu_idx, relu) in graph.node.iter().enumerate() {
+            if relu.op_type != "Relu" || !relu.domain.is_empty() {
+                continue;
+            }
+            let input = match relu.input.first() {
+                Some(input) => input.as_str(),
+                None => continue,
+            };
+            let base_idx = match producers.get(input) {
+                Some(&idx) => idx,
+                None => continue,
+            };
+            let base = &graph.node[base_idx];
+            if (base.op_type == "Conv" || base.op_type == "Add")
+                && base.domain.is_empty()
+                && base.output.len() == 1
+                && counts.get(input) == Some(&1)
+            {
+                fusions.push((base_idx, relu_idx))
+            }
+        }
+    }
+    if fusions.is_empty() {
+        return;
+    }
+    let mut removed = vec![false; graph.node.len()];
+    for (base_idx, relu_idx) in fusions {
+        let output = graph.node[relu_idx].output[0].clone();
+        let base = &mut graph.node[base_idx];
+        base.op_type = format!("Fused{}", base.op_type);
+        base.output[0] = output;
+        base.attribute.push(onnx::AttributeProto {
+            name: "activation".to_string(),
+            s: b"Relu".to_vec(),
+            r#type: onnx::attribute_proto::AttributeType::String.into(),
+            ..onnx::AttributeProto::default()
+        });
+        removed[relu_idx] = true;
+    }
+    let mut idx = 0;
+    graph.node.retain(|_| {
+        idx += 1;
+        !removed[idx - 1]
+    });
+}
+
+// A Mul of a value by its own Sigmoid is the x * sigmoid(x) pattern that onnxruntime fuses into a
+// single com.microsoft node. When the Sigmoid output is only used by the Mul, both nodes are
+// replaced by a Swish node.
+fn fuse_swish(graph: &mut onnx::GraphProto) {
+    let mut fusions = vec![];
+    {
+        let counts = use_counts(graph);
+        let producers: HashMap<&str, usize> = graph
+            .node
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, node)| node.output.iter().map(move |o| (o.as_str(), idx)))
+            .collect();
+        for (mul_idx, mul) in graph.node.iter().enumerate() {
+            if mul.op_type != "Mul" || !mul.domain.is_empty() || mul.input.len() != 2 {
+                continue;
+            }
+            for (x, s) in [(0, 1), (1, 0)] {
+                let sigmoid_idx = match producers.get(mul.input[s].as_str()) {
+                    Some(&idx) => idx,
+                    None => continue,
+                };
+                let sigmoid = &graph.node[sigmoid_idx];
+                if sigmoid.op_type == "Sigmoid"
+                    && sigmoid.domain.is_empty()
+                    && sigmoid.input.first() == Some(&mul.input[x])
+                    && counts.get(mul.input[s].as_str()) == Some(&1)
+                {
+                    fusions.push((sigmoid_idx, mul_idx, x));
+                    break;
+                }
+            }
+        }
+    }
+    if fusions.is_empty() {
+        return;
+    }
+    let mut removed = vec![false; graph.node.len()];
+    for (sigmoid_idx, mul_idx, x) in fusions {
+        let mul = &mut graph.node[mul_idx];
+        mul.op_type = "Swish".to_string();
+        mul.input = vec![mul.input[x].clone()];
+        removed[sigmoid_idx] = true;
+    }
+    let mut idx = 0;
+    graph.node.retain(|_| {
+        idx += 1;
+        !removed[idx - 1]
+    });
+}
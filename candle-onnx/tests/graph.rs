@@ -0,0 +1,798 @@
+use candle_onnx::onnx::{GraphProto, NodeProto, TensorProto, ValueInfoProto};
+
+fn node(op_type: &str, inputs: &[&str], output: &str) -> NodeProto {
+    NodeProto {
+        op_type: op_type.to_string(),
+        name: output.to_string(),
+        input: inputs.iter().map(|s| s.to_string()).collect(),
+        output: vec![output.to_string()],
+        ..NodeProto::default()
+    }
+}
+
+fn value_info(name: &str) -> ValueInfoProto {
+    ValueInfoProto {
+        name: name.to_string(),
+        ..ValueInfoProto::default()
+    }
+}
+
+fn graph(node: Vec<NodeProto>) -> GraphProto {
+    GraphProto {
+        node,
+        input: vec![value_info("x")],
+        initializer: vec![TensorProto {
+            name: "w".to_string(),
+            ..TensorProto::default()
+        }],
+        output: vec![value_info("z")],
+        ..GraphProto::default()
+    }
+}
+
+#[test]
+fn validate_sorted() {
+    let g = graph(vec![
+        node("Relu", &["x", "w"], "a_out"),
+        node("Relu", &["a_out", ""], "b_out"),
+        node("Relu", &["b_out", "x"], "z"),
+    ]);
+    candle_onnx::validate(&g).unwrap();
+}
+
+#[test]
+fn validate_unsorted() {
+    // The first node consumes a value produced two nodes later.
+    let g = graph(vec![
+        node("Relu", &["c_out"], "a_out"),
+        node("Relu", &["x"], "b_out"),
+        node("Relu", &["b_out"], "c_out"),
+        node("Relu", &["a_out"], "z"),
+    ]);
+    let err = candle_onnx::validate(&g).unwrap_err().to_string();
+    assert!(err.contains("c_out is used in op a_out before being produced by op c_out"));
+}
+
+#[test]
+fn validate_missing_producer() {
+    let g = graph(vec![node("Relu", &["y"], "z")]);
+    let err = candle_onnx::validate(&g).unwrap_err().to_string();
+    assert!(err.contains("no producer for y"));
+    let g = graph(vec![node("Relu", &["x"], "a_out")]);
+    assert!(candle_onnx::validate(&g).is_err());
+}
+
+#[test]
+fn validate_cycle() {
+    let g = graph(vec![
+        node("Relu", &["b_out"], "a_out"),
+        node("Relu", &["a_out"], "b_out"),
+    ]);
+    assert!(candle_onnx::validate(&g).is_err());
+}
+
+#[test]
+fn eval_unsorted() -> candle::Result<()> {
+    use candle::{Device, Tensor};
+    // The Neg node comes first but consumes the output of the Relu node.
+    let model = candle_onnx::onnx::ModelProto {
+        graph: Some(GraphProto {
+            node: vec![node("Neg", &["r"], "z"), node("Relu", &["x"], "r")],
+            output: vec![value_info("z")],
+            ..GraphProto::default()
+        }),
+        ..Default::default()
+    };
+    let mut inputs = std::collections::HashMap::new();
+    let xs = Tensor::new(&[-1f32, 2.], &Device::Cpu)?;
+    inputs.insert("x".to_string(), xs.into());
+    let z = candle_onnx::simple_eval(&model, inputs.clone())?
+        .remove("z")
+        .unwrap()
+        .into_tensor()?;
+    assert_eq!(z.to_vec1::<f32>()?, vec![0., -2.]);
+
+    let mut model = model;
+    let graph = model.graph.as_mut().unwrap();
+    graph.node[1].input = vec!["z".to_string()];
+    let err = candle_onnx::simple_eval(&model, inputs).unwrap_err();
+    assert!(err.to_string().contains("cycle"));
+    Ok(())
+}
+
+#[test]
+fn optimize_conv_relu() -> candle::Result<()> {
+    use candle::{Device, Tensor};
+    let mut model = candle_onnx::onnx::ModelProto {
+        graph: Some(GraphProto {
+            node: vec![
+                node("Conv", &["x", "w"], "conv"),
+                node("Relu", &["conv"], "relu"),
+                node("Add", &["relu", "x"], "add"),
+                node("Relu", &["add"], "z"),
+            ],
+            output: vec![value_info("z")],
+            ..GraphProto::default()
+        }),
+        ..Default::default()
+    };
+    let dev = &Device::Cpu;
+    let mut inputs = std::collections::HashMap::new();
+    let xs = Tensor::arange(-8f32, 8., dev)?.reshape((1, 1, 4, 4))?;
+    let ws = Tensor::new(&[[[[1f32]]]], dev)?.neg()?;
+    inputs.insert("x".to_string(), xs.into());
+    inputs.insert("w".to_string(), ws.into());
+    let eval = |model: &candle_onnx::onnx::ModelProto| -> candle::Result<Vec<f32>> {
+        let z = candle_onnx::simple_eval(model, inputs.clone())?
+            .remove("z")
+            .unwrap()
+            .into_tensor()?;
+        z.flatten_all()?.to_vec1::<f32>()
+    };
+    let expected = eval(&model)?;
+
+    candle_onnx::optimize(model.graph.as_mut().unwrap())?;
+    let graph = model.graph.as_ref().unwrap();
+    let op_types = graph
+        .node
+        .iter()
+        .map(|n| n.op_type.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(op_types, ["FusedConv", "FusedAdd"]);
+    assert_eq!(graph.node[0].output, ["relu"]);
+    assert_eq!(eval(&model)?, expected);
+
+    // A Conv output that is also used elsewhere cannot be fused.
+    let graph = model.graph.as_mut().unwrap();
+    graph.node = vec![
+        node("Conv", &["x", "w"], "conv"),
+        node("Relu", &["conv"], "relu"),
+        node("Add", &["relu", "conv"], "z"),
+    ];
+    candle_onnx::optimize(graph)?;
+    assert_eq!(graph.node.len(), 3);
+
+    // The com.microsoft FusedConv is not the node produced by the optimize pass.
+    graph.node = vec![candle_onnx::onnx::NodeProto {
+        domain: "com.microsoft".to_string(),
+        ..node("FusedConv", &["x", "w"], "z")
+    }];
+    assert!(eval(&model).is_err());
+    Ok(())
+}
+
+#[test]
+fn optimize_mul_sigmoid() -> candle::Result<()> {
+    use candle::{Device, Tensor};
+    let mut model = candle_onnx::onnx::ModelProto {
+        graph: Some(GraphProto {
+            node: vec![node("Sigmoid", &["x"], "s"), node("Mul", &["s", "x"], "z")],
+            output: vec![value_info("z")],
+            ..GraphProto::default()
+        }),
+        ..Default::default()
+    };
+    let mut inputs = std::collections::HashMap::new();
+    let xs = Tensor::arange(-4f32, 4., &Device::Cpu)?;
+    inputs.insert("x".to_string(), xs.into());
+    let eval = |model: &candle_onnx::onnx::ModelProto| -> candle::Result<Vec<f32>> {
+        let z = candle_onnx::simple_eval(model, inputs.clone())?
+            .remove("z")
+            .unwrap()
+            .into_tensor()?;
+        z.to_vec1::<f32>()
+    };
+    let expected = eval(&model)?;
+
+    candle_onnx::optimize(model.graph.as_mut().unwrap())?;
+    let graph = model.graph.as_ref().unwrap();
+    assert_eq!(graph.node.len(), 1);
+    assert_eq!(graph.node[0].op_type, "Swish");
+    assert_eq!(graph.node[0].input, ["x"]);
+    assert_eq!(eval(&model)?, expected);
+    Ok(())
+}
+
+#[test]
+fn optimize_common_subexpressions() -> candle::Result<()> {
+    use candle::{Device, Tensor};
+    let mut model = candle_onnx::onnx::ModelProto {
+        graph: Some(GraphProto {
+            node: vec![
+                node("Shape", &["x"], "shape1"),
+                node("Gather", &["shape1", "idx"], "gather1"),
+                node("Shape", &["x"], "shape2"),
+                node("Gather", &["shape2", "idx"], "gather2"),
+                // Same inputs but a different op, this one is kept.
+                node("Sub", &["gather1", "gather2"], "sub"),
+                node("Add", &["gather1", "gather2"], "add"),
+                node("Add", &["add", "sub"], "z"),
+            ],
+            output: vec![value_info("z")],
+            ..GraphProto::default()
+        }),
+        ..Default::default()
+    };
+    let dev = &Device::Cpu;
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert(
+        "x".to_string(),
+        Tensor::zeros((2, 3, 5), candle::DType::F32, dev)?.into(),
+    );
+    inputs.insert("idx".to_string(), Tensor::new(&[2i64, 0], dev)?.into());
+    let eval = |model: &candle_onnx::onnx::ModelProto| -> candle::Result<Vec<i64>> {
+        let z = candle_onnx::simple_eval(model, inputs.clone())?
+            .remove("z")
+            .unwrap()
+            .into_tensor()?;
+        z.to_vec1::<i64>()
+    };
+    let expected = eval(&model)?;
+    assert_eq!(expected, [10, 4]);
+
+    candle_onnx::optimize(model.graph.as_mut().unwrap())?;
+    let graph = model.graph.as_ref().unwrap();
+    let op_types = graph
+        .node
+        .iter()
+        .map(|n| n.op_type.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(op_types, ["Shape", "Gather", "Sub", "Add", "Add"]);
+    assert_eq!(graph.node[3].input, ["gather1", "gather1"]);
+    assert_eq!(eval(&model)?, expected);
+    Ok(())
+}
+
+#[test]
+fn optimize_dynamic_reshape() -> candle::Result<()> {
+    use candle::{Device, Tensor};
+    use candle_onnx::onnx::tensor_shape_proto::{dimension::Value as DimValue, Dimension};
+    use candle_onnx::onnx::{tensor_proto::DataType, type_proto, TensorShapeProto, TypeProto};
+    let int64s = |name: &str, vs: &[i64]| TensorProto {
+        name: name.to_string(),
+        dims: vec![vs.len() as i64],
+        data_type: DataType::Int64.into(),
+        int64_data: vs.to_vec(),
+        ..TensorProto::default()
+    };
+    let x = ValueInfoProto {
+        name: "x".to_string(),
+        r#type: Some(TypeProto {
+            value: Some(type_proto::Value::TensorType(type_proto::Tensor {
+                elem_type: DataType::Float.into(),
+                shape: Some(TensorShapeProto {
+                    dim: [2, 3, 4, 5]
+                        .into_iter()
+                        .map(|v| Dimension {
+                            value: Some(DimValue::DimValue(v)),
+                            ..Dimension::default()
+                        })
+                        .collect(),
+                }),
+            })),
+            ..TypeProto::default()
+        }),
+        ..ValueInfoProto::default()
+    };
+    // shape = concat(shape(x)[0:1], [-1], shape(x)[[3]]), i.e. [2, -1, 5].
+    let mut model = candle_onnx::onnx::ModelProto {
+        graph: Some(GraphProto {
+            node: vec![
+                node("Shape", &["x"], "x_shape"),
+                node("Slice", &["x_shape", "starts", "ends"], "batch"),
+                node("Gather", &["x_shape", "last"], "hidden"),
+                NodeProto {
+                    attribute: vec![candle_onnx::onnx::AttributeProto {
+                        name: "axis".to_string(),
+                        r#type: candle_onnx::onnx::attribute_proto::AttributeType::Int.into(),
+                        i: 0,
+                        ..Default::default()
+                    }],
+                    ..node("Concat", &["batch", "minus_one", "hidden"], "shape")
+                },
+                node("Reshape", &["x", "shape"], "reshaped"),
+                node("Relu", &["reshaped"], "z"),
+            ],
+            input: vec![x],
+            initializer: vec![
+                int64s("starts", &[0]),
+                int64s("ends", &[1]),
+                int64s("last", &[3]),
+                int64s("minus_one", &[-1]),
+            ],
+            output: vec![value_info("z")],
+            ..GraphProto::default()
+        }),
+        ..Default::default()
+    };
+    let mut inputs = std::collections::HashMap::new();
+    let xs = Tensor::arange(-60f32, 60., &Device::Cpu)?.reshape((2, 3, 4, 5))?;
+    inputs.insert("x".to_string(), xs.into());
+    let eval = |model: &candle_onnx::onnx::ModelProto| -> candle::Result<Tensor> {
+        candle_onnx::simple_eval(model, inputs.clone())?
+            .remove("z")
+            .unwrap()
+            .into_tensor()
+    };
+    let expected = eval(&model)?;
+    assert_eq!(expected.dims(), [2, 12, 5]);
+    let model_proto = model.clone();
+
+    candle_onnx::optimize(model.graph.as_mut().unwrap())?;
+    let graph = model.graph.as_ref().unwrap();
+    let op_types = graph
+        .node
+        .iter()
+        .map(|n| n.op_type.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(op_types, ["Reshape", "Relu"]);
+    let shape = graph
+        .initializer
+        .iter()
+        .find(|t| t.name == "shape")
+        .unwrap();
+    assert_eq!(shape.int64_data, [2, -1, 5]);
+    let z = eval(&model)?;
+    assert_eq!(z.dims(), [2, 12, 5]);
+    assert_eq!(
+        z.flatten_all()?.to_vec1::<f32>()?,
+        expected.flatten_all()?.to_vec1::<f32>()?
+    );
+
+    // When the gathered index is also a graph input, its initializer is only a default and the
+    // shape must not be folded.
+    let mut model = model_proto;
+    let graph = model.graph.as_mut().unwrap();
+    graph.input.push(value_info("last"));
+    candle_onnx::optimize(graph)?;
+    assert!(graph.initializer.iter().all(|t| t.name != "shape"));
+    let model = candle_onnx::Model::new(model)?;
+    let z = |inputs| -> candle::Result<Vec<usize>> {
+        Ok(model.eval(inputs)?["z"].as_tensor()?.dims().to_vec())
+    };
+    assert_eq!(z(inputs.clone())?, [2, 12, 5]);
+    let mut overridden = inputs.clone();
+    overridden.insert(
+        "last".to_string(),
+        Tensor::new(&[2i64], &Device::Cpu)?.into(),
+    );
+    assert_eq!(z(overridden)?, [2, 15, 4]);
+    Ok(())
+}
+
+#[test]
+fn model_cached_constants() -> candle::Result<()> {
+    use candle::{Device, Tensor};
+    use candle_onnx::onnx::{attribute_proto::AttributeType, tensor_proto::DataType};
+    use candle_onnx::onnx::{AttributeProto, ModelProto};
+    let constant = |name: &str, v: f32| NodeProto {
+        attribute: vec![AttributeProto {
+            name: "value".to_string(),
+            r#type: AttributeType::Tensor.into(),
+            t: Some(TensorProto {
+                dims: vec![256, 256],
+                data_type: DataType::Float.into(),
+                float_data: vec![v; 256 * 256],
+                ..TensorProto::default()
+            }),
+            ..AttributeProto::default()
+        }],
+        ..node("Constant", &[], name)
+    };
+    let mut nodes = vec![];
+    let mut acc = "x".to_string();
+    for i in 0..8 {
+        let c = format!("c{i}");
+        nodes.push(constant(&c, i as f32));
+        let out = if i == 7 {
+            "z".to_string()
+        } else {
+            format!("add{i}")
+        };
+        nodes.push(node("Add", &[&acc, &c], &out));
+        acc = out;
+    }
+    let proto = ModelProto {
+        graph: Some(GraphProto {
+            node: nodes,
+            output: vec![value_info("z")],
+            ..GraphProto::default()
+        }),
+        ..ModelProto::default()
+    };
+    let mut inputs = std::collections::HashMap::new();
+    let xs = Tensor::ones((256, 256), candle::DType::F32, &Device::Cpu)?;
+    inputs.insert("x".to_string(), xs.into());
+    let eval = |outputs: std::collections::HashMap<String, candle_onnx::Value>| {
+        let z = outputs.get("z").unwrap().as_tensor()?;
+        z.flatten_all()?.to_vec1::<f32>()
+    };
+    let expected = eval(candle_onnx::simple_eval(&proto, inputs.clone())?)?;
+    assert!(expected.iter().all(|&v| v == 29.));
+
+    let model = candle_onnx::Model::new(proto)?;
+    for _ in 0..3 {
+        assert_eq!(eval(model.eval(inputs.clone())?)?, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn model_lazy_initializers() -> candle::Result<()> {
+    use candle::{Device, Tensor};
+    use candle_onnx::onnx::{tensor_proto::DataType, ModelProto};
+    use prost::Message;
+    let weight = |name: &str, i: usize| TensorProto {
+        name: name.to_string(),
+        dims: vec![4, 4],
+        data_type: DataType::Float.into(),
+        raw_data: (0..16)
+            .flat_map(|j| ((i * 16 + j) as f32 / 10.).to_le_bytes())
+            .collect(),
+        ..TensorProto::default()
+    };
+    let mut nodes = vec![];
+    let mut acc = "x".to_string();
+    for (i, op_type) in ["MatMul", "Add", "MatMul", "Sub"].iter().enumerate() {
+        let out = if i == 3 {
+            "z".to_string()
+        } else {
+            format!("h{i}")
+        };
+        nodes.push(node(op_type, &[&acc, &format!("w{}", i % 3)], &out));
+        acc = out;
+    }
+    let proto = ModelProto {
+        graph: Some(GraphProto {
+            node: nodes,
+            // Older exporters also list the initializers as inputs.
+            input: vec![value_info("x"), value_info("w0")],
+            initializer: (0..3).map(|i| weight(&format!("w{i}"), i)).collect(),
+            output: vec![value_info("z"), value_info("w2")],
+            ..GraphProto::default()
+        }),
+        ..ModelProto::default()
+    };
+    let xs = Tensor::arange(0f32, 16., &Device::Cpu)?.reshape((4, 4))?;
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert("x".to_string(), xs.into());
+    let eval = |outputs: std::collections::HashMap<String, candle_onnx::Value>, name: &str| {
+        let z = outputs.get(name).unwrap().as_tensor()?;
+        z.flatten_all()?.to_vec1::<f32>()
+    };
+    let eager = candle_onnx::simple_eval(&proto, inputs.clone())?;
+
+    let path = std::env::temp_dir().join(format!("candle-onnx-lazy-{}.onnx", std::process::id()));
+    std::fs::write(&path, proto.encode_to_vec())?;
+    let loaded = unsafe { candle_onnx::read_file_mmap(&path)? };
+    std::fs::remove_file(&path)?;
+    assert_eq!(loaded, proto);
+
+    let model = candle_onnx::Model::new_lazy(loaded)?;
+    for _ in 0..2 {
+        let lazy = model.eval(inputs.clone())?;
+        assert_eq!(eval(lazy.clone(), "z")?, eval(eager.clone(), "z")?);
+        assert_eq!(eval(lazy, "w2")?, eval(eager.clone(), "w2")?);
+    }
+    Ok(())
+}
+
+#[test]
+fn eval_strict() -> candle::Result<()> {
+    use candle::{Device, Tensor};
+    // The first node uses an unknown op so the evaluation fails as soon as it gets computed.
+    let model = candle_onnx::onnx::ModelProto {
+        graph: Some(GraphProto {
+            node: vec![
+                node("UnknownOp", &["x"], "a"),
+                node("Add", &["a", "y"], "b"),
+                node("Add", &["b", "w"], "z"),
+            ],
+            input: vec![value_info("x")],
+            output: vec![value_info("z")],
+            ..GraphProto::default()
+        }),
+        ..Default::default()
+    };
+    let mut inputs = std::collections::HashMap::new();
+    let xs = Tensor::new(&[1f32, 2.], &Device::Cpu)?;
+    inputs.insert("x".to_string(), xs.into());
+    let err = candle_onnx::simple_eval(&model, inputs.clone()).unwrap_err();
+    assert!(err.to_string().contains("unsupported op_type UnknownOp"));
+
+    let err = candle_onnx::simple_eval_strict(&model, inputs.clone()).unwrap_err();
+    let err = err.to_string();
+    assert!(err.contains("y used in op b"), "{err}");
+    assert!(err.contains("w used in op z"), "{err}");
+
+    // Provided values count as available.
+    let ys = Tensor::new(&[3f32, 4.], &Device::Cpu)?;
+    inputs.insert("y".to_string(), ys.clone().into());
+    inputs.insert("w".to_string(), ys.into());
+    let err = candle_onnx::simple_eval_strict(&model, inputs).unwrap_err();
+    assert!(err.to_string().contains("unsupported op_type UnknownOp"));
+    Ok(())
+}
+
+#[test]
+fn eval_initializer_defaults() -> candle::Result<()> {
+    use candle::{Device, Tensor};
+    use candle_onnx::onnx::{tensor_proto::DataType, ModelProto};
+    // The y input has a default value provided by an initializer of the same name.
+    let proto = ModelProto {
+        graph: Some(GraphProto {
+            node: vec![node("Add", &["x", "y"], "z")],
+            input: vec![value_info("x"), value_info("y")],
+            initializer: vec![TensorProto {
+                name: "y".to_string(),
+                dims: vec![2],
+                data_type: DataType::Float.into(),
+                float_data: vec![10., 20.],
+                ..TensorProto::default()
+            }],
+            output: vec![value_info("z")],
+            ..GraphProto::default()
+        }),
+        ..ModelProto::default()
+    };
+    let mut inputs = std::collections::HashMap::new();
+    inputs.insert(
+        "x".to_string(),
+        Tensor::new(&[1f32, 2.], &Device::Cpu)?.into(),
+    );
+    let mut overridden = inputs.clone();
+    let ys = Tensor::new(&[100f32, 200.], &Device::Cpu)?;
+    overridden.insert("y".to_string(), ys.into());
+    let eval = |outputs: std::collections::HashMap<String, candle_onnx::Value>| {
+        let z = outputs.get("z").unwrap().as_tensor()?;
+        z.to_vec1::<f32>()
+    };
+
+    assert_eq!(
+        eval(candle_onnx::simple_eval(&proto, inputs.clone())?)?,
+        [11., 22.]
+    );
+    assert_eq!(
+        eval(candle_onnx::simple_eval(&proto, overridden.clone())?)?,
+        [101., 202.]
+    );
+    for model in [
+        candle_onnx::Model::new(proto.clone())?,
+        candle_onnx::Model::new_lazy(proto)?,
+    ] {
+        assert_eq!(eval(model.eval(inputs.clone())?)?, [11., 22.]);
+        assert_eq!(eval(model.eval(overridden.clone())?)?, [101., 202.]);
+    }
+    Ok(())
+}
+
+#[test]
+fn model_required_inputs() -> candle::Result<()> {
+    use candle::DType;
+    use candle_onnx::onnx::tensor_shape_proto::{dimension::Value as DimValue, Dimension};
+    use candle_onnx::onnx::{tensor_proto::DataType, type_proto, ModelProto};
+    use candle_onnx::onnx::{TensorShapeProto, TypeProto};
+    let input = |name: &str, dt: DataType, dims: Vec<DimValue>| ValueInfoProto {
+        name: name.to_string(),
+        r#type: Some(TypeProto {
+            value: Some(type_proto::Value::TensorType(type_proto::Tensor {
+                elem_type: dt.into(),
+                shape: Some(TensorShapeProto {
+                    dim: dims
+                        .into_iter()
+                        .map(|v| Dimension {
+                            value: Some(v),
+                            ..Dimension::default()
+                        })
+                        .collect(),
+                }),
+            })),
+            ..TypeProto::default()
+        }),
+        ..ValueInfoProto::default()
+    };
+    let batch = || DimValue::DimParam("batch".to_string());
+    let proto = ModelProto {
+        graph: Some(GraphProto {
+            input: vec![
+                input(
+                    "x",
+                    DataType::Float,
+                    vec![batch(), DimValue::DimValue(3), DimValue::DimValue(224)],
+                ),
+                input("ids", DataType::Int32, vec![batch(), DimValue::DimValue(8)]),
+                // Initializer-backed inputs do not have to be provided.
+                input("w", DataType::Float, vec![DimValue::DimValue(3)]),
+            ],
+            initializer: vec![TensorProto {
+                name: "w".to_string(),
+                ..TensorProto::default()
+            }],
+            ..GraphProto::default()
+        }),
+        ..ModelProto::default()
+    };
+    let inputs = candle_onnx::required_inputs(&proto)?;
+    assert_eq!(
+        inputs,
+        [
+            ("x".to_string(), DType::F32, vec![None, Some(3), Some(224)]),
+            ("ids".to_string(), DType::I64, vec![None, Some(8)]),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn eval_bytes() -> candle::Result<()> {
+    use candle::{Device, Tensor};
+    use prost::Message;
+    let model = candle_onnx::onnx::ModelProto {
+        graph: Some(GraphProto {
+            node: vec![node("Relu", &["x"], "z")],
+            input: vec![value_info("x")],
+            output: vec![value_info("z")],
+            ..GraphProto::default()
+        }),
+        ..Default::default()
+    };
+    let bytes = model.encode_to_vec();
+    let mut inputs = std::collections::HashMap::new();
+    let xs = Tensor::new(&[-1f32, 2.], &Device::Cpu)?;
+    inputs.insert("x".to_string(), xs.into());
+    let outputs = candle_onnx::simple_eval_bytes(&bytes, inputs.clone())?;
+    let z = outputs.get("z").unwrap().as_tensor()?;
+    assert_eq!(z.to_vec1::<f32>()?, [0., 2.]);
+
+    // Truncated bytes fail to decode.
+    assert!(candle_onnx::simple_eval_bytes(&bytes[..bytes.len() - 1], inputs).is_err());
+    Ok(())
+}
+
+#[test]
+fn eval_on_device() -> candle::Result<()> {
+    use candle::{Device, Tensor};
+    // Use an accelerator when available, the outputs stay on the device of the inputs.
+    let dev = &Device::cuda_if_available(0)?;
+    let model = candle_onnx::onnx::ModelProto {
+        graph: Some(GraphProto {
+            node: vec![
+                node("Conv", &["x", "w"], "conv"),
+                node("Relu", &["conv"], "z"),
+            ],
+            output: vec![value_info("z")],
+            ..GraphProto::default()
+        }),
+        ..Default::default()
+    };
+    let mut inputs = std::collections::HashMap::new();
+    let xs = Tensor::arange(-8f32, 8., dev)?.reshape((1, 1, 4, 4))?;
+    let ws = Tensor::new(&[[[[1f32]]]], dev)?.neg()?;
+    inputs.insert("x".to_string(), xs.into());
+    inputs.insert("w".to_string(), ws.into());
+    let z = candle_onnx::simple_eval(&model, inputs)?
+        .remove("z")
+        .unwrap()
+        .into_tensor()?;
+    assert!(z.device().same_device(dev));
+    let z = z.flatten_all()?.to_vec1::<f32>()?;
+    let expected = (-8..8).map(|v| (-v).max(0) as f32).collect::<Vec<_>>();
+    assert_eq!(z, expected);
+    Ok(())
+}
+
+#[test]
+fn model_supported_ops() {
+    use candle_onnx::onnx::{AttributeProto, FunctionProto, ModelProto};
+    let mut if_node = node("If", &["cond"], "z");
+    if_node.attribute = vec![AttributeProto {
+        name: "then_branch".to_string(),
+        g: Some(GraphProto {
+            node: vec![node("NotAnOp", &["b"], "then_out")],
+            ..GraphProto::default()
+        }),
+        ..AttributeProto::default()
+    }];
+    let model = ModelProto {
+        graph: Some(GraphProto {
+            node: vec![
+                node("Relu", &["x"], "a"),
+                node("Unique", &["a"], "b"),
+                node("MyFn", &["b"], "c"),
+                node("Relu", &["c"], "cond"),
+                if_node,
+                NodeProto {
+                    domain: "com.microsoft".to_string(),
+                    ..node("QuickGelu", &["a"], "d")
+                },
+                // The com.microsoft ops are not part of the default domain.
+                node("MatMulNBits", &["a"], "e"),
+            ],
+            ..GraphProto::default()
+        }),
+        functions: vec![FunctionProto {
+            name: "MyFn".to_string(),
+            node: vec![node("Mul", &["i", "i"], "o"), node("Hardmax", &["o"], "p")],
+            ..FunctionProto::default()
+        }],
+        ..ModelProto::default()
+    };
+    let (supported, unsupported) = candle_onnx::supported_ops(&model);
+    assert_eq!(
+        supported,
+        ["If", "Mul", "MyFn", "Relu", "com.microsoft.QuickGelu"]
+    );
+    assert_eq!(unsupported, ["Hardmax", "MatMulNBits", "NotAnOp", "Unique"]);
+}
+
+#[test]
+fn model_metadata() {
+    use candle_onnx::onnx::{ModelProto, StringStringEntryProto};
+    let prop = |key: &str, value: &str| StringStringEntryProto {
+        key: key.to_string(),
+        value: value.to_string(),
+    };
+    let model = ModelProto {
+        ir_version: 8,
+        producer_name: "pytorch".to_string(),
+        metadata_props: vec![
+            prop("tokenizer", "bpe"),
+            prop("max_length", "512"),
+            prop("tokenizer", "wordpiece"),
+        ],
+        ..ModelProto::default()
+    };
+    assert_eq!(candle_onnx::ir_version(&model), 8);
+    assert_eq!(candle_onnx::producer_name(&model), "pytorch");
+    let props = candle_onnx::metadata_props(&model);
+    assert_eq!(props.len(), 2);
+    assert_eq!(props["tokenizer"], "wordpiece");
+    assert_eq!(props["max_length"], "512");
+    assert!(candle_onnx::metadata_props(&ModelProto::default()).is_empty());
+}
+
+#[test]
+fn eval_initializer_output() -> candle::Result<()> {
+    use candle_onnx::onnx::{tensor_proto::DataType, ModelProto};
+    // The only output is an initializer, there are no nodes and the output has no type.
+    let proto = ModelProto {
+        graph: Some(GraphProto {
+            initializer: vec![TensorProto {
+                name: "w".to_string(),
+                dims: vec![3],
+                data_type: DataType::Float.into(),
+                float_data: vec![1., 2., 3.],
+                ..TensorProto::default()
+            }],
+            output: vec![value_info("w")],
+            ..GraphProto::default()
+        }),
+        ..ModelProto::default()
+    };
+    candle_onnx::validate(proto.graph.as_ref().unwrap())?;
+    let eval = |outputs: std::collections::HashMap<String, candle_onnx::Value>| {
+        assert_eq!(outputs.len(), 1);
+        outputs.get("w").unwrap().as_tensor()?.to_vec1::<f32>()
+    };
+    let inputs = std::collections::HashMap::new();
+    assert_eq!(
+        eval(candle_onnx::simple_eval(&proto, inputs.clone())?)?,
+        [1., 2., 3.]
+    );
+    assert_eq!(
+        eval(candle_onnx::simple_eval_strict(&proto, inputs.clone())?)?,
+        [1., 2., 3.]
+    );
+    let mut optimized = proto.clone();
+    candle_onnx::optimize(optimized.graph.as_mut().unwrap())?;
+    for model in [
+        candle_onnx::Model::new(proto.clone())?,
+        candle_onnx::Model::new_lazy(proto)?,
+        candle_onnx::Model::new(optimized)?,
+    ] {
+        assert_eq!(eval(model.eval(inputs.clone())?)?, [1., 2., 3.]);
+        // The model can be evaluated again, the initializer is not consumed.
+        assert_eq!(eval(model.eval(inputs.clone())?)?, [1., 2., 3.]);
+    }
+    Ok(())
+}
@@ -0,0 +1,3738 @@
+use candle::{Device, Result, Tensor};
+use candle_onnx::onnx::attribute_proto::AttributeType;
+use candle_onnx::onnx::{
+    AttributeProto, FunctionProto, GraphProto, ModelProto, NodeProto, ValueInfoProto,
+};
+use candle_onnx::Value;
+use std::collections::HashMap;
+
+const INPUT_X: &str = "x";
+const INPUT_Y: &str = "y";
+const OUTPUT_Z: &str = "z";
+
+fn create_model_proto_with_graph(graph: Option<GraphProto>) -> ModelProto {
+    ModelProto {
+        graph,
+        ..ModelProto::default()
+    }
+}
+
+fn create_node(op_type: &str, inputs: &[&str], attribute: Vec<AttributeProto>) -> NodeProto {
+    NodeProto {
+        op_type: op_type.to_string(),
+        name: format!("{op_type}_node"),
+        input: inputs.iter().map(|s| s.to_string()).collect(),
+        output: vec![OUTPUT_Z.to_string()],
+        attribute,
+        ..NodeProto::default()
+    }
+}
+
+// Builds a node for graphs made of several nodes, the node is named after its first output.
+fn create_graph_node(
+    op_type: &str,
+    inputs: &[&str],
+    outputs: &[&str],
+    attribute: Vec<AttributeProto>,
+) -> NodeProto {
+    NodeProto {
+        op_type: op_type.to_string(),
+        name: outputs[0].to_string(),
+        input: inputs.iter().map(|s| s.to_string()).collect(),
+        output: outputs.iter().map(|s| s.to_string()).collect(),
+        attribute,
+        ..NodeProto::default()
+    }
+}
+
+// Builds a model made of a single node producing the `z` output.
+fn create_single_node_model(node: NodeProto) -> ModelProto {
+    create_model_proto_with_graph(Some(GraphProto {
+        node: vec![node],
+        output: vec![ValueInfoProto {
+            name: OUTPUT_Z.to_string(),
+            ..ValueInfoProto::default()
+        }],
+        ..GraphProto::default()
+    }))
+}
+
+fn str_attr(name: &str, value: &str) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        r#type: AttributeType::String.into(),
+        s: value.as_bytes().to_vec(),
+        ..AttributeProto::default()
+    }
+}
+
+fn int_attr(name: &str, value: i64) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        r#type: AttributeType::Int.into(),
+        i: value,
+        ..AttributeProto::default()
+    }
+}
+
+fn ints_attr(name: &str, values: &[i64]) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        r#type: AttributeType::Ints.into(),
+        ints: values.to_vec(),
+        ..AttributeProto::default()
+    }
+}
+
+fn float_attr(name: &str, value: f32) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        r#type: AttributeType::Float.into(),
+        f: value,
+        ..AttributeProto::default()
+    }
+}
+
+fn floats_attr(name: &str, values: &[f32]) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        r#type: AttributeType::Floats.into(),
+        floats: values.to_vec(),
+        ..AttributeProto::default()
+    }
+}
+
+fn to_values(inputs: HashMap<String, Tensor>) -> HashMap<String, Value> {
+    inputs.into_iter().map(|(k, v)| (k, v.into())).collect()
+}
+
+fn eval_single_output(model: &ModelProto, inputs: HashMap<String, Tensor>) -> Result<Tensor> {
+    let mut eval = candle_onnx::simple_eval(model, to_values(inputs))?;
+    assert_eq!(eval.len(), 1);
+    eval.remove(OUTPUT_Z)
+        .expect("Output 'z' not found")
+        .into_tensor()
+}
+
+#[test]
+fn test_bit_shift_left() -> Result<()> {
+    let model = create_single_node_model(create_node(
+        "BitShift",
+        &[INPUT_X, INPUT_Y],
+        vec![str_attr("direction", "LEFT")],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(
+        INPUT_X.to_string(),
+        Tensor::new(&[1u32, 3, 16, 255], &Device::Cpu)?,
+    );
+    inputs.insert(INPUT_Y.to_string(), Tensor::new(&[2u32], &Device::Cpu)?);
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.to_vec1::<u32>()?, vec![4, 12, 64, 1020]);
+    Ok(())
+}
+
+#[test]
+fn test_bitwise_and() -> Result<()> {
+    let model = create_single_node_model(create_node("BitwiseAnd", &[INPUT_X, INPUT_Y], vec![]));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(
+        INPUT_X.to_string(),
+        Tensor::new(&[[12u32, 10], [7, 255]], &Device::Cpu)?,
+    );
+    inputs.insert(
+        INPUT_Y.to_string(),
+        Tensor::new(&[[10u32, 6], [5, 15]], &Device::Cpu)?,
+    );
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.to_vec2::<u32>()?, vec![vec![8, 2], vec![5, 15]]);
+
+    // Bitwise ops are only defined on integer tensors.
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), Tensor::new(&[1f32], &Device::Cpu)?);
+    inputs.insert(INPUT_Y.to_string(), Tensor::new(&[1f32], &Device::Cpu)?);
+    assert!(candle_onnx::simple_eval(&model, to_values(inputs)).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_grid_sample_identity() -> Result<()> {
+    let xs = Tensor::arange(0f32, 6f32, &Device::Cpu)?.reshape((1, 1, 2, 3))?;
+    // Without align_corners, the pixel centers are at (2i + 1) / size - 1.
+    let identity = |align_corners: bool| -> Result<Tensor> {
+        let coord = |i: usize, size: usize| {
+            if align_corners {
+                2. * i as f32 / (size - 1) as f32 - 1.
+            } else {
+                (2 * i + 1) as f32 / size as f32 - 1.
+            }
+        };
+        let mut grid = vec![];
+        for y in 0..2 {
+            for x in 0..3 {
+                grid.push(coord(x, 3));
+                grid.push(coord(y, 2));
+            }
+        }
+        Tensor::from_vec(grid, (1, 2, 3, 2), &Device::Cpu)
+    };
+    for align_corners in [0, 1] {
+        let model = create_single_node_model(create_node(
+            "GridSample",
+            &[INPUT_X, INPUT_Y],
+            vec![
+                str_attr("mode", "bilinear"),
+                int_attr("align_corners", align_corners),
+            ],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        inputs.insert(INPUT_Y.to_string(), identity(align_corners != 0)?);
+        let z = eval_single_output(&model, inputs)?;
+        assert_eq!(z.dims(), &[1, 1, 2, 3]);
+        let diff = (z - &xs)?.abs()?.flatten_all()?.max(0)?.to_vec0::<f32>()?;
+        assert!(diff < 1e-5, "align_corners {align_corners}, diff {diff}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_non_max_suppression() -> Result<()> {
+    let model = create_single_node_model(create_node(
+        "NonMaxSuppression",
+        &[INPUT_X, INPUT_Y, "max_boxes", "iou_threshold"],
+        vec![],
+    ));
+    // The first two boxes overlap heavily, the third one is disjoint.
+    let boxes = Tensor::new(
+        &[[[0f32, 0., 1., 1.], [0., 0.1, 1., 1.1], [0., 2., 1., 3.]]],
+        &Device::Cpu,
+    )?;
+    let scores = Tensor::new(&[[[0.8f32, 0.9, 0.3]]], &Device::Cpu)?;
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), boxes);
+    inputs.insert(INPUT_Y.to_string(), scores);
+    inputs.insert("max_boxes".to_string(), Tensor::new(&[3i64], &Device::Cpu)?);
+    inputs.insert(
+        "iou_threshold".to_string(),
+        Tensor::new(&[0.5f32], &Device::Cpu)?,
+    );
+    let z = eval_single_output(&model, inputs.clone())?;
+    assert_eq!(z.to_vec2::<i64>()?, vec![vec![0, 0, 1], vec![0, 0, 2]]);
+
+    // The scalar inputs cannot be empty.
+    inputs.insert(
+        "max_boxes".to_string(),
+        Tensor::new(&[0i64; 0], &Device::Cpu)?,
+    );
+    assert!(eval_single_output(&model, inputs).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_roi_align() -> Result<()> {
+    let model = create_single_node_model(create_node(
+        "RoiAlign",
+        &[INPUT_X, INPUT_Y, "batch_indices"],
+        vec![
+            int_attr("output_height", 2),
+            int_attr("output_width", 2),
+            int_attr("sampling_ratio", 2),
+        ],
+    ));
+    // x[y, x] = 4y + x is linear so bilinear sampling is exact, the samples for each bin are
+    // at coordinates {0, 1} or {2, 3} on each axis.
+    let xs = Tensor::arange(0f32, 16f32, &Device::Cpu)?.reshape((1, 1, 4, 4))?;
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs);
+    inputs.insert(
+        INPUT_Y.to_string(),
+        Tensor::new(&[[0f32, 0., 4., 4.]], &Device::Cpu)?,
+    );
+    inputs.insert(
+        "batch_indices".to_string(),
+        Tensor::new(&[0i64], &Device::Cpu)?,
+    );
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.dims(), &[1, 1, 2, 2]);
+    assert_eq!(
+        z.flatten_all()?.to_vec1::<f32>()?,
+        vec![2.5, 4.5, 10.5, 12.5]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_hann_window() -> Result<()> {
+    let model = create_single_node_model(create_node("HannWindow", &[INPUT_X], vec![]));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), Tensor::new(8i64, &Device::Cpu)?);
+    let z = eval_single_output(&model, inputs)?;
+    let z = z.to_vec1::<f32>()?;
+    let expected = [
+        0f32,
+        0.146_446_6,
+        0.5,
+        0.853_553_4,
+        1.,
+        0.853_553_4,
+        0.5,
+        0.146_446_6,
+    ];
+    assert_eq!(z.len(), expected.len());
+    for (v, e) in z.iter().zip(expected.iter()) {
+        assert!((v - e).abs() < 1e-6, "{z:?}")
+    }
+    Ok(())
+}
+
+#[test]
+fn test_reverse_sequence() -> Result<()> {
+    let model = create_single_node_model(create_node(
+        "ReverseSequence",
+        &[INPUT_X, INPUT_Y],
+        vec![int_attr("batch_axis", 1), int_attr("time_axis", 0)],
+    ));
+    // The input has shape [time, batch] with 4 timesteps and 3 sequences.
+    let xs = Tensor::new(
+        &[[0f32, 4., 8.], [1., 5., 9.], [2., 6., 10.], [3., 7., 11.]],
+        &Device::Cpu,
+    )?;
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs);
+    inputs.insert(
+        INPUT_Y.to_string(),
+        Tensor::new(&[4i64, 2, 1], &Device::Cpu)?,
+    );
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(
+        z.to_vec2::<f32>()?,
+        vec![
+            vec![3., 5., 8.],
+            vec![2., 4., 9.],
+            vec![1., 6., 10.],
+            vec![0., 7., 11.]
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_multinomial() -> Result<()> {
+    let model = create_single_node_model(create_node(
+        "Multinomial",
+        &[INPUT_X],
+        vec![
+            int_attr("sample_size", 16),
+            int_attr("dtype", 7),
+            float_attr("seed", 42.),
+        ],
+    ));
+    // The inputs are log-probabilities, each row is one-hot once exponentiated.
+    let logits = Tensor::new(&[[0f32, 1., 0.], [1., 0., 0.]], &Device::Cpu)?.log()?;
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), logits.clone());
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.dims(), &[2, 16]);
+    let z = z.to_vec2::<i64>()?;
+    assert!(z[0].iter().all(|&v| v == 1), "{z:?}");
+    assert!(z[1].iter().all(|&v| v == 0), "{z:?}");
+
+    // Sampling with a fixed seed is reproducible.
+    let probs = Tensor::new(&[[0.1f32, 0.2, 0.3, 0.4]], &Device::Cpu)?.log()?;
+    let sample = || -> Result<Vec<Vec<i64>>> {
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), probs.clone());
+        eval_single_output(&model, inputs)?.to_vec2::<i64>()
+    };
+    assert_eq!(sample()?, sample()?);
+    Ok(())
+}
+
+#[test]
+fn test_lrn() -> Result<()> {
+    let (alpha, beta, bias, size) = (0.002f32, 0.5f32, 2f32, 3usize);
+    let model = create_single_node_model(create_node(
+        "LRN",
+        &[INPUT_X],
+        vec![
+            float_attr("alpha", alpha),
+            float_attr("beta", beta),
+            float_attr("bias", bias),
+            int_attr("size", size as i64),
+        ],
+    ));
+    let xs = Tensor::arange(0f32, 20f32, &Device::Cpu)?.reshape((1, 5, 2, 2))?;
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs.clone());
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.dims(), &[1, 5, 2, 2]);
+
+    let xs = xs.flatten_all()?.to_vec1::<f32>()?;
+    let z = z.flatten_all()?.to_vec1::<f32>()?;
+    for c in 0..5usize {
+        for i in 0..4 {
+            let lo = c.saturating_sub((size - 1) / 2);
+            let hi = usize::min(c + size / 2, 4);
+            let square_sum: f32 = (lo..=hi).map(|c| xs[c * 4 + i] * xs[c * 4 + i]).sum();
+            let expected = xs[c * 4 + i] / (bias + alpha / size as f32 * square_sum).powf(beta);
+            assert!((z[c * 4 + i] - expected).abs() < 1e-5, "{z:?}");
+        }
+    }
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#BatchNormalization
+#[test]
+fn test_batch_norm() -> Result<()> {
+    let dev = &Device::Cpu;
+    let model = create_single_node_model(create_node(
+        "BatchNormalization",
+        &[INPUT_X, "scale", "bias", "mean", "var"],
+        vec![float_attr("epsilon", 0.)],
+    ));
+    let (scale, bias, mean, var) = (
+        [1f32, 2., 0.5],
+        [0f32, 1., -1.],
+        [1f32, 2., 3.],
+        [4f32, 1., 16.],
+    );
+    let eval = |xs: &Tensor| -> Result<Tensor> {
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        inputs.insert("scale".to_string(), Tensor::new(&scale, dev)?);
+        inputs.insert("bias".to_string(), Tensor::new(&bias, dev)?);
+        inputs.insert("mean".to_string(), Tensor::new(&mean, dev)?);
+        inputs.insert("var".to_string(), Tensor::new(&var, dev)?);
+        eval_single_output(&model, inputs)
+    };
+    // The channel is always dim 1 whatever the rank of the input.
+    for shape in [vec![2, 3], vec![2, 3, 4], vec![2, 3, 2, 2, 3]] {
+        let numel = shape.iter().product::<usize>();
+        let xs = Tensor::arange(0f32, numel as f32, dev)?.reshape(shape.as_slice())?;
+        let z = eval(&xs)?;
+        assert_eq!(z.dims(), shape.as_slice());
+        let inner = numel / shape[0] / 3;
+        let xs = xs.flatten_all()?.to_vec1::<f32>()?;
+        let z = z.flatten_all()?.to_vec1::<f32>()?;
+        for (i, (x, z)) in xs.iter().zip(z.iter()).enumerate() {
+            let c = i / inner % 3;
+            let expected = (x - mean[c]) / var[c].sqrt() * scale[c] + bias[c];
+            assert!((z - expected).abs() < 1e-5, "{shape:?} {i} {z} {expected}");
+        }
+    }
+    assert!(eval(&Tensor::new(&[1f32, 2., 3.], dev)?).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_mean_variance_normalization() -> Result<()> {
+    let model =
+        create_single_node_model(create_node("MeanVarianceNormalization", &[INPUT_X], vec![]));
+    let xs = Tensor::arange(0f32, 96f32, &Device::Cpu)?
+        .sqr()?
+        .reshape((2, 3, 4, 4))?;
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs);
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.dims(), &[2, 3, 4, 4]);
+    // With the default axes [0, 2, 3], each channel has zero mean and unit variance.
+    let mean = z.mean_keepdim((0, 2, 3))?;
+    let var = z.broadcast_sub(&mean)?.sqr()?.mean_keepdim((0, 2, 3))?;
+    for m in mean.flatten_all()?.to_vec1::<f32>()? {
+        assert!(m.abs() < 1e-5, "{m}")
+    }
+    for v in var.flatten_all()?.to_vec1::<f32>()? {
+        assert!((v - 1.).abs() < 1e-4, "{v}")
+    }
+    Ok(())
+}
+
+#[test]
+fn test_global_lp_pool() -> Result<()> {
+    let model = create_single_node_model(create_node(
+        "GlobalLpPool",
+        &[INPUT_X],
+        vec![int_attr("p", 2)],
+    ));
+    let xs = Tensor::new(
+        &[[[[1f32, -2.], [2., 4.]], [[3., 0.], [0., -4.]]]],
+        &Device::Cpu,
+    )?;
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs);
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.dims(), &[1, 2, 1, 1]);
+    assert_eq!(z.flatten_all()?.to_vec1::<f32>()?, vec![5., 5.]);
+    Ok(())
+}
+
+#[test]
+fn test_global_pools() -> Result<()> {
+    for (op_type, xs, expected_dims, expected) in [
+        (
+            "GlobalAveragePool",
+            Tensor::arange(0f32, 12., &Device::Cpu)?.reshape((1, 3, 4))?,
+            vec![1, 3, 1],
+            vec![1.5f32, 5.5, 9.5],
+        ),
+        (
+            "GlobalMaxPool",
+            Tensor::arange(0f32, 12., &Device::Cpu)?.reshape((1, 3, 4))?,
+            vec![1, 3, 1],
+            vec![3., 7., 11.],
+        ),
+        (
+            "GlobalAveragePool",
+            Tensor::arange(0f32, 16., &Device::Cpu)?.reshape((1, 2, 2, 2, 2))?,
+            vec![1, 2, 1, 1, 1],
+            vec![3.5, 11.5],
+        ),
+        (
+            "GlobalMaxPool",
+            Tensor::arange(0f32, 16., &Device::Cpu)?.reshape((1, 2, 2, 2, 2))?,
+            vec![1, 2, 1, 1, 1],
+            vec![7., 15.],
+        ),
+    ] {
+        let model = create_single_node_model(create_node(op_type, &[INPUT_X], vec![]));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs);
+        let z = eval_single_output(&model, inputs)?;
+        assert_eq!(z.dims(), expected_dims.as_slice(), "{op_type}");
+        assert_eq!(z.flatten_all()?.to_vec1::<f32>()?, expected, "{op_type}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_lp_pool() -> Result<()> {
+    let model = create_single_node_model(create_node(
+        "LpPool",
+        &[INPUT_X],
+        vec![
+            ints_attr("kernel_shape", &[2, 2]),
+            ints_attr("strides", &[2, 2]),
+            int_attr("p", 2),
+        ],
+    ));
+    let xs = Tensor::arange(0f32, 16f32, &Device::Cpu)?.reshape((1, 1, 4, 4))?;
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs.clone());
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.dims(), &[1, 1, 2, 2]);
+
+    let xs = xs.flatten_all()?.to_vec1::<f32>()?;
+    let z = z.flatten_all()?.to_vec1::<f32>()?;
+    for i in 0..2 {
+        for j in 0..2 {
+            let mut square_sum = 0f32;
+            for di in 0..2 {
+                for dj in 0..2 {
+                    let v = xs[(2 * i + di) * 4 + 2 * j + dj];
+                    square_sum += v * v
+                }
+            }
+            assert!((z[i * 2 + j] - square_sum.sqrt()).abs() < 1e-4, "{z:?}")
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_arg_max_select_last_index() -> Result<()> {
+    let xs = Tensor::new(&[1f32, 3., 3., 2.], &Device::Cpu)?;
+    for (select_last_index, expected) in [(0, 1i64), (1, 2)] {
+        let model = create_single_node_model(create_node(
+            "ArgMax",
+            &[INPUT_X],
+            vec![
+                int_attr("axis", 0),
+                int_attr("keepdims", 1),
+                int_attr("select_last_index", select_last_index),
+            ],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        let z = eval_single_output(&model, inputs)?;
+        assert_eq!(z.to_vec1::<i64>()?, vec![expected]);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_label_encoder() -> Result<()> {
+    let model = create_single_node_model(create_node(
+        "LabelEncoder",
+        &[INPUT_X],
+        vec![
+            ints_attr("keys_int64s", &[1, 3, 5]),
+            ints_attr("values_int64s", &[10, 30, 50]),
+            int_attr("default_int64", -7),
+        ],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(
+        INPUT_X.to_string(),
+        Tensor::new(&[[1i64, 2, 3], [5, 4, 1]], &Device::Cpu)?,
+    );
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(
+        z.to_vec2::<i64>()?,
+        vec![vec![10, -7, 30], vec![50, -7, 10]]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_cast_map() -> Result<()> {
+    use candle_onnx::MapKey;
+    let map: std::collections::BTreeMap<_, _> = [(3, 0.5f32), (0, 2.), (1, -1.)]
+        .into_iter()
+        .map(|(k, v)| Ok((MapKey::Int(k), Tensor::new(v, &Device::Cpu)?.into())))
+        .collect::<Result<_>>()?;
+    let cast_map = |attrs: Vec<AttributeProto>| -> Result<Tensor> {
+        let model = create_single_node_model(create_node("CastMap", &[INPUT_X], attrs));
+        let mut inputs = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), Value::Map(map.clone()));
+        candle_onnx::simple_eval(&model, inputs)?
+            .remove(OUTPUT_Z)
+            .unwrap()
+            .into_tensor()
+    };
+    // The dense form lists the values in key order.
+    let z = cast_map(vec![])?;
+    assert_eq!(z.to_vec2::<f32>()?, [[2., -1., 0.5]]);
+    // The sparse form is indexed by key, missing keys get 0.
+    let z = cast_map(vec![
+        str_attr("cast_to", "TO_INT64"),
+        str_attr("map_form", "SPARSE"),
+        int_attr("max_map", 5),
+    ])?;
+    assert_eq!(z.to_vec2::<i64>()?, [[2, -1, 0, 0, 0]]);
+    let err = cast_map(vec![str_attr("map_form", "SPARSE"), int_attr("max_map", 3)]);
+    assert!(err.is_err());
+    Ok(())
+}
+
+#[test]
+fn test_normalizer_l2() -> Result<()> {
+    let model = create_single_node_model(create_node(
+        "Normalizer",
+        &[INPUT_X],
+        vec![str_attr("norm", "L2")],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(
+        INPUT_X.to_string(),
+        Tensor::new(&[[3f32, 0., 4.], [1., 2., 2.]], &Device::Cpu)?,
+    );
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(
+        z.to_vec2::<f32>()?,
+        vec![vec![0.6, 0., 0.8], vec![1. / 3., 2. / 3., 2. / 3.]]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_binarizer() -> Result<()> {
+    let model = create_single_node_model(create_node(
+        "Binarizer",
+        &[INPUT_X],
+        vec![float_attr("threshold", 1.5)],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(
+        INPUT_X.to_string(),
+        Tensor::new(&[[3f32, 0., 1.5], [1., 2., -2.]], &Device::Cpu)?,
+    );
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(
+        z.to_vec2::<f32>()?,
+        vec![vec![1., 0., 0.], vec![0., 1., 0.]]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_imputer_nan() -> Result<()> {
+    // Impute the NaNs using the per-column means of the non-NaN values.
+    let model = create_single_node_model(create_node(
+        "Imputer",
+        &[INPUT_X],
+        vec![
+            floats_attr("imputed_value_floats", &[2., 3.5, 6.]),
+            float_attr("replaced_value_float", f32::NAN),
+        ],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(
+        INPUT_X.to_string(),
+        Tensor::new(
+            &[[2f32, f32::NAN, 6.], [f32::NAN, 3.5, f32::NAN]],
+            &Device::Cpu,
+        )?,
+    );
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(
+        z.to_vec2::<f32>()?,
+        vec![vec![2., 3.5, 6.], vec![2., 3.5, 6.]]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_scatter_nd_max_reduction() -> Result<()> {
+    let model = create_single_node_model(create_node(
+        "ScatterND",
+        &[INPUT_X, INPUT_Y, "updates"],
+        vec![str_attr("reduction", "max")],
+    ));
+    // Both updates target the second cell, only the largest value is kept.
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(
+        INPUT_X.to_string(),
+        Tensor::new(&[1f32, 2., 3., 4.], &Device::Cpu)?,
+    );
+    inputs.insert(
+        INPUT_Y.to_string(),
+        Tensor::new(&[[1i64], [1], [3]], &Device::Cpu)?,
+    );
+    inputs.insert(
+        "updates".to_string(),
+        Tensor::new(&[5f32, 7., 0.], &Device::Cpu)?,
+    );
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.to_vec1::<f32>()?, vec![1., 7., 3., 4.]);
+    Ok(())
+}
+
+#[test]
+fn test_scatter_nd_rows() -> Result<()> {
+    let scatter = |reduction: &str, indices: Tensor, updates: Tensor| {
+        let model = create_single_node_model(create_node(
+            "ScatterND",
+            &[INPUT_X, INPUT_Y, "updates"],
+            vec![str_attr("reduction", reduction)],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        let xs = Tensor::arange(0f32, 12., &Device::Cpu)?.reshape((2, 3, 2))?;
+        inputs.insert(INPUT_X.to_string(), xs);
+        inputs.insert(INPUT_Y.to_string(), indices);
+        inputs.insert("updates".to_string(), updates);
+        eval_single_output(&model, inputs)?
+            .flatten_all()?
+            .to_vec1::<f32>()
+    };
+    // Each index tuple selects a row of two elements.
+    let indices = Tensor::new(&[[1i64, -1], [0, 1]], &Device::Cpu)?;
+    let updates = Tensor::new(&[[20f32, 21.], [30., 31.]], &Device::Cpu)?;
+    let z = scatter("none", indices.clone(), updates.clone())?;
+    assert_eq!(z, [0., 1., 30., 31., 4., 5., 6., 7., 8., 9., 20., 21.]);
+    let z = scatter("add", indices, updates)?;
+    assert_eq!(z, [0., 1., 32., 34., 4., 5., 6., 7., 8., 9., 30., 32.]);
+
+    // Duplicate indices accumulate with the add reduction, the last update wins without one.
+    let indices = Tensor::new(&[[1i64, 0], [1, 0]], &Device::Cpu)?;
+    let updates = Tensor::new(&[[1f32, 1.], [2., 2.]], &Device::Cpu)?;
+    let z = scatter("add", indices.clone(), updates.clone())?;
+    assert_eq!(z, [0., 1., 2., 3., 4., 5., 9., 10., 8., 9., 10., 11.]);
+    let z = scatter("none", indices, updates)?;
+    assert_eq!(z, [0., 1., 2., 3., 4., 5., 2., 2., 8., 9., 10., 11.]);
+
+    // Data without any row is returned as is.
+    let model = create_single_node_model(create_node(
+        "ScatterND",
+        &[INPUT_X, INPUT_Y, "updates"],
+        vec![],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    let dev = &Device::Cpu;
+    inputs.insert(
+        INPUT_X.to_string(),
+        Tensor::zeros((0, 2), candle::DType::F32, dev)?,
+    );
+    inputs.insert(
+        INPUT_Y.to_string(),
+        Tensor::zeros((0, 1), candle::DType::I64, dev)?,
+    );
+    inputs.insert(
+        "updates".to_string(),
+        Tensor::zeros((0, 2), candle::DType::F32, dev)?,
+    );
+    assert_eq!(eval_single_output(&model, inputs)?.dims(), &[0, 2]);
+    Ok(())
+}
+
+#[test]
+fn test_scatter_elements_add_reduction() -> Result<()> {
+    let scatter = |indices: Tensor, updates: Tensor| {
+        let model = create_single_node_model(create_node(
+            "ScatterElements",
+            &[INPUT_X, INPUT_Y, "updates"],
+            vec![str_attr("reduction", "add"), int_attr("axis", 1)],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(
+            INPUT_X.to_string(),
+            Tensor::new(&[[1f32, 2., 3.], [4., 5., 6.]], &Device::Cpu)?,
+        );
+        inputs.insert(INPUT_Y.to_string(), indices);
+        inputs.insert("updates".to_string(), updates);
+        eval_single_output(&model, inputs)?.to_vec2::<f32>()
+    };
+    // Indices spanning all the rows of data, with duplicates along the axis.
+    let z = scatter(
+        Tensor::new(&[[0i64, 0], [2, -3]], &Device::Cpu)?,
+        Tensor::new(&[[10f32, 20.], [30., 40.]], &Device::Cpu)?,
+    )?;
+    assert_eq!(z, [[31., 2., 3.], [44., 5., 36.]]);
+    // Indices covering only the first row.
+    let z = scatter(
+        Tensor::new(&[[1i64, 1]], &Device::Cpu)?,
+        Tensor::new(&[[10f32, 20.]], &Device::Cpu)?,
+    )?;
+    assert_eq!(z, [[1., 32., 3.], [4., 5., 6.]]);
+    Ok(())
+}
+
+#[test]
+fn test_scatter_elements_min_reduction() -> Result<()> {
+    let model = create_single_node_model(create_node(
+        "ScatterElements",
+        &[INPUT_X, INPUT_Y, "updates"],
+        vec![str_attr("reduction", "min"), int_attr("axis", 1)],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(
+        INPUT_X.to_string(),
+        Tensor::new(&[[1f32, 2., 3.], [4., 5., 6.]], &Device::Cpu)?,
+    );
+    inputs.insert(
+        INPUT_Y.to_string(),
+        Tensor::new(&[[0i64, 0], [2, -1]], &Device::Cpu)?,
+    );
+    inputs.insert(
+        "updates".to_string(),
+        Tensor::new(&[[0f32, -1.], [7., 1.]], &Device::Cpu)?,
+    );
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(
+        z.to_vec2::<f32>()?,
+        vec![vec![-1., 2., 3.], vec![4., 5., 1.]]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_resize_cubic_asymmetric() -> Result<()> {
+    let model = create_single_node_model(create_node(
+        "Resize",
+        &[INPUT_X, "", INPUT_Y],
+        vec![
+            str_attr("mode", "cubic"),
+            str_attr("coordinate_transformation_mode", "asymmetric"),
+        ],
+    ));
+    let xs = Tensor::arange(1f32, 17f32, &Device::Cpu)?.reshape((1, 1, 4, 4))?;
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs);
+    inputs.insert(
+        INPUT_Y.to_string(),
+        Tensor::new(&[1f32, 1., 2., 2.], &Device::Cpu)?,
+    );
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.dims(), &[1, 1, 8, 8]);
+    // The input is x[r, c] = 4r + c + 1 and the cubic weights sum to one, so the output is
+    // 4 f(r) + f(c) + 1 where f is the 1d upsampling of [0, 1, 2, 3], matching the onnx
+    // reference for resize_upsample_scales_cubic_asymmetric.
+    let f = [0., 0.40625, 1., 1.5, 2., 2.59375, 3., 3.09375];
+    let z = z.flatten_all()?.to_vec1::<f32>()?;
+    for i in 0..8 {
+        for j in 0..8 {
+            let expected = 4. * f[i] + f[j] + 1.;
+            assert!((z[i * 8 + j] - expected).abs() < 1e-4, "{i} {j} {z:?}")
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_resize_coordinate_transformation_modes() -> Result<()> {
+    let resize = |xs: Tensor, roi: &[f32], scales: &[f32], attrs: Vec<AttributeProto>| {
+        let model = create_single_node_model(create_node(
+            "Resize",
+            &[INPUT_X, "roi", "scales"],
+            [vec![str_attr("mode", "linear")], attrs].concat(),
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs);
+        inputs.insert("roi".to_string(), Tensor::new(roi, &Device::Cpu)?);
+        inputs.insert("scales".to_string(), Tensor::new(scales, &Device::Cpu)?);
+        eval_single_output(&model, inputs)
+    };
+    let mode = |mode: &str| vec![str_attr("coordinate_transformation_mode", mode)];
+
+    // Reference values from torch.nn.functional.interpolate(xs, scale_factor=2, mode="bilinear").
+    let xs = Tensor::new(&[[[[1f32, 2.], [3., 4.]]]], &Device::Cpu)?;
+    let expected = [
+        [1f32, 1.25, 1.75, 2.],
+        [1.5, 1.75, 2.25, 2.5],
+        [2.5, 2.75, 3.25, 3.5],
+        [3., 3.25, 3.75, 4.],
+    ];
+    for m in ["pytorch_half_pixel", "half_pixel"] {
+        let z = resize(xs.clone(), &[], &[1., 1., 2., 2.], mode(m))?;
+        let z = z.squeeze(0)?.squeeze(0)?.to_vec2::<f32>()?;
+        for (z, e) in z.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((z - e).abs() < 1e-5, "{m} {z:?}")
+        }
+    }
+
+    // The modes only differ when a single position is output.
+    let xs = Tensor::new(&[1f32, 2., 3., 4.], &Device::Cpu)?;
+    let z = resize(xs.clone(), &[], &[0.25], mode("pytorch_half_pixel"))?;
+    assert_eq!(z.to_vec1::<f32>()?, [1.]);
+    let z = resize(xs.clone(), &[], &[0.25], mode("half_pixel"))?;
+    assert_eq!(z.to_vec1::<f32>()?, [2.5]);
+
+    // The [0.5, 1.5] roi starts in the middle of the input, the positions past its end get the
+    // extrapolation value.
+    let attrs = [
+        mode("tf_crop_and_resize"),
+        vec![float_attr("extrapolation_value", 10.)],
+    ]
+    .concat();
+    let z = resize(xs, &[0.5, 1.5], &[1.], attrs)?;
+    assert_eq!(z.to_vec1::<f32>()?, [2.5, 3.5, 10., 10.]);
+    Ok(())
+}
+
+#[test]
+fn test_resize_antialias() -> Result<()> {
+    let resize = |mode: &str, antialias: i64| {
+        let model = create_single_node_model(create_node(
+            "Resize",
+            &[INPUT_X, "", INPUT_Y],
+            vec![str_attr("mode", mode), int_attr("antialias", antialias)],
+        ));
+        let xs = Tensor::arange(1f32, 17., &Device::Cpu)?.reshape((1, 1, 4, 4))?;
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs);
+        inputs.insert(
+            INPUT_Y.to_string(),
+            Tensor::new(&[1f32, 1., 0.6, 0.6], &Device::Cpu)?,
+        );
+        eval_single_output(&model, inputs)?
+            .flatten_all()?
+            .to_vec1::<f32>()
+    };
+    let assert_close = |z: Vec<f32>, expected: [f32; 4]| {
+        for (z, e) in z.iter().zip(expected.iter()) {
+            assert!((z - e).abs() < 1e-4, "{z:?} {expected:?}")
+        }
+    };
+    // Reference values from the onnx backend tests resize_downsample_scales_linear_antialias and
+    // resize_downsample_scales_cubic_antialias.
+    assert_close(resize("linear", 1)?, [2.875, 4.5, 9.375, 11.]);
+    assert_close(
+        resize("cubic", 1)?,
+        [2.5180721, 4.2858863, 9.589329, 11.357142],
+    );
+    // Without antialias, only the two closest inputs contribute.
+    assert_close(resize("linear", 0)?, [2.6666665, 4.333_333, 9.333333, 11.]);
+    Ok(())
+}
+
+#[test]
+fn test_resize_sizes_nearest_modes() -> Result<()> {
+    let resize = |xs: &[f32], size: i64, nearest_mode: &str| {
+        let model = create_single_node_model(create_node(
+            "Resize",
+            &[INPUT_X, "", "", INPUT_Y],
+            vec![str_attr("nearest_mode", nearest_mode)],
+        ));
+        let xs = Tensor::new(xs, &Device::Cpu)?.reshape((1, 1, 1, ()))?;
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs);
+        inputs.insert(
+            INPUT_Y.to_string(),
+            Tensor::new(&[1i64, 1, 1, size], &Device::Cpu)?,
+        );
+        eval_single_output(&model, inputs)?
+            .flatten_all()?
+            .to_vec1::<f32>()
+    };
+    // Resizing 4 to 7 gives a scale of 1.75, the output positions map to the half pixel
+    // coordinates -0.21, 0.36, 0.93, 1.5, 2.07, 2.64 and 3.21 in the input.
+    let xs = [10f32, 20., 30., 40.];
+    let z = resize(&xs, 7, "round_prefer_floor")?;
+    assert_eq!(z, [10., 10., 20., 20., 30., 40., 40.]);
+    let z = resize(&xs, 7, "round_prefer_ceil")?;
+    assert_eq!(z, [10., 10., 20., 30., 30., 40., 40.]);
+    let z = resize(&xs, 7, "floor")?;
+    assert_eq!(z, [10., 10., 10., 20., 30., 30., 40.]);
+    let z = resize(&xs, 7, "ceil")?;
+    assert_eq!(z, [10., 20., 20., 30., 40., 40., 40.]);
+    // Downsampling 5 to 3 maps to 0.33, 2 and 3.67.
+    let xs = [1f32, 2., 3., 4., 5.];
+    assert_eq!(resize(&xs, 3, "round_prefer_floor")?, [1., 3., 5.]);
+    assert_eq!(resize(&xs, 3, "floor")?, [1., 3., 4.]);
+    assert_eq!(resize(&xs, 3, "ceil")?, [2., 3., 5.]);
+    // There are no values to interpolate from on an empty axis.
+    assert!(resize(&[], 2, "floor").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_pad_modes() -> Result<()> {
+    let pad = |mode: &str| {
+        let model = create_single_node_model(create_node(
+            "Pad",
+            &[INPUT_X, INPUT_Y],
+            vec![str_attr("mode", mode)],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(
+            INPUT_X.to_string(),
+            Tensor::new(&[1f32, 2., 3., 4.], &Device::Cpu)?,
+        );
+        inputs.insert(INPUT_Y.to_string(), Tensor::new(&[2i64, 2], &Device::Cpu)?);
+        eval_single_output(&model, inputs)?.to_vec1::<f32>()
+    };
+    assert_eq!(pad("wrap")?, [3., 4., 1., 2., 3., 4., 1., 2.]);
+    assert_eq!(pad("reflect")?, [3., 2., 1., 2., 3., 4., 3., 2.]);
+    assert_eq!(pad("edge")?, [1., 1., 1., 2., 3., 4., 4., 4.]);
+    assert_eq!(pad("constant")?, [0., 0., 1., 2., 3., 4., 0., 0.]);
+    Ok(())
+}
+
+#[test]
+fn test_pad_negative() -> Result<()> {
+    let model = create_single_node_model(create_node("Pad", &[INPUT_X, INPUT_Y], vec![]));
+    let xs = Tensor::arange(0f32, 16f32, &Device::Cpu)?.reshape((1, 1, 4, 4))?;
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs);
+    // Pad one row at the top and crop one at the bottom, crop one column on the left and pad
+    // two on the right.
+    inputs.insert(
+        INPUT_Y.to_string(),
+        Tensor::new(&[0i64, 0, 1, -1, 0, 0, -1, 2], &Device::Cpu)?,
+    );
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.dims(), &[1, 1, 4, 5]);
+    assert_eq!(
+        z.flatten_to(1)?.squeeze(0)?.to_vec2::<f32>()?,
+        vec![
+            vec![0., 0., 0., 0., 0.],
+            vec![1., 2., 3., 0., 0.],
+            vec![5., 6., 7., 0., 0.],
+            vec![9., 10., 11., 0., 0.],
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_slice_legacy_attributes() -> Result<()> {
+    // Before opset 10, the bounds are stored as attributes rather than inputs.
+    let model = create_single_node_model(create_node(
+        "Slice",
+        &[INPUT_X],
+        vec![
+            ints_attr("starts", &[1, 0]),
+            ints_attr("ends", &[2, -1]),
+            ints_attr("axes", &[0, 1]),
+        ],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(
+        INPUT_X.to_string(),
+        Tensor::new(&[[1f32, 2., 3., 4.], [5., 6., 7., 8.]], &Device::Cpu)?,
+    );
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.to_vec2::<f32>()?, vec![vec![5., 6., 7.]]);
+    Ok(())
+}
+
+#[test]
+fn test_slice_clamping() -> Result<()> {
+    let xs = Tensor::arange(0f32, 8., &Device::Cpu)?.reshape((2, 4))?;
+    let slice = |starts: &[i64], ends: &[i64], steps: &[i64]| {
+        let model = create_single_node_model(create_node(
+            "Slice",
+            &[INPUT_X, "starts", "ends", "axes", "steps"],
+            vec![],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        inputs.insert("starts".to_string(), Tensor::new(starts, &Device::Cpu)?);
+        inputs.insert("ends".to_string(), Tensor::new(ends, &Device::Cpu)?);
+        inputs.insert("axes".to_string(), Tensor::new(&[1i64], &Device::Cpu)?);
+        inputs.insert("steps".to_string(), Tensor::new(steps, &Device::Cpu)?);
+        eval_single_output(&model, inputs)
+    };
+    // A start beyond the dim gives an empty slice along the axis.
+    let z = slice(&[10], &[20], &[1])?;
+    assert_eq!(z.dims(), &[2, 0]);
+    let z = slice(&[10], &[i64::MAX], &[2])?;
+    assert_eq!(z.dims(), &[2, 0]);
+    // Very negative bounds are clamped to 0.
+    let z = slice(&[-100], &[2], &[1])?;
+    assert_eq!(z.to_vec2::<f32>()?, [[0., 1.], [4., 5.]]);
+    let z = slice(&[0], &[i64::MIN], &[1])?;
+    assert_eq!(z.dims(), &[2, 0]);
+    let z = slice(&[-3], &[100], &[2])?;
+    assert_eq!(z.to_vec2::<f32>()?, [[1., 3.], [5., 7.]]);
+
+    // Negative steps go down from start to end + 1, the bounds are clamped to [-1, dim - 1].
+    let z = slice(&[-1], &[i64::MIN], &[-1])?;
+    assert_eq!(z.to_vec2::<f32>()?, [[3., 2., 1., 0.], [7., 6., 5., 4.]]);
+    let z = slice(&[100], &[0], &[-2])?;
+    assert_eq!(z.to_vec2::<f32>()?, [[3., 1.], [7., 5.]]);
+    let z = slice(&[-100], &[-100], &[-1])?;
+    assert_eq!(z.dims(), &[2, 0]);
+    assert!(slice(&[0], &[4], &[0]).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_optional() -> Result<()> {
+    let graph = |optional_inputs: &[&str], op_type: &str| {
+        let optional = NodeProto {
+            op_type: "Optional".to_string(),
+            name: "optional".to_string(),
+            input: optional_inputs.iter().map(|s| s.to_string()).collect(),
+            output: vec!["opt".to_string()],
+            ..NodeProto::default()
+        };
+        let mut node = create_node(op_type, &["opt"], vec![]);
+        node.name = "unwrap".to_string();
+        create_model_proto_with_graph(Some(GraphProto {
+            node: vec![optional, node],
+            output: vec![ValueInfoProto {
+                name: OUTPUT_Z.to_string(),
+                ..ValueInfoProto::default()
+            }],
+            ..GraphProto::default()
+        }))
+    };
+    let xs = Tensor::new(&[1f32, 2., 3.], &Device::Cpu)?;
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs.clone());
+
+    // A present optional wraps the input tensor.
+    let z = eval_single_output(&graph(&[INPUT_X], "OptionalHasElement"), inputs.clone())?;
+    assert_eq!(z.to_vec0::<u8>()?, 1);
+    let z = eval_single_output(&graph(&[INPUT_X], "OptionalGetElement"), inputs.clone())?;
+    assert_eq!(z.to_vec1::<f32>()?, vec![1., 2., 3.]);
+
+    // An absent optional has no element and cannot be unwrapped.
+    let z = eval_single_output(&graph(&[], "OptionalHasElement"), inputs.clone())?;
+    assert_eq!(z.to_vec0::<u8>()?, 0);
+    let model = graph(&[], "OptionalGetElement");
+    assert!(candle_onnx::simple_eval(&model, to_values(inputs)).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_value_round_trip() -> Result<()> {
+    // A graph without nodes, its output is its input.
+    let x = ValueInfoProto {
+        name: INPUT_X.to_string(),
+        ..ValueInfoProto::default()
+    };
+    let model = create_model_proto_with_graph(Some(GraphProto {
+        input: vec![x.clone()],
+        output: vec![x],
+        ..GraphProto::default()
+    }));
+    let xs = Tensor::new(&[[1i64, 2], [3, 4]], &Device::Cpu)?;
+
+    // Tensor values still round-trip through tensor-only graphs.
+    let mut inputs: HashMap<String, Value> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs.clone().into());
+    let mut outputs = candle_onnx::simple_eval(&model, inputs)?;
+    let z = outputs.remove(INPUT_X).unwrap().into_tensor()?;
+    assert_eq!(z.to_vec2::<i64>()?, vec![vec![1, 2], vec![3, 4]]);
+
+    // Sequences are passed through as is.
+    let seq = Value::Sequence(vec![xs.clone().into(), xs.sum_all()?.into()]);
+    let mut inputs: HashMap<String, Value> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), seq);
+    let mut outputs = candle_onnx::simple_eval(&model, inputs)?;
+    let seq = outputs.remove(INPUT_X).unwrap().into_sequence()?;
+    assert_eq!(seq.len(), 2);
+    assert_eq!(
+        seq[0].as_tensor()?.to_vec2::<i64>()?,
+        vec![vec![1, 2], vec![3, 4]]
+    );
+    assert_eq!(seq[1].as_tensor()?.to_vec0::<i64>()?, 10);
+    assert!(seq[0].as_map().is_err());
+    Ok(())
+}
+
+#[test]
+fn test_sequence_ops() -> Result<()> {
+    let output = |name: &str| ValueInfoProto {
+        name: name.to_string(),
+        ..ValueInfoProto::default()
+    };
+    let model = create_model_proto_with_graph(Some(GraphProto {
+        node: vec![
+            create_graph_node("SequenceConstruct", &["a", "b", "c"], &["seq"], vec![]),
+            create_graph_node(
+                "SequenceInsert",
+                &["seq", "d", "front"],
+                &["inserted"],
+                vec![],
+            ),
+            create_graph_node("SequenceAt", &["inserted", "last"], &["at"], vec![]),
+            create_graph_node("SequenceAt", &["inserted", "first"], &["at_first"], vec![]),
+            create_graph_node("SequenceLength", &["inserted"], &["len"], vec![]),
+        ],
+        output: vec![output("at"), output("at_first"), output("len")],
+        ..GraphProto::default()
+    }));
+    let dev = &Device::Cpu;
+    let mut inputs: HashMap<String, Value> = HashMap::new();
+    for (name, v) in [("a", 1f32), ("b", 2.), ("c", 3.), ("d", 0.)] {
+        inputs.insert(name.to_string(), Tensor::new(&[v], dev)?.into());
+    }
+    inputs.insert("front".to_string(), Tensor::new(0i64, dev)?.into());
+    inputs.insert("last".to_string(), Tensor::new(-1i64, dev)?.into());
+    inputs.insert("first".to_string(), Tensor::new(0i64, dev)?.into());
+    let mut outputs = candle_onnx::simple_eval(&model, inputs)?;
+    let mut get = |name: &str| outputs.remove(name).unwrap().into_tensor();
+    assert_eq!(get("at")?.to_vec1::<f32>()?, vec![3.]);
+    assert_eq!(get("at_first")?.to_vec1::<f32>()?, vec![0.]);
+    assert_eq!(get("len")?.to_vec0::<i64>()?, 4);
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Split
+#[test]
+fn test_split() -> Result<()> {
+    let dev = &Device::Cpu;
+    let model = |inputs: &[&str], attrs: Vec<AttributeProto>| {
+        create_model_proto_with_graph(Some(GraphProto {
+            node: vec![NodeProto {
+                op_type: "Split".to_string(),
+                input: inputs.iter().map(|s| s.to_string()).collect(),
+                output: vec!["a".to_string(), "b".to_string()],
+                attribute: attrs,
+                ..NodeProto::default()
+            }],
+            output: ["a", "b"]
+                .iter()
+                .map(|name| ValueInfoProto {
+                    name: name.to_string(),
+                    ..ValueInfoProto::default()
+                })
+                .collect(),
+            ..GraphProto::default()
+        }))
+    };
+    let xs = Tensor::arange(0f32, 10., dev)?.reshape((2, 5))?;
+    let eval = |model: &ModelProto, split: Option<&[i64]>| -> Result<(Tensor, Tensor)> {
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        if let Some(split) = split {
+            inputs.insert("split".to_string(), Tensor::new(split, dev)?);
+        }
+        let mut outputs = candle_onnx::simple_eval(model, to_values(inputs))?;
+        let mut get = |name: &str| outputs.remove(name).unwrap().into_tensor();
+        Ok((get("a")?, get("b")?))
+    };
+
+    // A size 5 axis split in two gives chunks of 3 and 2, the axis can be negative.
+    for attrs in [
+        vec![int_attr("axis", -1)],
+        vec![int_attr("axis", 1), int_attr("num_outputs", 2)],
+    ] {
+        let (a, b) = eval(&model(&[INPUT_X], attrs), None)?;
+        assert_eq!(a.to_vec2::<f32>()?, [[0., 1., 2.], [5., 6., 7.]]);
+        assert_eq!(b.to_vec2::<f32>()?, [[3., 4.], [8., 9.]]);
+    }
+
+    // Explicit sizes, either as an input or as an attribute on older opsets.
+    let (a, b) = eval(
+        &model(&[INPUT_X, "split"], vec![int_attr("axis", 1)]),
+        Some(&[1, 4]),
+    )?;
+    assert_eq!(a.dims(), &[2, 1]);
+    assert_eq!(b.dims(), &[2, 4]);
+    let attrs = vec![int_attr("axis", 1), ints_attr("split", &[4, 1])];
+    let (a, b) = eval(&model(&[INPUT_X], attrs), None)?;
+    assert_eq!(a.dims(), &[2, 4]);
+    assert_eq!(b.dims(), &[2, 1]);
+    let attrs = vec![int_attr("axis", 1), ints_attr("split", &[2, 2])];
+    assert!(eval(&model(&[INPUT_X], attrs), None).is_err());
+    // Negative sizes are rejected even when they sum to the axis size.
+    let attrs = vec![int_attr("axis", 1), ints_attr("split", &[-1, 6])];
+    let err = eval(&model(&[INPUT_X], attrs), None).unwrap_err();
+    assert!(err.to_string().contains("negative"), "{err}");
+    Ok(())
+}
+
+#[test]
+fn test_split_to_sequence_round_trip() -> Result<()> {
+    let model = |split_attrs: Vec<AttributeProto>, concat_attrs: Vec<AttributeProto>| {
+        create_model_proto_with_graph(Some(GraphProto {
+            node: vec![
+                create_graph_node("SplitToSequence", &[INPUT_X], &["seq"], split_attrs),
+                create_graph_node("ConcatFromSequence", &["seq"], &[OUTPUT_Z], concat_attrs),
+            ],
+            output: vec![ValueInfoProto {
+                name: OUTPUT_Z.to_string(),
+                ..ValueInfoProto::default()
+            }],
+            ..GraphProto::default()
+        }))
+    };
+    let xs = Tensor::arange(0f32, 6., &Device::Cpu)?.reshape((2, 3))?;
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs.clone());
+
+    // Splitting in chunks of size 1 then concatenating along the same axis is the identity.
+    let m = model(vec![int_attr("axis", 1)], vec![int_attr("axis", 1)]);
+    let z = eval_single_output(&m, inputs.clone())?;
+    assert_eq!(z.to_vec2::<f32>()?, xs.to_vec2::<f32>()?);
+
+    // Dropping the split axis and stacking with new_axis restores the original tensor too.
+    let m = model(
+        vec![int_attr("axis", 1), int_attr("keepdims", 0)],
+        vec![int_attr("axis", -1), int_attr("new_axis", 1)],
+    );
+    let z = eval_single_output(&m, inputs.clone())?;
+    assert_eq!(z.to_vec2::<f32>()?, xs.to_vec2::<f32>()?);
+
+    // Stacking the rows on a new leading axis rather than concatenating them.
+    let m = model(
+        vec![int_attr("keepdims", 0)],
+        vec![int_attr("axis", 0), int_attr("new_axis", 1)],
+    );
+    let z = eval_single_output(&m, inputs.clone())?;
+    assert_eq!(z.dims(), &[2, 3]);
+    let m = model(vec![], vec![int_attr("axis", 1), int_attr("new_axis", 1)]);
+    let z = eval_single_output(&m, inputs.clone())?;
+    assert_eq!(z.dims(), &[1, 2, 3]);
+
+    // An explicit split with an uneven last chunk.
+    let mut m = model(vec![int_attr("axis", 1)], vec![int_attr("axis", 1)]);
+    let graph = m.graph.as_mut().unwrap();
+    graph.node[0].input.push("split".to_string());
+    let mut inputs = inputs;
+    inputs.insert("split".to_string(), Tensor::new(2i64, &Device::Cpu)?);
+    let z = eval_single_output(&m, inputs.clone())?;
+    assert_eq!(z.to_vec2::<f32>()?, xs.to_vec2::<f32>()?);
+
+    // Negative sizes are rejected even when they sum to the axis size.
+    inputs.insert("split".to_string(), Tensor::new(&[-1i64, 4], &Device::Cpu)?);
+    let err = eval_single_output(&m, inputs).unwrap_err();
+    assert!(err.to_string().contains("negative"), "{err}");
+    Ok(())
+}
+
+#[test]
+fn test_bind_attributes() -> Result<()> {
+    // The keepdims attribute of this function body node refers to the keep attribute of the caller.
+    let body = create_node(
+        "ArgMax",
+        &[INPUT_X],
+        vec![AttributeProto {
+            name: "keepdims".to_string(),
+            ref_attr_name: "keep".to_string(),
+            r#type: AttributeType::Int.into(),
+            ..AttributeProto::default()
+        }],
+    );
+    let xs = Tensor::new(&[[1f32, 5., 2.], [6., 3., 4.]], &Device::Cpu)?;
+    let eval = |node: NodeProto| {
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        eval_single_output(&create_single_node_model(node), inputs)
+    };
+    // References cannot be evaluated before being resolved.
+    let err = eval(body.clone()).unwrap_err();
+    assert!(err.to_string().contains("keep"), "{err}");
+
+    let mut bindings = HashMap::new();
+    bindings.insert("keep".to_string(), int_attr("keep", 0));
+    let z = eval(candle_onnx::bind_attributes(&body, &bindings))?;
+    assert_eq!(z.to_vec1::<i64>()?, [1, 0, 1]);
+
+    // A reference with no binding is dropped so that the default keepdims applies.
+    let z = eval(candle_onnx::bind_attributes(&body, &HashMap::new()))?;
+    assert_eq!(z.to_vec2::<i64>()?, [[1, 0, 1]]);
+    Ok(())
+}
+
+#[test]
+fn test_local_function() -> Result<()> {
+    let body = NodeProto {
+        op_type: "Add".to_string(),
+        name: "add".to_string(),
+        input: vec!["a".to_string(), "b".to_string()],
+        output: vec!["sum".to_string()],
+        ..NodeProto::default()
+    };
+    let function = FunctionProto {
+        name: "AddTwo".to_string(),
+        domain: "custom".to_string(),
+        input: vec!["a".to_string(), "b".to_string()],
+        output: vec!["sum".to_string()],
+        node: vec![body],
+        ..FunctionProto::default()
+    };
+    let mut node = create_node("AddTwo", &[INPUT_X, INPUT_Y], vec![]);
+    node.domain = "custom".to_string();
+    let mut model = create_single_node_model(node);
+    model.functions.push(function);
+
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), Tensor::new(&[1f32, 2.], &Device::Cpu)?);
+    inputs.insert(INPUT_Y.to_string(), Tensor::new(&[3f32, 4.], &Device::Cpu)?);
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.to_vec1::<f32>()?, vec![4., 6.]);
+    Ok(())
+}
+
+#[test]
+fn test_per_axis_quantization() -> Result<()> {
+    let dev = &Device::Cpu;
+    // One scale and zero-point per row, i.e. along the output channels of a [3, 2] weight.
+    let xs = Tensor::new(&[[1u8, 2], [3, 4], [5, 6]], dev)?;
+    let scale = Tensor::new(&[2f32, 0.5, 4.], dev)?;
+    let zero_point = Tensor::new(&[1u8, 3, 0], dev)?;
+    let model = create_single_node_model(create_node(
+        "DequantizeLinear",
+        &[INPUT_X, "scale", "zero_point"],
+        vec![int_attr("axis", 0)],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs.clone());
+    inputs.insert("scale".to_string(), scale.clone());
+    inputs.insert("zero_point".to_string(), zero_point.clone());
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(
+        z.to_vec2::<f32>()?,
+        vec![vec![0., 2.], vec![0., 0.5], vec![20., 24.]]
+    );
+
+    let model = create_single_node_model(create_node(
+        "QuantizeLinear",
+        &[INPUT_X, "scale", "zero_point"],
+        vec![int_attr("axis", 0)],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), z);
+    inputs.insert("scale".to_string(), scale);
+    inputs.insert("zero_point".to_string(), zero_point);
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.to_vec2::<u8>()?, xs.to_vec2::<u8>()?);
+    Ok(())
+}
+
+#[test]
+fn test_block_quantization() -> Result<()> {
+    let dev = &Device::Cpu;
+    // Blocks of 2 along the last axis, the last block of each row is incomplete.
+    let xs = Tensor::new(&[[1u8, 2, 3, 4, 5], [6, 7, 8, 9, 10]], dev)?;
+    let scale = Tensor::new(&[[1f32, 0.5, 2.], [0.25, 4., 1.]], dev)?;
+    let zero_point = Tensor::new(&[[0u8, 1, 2], [2, 0, 1]], dev)?;
+    let attrs = || vec![int_attr("axis", -1), int_attr("block_size", 2)];
+
+    let model = create_single_node_model(create_node(
+        "DequantizeLinear",
+        &[INPUT_X, "scale", "zero_point"],
+        attrs(),
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs.clone());
+    inputs.insert("scale".to_string(), scale.clone());
+    inputs.insert("zero_point".to_string(), zero_point.clone());
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(
+        z.to_vec2::<f32>()?,
+        vec![vec![1., 2., 1., 1.5, 6.], vec![1., 1.25, 32., 36., 9.]]
+    );
+
+    // Quantizing the dequantized values gives back the original data.
+    let model = create_single_node_model(create_node(
+        "QuantizeLinear",
+        &[INPUT_X, "scale", "zero_point"],
+        attrs(),
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), z);
+    inputs.insert("scale".to_string(), scale);
+    inputs.insert("zero_point".to_string(), zero_point);
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.dtype(), candle::DType::U8);
+    assert_eq!(z.to_vec2::<u8>()?, xs.to_vec2::<u8>()?);
+    Ok(())
+}
+
+#[test]
+fn test_quantize_linear_rounding() -> Result<()> {
+    let dev = &Device::Cpu;
+    let model = create_single_node_model(create_node(
+        "QuantizeLinear",
+        &[INPUT_X, "scale", "zero_point"],
+        vec![],
+    ));
+    let eval = |xs: &[f32], zero_point: Tensor| {
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), Tensor::new(xs, dev)?);
+        inputs.insert("scale".to_string(), Tensor::new(1f32, dev)?);
+        inputs.insert("zero_point".to_string(), zero_point);
+        eval_single_output(&model, inputs)
+    };
+    // Ties round to even before the odd zero-point gets added.
+    let z = eval(&[0.5, 2.5, -0.5, 1.5], Tensor::new(1u8, dev)?)?;
+    assert_eq!(z.to_vec1::<u8>()?, [1, 3, 1, 3]);
+
+    // Int32 zero-points give I64 outputs saturated to the int32 range.
+    let z = eval(&[1e10, -1e10, 2.5], Tensor::new(1i64, dev)?)?;
+    assert_eq!(z.to_vec1::<i64>()?, [i32::MAX as i64, i32::MIN as i64, 3]);
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Range
+#[test]
+fn test_range() -> Result<()> {
+    let dev = &Device::Cpu;
+    let model =
+        create_single_node_model(create_node("Range", &["start", "limit", "delta"], vec![]));
+    let eval = |start: Tensor, limit: Tensor, delta: Tensor| -> Result<Tensor> {
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert("start".to_string(), start);
+        inputs.insert("limit".to_string(), limit);
+        inputs.insert("delta".to_string(), delta);
+        eval_single_output(&model, inputs)
+    };
+    let range = |start: i64, limit: i64, delta: i64| -> Result<Vec<i64>> {
+        let t = |v: i64| Tensor::new(v, dev);
+        eval(t(start)?, t(limit)?, t(delta)?)?.to_vec1::<i64>()
+    };
+    assert_eq!(range(0, 5, 2)?, [0, 2, 4]);
+    assert_eq!(range(0, 6, 2)?, [0, 2, 4]);
+    assert_eq!(range(10, 4, -3)?, [10, 7]);
+    assert_eq!(range(10, 3, -3)?, [10, 7, 4]);
+    assert_eq!(range(0, 5, -1)?, [] as [i64; 0]);
+    // Large integers where a float count would be off.
+    let big = 1i64 << 60;
+    assert_eq!(range(big, big + 3, 1)?, [big, big + 1, big + 2]);
+    assert!(range(0, 5, 0).is_err());
+
+    let z = eval(
+        Tensor::new(1f32, dev)?,
+        Tensor::new(2f32, dev)?,
+        Tensor::new(0.25f32, dev)?,
+    )?;
+    assert_eq!(z.dtype(), candle::DType::F32);
+    assert_eq!(z.to_vec1::<f32>()?, [1., 1.25, 1.5, 1.75]);
+    Ok(())
+}
+
+#[test]
+fn test_reduce_empty_axes() -> Result<()> {
+    let xs = Tensor::new(&[[1f32, 2., 3.], [4., 5., 6.]], &Device::Cpu)?;
+    let empty = Tensor::new(&[] as &[i64], &Device::Cpu)?;
+    let eval = |inputs: &[&str], noop: i64, with_empty_axes: bool| {
+        let attrs = vec![
+            int_attr("keepdims", 0),
+            int_attr("noop_with_empty_axes", noop),
+        ];
+        let model = create_single_node_model(create_node("ReduceSum", inputs, attrs));
+        let mut values: HashMap<String, Tensor> = HashMap::new();
+        values.insert(INPUT_X.to_string(), xs.clone());
+        if with_empty_axes {
+            values.insert("axes".to_string(), empty.clone());
+        }
+        eval_single_output(&model, values)
+    };
+
+    // The axes input is absent, all the axes are reduced.
+    let z = eval(&[INPUT_X], 0, false)?;
+    assert_eq!(z.to_vec0::<f32>()?, 21.);
+    let z = eval(&[INPUT_X, ""], 0, false)?;
+    assert_eq!(z.to_vec0::<f32>()?, 21.);
+
+    // The axes input is present but empty, all the axes are reduced too.
+    let z = eval(&[INPUT_X, "axes"], 0, true)?;
+    assert_eq!(z.to_vec0::<f32>()?, 21.);
+
+    // With noop_with_empty_axes, empty axes leave the input untouched.
+    let z = eval(&[INPUT_X, "axes"], 1, true)?;
+    assert_eq!(z.to_vec2::<f32>()?, xs.to_vec2::<f32>()?);
+    let z = eval(&[INPUT_X], 1, false)?;
+    assert_eq!(z.to_vec2::<f32>()?, xs.to_vec2::<f32>()?);
+
+    // Non-empty axes are reduced regardless of the flag.
+    let model = create_single_node_model(create_node(
+        "ReduceMax",
+        &[INPUT_X, "axes"],
+        vec![int_attr("noop_with_empty_axes", 1)],
+    ));
+    let mut values: HashMap<String, Tensor> = HashMap::new();
+    values.insert(INPUT_X.to_string(), xs.clone());
+    values.insert("axes".to_string(), Tensor::new(&[-1i64], &Device::Cpu)?);
+    let z = eval_single_output(&model, values)?;
+    assert_eq!(z.to_vec2::<f32>()?, vec![vec![3.], vec![6.]]);
+    Ok(())
+}
+
+#[test]
+fn test_reduce_integers() -> Result<()> {
+    // Values that do not round trip through f32 or f64.
+    let big = (1i64 << 60) + 1;
+    let xs = Tensor::new(&[[big, -3, 7], [2, big + 2, -big]], &Device::Cpu)?;
+    let eval = |op_type: &str, axis: i64| -> Result<Tensor> {
+        let attrs = vec![ints_attr("axes", &[axis]), int_attr("keepdims", 0)];
+        let model = create_single_node_model(create_node(op_type, &[INPUT_X], attrs));
+        let mut values: HashMap<String, Tensor> = HashMap::new();
+        values.insert(INPUT_X.to_string(), xs.clone());
+        eval_single_output(&model, values)
+    };
+    let z = eval("ReduceMax", 1)?;
+    assert_eq!(z.dtype(), candle::DType::I64);
+    assert_eq!(z.to_vec1::<i64>()?, [big, big + 2]);
+    assert_eq!(eval("ReduceMin", 0)?.to_vec1::<i64>()?, [2, -3, -big]);
+    assert_eq!(
+        eval("ReduceSum", 0)?.to_vec1::<i64>()?,
+        [big + 2, big - 1, 7 - big]
+    );
+    // The integer mean truncates towards zero.
+    assert_eq!(
+        eval("ReduceMean", 0)?.to_vec1::<i64>()?,
+        [(big + 2) / 2, (big - 1) / 2, (7 - big) / 2]
+    );
+
+    // The mean over a size 0 axis has no integer value.
+    let model = create_single_node_model(create_node(
+        "ReduceMean",
+        &[INPUT_X],
+        vec![ints_attr("axes", &[1])],
+    ));
+    let mut values: HashMap<String, Tensor> = HashMap::new();
+    values.insert(
+        INPUT_X.to_string(),
+        Tensor::zeros((2, 0), candle::DType::I64, &Device::Cpu)?,
+    );
+    let err = eval_single_output(&model, values).unwrap_err();
+    assert!(err.to_string().contains("no element"), "{err}");
+    Ok(())
+}
+
+#[test]
+fn test_gather_nd() -> Result<()> {
+    let data = Tensor::arange(0f32, 24., &Device::Cpu)?.reshape((2, 3, 4))?;
+    let eval = |indices: Tensor, batch_dims: i64| {
+        let model = create_single_node_model(create_node(
+            "GatherND",
+            &[INPUT_X, INPUT_Y],
+            vec![int_attr("batch_dims", batch_dims)],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), data.clone());
+        inputs.insert(INPUT_Y.to_string(), indices);
+        eval_single_output(&model, inputs)
+    };
+
+    // Each batch picks a row of its own [3, 4] slice.
+    let indices = Tensor::new(&[[[2i64]], [[-3]]], &Device::Cpu)?;
+    let z = eval(indices, 1)?;
+    assert_eq!(z.dims(), &[2, 1, 4]);
+    assert_eq!(
+        z.to_vec3::<f32>()?,
+        vec![vec![vec![8., 9., 10., 11.]], vec![vec![12., 13., 14., 15.]]]
+    );
+
+    // Full index tuples within each batch select single elements.
+    let indices = Tensor::new(&[[[0i64, 1], [2, 3]], [[1, 0], [0, 0]]], &Device::Cpu)?;
+    let z = eval(indices, 1)?;
+    assert_eq!(z.to_vec2::<f32>()?, vec![vec![1., 11.], vec![16., 12.]]);
+
+    // Without batch dims, the indices address the leading dims of data.
+    let indices = Tensor::new(&[[1i64, 2], [0, 1]], &Device::Cpu)?;
+    let z = eval(indices, 0)?;
+    assert_eq!(
+        z.to_vec2::<f32>()?,
+        vec![vec![20., 21., 22., 23.], vec![4., 5., 6., 7.]]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_softmax_large_logits() -> Result<()> {
+    let xs = Tensor::new(
+        &[[1000f32, 0., -1000.], [1000., 1000., 1000.]],
+        &Device::Cpu,
+    )?;
+    for (op_type, axis) in [("Softmax", 0), ("Softmax", -2), ("LogSoftmax", 0)] {
+        let model = create_single_node_model(create_node(
+            op_type,
+            &[INPUT_X],
+            vec![int_attr("axis", axis)],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        let z = eval_single_output(&model, inputs)?;
+        let z = if op_type == "LogSoftmax" { z.exp()? } else { z };
+        let z = z.to_vec2::<f32>()?;
+        assert!(z.iter().flatten().all(|v| v.is_finite()), "{op_type} {z:?}");
+        assert_eq!(z, vec![vec![0.5, 0., 0.], vec![0.5, 1., 1.]]);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_affine_grid() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::arange(0f32, 12., dev)?.reshape((1, 1, 3, 4))?;
+    let theta = Tensor::new(&[[[1f32, 0., 0.], [0., 1., 0.]]], dev)?;
+    let size = Tensor::new(&[1i64, 1, 3, 4], dev)?;
+    for align_corners in [0, 1] {
+        let affine_grid = NodeProto {
+            op_type: "AffineGrid".to_string(),
+            name: "affine_grid".to_string(),
+            input: vec!["theta".to_string(), "size".to_string()],
+            output: vec!["grid".to_string()],
+            attribute: vec![int_attr("align_corners", align_corners)],
+            ..NodeProto::default()
+        };
+        let grid_sample = create_node(
+            "GridSample",
+            &[INPUT_X, "grid"],
+            vec![int_attr("align_corners", align_corners)],
+        );
+        let model = create_model_proto_with_graph(Some(GraphProto {
+            node: vec![affine_grid, grid_sample],
+            output: vec![
+                ValueInfoProto {
+                    name: "grid".to_string(),
+                    ..ValueInfoProto::default()
+                },
+                ValueInfoProto {
+                    name: OUTPUT_Z.to_string(),
+                    ..ValueInfoProto::default()
+                },
+            ],
+            ..GraphProto::default()
+        }));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        inputs.insert("theta".to_string(), theta.clone());
+        inputs.insert("size".to_string(), size.clone());
+        let mut outputs = candle_onnx::simple_eval(&model, to_values(inputs))?;
+        let grid = outputs.remove("grid").unwrap().into_tensor()?;
+        assert_eq!(grid.dims(), &[1, 3, 4, 2]);
+        // The first row of the grid only has x varying.
+        let row = grid.get(0)?.get(0)?.to_vec2::<f32>()?;
+        let expected = if align_corners == 1 {
+            vec![[-1., -1.], [-1. / 3., -1.], [1. / 3., -1.], [1., -1.]]
+        } else {
+            vec![
+                [-0.75, -2. / 3.],
+                [-0.25, -2. / 3.],
+                [0.25, -2. / 3.],
+                [0.75, -2. / 3.],
+            ]
+        };
+        for (v, e) in row.iter().zip(expected.iter()) {
+            assert!(
+                (v[0] - e[0]).abs() < 1e-6 && (v[1] - e[1]).abs() < 1e-6,
+                "{row:?}"
+            );
+        }
+        // An identity theta samples the input at its own pixel positions.
+        let z = outputs.remove(OUTPUT_Z).unwrap().into_tensor()?;
+        let diff = (z - &xs)?.abs()?.flatten_all()?.max(0)?.to_vec0::<f32>()?;
+        assert!(diff < 1e-5, "align_corners {align_corners} diff {diff}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_max_unpool() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(
+        &[[[
+            [1f32, 8., 3., 2.],
+            [4., 2., 7., 6.],
+            [9., 0., 1., 5.],
+            [3., 2., 4., 1.],
+        ]]],
+        dev,
+    )?;
+    let attrs = || {
+        vec![
+            ints_attr("kernel_shape", &[2, 2]),
+            ints_attr("strides", &[2, 2]),
+        ]
+    };
+    let model = create_single_node_model(create_node("MaxPool", &[INPUT_X], attrs()));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs.clone());
+    let pooled = eval_single_output(&model, inputs)?;
+    assert_eq!(
+        pooled.get(0)?.get(0)?.to_vec2::<f32>()?,
+        vec![vec![8., 7.], vec![9., 5.]]
+    );
+
+    // The flat positions of the max elements in the MaxPool input.
+    let indices = Tensor::new(&[[[[1i64, 6], [8, 11]]]], dev)?;
+    let model = create_single_node_model(create_node("MaxUnpool", &[INPUT_X, INPUT_Y], attrs()));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), pooled.clone());
+    inputs.insert(INPUT_Y.to_string(), indices.clone());
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(
+        z.get(0)?.get(0)?.to_vec2::<f32>()?,
+        vec![
+            vec![0., 8., 0., 0.],
+            vec![0., 0., 7., 0.],
+            vec![9., 0., 0., 5.],
+            vec![0., 0., 0., 0.]
+        ]
+    );
+
+    // An explicit output shape larger than the inferred one.
+    let model = create_single_node_model(create_node(
+        "MaxUnpool",
+        &[INPUT_X, INPUT_Y, "output_shape"],
+        attrs(),
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), pooled);
+    inputs.insert(INPUT_Y.to_string(), indices);
+    inputs.insert(
+        "output_shape".to_string(),
+        Tensor::new(&[1i64, 1, 5, 5], dev)?,
+    );
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.dims(), &[1, 1, 5, 5]);
+    assert_eq!(z.sum_all()?.to_vec0::<f32>()?, 29.);
+    Ok(())
+}
+
+#[test]
+fn test_max_pool_indices() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(
+        &[[[[1f32, 8., 3., 2.], [4., 2., 7., 6.], [9., 0., 1., 5.]]]],
+        dev,
+    )?;
+    let eval = |storage_order: i64| -> Result<(Tensor, Tensor)> {
+        let mut node = create_node(
+            "MaxPool",
+            &[INPUT_X],
+            vec![
+                ints_attr("kernel_shape", &[2, 2]),
+                int_attr("storage_order", storage_order),
+            ],
+        );
+        node.output.push("indices".to_string());
+        let mut model = create_single_node_model(node);
+        model.graph.as_mut().unwrap().output.push(ValueInfoProto {
+            name: "indices".to_string(),
+            ..ValueInfoProto::default()
+        });
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        let mut outputs = candle_onnx::simple_eval(&model, to_values(inputs))?;
+        let z = outputs.remove(OUTPUT_Z).unwrap().into_tensor()?;
+        let indices = outputs.remove("indices").unwrap().into_tensor()?;
+        Ok((z, indices))
+    };
+
+    // With the default unit strides, the 2x2 windows overlap.
+    let (z, indices) = eval(0)?;
+    assert_eq!(
+        z.get(0)?.get(0)?.to_vec2::<f32>()?,
+        vec![vec![8., 8., 7.], vec![9., 7., 7.]]
+    );
+    assert_eq!(indices.dtype(), candle::DType::I64);
+    assert_eq!(
+        indices.get(0)?.get(0)?.to_vec2::<i64>()?,
+        vec![vec![1, 1, 6], vec![8, 6, 6]]
+    );
+    // The indices locate the max elements in the flattened input.
+    let located = xs
+        .flatten_all()?
+        .index_select(&indices.flatten_all()?, 0)?
+        .reshape(z.shape())?;
+    assert_eq!(
+        located.get(0)?.get(0)?.to_vec2::<f32>()?,
+        z.get(0)?.get(0)?.to_vec2::<f32>()?
+    );
+
+    // Column major positions, e.g. the 7 at row 1 and column 2 is at 2 * 3 + 1.
+    let (_, indices) = eval(1)?;
+    assert_eq!(
+        indices.get(0)?.get(0)?.to_vec2::<i64>()?,
+        vec![vec![3, 3, 7], vec![2, 7, 7]]
+    );
+
+    // Without the indices output, the strides also default to 1 rather than to the kernel size.
+    let model = create_single_node_model(create_node(
+        "MaxPool",
+        &[INPUT_X],
+        vec![ints_attr("kernel_shape", &[2, 2])],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs);
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(
+        z.get(0)?.get(0)?.to_vec2::<f32>()?,
+        vec![vec![8., 8., 7.], vec![9., 7., 7.]]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_softmax_cross_entropy_loss() -> Result<()> {
+    let dev = &Device::Cpu;
+    let scores = Tensor::new(&[[1f32, 2., 3.], [1., 1., 1.], [0., 2., 0.]], dev)?;
+    let labels = Tensor::new(&[2i64, -1, 0], dev)?;
+    let model = create_single_node_model(create_node(
+        "SoftmaxCrossEntropyLoss",
+        &[INPUT_X, INPUT_Y],
+        vec![str_attr("reduction", "mean"), int_attr("ignore_index", -1)],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), scores);
+    inputs.insert(INPUT_Y.to_string(), labels);
+    let z = eval_single_output(&model, inputs)?;
+
+    // The second sample is ignored, the mean is over the two remaining samples.
+    let log_softmax = |xs: &[f32], i: usize| {
+        let sum_exp = xs.iter().map(|x| x.exp()).sum::<f32>();
+        xs[i] - sum_exp.ln()
+    };
+    let expected = -(log_softmax(&[1., 2., 3.], 2) + log_softmax(&[0., 2., 0.], 0)) / 2.;
+    assert!((z.to_vec0::<f32>()? - expected).abs() < 1e-5);
+    Ok(())
+}
+
+#[test]
+fn test_negative_log_likelihood_loss() -> Result<()> {
+    let dev = &Device::Cpu;
+    // Log-probabilities with a shape (N, C, d1) = (2, 3, 2).
+    let log_prob = Tensor::new(
+        &[
+            [[-0.1f32, -2.0], [-1.5, -0.3], [-3.0, -1.2]],
+            [[-0.7, -0.9], [-0.8, -2.5], [-1.1, -0.4]],
+        ],
+        dev,
+    )?;
+    let target = Tensor::new(&[[0i64, 1], [2, 5]], dev)?;
+    let weight = Tensor::new(&[0.5f32, 2., 1.], dev)?;
+    let model = create_single_node_model(create_node(
+        "NegativeLogLikelihoodLoss",
+        &[INPUT_X, INPUT_Y, "weight"],
+        vec![int_attr("ignore_index", 5)],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), log_prob);
+    inputs.insert(INPUT_Y.to_string(), target);
+    inputs.insert("weight".to_string(), weight);
+    let z = eval_single_output(&model, inputs)?;
+
+    // The target 5 is ignored, the weighted mean only uses the other three elements.
+    let expected = (0.5 * 0.1 + 2. * 0.3 + 1. * 1.1) / (0.5 + 2. + 1.);
+    assert!((z.to_vec0::<f32>()? - expected).abs() < 1e-6);
+    Ok(())
+}
+
+#[test]
+fn test_f64_raw_data_initializer() -> Result<()> {
+    use candle_onnx::onnx::{tensor_proto::DataType, TensorProto};
+    let vs = [1.5f64, -2.25, 1e300, f64::MIN_POSITIVE, 0.1, -0.0];
+    let raw_data = vs.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>();
+    let initializer = |name: &str, raw_data: Vec<u8>, double_data: Vec<f64>| TensorProto {
+        name: name.to_string(),
+        dims: vec![2, 3],
+        data_type: DataType::Double.into(),
+        raw_data,
+        double_data,
+        ..TensorProto::default()
+    };
+    let output = |name: &str| ValueInfoProto {
+        name: name.to_string(),
+        ..ValueInfoProto::default()
+    };
+    let model = create_model_proto_with_graph(Some(GraphProto {
+        initializer: vec![
+            initializer("raw", raw_data, vec![]),
+            initializer("double", vec![], vs.to_vec()),
+        ],
+        // The initializers are directly used as the graph outputs.
+        output: vec![output("raw"), output("double")],
+        ..GraphProto::default()
+    }));
+    let mut outputs = candle_onnx::simple_eval(&model, HashMap::new())?;
+    let from_raw = outputs.remove("raw").unwrap().into_tensor()?;
+    let from_double = outputs.remove("double").unwrap().into_tensor()?;
+    assert_eq!(from_raw.dtype(), candle::DType::F64);
+    assert_eq!(from_raw.to_vec2::<f64>()?, from_double.to_vec2::<f64>()?);
+    assert_eq!(from_raw.flatten_all()?.to_vec1::<f64>()?, vs.to_vec());
+
+    // A truncated raw_data is reported rather than silently misread.
+    let mut model = model;
+    let graph = model.graph.as_mut().unwrap();
+    graph.initializer[0].raw_data.pop();
+    assert!(candle_onnx::simple_eval(&model, HashMap::new()).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_swish() -> Result<()> {
+    let xs = Tensor::new(&[[-3f32, -0.5, 0.], [0.5, 2., 10.]], &Device::Cpu)?;
+    for (op_type, alpha) in [("Swish", None), ("SiLU", None), ("Swish", Some(1.702f32))] {
+        let attrs = alpha.map_or(vec![], |a| vec![float_attr("alpha", a)]);
+        let model = create_single_node_model(create_node(op_type, &[INPUT_X], attrs));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        let z = eval_single_output(&model, inputs)?;
+
+        // x * sigmoid(alpha * x) with the sigmoid computed by hand.
+        let alpha = alpha.unwrap_or(1.) as f64;
+        let sigmoid = (xs.affine(-alpha, 0.)?.exp()? + 1.)?.recip()?;
+        let expected = (&xs * sigmoid)?;
+        let diff = (z - expected)?.abs()?.flatten_all()?.max(0)?;
+        assert!(diff.to_vec0::<f32>()? < 1e-6, "{op_type} {alpha}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_skip_layer_normalization() -> Result<()> {
+    let dev = &Device::Cpu;
+    let output = |name: &str| ValueInfoProto {
+        name: name.to_string(),
+        ..ValueInfoProto::default()
+    };
+    let eps = || vec![float_attr("epsilon", 1e-5)];
+    let fused = create_model_proto_with_graph(Some(GraphProto {
+        node: vec![create_graph_node(
+            "SkipLayerNormalization",
+            &["x", "skip", "gamma", "beta", "bias"],
+            &["z", "", "", "sum"],
+            eps(),
+        )],
+        output: vec![output("z"), output("sum")],
+        ..GraphProto::default()
+    }));
+    let unfused = create_model_proto_with_graph(Some(GraphProto {
+        node: vec![
+            create_graph_node("Add", &["x", "skip"], &["x_skip"], vec![]),
+            create_graph_node("Add", &["x_skip", "bias"], &["sum"], vec![]),
+            create_graph_node(
+                "LayerNormalization",
+                &["sum", "gamma", "beta"],
+                &["z"],
+                eps(),
+            ),
+        ],
+        output: vec![output("z"), output("sum")],
+        ..GraphProto::default()
+    }));
+
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    let xs = Tensor::arange(0f32, 24., dev)?.reshape((2, 3, 4))?;
+    inputs.insert("x".to_string(), xs.sin()?);
+    inputs.insert("skip".to_string(), xs.cos()?);
+    inputs.insert(
+        "gamma".to_string(),
+        Tensor::new(&[1f32, 2., 0.5, -1.], dev)?,
+    );
+    inputs.insert(
+        "beta".to_string(),
+        Tensor::new(&[0f32, 0.1, 0.2, 0.3], dev)?,
+    );
+    inputs.insert("bias".to_string(), Tensor::new(&[1f32, -1., 0.5, 0.], dev)?);
+    let mut fused = candle_onnx::simple_eval(&fused, to_values(inputs.clone()))?;
+    let mut unfused = candle_onnx::simple_eval(&unfused, to_values(inputs))?;
+    for name in ["z", "sum"] {
+        let f = fused.remove(name).unwrap().into_tensor()?;
+        let u = unfused.remove(name).unwrap().into_tensor()?;
+        assert_eq!(f.dims(), &[2, 3, 4]);
+        let diff = (f - u)?.abs()?.flatten_all()?.max(0)?.to_vec0::<f32>()?;
+        assert!(diff < 1e-6, "{name} {diff}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_fast_gelu_bias_gelu() -> Result<()> {
+    let xs = [-3f32, -1., -0.25, 0., 0.5, 1., 2.5, 4.];
+    let bias = [0.5f32, -0.5];
+    let input = Tensor::new(&xs, &Device::Cpu)?.reshape((4, 2))?;
+    let bias_t = Tensor::new(&bias, &Device::Cpu)?;
+    let eval = |op_type: &str, with_bias: bool| {
+        let inputs_names: &[&str] = if with_bias {
+            &[INPUT_X, INPUT_Y]
+        } else {
+            &[INPUT_X]
+        };
+        let model = create_single_node_model(create_node(op_type, inputs_names, vec![]));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), input.clone());
+        inputs.insert(INPUT_Y.to_string(), bias_t.clone());
+        eval_single_output(&model, inputs)?
+            .flatten_all()?
+            .to_vec1::<f32>()
+    };
+    let fast_gelu =
+        |x: f64| 0.5 * x * (1. + (0.7978845608028654 * (x + 0.044715 * x * x * x)).tanh());
+    let with_bias = xs
+        .iter()
+        .enumerate()
+        .map(|(i, &x)| (x + bias[i % 2]) as f64)
+        .collect::<Vec<_>>();
+    let check = |zs: Vec<f32>, expected: Vec<f64>| {
+        for (z, e) in zs.iter().zip(expected.iter()) {
+            assert!((*z as f64 - e).abs() < 1e-5, "{zs:?} {expected:?}");
+        }
+    };
+    check(
+        eval("FastGelu", false)?,
+        xs.iter().map(|&x| fast_gelu(x as f64)).collect(),
+    );
+    check(
+        eval("FastGelu", true)?,
+        with_bias.iter().map(|&x| fast_gelu(x)).collect(),
+    );
+    // Reference values for 0.5x(1 + erf(x / sqrt(2))) on x + bias.
+    let gelu_erf = [
+        -0.0155242, -0.1002108, 0.1496766, -0.1542688, 0.8413447, 0.3457312, 2.9959503, 3.4991858,
+    ];
+    check(eval("BiasGelu", true)?, gelu_erf.to_vec());
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#GroupNormalization
+// https://github.com/microsoft/onnxruntime/blob/main/docs/ContribOperators.md#com.microsoft.GroupNorm
+#[test]
+fn test_group_norm() -> Result<()> {
+    let dev = &Device::Cpu;
+    let (n, c, h, w, groups) = (2, 4, 3, 2, 2);
+    let xs = Tensor::arange(0f32, (n * c * h * w) as f32, dev)?
+        .sqr()?
+        .reshape((n, c, h, w))?;
+    let scale = Tensor::new(&[1f32, 2., 0.5, -1.], dev)?;
+    let bias = Tensor::new(&[0f32, 1., -1., 0.5], dev)?;
+    let model = |node: NodeProto| {
+        create_model_proto_with_graph(Some(GraphProto {
+            node: vec![node],
+            output: vec![ValueInfoProto {
+                name: OUTPUT_Z.to_string(),
+                ..ValueInfoProto::default()
+            }],
+            ..GraphProto::default()
+        }))
+    };
+    let eval = |model: &ModelProto, xs: &Tensor, scale: &Tensor, bias: &Tensor| {
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        inputs.insert("scale".to_string(), scale.clone());
+        inputs.insert("bias".to_string(), bias.clone());
+        eval_single_output(model, inputs)
+    };
+
+    // The NCHW op against a direct computation.
+    let onnx_node = create_node(
+        "GroupNormalization",
+        &[INPUT_X, "scale", "bias"],
+        vec![int_attr("num_groups", groups as i64)],
+    );
+    let z = eval(&model(onnx_node.clone()), &xs, &scale, &bias)?;
+    let xv = xs.flatten_all()?.to_vec1::<f32>()?;
+    let zv = z.flatten_all()?.to_vec1::<f32>()?;
+    let (sv, bv) = (scale.to_vec1::<f32>()?, bias.to_vec1::<f32>()?);
+    let group_size = c / groups * h * w;
+    for chunk in 0..n * groups {
+        let vs = &xv[chunk * group_size..(chunk + 1) * group_size];
+        let mean = vs.iter().sum::<f32>() / group_size as f32;
+        let var = vs.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / group_size as f32;
+        for (i, v) in vs.iter().enumerate() {
+            let ch = (chunk % groups) * (c / groups) + i / (h * w);
+            let expected = (v - mean) / (var + 1e-5).sqrt() * sv[ch] + bv[ch];
+            let z = zv[chunk * group_size + i];
+            assert!((z - expected).abs() < 1e-4, "{z} {expected}");
+        }
+    }
+
+    // The channels last fused swish variant matches GroupNormalization followed by SiLU.
+    let silu = NodeProto {
+        op_type: "SiLU".to_string(),
+        input: vec!["gn".to_string()],
+        output: vec![OUTPUT_Z.to_string()],
+        ..NodeProto::default()
+    };
+    let mut gn = onnx_node;
+    gn.output = vec!["gn".to_string()];
+    let reference = create_model_proto_with_graph(Some(GraphProto {
+        node: vec![gn, silu],
+        output: vec![ValueInfoProto {
+            name: OUTPUT_Z.to_string(),
+            ..ValueInfoProto::default()
+        }],
+        ..GraphProto::default()
+    }));
+    let expected = eval(&reference, &xs, &scale, &bias)?;
+    let mut fused = create_node(
+        "GroupNorm",
+        &[INPUT_X, "scale", "bias"],
+        vec![
+            int_attr("groups", groups as i64),
+            int_attr("activation", 1),
+            int_attr("channels_last", 1),
+        ],
+    );
+    fused.domain = "com.microsoft".to_string();
+    let nhwc = xs.permute((0, 2, 3, 1))?.contiguous()?;
+    let z = eval(&model(fused), &nhwc, &scale, &bias)?;
+    assert_eq!(z.dims(), &[n, h, w, c]);
+    let diff = z
+        .permute((0, 3, 1, 2))?
+        .sub(&expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?;
+    assert!(diff.to_vec0::<f32>()? < 1e-5);
+
+    // Before opset 21 scale and bias are per group.
+    let per_group = eval(
+        &model(create_node(
+            "GroupNormalization",
+            &[INPUT_X, "scale", "bias"],
+            vec![int_attr("num_groups", groups as i64)],
+        )),
+        &xs,
+        &Tensor::new(&[2f32, 3.], dev)?,
+        &Tensor::new(&[1f32, -1.], dev)?,
+    )?;
+    let per_channel = eval(
+        &model(create_node(
+            "GroupNormalization",
+            &[INPUT_X, "scale", "bias"],
+            vec![int_attr("num_groups", groups as i64)],
+        )),
+        &xs,
+        &Tensor::new(&[2f32, 2., 3., 3.], dev)?,
+        &Tensor::new(&[1f32, 1., -1., -1.], dev)?,
+    )?;
+    assert_eq!(
+        per_group.flatten_all()?.to_vec1::<f32>()?,
+        per_channel.flatten_all()?.to_vec1::<f32>()?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_quick_gelu() -> Result<()> {
+    let xs = [-3f32, -1., -0.25, 0., 0.5, 1., 2.5, 4.];
+    let quick_gelu = |alpha: Option<f32>| {
+        let attrs = alpha.map(|a| float_attr("alpha", a)).into_iter().collect();
+        let model = create_single_node_model(create_node("QuickGelu", &[INPUT_X], attrs));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), Tensor::new(&xs, &Device::Cpu)?);
+        eval_single_output(&model, inputs)?.to_vec1::<f32>()
+    };
+    for (alpha, expected_alpha) in [(None, 1.702f64), (Some(1.5), 1.5)] {
+        let zs = quick_gelu(alpha)?;
+        for (z, &x) in zs.iter().zip(xs.iter()) {
+            let x = x as f64;
+            let expected = x / (1. + (-expected_alpha * x).exp());
+            assert!((*z as f64 - expected).abs() < 1e-5, "{zs:?}");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_attention() -> Result<()> {
+    let dev = &Device::Cpu;
+    let graph = |node: Vec<NodeProto>| {
+        create_model_proto_with_graph(Some(GraphProto {
+            node,
+            output: vec![ValueInfoProto {
+                name: OUTPUT_Z.to_string(),
+                ..ValueInfoProto::default()
+            }],
+            ..GraphProto::default()
+        }))
+    };
+    let fused = graph(vec![create_graph_node(
+        "Attention",
+        &["x", "w", "bias", "mask_index"],
+        &[OUTPUT_Z],
+        vec![int_attr("num_heads", 2)],
+    )]);
+
+    // The same computation unfused: projection, split, per head attention and merge.
+    let perm = |p: &[i64]| vec![ints_attr("perm", p)];
+    let mut nodes = vec![
+        create_graph_node("MatMul", &["x", "w"], &["qkv"], vec![]),
+        create_graph_node("Add", &["qkv", "bias"], &["qkv_b"], vec![]),
+    ];
+    for (name, p) in [
+        ("q", [0, 2, 1, 3]),
+        ("k", [0, 2, 3, 1]),
+        ("v", [0, 2, 1, 3]),
+    ] {
+        let (starts, ends) = (format!("{name}_starts"), format!("{name}_ends"));
+        nodes.push(create_graph_node(
+            "Slice",
+            &["qkv_b", &starts, &ends, "axes"],
+            &[name],
+            vec![],
+        ));
+        nodes.push(create_graph_node(
+            "Reshape",
+            &[name, "heads_shape"],
+            &[&format!("{name}4")],
+            vec![],
+        ));
+        nodes.push(create_graph_node(
+            "Transpose",
+            &[&format!("{name}4")],
+            &[&format!("{name}t")],
+            perm(&p),
+        ));
+    }
+    nodes.extend([
+        create_graph_node("MatMul", &["qt", "kt"], &["scores"], vec![]),
+        create_graph_node("Mul", &["scores", "scale"], &["scaled"], vec![]),
+        create_graph_node("Add", &["scaled", "additive_mask"], &["masked"], vec![]),
+        create_graph_node(
+            "Softmax",
+            &["masked"],
+            &["probs"],
+            vec![int_attr("axis", -1)],
+        ),
+        create_graph_node("MatMul", &["probs", "vt"], &["attn"], vec![]),
+        create_graph_node("Transpose", &["attn"], &["attn_t"], perm(&[0, 2, 1, 3])),
+        create_graph_node("Reshape", &["attn_t", "out_shape"], &[OUTPUT_Z], vec![]),
+    ]);
+    let unfused = graph(nodes);
+
+    let (b_sz, seq_len, hidden) = (2, 4, 6);
+    let xs = Tensor::arange(0f32, (b_sz * seq_len * 5) as f32, dev)?
+        .reshape((b_sz, seq_len, 5))?
+        .affine(0.1, -1.)?
+        .sin()?;
+    let w = Tensor::arange(0f32, (5 * 3 * hidden) as f32, dev)?
+        .reshape((5, 3 * hidden))?
+        .affine(0.37, 0.)?
+        .cos()?;
+    let bias = Tensor::arange(0f32, (3 * hidden) as f32, dev)?.affine(0.05, -0.4)?;
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert("x".to_string(), xs);
+    inputs.insert("w".to_string(), w);
+    inputs.insert("bias".to_string(), bias);
+
+    // Key padding, the last key of the first sequence and the last two of the second are masked.
+    let mut fused_inputs = inputs.clone();
+    let mask_index = Tensor::new(&[[1i64, 1, 1, 0], [1, 1, 0, 0]], dev)?;
+    fused_inputs.insert("mask_index".to_string(), mask_index);
+    let z_fused = eval_single_output(&fused, fused_inputs)?;
+
+    let mut unfused_inputs = inputs;
+    for (name, start) in [("q", 0i64), ("k", 6), ("v", 12)] {
+        unfused_inputs.insert(format!("{name}_starts"), Tensor::new(&[start], dev)?);
+        unfused_inputs.insert(format!("{name}_ends"), Tensor::new(&[start + 6], dev)?);
+    }
+    unfused_inputs.insert("axes".to_string(), Tensor::new(&[2i64], dev)?);
+    unfused_inputs.insert(
+        "heads_shape".to_string(),
+        Tensor::new(&[2i64, 4, 2, 3], dev)?,
+    );
+    unfused_inputs.insert("out_shape".to_string(), Tensor::new(&[2i64, 4, 6], dev)?);
+    unfused_inputs.insert("scale".to_string(), Tensor::new(1f32 / 3f32.sqrt(), dev)?);
+    let additive_mask = Tensor::new(&[[0f32, 0., 0., -10000.], [0., 0., -10000., -10000.]], dev)?
+        .reshape((2, 1, 1, 4))?;
+    unfused_inputs.insert("additive_mask".to_string(), additive_mask);
+    let z_unfused = eval_single_output(&unfused, unfused_inputs)?;
+
+    assert_eq!(z_fused.dims(), &[b_sz, seq_len, hidden]);
+    let diff = (z_fused - z_unfused)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_vec0::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+    Ok(())
+}
+
+#[test]
+fn test_matmul_rank_mismatch() -> Result<()> {
+    let dev = &Device::Cpu;
+    let eval = |lhs: &Tensor, rhs: &Tensor| {
+        let model = create_single_node_model(create_node("MatMul", &[INPUT_X, INPUT_Y], vec![]));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), lhs.clone());
+        inputs.insert(INPUT_Y.to_string(), rhs.clone());
+        eval_single_output(&model, inputs)
+    };
+    let lhs = Tensor::arange(0f32, 24., dev)?.reshape((2, 1, 3, 4))?;
+    let rhs = Tensor::arange(0f32, 20., dev)?.reshape((4, 5))?;
+    let z = eval(&lhs, &rhs)?;
+    assert_eq!(z.dims(), &[2, 1, 3, 5]);
+    for b in 0..2 {
+        let expected = lhs.get(b)?.get(0)?.matmul(&rhs)?;
+        assert_eq!(
+            z.get(b)?.get(0)?.to_vec2::<f32>()?,
+            expected.to_vec2::<f32>()?
+        );
+    }
+
+    // Batch dims of different ranks broadcast together.
+    let rhs3 = Tensor::arange(0f32, 60., dev)?.reshape((3, 4, 5))?;
+    assert_eq!(eval(&lhs, &rhs3)?.dims(), &[2, 3, 3, 5]);
+
+    // 1D operands are promoted to matrices and the added dim is removed.
+    let v = Tensor::new(&[1f32, 0., -1., 2.], dev)?;
+    assert_eq!(eval(&lhs, &v)?.dims(), &[2, 1, 3]);
+    let z = eval(&v, &rhs)?;
+    assert_eq!(z.to_vec1::<f32>()?, vec![20., 22., 24., 26., 28.]);
+    assert_eq!(eval(&v, &v)?.to_vec0::<f32>()?, 6.);
+    Ok(())
+}
+
+#[test]
+fn test_error_shapes() -> Result<()> {
+    let eval = |op_type: &str, xs: Tensor, ys: Tensor| {
+        let model = create_single_node_model(create_node(op_type, &[INPUT_X, INPUT_Y], vec![]));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs);
+        inputs.insert(INPUT_Y.to_string(), ys);
+        eval_single_output(&model, inputs).unwrap_err().to_string()
+    };
+    // The batch dims get folded by MatMul but the error reports the original shapes.
+    let err = eval(
+        "MatMul",
+        Tensor::zeros((2, 3, 4), candle::DType::F32, &Device::Cpu)?,
+        Tensor::zeros((5, 6), candle::DType::F32, &Device::Cpu)?,
+    );
+    assert!(err.contains("[2, 3, 4] F32"), "{err}");
+    assert!(err.contains("[5, 6] F32"), "{err}");
+    let err = eval(
+        "Add",
+        Tensor::zeros((2, 3), candle::DType::F32, &Device::Cpu)?,
+        Tensor::zeros(4, candle::DType::F32, &Device::Cpu)?,
+    );
+    assert!(err.contains("with inputs [2, 3] F32, [4] F32"), "{err}");
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Conv
+#[test]
+fn test_conv1d_pads() -> Result<()> {
+    let dev = &Device::Cpu;
+    let conv = |pads: &[i64]| -> Result<Vec<f32>> {
+        let model = create_single_node_model(create_node(
+            "Conv",
+            &[INPUT_X, "w"],
+            vec![ints_attr("pads", pads)],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(
+            INPUT_X.to_string(),
+            Tensor::new(&[[[1f32, 2., 3., 4.]]], dev)?,
+        );
+        inputs.insert("w".to_string(), Tensor::new(&[[[1f32, 1., 1.]]], dev)?);
+        eval_single_output(&model, inputs)?
+            .flatten_all()?
+            .to_vec1::<f32>()
+    };
+    // The begin and end pads differ, [0, 1, 2, 3, 4, 0, 0] then [0, 0, 1, 2, 3, 4, 0].
+    assert_eq!(conv(&[1, 2])?, [3., 6., 9., 7., 4.]);
+    assert_eq!(conv(&[2, 1])?, [1., 3., 6., 9., 7.]);
+    // Symmetric pads take the fast path.
+    assert_eq!(conv(&[1, 1])?, [3., 6., 9., 7.]);
+    assert_eq!(conv(&[0, 0])?, [6., 9.]);
+    Ok(())
+}
+
+#[test]
+fn test_gemm() -> Result<()> {
+    let a = Tensor::new(&[[1f32, 2., 3.], [4., 5., 6.]], &Device::Cpu)?;
+    let b = Tensor::new(&[[1f32, 0.], [0., 1.], [1., 1.]], &Device::Cpu)?;
+    // a @ b = [[4, 5], [10, 11]]
+    for (c, expected) in [
+        (
+            Tensor::new(&[1f32, 2.], &Device::Cpu)?,
+            [[5f32, 7.], [11., 13.]],
+        ),
+        (
+            Tensor::new(&[[1f32, 2.]], &Device::Cpu)?,
+            [[5., 7.], [11., 13.]],
+        ),
+        (
+            Tensor::new(&[[1f32], [2.]], &Device::Cpu)?,
+            [[5., 6.], [12., 13.]],
+        ),
+        (
+            Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?,
+            [[5., 7.], [13., 15.]],
+        ),
+    ] {
+        let model = create_single_node_model(create_node("Gemm", &[INPUT_X, INPUT_Y, "c"], vec![]));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), a.clone());
+        inputs.insert(INPUT_Y.to_string(), b.clone());
+        inputs.insert("c".to_string(), c);
+        let z = eval_single_output(&model, inputs)?;
+        assert_eq!(z.to_vec2::<f32>()?, expected);
+    }
+
+    // alpha, beta and the transpositions.
+    let model = create_single_node_model(create_node(
+        "Gemm",
+        &[INPUT_X, INPUT_Y, "c"],
+        vec![
+            float_attr("alpha", 2.),
+            float_attr("beta", 0.5),
+            int_attr("transA", 1),
+            int_attr("transB", 1),
+        ],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), a.t()?);
+    inputs.insert(INPUT_Y.to_string(), b.t()?);
+    inputs.insert("c".to_string(), Tensor::new(&[2f32, 4.], &Device::Cpu)?);
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.to_vec2::<f32>()?, [[9., 12.], [21., 24.]]);
+
+    // C is optional.
+    let model = create_single_node_model(create_node("Gemm", &[INPUT_X, INPUT_Y], vec![]));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), a);
+    inputs.insert(INPUT_Y.to_string(), b);
+    let z = eval_single_output(&model, inputs.clone())?;
+    assert_eq!(z.to_vec2::<f32>()?, [[4., 5.], [10., 11.]]);
+
+    // A [1, M, K] input is treated as [M, K] and the output keeps the leading dim.
+    let mut batched = inputs.clone();
+    batched.insert(INPUT_X.to_string(), inputs[INPUT_X].unsqueeze(0)?);
+    let z = eval_single_output(&model, batched)?;
+    assert_eq!(z.dims(), &[1, 2, 2]);
+    assert_eq!(z.squeeze(0)?.to_vec2::<f32>()?, [[4., 5.], [10., 11.]]);
+    // Actual batches are not supported.
+    let mut batched = inputs.clone();
+    let a = &inputs[INPUT_X];
+    batched.insert(INPUT_X.to_string(), Tensor::stack(&[a, a], 0)?);
+    assert!(eval_single_output(&model, batched).is_err());
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Einsum
+#[test]
+fn test_einsum() -> Result<()> {
+    let dev = &Device::Cpu;
+    let eval = |equation: &str, inputs: &[&Tensor]| -> Result<Tensor> {
+        let names = ["x", "y", "w"];
+        let model = create_single_node_model(create_node(
+            "Einsum",
+            &names[..inputs.len()],
+            vec![str_attr("equation", equation)],
+        ));
+        let inputs = names
+            .iter()
+            .zip(inputs.iter())
+            .map(|(n, t)| (n.to_string(), (*t).clone()))
+            .collect();
+        eval_single_output(&model, inputs)
+    };
+    let a = Tensor::arange(0f32, 24., dev)?.reshape((2, 3, 4))?;
+    let b = Tensor::arange(0f32, 40., dev)?.reshape((2, 4, 5))?;
+    let z = eval("...ij,...jk->...ik", &[&a, &b])?;
+    assert_eq!(z.dims(), &[2, 3, 5]);
+    assert_eq!(z.to_vec3::<f32>()?, a.matmul(&b)?.to_vec3::<f32>()?);
+
+    // Implicit output, the ellipsis dims come first followed by the sorted unique letters.
+    let z = eval("...ji,...jk", &[&a.transpose(1, 2)?, &b])?;
+    assert_eq!(z.to_vec3::<f32>()?, a.matmul(&b)?.to_vec3::<f32>()?);
+
+    // Transpose, reductions and outer products.
+    let m = Tensor::new(&[[1f32, 2., 3.], [4., 5., 6.]], dev)?;
+    let z = eval("ij->ji", &[&m])?;
+    assert_eq!(z.to_vec2::<f32>()?, [[1., 4.], [2., 5.], [3., 6.]]);
+    assert_eq!(eval("ij->", &[&m])?.to_vec0::<f32>()?, 21.);
+    assert_eq!(eval("ij->i", &[&m])?.to_vec1::<f32>()?, [6., 15.]);
+    let v = Tensor::new(&[1f32, 2.], dev)?;
+    let z = eval("i,j->ij", &[&v, &v])?;
+    assert_eq!(z.to_vec2::<f32>()?, [[1., 2.], [2., 4.]]);
+    let z = eval("i,ij,j->", &[&v, &m.narrow(1, 0, 2)?, &v])?;
+    assert_eq!(z.to_vec0::<f32>()?, 1. + 4. + 2. * 4. + 4. * 5.);
+
+    // The ellipsis dims broadcast.
+    let c = Tensor::arange(0f32, 20., dev)?.reshape((1, 4, 5))?;
+    let z = eval("...ij,...jk->...ik", &[&a, &c])?;
+    assert_eq!(
+        z.to_vec3::<f32>()?,
+        a.broadcast_matmul(&c)?.to_vec3::<f32>()?
+    );
+
+    // Repeated indices within a term take the diagonal.
+    let sq = Tensor::arange(0f32, 9., dev)?.reshape((3, 3))?;
+    assert_eq!(eval("ii->i", &[&sq])?.to_vec1::<f32>()?, [0., 4., 8.]);
+    assert_eq!(eval("ii", &[&sq])?.to_vec0::<f32>()?, 12.);
+    let bsq = Tensor::arange(0f32, 18., dev)?.reshape((2, 3, 3))?;
+    let z = eval("bii->ib", &[&bsq])?;
+    assert_eq!(z.to_vec2::<f32>()?, [[0., 9.], [4., 13.], [8., 17.]]);
+    assert!(eval("ii->i", &[&m]).is_err());
+    Ok(())
+}
+
+// https://github.com/microsoft/onnxruntime/blob/main/docs/ContribOperators.md#com.microsoft.GatherBlockQuantized
+#[test]
+fn test_gather_block_quantized() -> Result<()> {
+    let dev = &Device::Cpu;
+    // A [3, 8] table of 4-bit values quantized in blocks of 4 along the last dim.
+    let q: Vec<Vec<u8>> = (0..3)
+        .map(|r| (0..8).map(|c| ((r * 8 + c) * 5 % 16) as u8).collect())
+        .collect();
+    let zp: Vec<Vec<u8>> = vec![vec![8, 3], vec![0, 15], vec![7, 9]];
+    let scales: Vec<Vec<f32>> = vec![vec![0.5, 0.25], vec![1., 2.], vec![0.125, 4.]];
+    let pack = |vs: &[u8]| -> Vec<u8> {
+        vs.chunks(2)
+            .map(|c| c[0] | (c.get(1).copied().unwrap_or(0) << 4))
+            .collect()
+    };
+    let data = q.iter().flat_map(|r| pack(r)).collect::<Vec<_>>();
+    let data = Tensor::from_vec(data, (3, 4), dev)?;
+    let zero_points = zp.iter().flat_map(|r| pack(r)).collect::<Vec<_>>();
+    let zero_points = Tensor::from_vec(zero_points, (3, 1), dev)?;
+    let scales_t = Tensor::new(scales.clone(), dev)?;
+    let dequantize = |r: usize, zp: Option<&[u8]>| -> Vec<f32> {
+        (0..8)
+            .map(|c| {
+                let z = zp.map_or(8., |zp| zp[c / 4] as f32);
+                (q[r][c] as f32 - z) * scales[r][c / 4]
+            })
+            .collect()
+    };
+
+    let attrs = vec![int_attr("block_size", 4), int_attr("bits", 4)];
+    let model = create_single_node_model(create_node(
+        "GatherBlockQuantized",
+        &["data", "indices", "scales", "zero_points"],
+        attrs.clone(),
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert("data".to_string(), data.clone());
+    inputs.insert("indices".to_string(), Tensor::new(&[2i64, 0], dev)?);
+    inputs.insert("scales".to_string(), scales_t.clone());
+    inputs.insert("zero_points".to_string(), zero_points);
+    let z = eval_single_output(&model, inputs)?;
+    let expected = vec![dequantize(2, Some(&zp[2])), dequantize(0, Some(&zp[0]))];
+    assert_eq!(z.to_vec2::<f32>()?, expected);
+
+    // Without zero points these default to 8, the indices shape is kept.
+    let model = create_single_node_model(create_node(
+        "GatherBlockQuantized",
+        &["data", "indices", "scales"],
+        attrs,
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert("data".to_string(), data);
+    inputs.insert("indices".to_string(), Tensor::new(&[[-1i64], [1]], dev)?);
+    inputs.insert("scales".to_string(), scales_t);
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.dims(), &[2, 1, 8]);
+    let expected = vec![vec![dequantize(2, None)], vec![dequantize(1, None)]];
+    assert_eq!(z.to_vec3::<f32>()?, expected);
+    Ok(())
+}
+
+// https://github.com/microsoft/onnxruntime/blob/main/docs/ContribOperators.md#com.microsoft.MatMulNBits
+#[test]
+fn test_matmul_nbits() -> Result<()> {
+    let dev = &Device::Cpu;
+    let (n, k, block_size) = (3usize, 20usize, 16usize);
+    // Each row of the weights is padded to 2 blocks of 16 4-bit values, i.e. 2 blobs of 8 bytes.
+    let q: Vec<Vec<u8>> = (0..n)
+        .map(|r| (0..32).map(|c| ((r * 7 + c * 3) % 16) as u8).collect())
+        .collect();
+    let zp: Vec<Vec<u8>> = vec![vec![8, 5], vec![2, 12], vec![9, 0]];
+    let scales = [0.5f32, 0.25, 0.125, 1., 0.75, 0.0625];
+    let pack = |vs: &[u8]| -> Vec<u8> { vs.chunks(2).map(|c| c[0] | (c[1] << 4)).collect() };
+    let b = q.iter().flat_map(|r| pack(r)).collect::<Vec<_>>();
+    let b = Tensor::from_vec(b, (n, 2, 8), dev)?;
+    let zero_points = zp.iter().flat_map(|r| pack(r)).collect::<Vec<_>>();
+    let zero_points = Tensor::from_vec(zero_points, n, dev)?;
+    let w = (0..n)
+        .flat_map(|r| {
+            let (q, zp) = (&q[r], &zp[r]);
+            (0..k).map(move |c| (q[c] as f32 - zp[c / 16] as f32) * scales[r * 2 + c / 16])
+        })
+        .collect::<Vec<_>>();
+    let w = Tensor::from_vec(w, (n, k), dev)?;
+    let a = Tensor::arange(0f32, 40., dev)?
+        .affine(0.1, -2.)?
+        .reshape((2, k))?;
+    let bias = Tensor::new(&[1f32, -1., 0.5], dev)?;
+
+    let model = create_single_node_model(create_node(
+        "MatMulNBits",
+        &["a", "b", "scales", "zero_points", "", "bias"],
+        vec![
+            int_attr("K", k as i64),
+            int_attr("N", n as i64),
+            int_attr("bits", 4),
+            int_attr("block_size", block_size as i64),
+        ],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert("a".to_string(), a.clone());
+    inputs.insert("b".to_string(), b);
+    inputs.insert("scales".to_string(), Tensor::new(&scales, dev)?);
+    inputs.insert("zero_points".to_string(), zero_points);
+    inputs.insert("bias".to_string(), bias.clone());
+    let z = eval_single_output(&model, inputs)?;
+    let expected = a.matmul(&w.t()?)?.broadcast_add(&bias)?;
+    assert_eq!(z.dims(), &[2, n]);
+    let diff = (z - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_vec0::<f32>()? < 1e-4);
+    Ok(())
+}
+
+// https://github.com/microsoft/onnxruntime/blob/main/docs/ContribOperators.md#com.microsoft.RotaryEmbedding
+#[test]
+fn test_rotary_embedding() -> Result<()> {
+    let dev = &Device::Cpu;
+    // A (B, S, N * H) input with 2 heads of size 4.
+    let (s, n, h) = (3usize, 2usize, 4usize);
+    let xs: Vec<f32> = (0..s * n * h).map(|i| (i as f32 * 0.37).sin()).collect();
+    let positions = [1usize, 3, 4];
+    let angle = |p: usize, i: usize| p as f32 / 10f32.powf(i as f32 / 2.);
+    let cos: Vec<f32> = (0..5 * h / 2).map(|j| angle(j / 2, j % 2).cos()).collect();
+    let sin: Vec<f32> = (0..5 * h / 2).map(|j| angle(j / 2, j % 2).sin()).collect();
+
+    for interleaved in [0, 1] {
+        let mut expected = xs.clone();
+        for (si, &p) in positions.iter().enumerate().take(s) {
+            for ni in 0..n {
+                let base = (si * n + ni) * h;
+                for i in 0..h / 2 {
+                    let (j1, j2) = if interleaved == 1 {
+                        (base + 2 * i, base + 2 * i + 1)
+                    } else {
+                        (base + i, base + i + h / 2)
+                    };
+                    let (c, sn) = (angle(p, i).cos(), angle(p, i).sin());
+                    expected[j1] = xs[j1] * c - xs[j2] * sn;
+                    expected[j2] = xs[j2] * c + xs[j1] * sn;
+                }
+            }
+        }
+        let model = create_single_node_model(create_node(
+            "RotaryEmbedding",
+            &["x", "position_ids", "cos_cache", "sin_cache"],
+            vec![
+                int_attr("interleaved", interleaved),
+                int_attr("num_heads", n as i64),
+            ],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(
+            "x".to_string(),
+            Tensor::from_vec(xs.clone(), (1, s, n * h), dev)?,
+        );
+        inputs.insert(
+            "position_ids".to_string(),
+            Tensor::new(&[[1i64, 3, 4]], dev)?,
+        );
+        inputs.insert(
+            "cos_cache".to_string(),
+            Tensor::from_vec(cos.clone(), (5, h / 2), dev)?,
+        );
+        inputs.insert(
+            "sin_cache".to_string(),
+            Tensor::from_vec(sin.clone(), (5, h / 2), dev)?,
+        );
+        let z = eval_single_output(&model, inputs)?;
+        assert_eq!(z.dims(), &[1, s, n * h]);
+        let z = z.flatten_all()?.to_vec1::<f32>()?;
+        for (a, b) in z.iter().zip(expected.iter()) {
+            assert!(
+                (a - b).abs() < 1e-6,
+                "interleaved {interleaved}: {z:?} {expected:?}"
+            );
+        }
+    }
+
+    // Without num_heads, a hidden size smaller than the rotary dim leaves no head to rotate.
+    let model = create_single_node_model(create_node(
+        "RotaryEmbedding",
+        &["x", "position_ids", "cos_cache", "sin_cache"],
+        vec![],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(
+        "x".to_string(),
+        Tensor::zeros((1, 3, 2), candle::DType::F32, dev)?,
+    );
+    inputs.insert(
+        "position_ids".to_string(),
+        Tensor::new(&[[1i64, 3, 4]], dev)?,
+    );
+    inputs.insert(
+        "cos_cache".to_string(),
+        Tensor::from_vec(cos, (5, h / 2), dev)?,
+    );
+    inputs.insert(
+        "sin_cache".to_string(),
+        Tensor::from_vec(sin, (5, h / 2), dev)?,
+    );
+    assert!(eval_single_output(&model, inputs).is_err());
+    Ok(())
+}
+
+// https://github.com/microsoft/onnxruntime/blob/main/onnxruntime/core/graph/contrib_ops/contrib_defs.cc
+#[test]
+fn test_simplified_layer_normalization() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::arange(0f32, 16., dev)?
+        .affine(0.5, -3.)?
+        .reshape((2, 8))?;
+    let scale = Tensor::arange(1f32, 9., dev)?.affine(0.25, 0.)?;
+    let model = create_single_node_model(create_node(
+        "SimplifiedLayerNormalization",
+        &[INPUT_X, INPUT_Y],
+        vec![int_attr("axis", -1), float_attr("epsilon", 1e-6)],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs.clone());
+    inputs.insert(INPUT_Y.to_string(), scale.clone());
+    let z = eval_single_output(&model, inputs)?;
+
+    // x / sqrt(mean(x^2) + eps) * scale, without subtracting the mean.
+    let rms = (xs.sqr()?.mean_keepdim(1)? + 1e-6)?.sqrt()?;
+    let expected = xs.broadcast_div(&rms)?.broadcast_mul(&scale)?;
+    let diff = (z - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_vec0::<f32>()? < 1e-5);
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Reshape
+#[test]
+fn test_reshape() -> Result<()> {
+    let dev = &Device::Cpu;
+    let eval = |xs: Tensor, shape: &[i64], allow_zero: i64| -> Result<Tensor> {
+        let model = create_single_node_model(create_node(
+            "Reshape",
+            &[INPUT_X, INPUT_Y],
+            vec![int_attr("allowzero", allow_zero)],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs);
+        inputs.insert(INPUT_Y.to_string(), Tensor::new(shape, dev)?);
+        eval_single_output(&model, inputs)
+    };
+    // Without allowzero, a 0 copies the input dim.
+    let xs = Tensor::arange(0f32, 24., dev)?.reshape((2, 3, 4))?;
+    assert_eq!(eval(xs.clone(), &[0, -1], 0)?.dims(), &[2, 12]);
+    assert_eq!(eval(xs, &[-1, 0, 2], 0)?.dims(), &[4, 3, 2]);
+
+    // With allowzero, a 0 is a zero-sized dim.
+    let xs = Tensor::zeros((2, 0), candle::DType::F32, dev)?;
+    let z = eval(xs.clone(), &[0, 5], 1)?;
+    assert_eq!(z.dims(), &[0, 5]);
+    assert_eq!(z.elem_count(), 0);
+    assert!(eval(xs, &[0, -1], 1).is_err());
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Clip
+#[test]
+fn test_clip_per_channel() -> Result<()> {
+    let dev = &Device::Cpu;
+    let eval = |xs: &Tensor, min: Tensor, max: Tensor| -> Result<Tensor> {
+        let model = create_single_node_model(create_node("Clip", &["x", "min", "max"], vec![]));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert("x".to_string(), xs.clone());
+        inputs.insert("min".to_string(), min);
+        inputs.insert("max".to_string(), max);
+        eval_single_output(&model, inputs)
+    };
+    // A [N, C, H, W] input with 3 channels and the bounds of each channel.
+    let xs = Tensor::arange(-12f32, 12., dev)?.reshape((2, 3, 2, 2))?;
+    let mins = [-1f32, 0., -10.];
+    let maxs = [1f32, 2., 10.];
+    let expected = xs
+        .flatten_all()?
+        .to_vec1::<f32>()?
+        .iter()
+        .enumerate()
+        .map(|(i, &v)| {
+            let c = (i / 4) % 3;
+            v.max(mins[c]).min(maxs[c])
+        })
+        .collect::<Vec<_>>();
+    let z = eval(
+        &xs,
+        Tensor::new(&mins, dev)?.reshape((3, 1, 1))?,
+        Tensor::new(&maxs, dev)?.reshape((3, 1, 1))?,
+    )?;
+    assert_eq!(z.dims(), &[2, 3, 2, 2]);
+    assert_eq!(z.flatten_all()?.to_vec1::<f32>()?, expected);
+
+    // A leading 1 dim on the bounds gives the same result.
+    let z = eval(
+        &xs,
+        Tensor::new(&mins, dev)?.reshape((1, 3, 1, 1))?,
+        Tensor::new(&maxs, dev)?.reshape((1, 3, 1, 1))?,
+    )?;
+    assert_eq!(z.flatten_all()?.to_vec1::<f32>()?, expected);
+
+    // A [W] shaped bound follows the numpy rules and applies to the last dim.
+    let z = eval(
+        &xs,
+        Tensor::new(&[0f32, -100.], dev)?,
+        Tensor::new(&[100f32, 0.], dev)?,
+    )?;
+    let z = z.flatten_all()?.to_vec1::<f32>()?;
+    assert!(z.iter().step_by(2).all(|&v| v >= 0.));
+    assert!(z.iter().skip(1).step_by(2).all(|&v| v <= 0.));
+
+    // Only the max bound, the min input is skipped with an empty name.
+    let model = create_single_node_model(create_node("Clip", &["x", "", "max"], vec![]));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert("x".to_string(), Tensor::new(&[-5f32, 0., 5.], dev)?);
+    inputs.insert("max".to_string(), Tensor::new(1f32, dev)?);
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.to_vec1::<f32>()?, [-5., 0., 1.]);
+    Ok(())
+}
+
+#[test]
+fn test_eval_node() -> Result<()> {
+    let node = create_node("Relu", &[INPUT_X], vec![]);
+    let xs = Tensor::new(&[-1f32, 0., 2.5], &Device::Cpu)?;
+    let mut inputs: HashMap<String, Value> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs.into());
+    let outputs = candle_onnx::eval_node(&node, inputs)?;
+    assert_eq!(outputs.len(), 1);
+    let z = outputs.get(OUTPUT_Z).unwrap().as_tensor()?;
+    assert_eq!(z.to_vec1::<f32>()?, vec![0., 0., 2.5]);
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Gather
+#[test]
+fn test_gather_negative_axis() -> Result<()> {
+    let xs = Tensor::arange(0f32, 6., &Device::Cpu)?.reshape((2, 3))?;
+    let gather = |axis: i64, indices: Tensor| {
+        let model = create_single_node_model(create_node(
+            "Gather",
+            &[INPUT_X, INPUT_Y],
+            vec![int_attr("axis", axis)],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        inputs.insert(INPUT_Y.to_string(), indices);
+        eval_single_output(&model, inputs)
+    };
+    // axis=-1 selects columns, the same as axis=1.
+    let indices = Tensor::new(&[2i64, -3], &Device::Cpu)?;
+    let z = gather(-1, indices.clone())?;
+    assert_eq!(z.to_vec2::<f32>()?, [[2., 0.], [5., 3.]]);
+    assert_eq!(gather(1, indices)?.to_vec2::<f32>()?, z.to_vec2::<f32>()?);
+    // axis=-2 selects rows.
+    let z = gather(-2, Tensor::new(&[[1i64]], &Device::Cpu)?)?;
+    assert_eq!(z.dims(), [1, 1, 3]);
+    assert_eq!(z.flatten_all()?.to_vec1::<f32>()?, [3., 4., 5.]);
+    assert!(gather(-3, Tensor::new(&[0i64], &Device::Cpu)?).is_err());
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#GatherElements
+#[test]
+fn test_gather_elements() -> Result<()> {
+    let xs = Tensor::arange(0f32, 6., &Device::Cpu)?.reshape((2, 3))?;
+    let gather = |axis: i64, indices: Tensor| {
+        let model = create_single_node_model(create_node(
+            "GatherElements",
+            &[INPUT_X, INPUT_Y],
+            vec![int_attr("axis", axis)],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        inputs.insert(INPUT_Y.to_string(), indices);
+        eval_single_output(&model, inputs)
+    };
+    // axis=-1 picks within each row, the indices can be negative too.
+    let indices = Tensor::new(&[[2i64, 0], [-1, 1]], &Device::Cpu)?;
+    let z = gather(-1, indices.clone())?;
+    assert_eq!(z.to_vec2::<f32>()?, [[2., 0.], [5., 4.]]);
+    assert_eq!(gather(1, indices)?.to_vec2::<f32>()?, z.to_vec2::<f32>()?);
+    // Along axis 0 with a single row of indices.
+    let z = gather(0, Tensor::new(&[[1i64, 0, 1]], &Device::Cpu)?)?;
+    assert_eq!(z.to_vec2::<f32>()?, [[3., 1., 5.]]);
+
+    // The indices must have the same rank as data and be in bounds.
+    assert!(gather(-1, Tensor::new(&[0i64, 1], &Device::Cpu)?).is_err());
+    assert!(gather(-1, Tensor::new(&[[3i64], [0]], &Device::Cpu)?).is_err());
+    assert!(gather(-1, Tensor::new(&[[0i64], [0], [0]], &Device::Cpu)?).is_err());
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#If
+#[test]
+fn test_if_optional() -> Result<()> {
+    // Both branches return an optional, only the then branch wraps a value, the x value being
+    // captured from the enclosing scope.
+    let branch = |optional_inputs: &[&str]| AttributeProto {
+        name: String::new(),
+        r#type: AttributeType::Graph.into(),
+        g: Some(GraphProto {
+            node: vec![NodeProto {
+                op_type: "Optional".to_string(),
+                name: "optional".to_string(),
+                input: optional_inputs.iter().map(|s| s.to_string()).collect(),
+                output: vec!["opt".to_string()],
+                ..NodeProto::default()
+            }],
+            output: vec![ValueInfoProto {
+                name: "opt".to_string(),
+                ..ValueInfoProto::default()
+            }],
+            ..GraphProto::default()
+        }),
+        ..AttributeProto::default()
+    };
+    let node = create_node(
+        "If",
+        &["cond"],
+        vec![
+            AttributeProto {
+                name: "then_branch".to_string(),
+                ..branch(&[INPUT_X])
+            },
+            AttributeProto {
+                name: "else_branch".to_string(),
+                ..branch(&[])
+            },
+        ],
+    );
+    let eval = |cond: u8| {
+        let mut inputs: HashMap<String, Value> = HashMap::new();
+        let xs = Tensor::new(&[1f32, 2., 3.], &Device::Cpu)?;
+        inputs.insert(INPUT_X.to_string(), xs.into());
+        inputs.insert("cond".to_string(), Tensor::new(cond, &Device::Cpu)?.into());
+        candle_onnx::eval_node(&node, inputs)?
+            .remove(OUTPUT_Z)
+            .ok_or_else(|| candle::Error::Msg("no output".to_string()))
+    };
+    match eval(1)? {
+        Value::Optional(Some(value)) => {
+            assert_eq!(value.as_tensor()?.to_vec1::<f32>()?, vec![1., 2., 3.])
+        }
+        value => panic!("unexpected value {value:?}"),
+    }
+    match eval(0)? {
+        Value::Optional(None) => (),
+        value => panic!("unexpected value {value:?}"),
+    }
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Cast
+#[test]
+fn test_cast_truncation() -> Result<()> {
+    use candle_onnx::onnx::tensor_proto::DataType;
+    let dev = &Device::Cpu;
+    let eval = |xs: Tensor, to: DataType| -> Result<Tensor> {
+        let model = create_single_node_model(create_node(
+            "Cast",
+            &[INPUT_X],
+            vec![int_attr("to", to as i64)],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs);
+        eval_single_output(&model, inputs)
+    };
+    // Floats are truncated towards zero rather than rounded, whatever the source dtype.
+    let xs = Tensor::new(&[-1.9f32, 1.9, -0.5, 2.5], dev)?;
+    for xs in [
+        xs.clone(),
+        xs.to_dtype(candle::DType::F64)?,
+        xs.to_dtype(candle::DType::F16)?,
+    ] {
+        for to in [DataType::Int64, DataType::Int32] {
+            let z = eval(xs.clone(), to)?;
+            assert_eq!(z.dtype(), candle::DType::I64);
+            assert_eq!(z.to_vec1::<i64>()?, [-1, 1, 0, 2]);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_cast_float8() -> Result<()> {
+    use candle_onnx::onnx::tensor_proto::DataType;
+    let dev = &Device::Cpu;
+    let eval = |to: DataType, saturate: i64| -> Result<Vec<f32>> {
+        let model = create_single_node_model(create_node(
+            "Cast",
+            &[INPUT_X],
+            vec![int_attr("to", to as i64), int_attr("saturate", saturate)],
+        ));
+        let xs = Tensor::new(&[1000f32, -1000., 0.3, -0.0009765625, 17., 0.], dev)?;
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs);
+        eval_single_output(&model, inputs)?.to_vec1::<f32>()
+    };
+    // 0.3 rounds to 0.3125, 2^-10 is too small even for a subnormal and 17 is a tie rounding
+    // down to the even 16.
+    let z = eval(DataType::Float8e4m3fn, 1)?;
+    assert_eq!(z, [448., -448., 0.3125, -0., 16., 0.]);
+    let z = eval(DataType::Float8e4m3fn, 0)?;
+    assert!(z[0].is_nan() && z[1].is_nan());
+    assert_eq!(&z[2..], [0.3125, -0., 16., 0.]);
+
+    // 0.3 rounds to 0.3125 and 2^-10 is a subnormal with 2 mantissa bits.
+    let z = eval(DataType::Float8e5m2, 1)?;
+    assert_eq!(z, [1024., -1024., 0.3125, -0.0009765625, 16., 0.]);
+    let z = eval(DataType::Float8e5m2, 0)?;
+    assert_eq!(z[4], 16.);
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#CumSum
+#[test]
+fn test_cumsum() -> Result<()> {
+    let dev = &Device::Cpu;
+    let eval = |xs: &Tensor, axis: i64, exclusive: i64, reverse: i64| -> Result<Tensor> {
+        let model = create_single_node_model(create_node(
+            "CumSum",
+            &[INPUT_X, INPUT_Y],
+            vec![
+                int_attr("exclusive", exclusive),
+                int_attr("reverse", reverse),
+            ],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        inputs.insert(INPUT_Y.to_string(), Tensor::new(axis, dev)?);
+        eval_single_output(&model, inputs)
+    };
+    let xs = Tensor::new(&[1f32, 2., 3.], dev)?;
+    for (exclusive, reverse, expected) in [
+        (0, 0, [1, 3, 6]),
+        (1, 0, [0, 1, 3]),
+        (0, 1, [6, 5, 3]),
+        (1, 1, [5, 3, 0]),
+    ] {
+        let z = eval(&xs, 0, exclusive, reverse)?;
+        assert_eq!(z.to_vec1::<f32>()?, expected.map(|v| v as f32));
+        let z = eval(&xs.to_dtype(candle::DType::I64)?, 0, exclusive, reverse)?;
+        assert_eq!(z.dtype(), candle::DType::I64);
+        assert_eq!(z.to_vec1::<i64>()?, expected.map(|v| v as i64));
+    }
+
+    // Along a negative axis of a matrix.
+    let xs = Tensor::new(&[[1f32, 2., 3.], [4., 5., 6.]], dev)?;
+    let z = eval(&xs, -2, 0, 0)?;
+    assert_eq!(z.to_vec2::<f32>()?, [[1., 2., 3.], [5., 7., 9.]]);
+    let z = eval(&xs, -1, 1, 1)?;
+    assert_eq!(z.to_vec2::<f32>()?, [[5., 3., 0.], [11., 6., 0.]]);
+
+    // Integers are summed exactly.
+    let big = 1i64 << 60;
+    let z = eval(&Tensor::new(&[big, 1, 1], dev)?, 0, 0, 0)?;
+    assert_eq!(z.to_vec1::<i64>()?, [big, big + 1, big + 2]);
+
+    // Half precision sums do not stall at 2048, where adding 1 is lost in f16.
+    let ones = Tensor::ones(4096, candle::DType::F16, dev)?;
+    let z = eval(&ones, 0, 0, 0)?.to_dtype(candle::DType::F32)?;
+    assert_eq!(z.to_vec1::<f32>()?[4095], 4096.);
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Trilu
+#[test]
+fn test_trilu() -> Result<()> {
+    let dev = &Device::Cpu;
+    let eval = |xs: &Tensor, k: Option<i64>, upper: i64| -> Result<Tensor> {
+        let (node_inputs, k) = match k {
+            None => (vec![INPUT_X], None),
+            Some(k) => (vec![INPUT_X, INPUT_Y], Some(Tensor::new(k, dev)?)),
+        };
+        let model = create_single_node_model(create_node(
+            "Trilu",
+            &node_inputs,
+            vec![int_attr("upper", upper)],
+        ));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        if let Some(k) = k {
+            inputs.insert(INPUT_Y.to_string(), k);
+        }
+        eval_single_output(&model, inputs)
+    };
+    let xs = Tensor::arange(1f32, 33., dev)?.reshape((2, 4, 4))?;
+    let z = eval(&xs, None, 0)?;
+    assert_eq!(
+        z.to_vec3::<f32>()?,
+        [
+            [
+                [1., 0., 0., 0.],
+                [5., 6., 0., 0.],
+                [9., 10., 11., 0.],
+                [13., 14., 15., 16.]
+            ],
+            [
+                [17., 0., 0., 0.],
+                [21., 22., 0., 0.],
+                [25., 26., 27., 0.],
+                [29., 30., 31., 32.]
+            ]
+        ]
+    );
+
+    // The k offset applies to each matrix of the batch.
+    let z = eval(&xs, Some(1), 1)?;
+    assert_eq!(
+        z.get(1)?.to_vec2::<f32>()?,
+        [
+            [0., 18., 19., 20.],
+            [0., 0., 23., 24.],
+            [0., 0., 0., 28.],
+            [0., 0., 0., 0.]
+        ]
+    );
+    let z = eval(&xs, Some(-1), 0)?;
+    assert_eq!(
+        z.get(0)?.to_vec2::<f32>()?,
+        [
+            [0., 0., 0., 0.],
+            [5., 0., 0., 0.],
+            [9., 10., 0., 0.],
+            [13., 14., 15., 0.]
+        ]
+    );
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#GRU
+#[test]
+fn test_gru_bidirectional() -> Result<()> {
+    let dev = &Device::Cpu;
+    let (seq_len, b_sz, input_size, hidden) = (3, 2, 3, 4);
+    let init = |n: usize, scale: f64| -> Result<Tensor> {
+        let xs = Tensor::arange(0f32, n as f32, dev)?;
+        (xs * scale)?.sin()
+    };
+    let xs = init(seq_len * b_sz * input_size, 0.7)?.reshape((seq_len, b_sz, input_size))?;
+    let w = init(2 * 3 * hidden * input_size, 0.3)?.reshape((2, 3 * hidden, input_size))?;
+    let r = init(2 * 3 * hidden * hidden, 0.45)?.reshape((2, 3 * hidden, hidden))?;
+    let b = (init(2 * 6 * hidden, 1.3)? * 0.5)?.reshape((2, 6 * hidden))?;
+
+    // A direct implementation of the GRU equations with linear_before_reset = 0.
+    let reference = |d: usize, reverse: bool| -> Result<(Vec<Tensor>, Tensor)> {
+        let (w, r, b) = (w.get(d)?, r.get(d)?, b.get(d)?);
+        let part = |t: &Tensor, i: usize| t.narrow(0, i * hidden, hidden);
+        let mut h = Tensor::zeros((b_sz, hidden), candle::DType::F32, dev)?;
+        let mut ys = vec![h.clone(); seq_len];
+        let steps: Vec<usize> = if reverse {
+            (0..seq_len).rev().collect()
+        } else {
+            (0..seq_len).collect()
+        };
+        for t in steps {
+            let x = xs.get(t)?;
+            let lin = |i: usize, h: &Tensor| -> Result<Tensor> {
+                x.matmul(&part(&w, i)?.t()?)?
+                    .broadcast_add(&part(&b, i)?)?
+                    .add(
+                        &h.matmul(&part(&r, i)?.t()?)?
+                            .broadcast_add(&part(&b, 3 + i)?)?,
+                    )
+            };
+            let z = candle_nn::ops::sigmoid(&lin(0, &h)?)?;
+            let rr = candle_nn::ops::sigmoid(&lin(1, &h)?)?;
+            let h_tilde = lin(2, &(rr * &h)?)?.tanh()?;
+            h = ((z.affine(-1., 1.)? * h_tilde)? + (z * &h)?)?;
+            ys[t] = h.clone();
+        }
+        Ok((ys, h))
+    };
+    let (forward, forward_h) = reference(0, false)?;
+    let (backward, backward_h) = reference(1, true)?;
+
+    let mut node = create_node(
+        "GRU",
+        &["x", "w", "r", "b"],
+        vec![
+            int_attr("hidden_size", hidden as i64),
+            str_attr("direction", "bidirectional"),
+        ],
+    );
+    node.output = vec!["y".to_string(), "y_h".to_string()];
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert("x".to_string(), xs.clone());
+    inputs.insert("w".to_string(), w.clone());
+    inputs.insert("r".to_string(), r.clone());
+    inputs.insert("b".to_string(), b.clone());
+    let outputs = candle_onnx::eval_node(&node, to_values(inputs))?;
+    let y = outputs.get("y").unwrap().as_tensor()?;
+    let y_h = outputs.get("y_h").unwrap().as_tensor()?;
+    assert_eq!(y.dims(), &[seq_len, 2, b_sz, hidden]);
+    assert_eq!(y_h.dims(), &[2, b_sz, hidden]);
+
+    // Y concatenates the forward and reverse passes along the num_directions dim.
+    let expected = Tensor::stack(
+        &[Tensor::stack(&forward, 0)?, Tensor::stack(&backward, 0)?],
+        1,
+    )?;
+    let diff = (y - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_vec0::<f32>()? < 1e-5);
+    let expected_h = Tensor::stack(&[forward_h, backward_h], 0)?;
+    let diff = (y_h - expected_h)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_vec0::<f32>()? < 1e-5);
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#LSTM
+#[test]
+fn test_lstm_bidirectional() -> Result<()> {
+    let dev = &Device::Cpu;
+    let (seq_len, b_sz, input_size, hidden) = (3, 2, 3, 4);
+    let init = |n: usize, scale: f64| -> Result<Tensor> {
+        let xs = Tensor::arange(0f32, n as f32, dev)?;
+        (xs * scale)?.sin()
+    };
+    let xs = init(seq_len * b_sz * input_size, 0.7)?.reshape((seq_len, b_sz, input_size))?;
+    let w = init(2 * 4 * hidden * input_size, 0.3)?.reshape((2, 4 * hidden, input_size))?;
+    let r = init(2 * 4 * hidden * hidden, 0.45)?.reshape((2, 4 * hidden, hidden))?;
+
+    // A direct implementation of the LSTM equations without biases nor peepholes.
+    let reference = |d: usize, reverse: bool| -> Result<Vec<Tensor>> {
+        let (w, r) = (w.get(d)?, r.get(d)?);
+        let mut h = Tensor::zeros((b_sz, hidden), candle::DType::F32, dev)?;
+        let mut c = h.clone();
+        let mut ys = vec![h.clone(); seq_len];
+        let steps: Vec<usize> = if reverse {
+            (0..seq_len).rev().collect()
+        } else {
+            (0..seq_len).collect()
+        };
+        for t in steps {
+            let x = xs.get(t)?;
+            let gate = |i: usize| -> Result<Tensor> {
+                let w = w.narrow(0, i * hidden, hidden)?;
+                let r = r.narrow(0, i * hidden, hidden)?;
+                x.matmul(&w.t()?)? + h.matmul(&r.t()?)?
+            };
+            let i = candle_nn::ops::sigmoid(&gate(0)?)?;
+            let o = candle_nn::ops::sigmoid(&gate(1)?)?;
+            let f = candle_nn::ops::sigmoid(&gate(2)?)?;
+            c = ((f * &c)? + (i * gate(3)?.tanh()?)?)?;
+            h = (o * c.tanh()?)?;
+            ys[t] = h.clone();
+        }
+        Ok(ys)
+    };
+    let expected = Tensor::stack(
+        &[
+            Tensor::stack(&reference(0, false)?, 0)?,
+            Tensor::stack(&reference(1, true)?, 0)?,
+        ],
+        1,
+    )?;
+
+    let node = create_node(
+        "LSTM",
+        &["x", "w", "r"],
+        vec![
+            int_attr("hidden_size", hidden as i64),
+            str_attr("direction", "bidirectional"),
+        ],
+    );
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert("x".to_string(), xs);
+    inputs.insert("w".to_string(), w);
+    inputs.insert("r".to_string(), r);
+    let outputs = candle_onnx::eval_node(&node, to_values(inputs))?;
+    let y = outputs.get(OUTPUT_Z).unwrap().as_tensor()?;
+    assert_eq!(y.dims(), &[seq_len, 2, b_sz, hidden]);
+    let diff = (y - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_vec0::<f32>()? < 1e-5);
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Dropout
+#[test]
+fn test_dropout() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::arange(1f32, 101., dev)?.reshape((10, 10))?;
+    let eval = |training_mode: u8| -> Result<(Tensor, Tensor)> {
+        let mut node = create_node(
+            "Dropout",
+            &[INPUT_X, "ratio", "training_mode"],
+            vec![int_attr("seed", 42)],
+        );
+        node.output.push("mask".to_string());
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        inputs.insert("ratio".to_string(), Tensor::new(0.5f32, dev)?);
+        inputs.insert(
+            "training_mode".to_string(),
+            Tensor::new(training_mode, dev)?,
+        );
+        let mut outputs = candle_onnx::eval_node(&node, to_values(inputs))?;
+        let z = outputs.remove(OUTPUT_Z).unwrap().into_tensor()?;
+        let mask = outputs.remove("mask").unwrap().into_tensor()?;
+        Ok((z, mask))
+    };
+    // In inference mode, the input is unchanged and the mask keeps everything.
+    let (z, mask) = eval(0)?;
+    assert_eq!(z.to_vec2::<f32>()?, xs.to_vec2::<f32>()?);
+    assert_eq!(mask.dtype(), candle::DType::U8);
+    assert!(mask.flatten_all()?.to_vec1::<u8>()?.iter().all(|&m| m == 1));
+
+    // In training mode, the kept values are scaled by 1 / (1 - ratio).
+    let (z, mask) = eval(1)?;
+    let mask = mask.flatten_all()?.to_vec1::<u8>()?;
+    let kept = mask.iter().filter(|&&m| m == 1).count();
+    assert!(kept > 20 && kept < 80, "{kept}");
+    let z = z.flatten_all()?.to_vec1::<f32>()?;
+    for (i, (z, m)) in z.iter().zip(mask.iter()).enumerate() {
+        assert_eq!(*z, (i + 1) as f32 * 2. * *m as f32);
+    }
+    // The mask only depends on the seed.
+    let (_, mask2) = eval(1)?;
+    assert_eq!(mask2.flatten_all()?.to_vec1::<u8>()?, mask);
+
+    // An empty ratio is rejected.
+    let node = create_node("Dropout", &[INPUT_X, "ratio"], vec![]);
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs.clone());
+    inputs.insert("ratio".to_string(), Tensor::new(&[0f32; 0], dev)?);
+    assert!(candle_onnx::eval_node(&node, to_values(inputs)).is_err());
+    Ok(())
+}
+
+// Shape inputs can use any integer dtype, int32 initializers are decoded as i64 tensors.
+#[test]
+fn test_int32_shape_inputs() -> Result<()> {
+    use candle_onnx::onnx::{tensor_proto::DataType, TensorProto};
+    let dev = &Device::Cpu;
+    let int32_initializer = |vs: &[i32]| TensorProto {
+        name: INPUT_Y.to_string(),
+        dims: vec![vs.len() as i64],
+        data_type: DataType::Int32.into(),
+        int32_data: vs.to_vec(),
+        ..TensorProto::default()
+    };
+    let eval = |op_type: &str, xs: &Tensor, shape: &[i32]| -> Result<Tensor> {
+        let mut model = create_single_node_model(create_node(op_type, &[INPUT_X, INPUT_Y], vec![]));
+        let graph = model.graph.as_mut().unwrap();
+        graph.initializer.push(int32_initializer(shape));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        eval_single_output(&model, inputs)
+    };
+    let xs = Tensor::arange(0f32, 6., dev)?;
+    let z = eval("Reshape", &xs, &[-1, 3])?;
+    assert_eq!(z.to_vec2::<f32>()?, [[0., 1., 2.], [3., 4., 5.]]);
+
+    let xs = Tensor::new(&[[1f32], [2.]], dev)?;
+    let z = eval("Expand", &xs, &[2, 1, 3])?;
+    assert_eq!(
+        z.to_vec3::<f32>()?,
+        [[[1., 1., 1.], [2., 2., 2.]], [[1., 1., 1.], [2., 2., 2.]]]
+    );
+    let z = eval("Tile", &xs, &[2, 2])?;
+    assert_eq!(
+        z.to_vec2::<f32>()?,
+        [[1., 1.], [2., 2.], [1., 1.], [2., 2.]]
+    );
+    assert_eq!(eval("Tile", &xs, &[0, 2])?.dims(), &[0, 2]);
+
+    // The raw_data encoding of int32 values is handled too.
+    let mut shape = int32_initializer(&[]);
+    shape.dims = vec![2];
+    shape.raw_data = [3i32, 2].iter().flat_map(|v| v.to_le_bytes()).collect();
+    let mut model = create_single_node_model(create_node("Reshape", &[INPUT_X, INPUT_Y], vec![]));
+    model.graph.as_mut().unwrap().initializer.push(shape);
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), Tensor::arange(0f32, 6., dev)?);
+    assert_eq!(eval_single_output(&model, inputs)?.dims(), &[3, 2]);
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Det
+#[test]
+fn test_det_inverse() -> Result<()> {
+    let dev = &Device::Cpu;
+    let eval = |op_type: &str, xs: &Tensor| -> Result<Tensor> {
+        let model = create_single_node_model(create_node(op_type, &[INPUT_X], vec![]));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        eval_single_output(&model, inputs)
+    };
+    let m = Tensor::new(&[[4f32, 7.], [2., 6.]], dev)?;
+    let inv = eval("Inverse", &m)?;
+    let expected = [[0.6f32, -0.7], [-0.2, 0.4]];
+    for (row, expected) in inv.to_vec2::<f32>()?.iter().zip(expected.iter()) {
+        for (v, e) in row.iter().zip(expected.iter()) {
+            assert!((v - e).abs() < 1e-6)
+        }
+    }
+    let identity = m.matmul(&inv)?.to_vec2::<f32>()?;
+    for (i, row) in identity.iter().enumerate() {
+        for (j, v) in row.iter().enumerate() {
+            assert!(
+                (v - if i == j { 1. } else { 0. }).abs() < 1e-5,
+                "{identity:?}"
+            )
+        }
+    }
+    assert!((eval("Det", &m)?.to_vec0::<f32>()? - 10.).abs() < 1e-5);
+
+    // A batch of matrices, the second one requires a row swap.
+    let xs = Tensor::new(
+        &[
+            [[2f32, 0., 0.], [0., 3., 0.], [0., 0., 4.]],
+            [[0., 1., 0.], [1., 0., 0.], [0., 0., 1.]],
+        ],
+        dev,
+    )?;
+    assert_eq!(eval("Det", &xs)?.to_vec1::<f32>()?, [24., -1.]);
+    let inv = eval("Inverse", &xs)?;
+    assert_eq!(inv.dims(), &[2, 3, 3]);
+    assert_eq!(inv.get(1)?.to_vec2::<f32>()?, xs.get(1)?.to_vec2::<f32>()?);
+
+    // Singular matrices have a zero determinant and no inverse.
+    let singular = Tensor::new(&[[1f32, 2.], [2., 4.]], dev)?;
+    assert_eq!(eval("Det", &singular)?.to_vec0::<f32>()?, 0.);
+    let err = eval("Inverse", &singular).unwrap_err().to_string();
+    assert!(err.contains("singular"), "{err}");
+
+    // Badly scaled rows are not singular.
+    let scaled = Tensor::new(&[[1e10f64, 0.], [0., 1e-10]], dev)?;
+    assert_eq!(
+        eval("Inverse", &scaled)?.to_vec2::<f64>()?,
+        [[1e-10, 0.], [0., 1e10]]
+    );
+    assert_eq!(eval("Det", &scaled)?.to_vec0::<f64>()?, 1.);
+
+    // A batch of 0x0 matrices.
+    let empty = Tensor::zeros((3, 0, 0), candle::DType::F32, dev)?;
+    assert_eq!(eval("Det", &empty)?.to_vec1::<f32>()?, [1., 1., 1.]);
+    assert_eq!(eval("Inverse", &empty)?.dims(), &[3, 0, 0]);
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#CenterCropPad
+#[test]
+fn test_center_crop_pad() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::arange(0f32, 192., dev)?.reshape((1, 3, 8, 8))?;
+    // Crop the height to 5 and pad the width to 11.
+    let model = create_single_node_model(create_node(
+        "CenterCropPad",
+        &[INPUT_X, INPUT_Y],
+        vec![ints_attr("axes", &[-2, 3])],
+    ));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs.clone());
+    inputs.insert(INPUT_Y.to_string(), Tensor::new(&[5i64, 11], dev)?);
+    let z = eval_single_output(&model, inputs)?;
+    assert_eq!(z.dims(), &[1, 3, 5, 11]);
+    // The crop starts at (8 - 5) / 2 = 1, the pad adds 1 column before and 2 after.
+    let row = z.get(0)?.get(1)?.get(0)?.to_vec1::<f32>()?;
+    let first = 64. + 8.;
+    let mut expected = vec![0f32];
+    expected.extend((0..8).map(|i| first + i as f32));
+    expected.extend([0., 0.]);
+    assert_eq!(row, expected);
+    let last = z.get(0)?.get(2)?.get(4)?.to_vec1::<f32>()?;
+    assert_eq!(last[1], 128. + 5. * 8.);
+
+    // Without axes, the shape covers all the dims.
+    let model = create_single_node_model(create_node("CenterCropPad", &[INPUT_X, INPUT_Y], vec![]));
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(INPUT_X.to_string(), xs);
+    inputs.insert(INPUT_Y.to_string(), Tensor::new(&[1i64, 1, 2, 2], dev)?);
+    let z = eval_single_output(&model, inputs)?;
+    // The middle channel is kept, (3 - 1) / 2 = 1.
+    assert_eq!(z.flatten_all()?.to_vec1::<f32>()?, [91., 92., 99., 100.]);
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Upsample
+#[test]
+fn test_upsample() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[[[[1f32, 2.], [3., 4.]]]], dev)?;
+    let scales = Tensor::new(&[1f32, 1., 2., 2.], dev)?;
+    let eval = |mode: &str, scales: Option<&Tensor>| -> Result<Tensor> {
+        let node = match scales {
+            Some(_) => create_node(
+                "Upsample",
+                &[INPUT_X, INPUT_Y],
+                vec![str_attr("mode", mode)],
+            ),
+            // opset 7 stores the scales in an attribute.
+            None => create_node(
+                "Upsample",
+                &[INPUT_X],
+                vec![
+                    str_attr("mode", mode),
+                    floats_attr("scales", &[1., 1., 2., 3.]),
+                ],
+            ),
+        };
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), xs.clone());
+        if let Some(scales) = scales {
+            inputs.insert(INPUT_Y.to_string(), scales.clone());
+        }
+        eval_single_output(&create_single_node_model(node), inputs)
+    };
+    let z = eval("nearest", Some(&scales))?;
+    assert_eq!(
+        z.get(0)?.get(0)?.to_vec2::<f32>()?,
+        [
+            [1., 1., 2., 2.],
+            [1., 1., 2., 2.],
+            [3., 3., 4., 4.],
+            [3., 3., 4., 4.]
+        ]
+    );
+    // The nearest index is floored, 2/3 maps to 0.
+    let z = eval("nearest", None)?;
+    assert_eq!(
+        z.get(0)?.get(0)?.get(0)?.to_vec1::<f32>()?,
+        [1., 1., 1., 2., 2., 2.]
+    );
+    let z = eval("linear", Some(&scales))?;
+    assert_eq!(
+        z.get(0)?.get(0)?.get(0)?.to_vec1::<f32>()?,
+        [1., 1.5, 2., 2.]
+    );
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Concat
+#[test]
+fn test_concat() -> Result<()> {
+    let dev = &Device::Cpu;
+    let eval = |inputs: &[Tensor], axis: i64| -> Result<Tensor> {
+        let names = ["a", "b", "c"];
+        let model = create_single_node_model(create_node(
+            "Concat",
+            &names[..inputs.len()],
+            vec![int_attr("axis", axis)],
+        ));
+        let inputs = names
+            .iter()
+            .zip(inputs.iter())
+            .map(|(n, t)| (n.to_string(), t.clone()))
+            .collect();
+        eval_single_output(&model, inputs)
+    };
+    let empty = Tensor::zeros((2, 0), candle::DType::F32, dev)?;
+    let xs = Tensor::arange(0f32, 6., dev)?.reshape((2, 3))?;
+    let z = eval(&[empty.clone(), xs.clone()], 1)?;
+    assert_eq!(z.to_vec2::<f32>()?, xs.to_vec2::<f32>()?);
+
+    // Empty inputs with a different rank are skipped too.
+    let z = eval(
+        &[
+            xs.clone(),
+            Tensor::zeros(0, candle::DType::F32, dev)?,
+            xs.clone(),
+        ],
+        0,
+    )?;
+    assert_eq!(z.dims(), &[4, 3]);
+
+    // Negative axes count from the end.
+    let z = eval(&[xs.clone(), empty, xs], -1)?;
+    assert_eq!(
+        z.to_vec2::<f32>()?,
+        [[0., 1., 2., 0., 1., 2.], [3., 4., 5., 3., 4., 5.]]
+    );
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Where
+#[test]
+fn test_where() -> Result<()> {
+    let dev = &Device::Cpu;
+    let eval = |cond: Tensor, x: Tensor, y: Tensor| -> Result<Tensor> {
+        let model = create_single_node_model(create_node("Where", &["cond", "x", "y"], vec![]));
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert("cond".to_string(), cond);
+        inputs.insert("x".to_string(), x);
+        inputs.insert("y".to_string(), y);
+        eval_single_output(&model, inputs)
+    };
+    let cond = Tensor::new(&[[1u8, 0, 1], [0, 0, 1]], dev)?;
+    let xs = Tensor::arange(1f32, 7., dev)?.reshape((2, 3))?;
+    let z = eval(cond.clone(), xs.clone(), Tensor::new(-1f32, dev)?)?;
+    assert_eq!(z.to_vec2::<f32>()?, [[1., -1., 3.], [-1., -1., 6.]]);
+
+    // All three operands broadcast to the common shape.
+    let z = eval(
+        Tensor::new(&[[1u8], [0]], dev)?,
+        Tensor::new(0f32, dev)?,
+        Tensor::new(&[7f32, 8., 9.], dev)?,
+    )?;
+    assert_eq!(z.to_vec2::<f32>()?, [[0., 0., 0.], [7., 8., 9.]]);
+    Ok(())
+}
+
+// https://github.com/onnx/onnx/blob/main/docs/Operators.md#Equal
+#[test]
+fn test_equal_where() -> Result<()> {
+    let dev = &Device::Cpu;
+    let model = |nodes: Vec<NodeProto>| {
+        create_model_proto_with_graph(Some(GraphProto {
+            node: nodes,
+            output: vec![ValueInfoProto {
+                name: OUTPUT_Z.to_string(),
+                ..ValueInfoProto::default()
+            }],
+            ..GraphProto::default()
+        }))
+    };
+    let ids = Tensor::new(&[[3i64, 0, 7], [0, 5, 0]], dev)?;
+    let pad_id = Tensor::new(0i64, dev)?;
+    let eval = |model: &ModelProto| -> Result<Tensor> {
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert("ids".to_string(), ids.clone());
+        inputs.insert("pad_id".to_string(), pad_id.clone());
+        inputs.insert("x".to_string(), Tensor::new(&[1f32, 2., 3.], dev)?);
+        inputs.insert("y".to_string(), Tensor::new(-1f32, dev)?);
+        eval_single_output(model, inputs)
+    };
+    // The mask broadcasts the scalar and is a U8 tensor.
+    let z = eval(&model(vec![create_graph_node(
+        "Equal",
+        &["ids", "pad_id"],
+        &[OUTPUT_Z],
+        vec![],
+    )]))?;
+    assert_eq!(z.dtype(), candle::DType::U8);
+    assert_eq!(z.to_vec2::<u8>()?, [[0, 1, 0], [1, 0, 1]]);
+
+    let z = eval(&model(vec![
+        create_graph_node("Equal", &["ids", "pad_id"], &["mask"], vec![]),
+        create_graph_node("Where", &["mask", "y", "x"], &[OUTPUT_Z], vec![]),
+    ]))?;
+    assert_eq!(z.to_vec2::<f32>()?, [[1., -1., 3.], [-1., 2., -1.]]);
+    Ok(())
+}